@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use chrono_tz::Tz;
+
+use crate::locale::Locale;
+
+/// options used to construct WechatManager
+#[derive(Debug, Clone)]
+pub struct ManagerConfig {
+    // the port set by set_hook_port command is persisted in state_dir and preferred over it
+    pub message_hook_port: u32,
+    pub save_path: String,
+    // buffer events until the bridge acks them and redeliver unacked events after reconnect
+    pub ack_events: bool,
+    pub replay_buffer_size: usize,
+    // directory to persist agent state like muted chats. state is kept in memory only when it is None
+    pub state_dir: Option<String>,
+    // allow debug commands like raw database queries and callback replay
+    pub enable_raw_query: bool,
+    // override the detected `WeChat Files` directory
+    pub wechat_files_dir: Option<String>,
+    // options of the http client shared by hook api calls and media downloads
+    pub http_connect_timeout_secs: u64,
+    pub http_timeout_secs: u64,
+    pub http_pool_max_idle_per_host: usize,
+    // blank avatar urls which fail HEAD requests
+    pub verify_avatars: bool,
+    // language of placeholders and notices generated by agent
+    pub locale: Locale,
+    // interval to detect hooks broken by wechat auto update and logouts. 0 disables both
+    pub health_check_interval_secs: u64,
+    // wechat version set when hooks are broken. nothing is tried if it is None
+    pub spoof_wechat_version: Option<String>,
+    // timezone attached to events for display. event timestamps are always epoch millis
+    pub timezone: Option<Tz>,
+    // kill instance logged out by wechat instead of keeping it for re-login
+    pub drop_on_logout: bool,
+    // hosts which outbound media can be fetched from. any host is allowed if it is empty
+    pub media_hosts: Vec<String>,
+    // media larger than them are replaced by placeholder when received, or rejected when sent. in bytes
+    pub max_inbound_media_size: usize,
+    pub max_outbound_media_size: usize,
+    // retention of message ids persisted in state_dir. 0 max count disables the store
+    pub msg_id_retention_days: u64,
+    pub msg_id_max_count: usize,
+    // journal events in state_dir before delivery and redeliver them after crash
+    pub journal_events: bool,
+    // callback messages with ids seen in the window are dropped. 0 disables it
+    pub dedup_window_secs: u64,
+    // user and group infos are answered from cache within it. 0 disables it
+    pub info_cache_ttl_secs: u64,
+    // acks of sends with idempotency keys are kept for it to answer retries. 0 disables it
+    pub send_idempotency_ttl_secs: u64,
+    // hook api calls and sql queries slower than it are logged as warnings. 0 disables it
+    pub slow_op_threshold_ms: u64,
+    // cached avatars older than it are revalidated before served. 0 disables the cache
+    pub avatar_cache_max_age_secs: u64,
+    // log raw wechat callbacks and their outcomes under save_path. off by default for privacy
+    pub audit_callbacks: bool,
+    // media failed to deliver is resent from its file after reconnecting if it is younger than it. 0 disables resending
+    pub media_resend_max_age_secs: u64,
+    // file recording pids of spawned wechat processes so that the next start kills only them
+    pub pid_file: Option<String>,
+    // translate wechat bracket emoticons like [捂脸] in text, reply and notice content to emoji
+    pub translate_emoticons: bool,
+    // convert emoji in sent text to wechat bracket emoticons which old clients can render
+    pub convert_outgoing_emoji: bool,
+    // members of watched groups are diffed in the interval to emit join and leave events. 0 disables it
+    pub group_member_poll_interval_secs: u64,
+    // contacts of logged in accounts are diffed in the interval to emit friend events. 0 disables it
+    pub contact_poll_interval_secs: u64,
+    // commands running longer are answered with a timeout error. 0 disables it
+    pub command_timeout_secs: u64,
+    // command name -> timeout overriding command_timeout_secs
+    pub command_timeouts: HashMap<String, u64>,
+    // static map url template with {lat} and {lon}. The map is sent as an image replying to the location
+    pub location_thumbnail_url: Option<String>,
+    // received files and videos are sent after wechat finishes downloading them or the timeout. 0 to check only briefly
+    pub file_complete_timeout_secs: u64,
+    // received media of unknown size is read after its size stays unchanged for it. in ms
+    pub media_stable_window_ms: u64,
+    // logical db name -> names wechat versions may open it with. The first opened one is queried
+    pub db_names: HashMap<String, Vec<String>>,
+    // callbacks of different chats handled at the same time. Callbacks of a chat are always in order
+    pub callback_concurrency: usize,
+}
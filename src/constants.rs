@@ -1,11 +1,26 @@
 #![allow(dead_code)]
 
+// bumped when commands or events change incompatibly. Since 2, command data may be tagged with "kind".
+// Since 3, get_group_member_nicknames answers with a batch result
+pub const PROTOCOL_VERSION: u32 = 3;
+// oldest bridge protocol version agent can talk to
+pub const MIN_BRIDGE_PROTOCOL_VERSION: u32 = 1;
+// headers exchanged in websocket upgrade. The min version is what the peer requires at least
+pub const PROTOCOL_VERSION_HEADER: &str = "X-Protocol-Version";
+pub const MIN_PROTOCOL_VERSION_HEADER: &str = "X-Min-Protocol-Version";
+// hooked wechat versions, joined by `,` if instances differ
+pub const WECHAT_VERSION_HEADER: &str = "X-Wechat-Version";
+
 pub fn wechat_api(port: u32, msg_type: u32) -> String {
     format!("http://127.0.0.1:{}/api/?type={}", port, msg_type)
 }
 
+pub const DRIVER_LIB_PATH: &str = "wxDriver64.dll";
+
 pub const DB_MICRO_MSG: &str = "MicroMsg.db";
 pub const DB_OPEN_IM_CONTACT: &str = "OpenIMContact.db";
+// messages are sharded into MSG0.db, MSG1.db, ...
+pub const DB_MSG_SHARD_PREFIX: &str = "MSG";
 
 // login check
 pub const WECHAT_IS_LOGIN: u32 = 0; // 登录检查
@@ -73,8 +88,69 @@ pub const WECHAT_MSG_SEND_XML: u32 = 43; // 发送xml消息
 pub const WECHAT_LOGOUT: u32 = 44; // 退出登录
 pub const WECHAT_GET_TRANSFER: u32 = 45; // 收款
 
+// message type of xml sent to share a card
+pub const WECHAT_CARD_XML_MSG_TYPE: u32 = 42;
+
 pub const DEFAULT_WRITE_WS_RETRY_TIME: u8 = 3;
+pub const DEFAULT_WRITE_WS_RETRY_DELAY_MS: u64 = 200;
 pub const MAX_WECHAT_CALLBACK_FAIL_COUNT: u8 = 0;
 pub const MAX_WS_RECONNECT_COUNT: u32 = 5;
+pub const FAILURE_WEBHOOK_TIMEOUT_SECS: u64 = 10;
+pub const FILE_STABLE_PROBE_INTERVAL_MS: u64 = 500;
+// callback queue of a chat exits after being idle for it
+pub const CHAT_QUEUE_IDLE_SECS: u64 = 60;
+// callbacks queued per chat. The callback reader waits once a chat is full
+pub const CHAT_QUEUE_CAPACITY: usize = 256;
+// images and voices are written by the hook right after the message. Wait for them up to it
+pub const MEDIA_COMPLETE_TIMEOUT_SECS: u64 = 15;
+pub const VOICE_FILE_EXTENSIONS: [&str; 3] = ["amr", "silk", "mp3"];
+pub const MAX_PATH_COMPONENT_LEN: usize = 200;
+pub const MAX_WXID_LEN: usize = 64;
+// raw payloads echoed in error responses, like malformed commands and hook bodies, are truncated to it
+pub const MAX_ERROR_PAYLOAD_LEN: usize = 1024;
+// members required besides self to create a group
+pub const MIN_CREATE_GROUP_MEMBERS: usize = 2;
+pub const DEFAULT_MEDIA_MANIFEST_PAGE_SIZE: usize = 100;
+pub const MAX_MEDIA_MANIFEST_PAGE_SIZE: usize = 500;
+pub const KILL_PROCESS_TIMEOUT_SECS: u64 = 5;
+pub const HOOK_API_PROBE_INTERVAL_MS: u64 = 500;
+pub const HOOK_API_PROBE_ATTEMPTS: u32 = 20;
+pub const LISTEN_PORT_PROBE_COUNT: u32 = 100;
+pub const HOOK_SILENCE_THRESHOLD_SECS: u64 = 600;
+pub const LOGIN_QRCODE_POLL_INTERVAL_SECS: u64 = 2;
+pub const LOGIN_QRCODE_TIMEOUT_SECS: u64 = 300;
+// gap between member queries of watched groups
+pub const GROUP_MEMBER_POLL_GAP_MS: u64 = 1000;
+// wechat writes the accepted friend to contact db shortly after the api returns
+pub const ACCEPTED_FRIEND_POLL_DELAY_MS: u64 = 2000;
+pub const STATE_DB_DIR: &str = "state_db";
+pub const SEEN_MESSAGE_EXPIRE_EVERY: usize = 1024;
+pub const MSG_ID_STORE_PRUNE_EVERY: u64 = 1024;
+pub const RECENT_EVENT_CACHE_SIZE: usize = 4096;
+pub const MAX_FAILED_MEDIA: usize = 1024;
+pub const RECENT_RESPONSE_CACHE_SIZE: usize = 256;
+pub const RECENT_RESPONSE_TTL_SECS: u64 = 300;
+
+pub const DOWNLOAD_TIMEOUT_SECS: u64 = 300;
+pub const MAX_DOWNLOAD_REDIRECTS: usize = 10;
+pub const MAX_DOWNLOAD_ATTEMPTS: u8 = 5;
+pub const MAX_STICKER_SIZE: usize = 10 * 1024 * 1024;
+pub const MAX_AVATAR_SIZE: usize = 5 * 1024 * 1024;
+pub const MAX_LOCATION_THUMBNAIL_SIZE: usize = 2 * 1024 * 1024;
+pub const LOCATION_THUMBNAIL_NAME: &str = "map.png";
+// composite avatar of groups without one
+pub const MAX_GROUP_AVATAR_MEMBERS: usize = 9;
+pub const GROUP_AVATAR_SIZE: u32 = 300;
+pub const GROUP_AVATAR_GAP: u32 = 6;
+pub const GROUP_AVATAR_QUALITY: u8 = 85;
+pub const DEFAULT_MAX_OUTBOUND_MEDIA_SIZE: usize = 200 * 1024 * 1024;
+pub const DEFAULT_MAX_INBOUND_MEDIA_SIZE: usize = 100 * 1024 * 1024;
+
+pub const AUDIT_LOG_MAX_SIZE: u64 = 20 * 1024 * 1024;
+pub const AUDIT_LOG_MAX_FILES: usize = 5;
+
+pub const URL_CHECK_TIMEOUT_SECS: u64 = 5;
+pub const URL_CHECK_CACHE_SECS: u64 = 3600;
+pub const MAX_URL_CHECK_CACHE_SIZE: usize = 4096;
 
 pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/87.0.4280.88 Safari/537.36 Edg/87.0.664.66";
@@ -0,0 +1,110 @@
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use crate::{constants, error::Result, utils, wechat::WechatInstance};
+
+/// options of the self test. They mirror the ones used to run agent
+pub struct DiagnoseOptions {
+    pub message_hook_port: u32,
+    pub save_path: String,
+    pub wechat_files_dir: Option<String>,
+    // start and kill a wechat instance to check injection
+    pub inject: bool,
+}
+
+struct Check {
+    name: &'static str,
+    result: std::result::Result<String, String>,
+    hint: &'static str,
+}
+
+///
+/// check prerequisites of agent without connecting to the bridge, print a checklist
+/// and return whether all checks pass
+///
+pub fn run(opts: &DiagnoseOptions) -> bool {
+    let mut checks = vec![
+        Check {
+            name: "driver lib loadable",
+            result: WechatInstance::check_driver_lib()
+                .map(|_| constants::DRIVER_LIB_PATH.to_string())
+                .map_err(|e| e.to_string()),
+            hint: "put wxDriver64.dll in the working directory and install the VC++ runtime",
+        },
+        Check {
+            name: "wechat installed",
+            result: utils::get_wechat_version()
+                .map(|v| format!("version {}", v))
+                .map_err(|e| e.to_string()),
+            hint: "install the wechat version supported by wxDriver and log in once manually",
+        },
+        Check {
+            name: "wechat files dir resolvable",
+            result: check_wechat_files_dir(opts.wechat_files_dir.as_deref()),
+            hint: "pass --wechat-files-dir with the `WeChat Files` directory",
+        },
+        Check {
+            name: "save path writable",
+            result: check_writable(Path::new(&opts.save_path)),
+            hint: "pass --save-path with a directory writable by agent",
+        },
+        Check {
+            name: "callback port bindable",
+            result: check_bindable(opts.message_hook_port),
+            hint: "stop the process using the port or pass another one by --port",
+        },
+    ];
+    if opts.inject {
+        checks.push(Check {
+            name: "test injection",
+            result: WechatInstance::test_injection(opts.message_hook_port + 1)
+                .map(|pid| format!("pid {}", pid))
+                .map_err(|e| e.to_string()),
+            hint: "run agent as administrator and make sure antivirus does not block the driver",
+        });
+    }
+
+    let mut ok = true;
+    for check in checks.iter() {
+        match &check.result {
+            Ok(detail) => println!("[PASS] {}: {}", check.name, detail),
+            Err(err) => {
+                ok = false;
+                println!("[FAIL] {}: {}", check.name, err);
+                println!("       hint: {}", check.hint);
+            }
+        }
+    }
+    ok
+}
+
+fn check_wechat_files_dir(dir: Option<&str>) -> std::result::Result<String, String> {
+    let dir = match dir {
+        Some(d) => PathBuf::from(d),
+        None => utils::get_wechat_document_dir().map_err(|e| e.to_string())?,
+    };
+    if dir.is_dir() {
+        Ok(dir.display().to_string())
+    } else {
+        Err(format!("{} does not exist", dir.display()))
+    }
+}
+
+fn check_writable(dir: &Path) -> std::result::Result<String, String> {
+    let probe = || -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let file = dir.join(".agent_diagnose");
+        std::fs::write(&file, b"ok")?;
+        std::fs::remove_file(&file)?;
+        Ok(())
+    };
+    probe()
+        .map(|_| dir.display().to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn check_bindable(port: u32) -> std::result::Result<String, String> {
+    TcpListener::bind(format!("127.0.0.1:{}", port))
+        .map(|_| format!("127.0.0.1:{}", port))
+        .map_err(|e| e.to_string())
+}
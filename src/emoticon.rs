@@ -0,0 +1,307 @@
+use std::borrow::Cow;
+
+///
+/// wechat bracket emoticons like `[捂脸]` and the closest unicode emoji. Wechat sends the chinese
+/// code or the english one depending on the client language. The first entry of an emoji is
+/// preferred when converting emoji back to codes
+///
+static EMOTICONS: &[(&str, Option<&str>, &str)] = &[
+    ("微笑", Some("Smile"), "🙂"),
+    ("撇嘴", Some("Grimace"), "😖"),
+    ("色", Some("Drool"), "😍"),
+    ("发呆", Some("Scowl"), "😳"),
+    ("得意", Some("CoolGuy"), "😎"),
+    ("流泪", Some("Sob"), "😭"),
+    ("害羞", Some("Shy"), "☺️"),
+    ("闭嘴", Some("Silent"), "🤐"),
+    ("睡", Some("Sleep"), "😴"),
+    ("大哭", Some("Cry"), "😫"),
+    ("尴尬", Some("Awkward"), "😅"),
+    ("发怒", Some("Angry"), "😡"),
+    ("调皮", Some("Tongue"), "😜"),
+    ("呲牙", Some("Grin"), "😁"),
+    ("惊讶", Some("Surprise"), "😲"),
+    ("难过", Some("Frown"), "🙁"),
+    ("囧", Some("Blush"), "😳"),
+    ("抓狂", Some("Scream"), "😩"),
+    ("吐", Some("Puke"), "🤮"),
+    ("偷笑", Some("Chuckle"), "🤭"),
+    ("愉快", Some("Joyful"), "😊"),
+    ("白眼", Some("Slight"), "🙄"),
+    ("傲慢", Some("Smug"), "😤"),
+    ("困", Some("Drowsy"), "🥱"),
+    ("惊恐", Some("Panic"), "😱"),
+    ("憨笑", Some("Laugh"), "😄"),
+    ("悠闲", Some("Commando"), "😌"),
+    ("咒骂", Some("Scold"), "🤬"),
+    ("疑问", Some("Shocked"), "❓"),
+    ("嘘", Some("Shhh"), "🤫"),
+    ("晕", Some("Dizzy"), "😵"),
+    ("衰", Some("Toasted"), "😞"),
+    ("骷髅", Some("Skull"), "💀"),
+    ("敲打", Some("Hammer"), "🔨"),
+    ("再见", Some("Wave"), "👋"),
+    ("擦汗", Some("Speechless"), "😓"),
+    ("抠鼻", Some("NosePick"), "👃"),
+    ("鼓掌", Some("Clap"), "👏"),
+    ("坏笑", Some("Trick"), "😏"),
+    ("哈欠", Some("Yawn"), "🥱"),
+    ("鄙视", Some("Pooh-pooh"), "😒"),
+    ("委屈", Some("Shrunken"), "🥺"),
+    ("快哭了", Some("TearingUp"), "🥹"),
+    ("阴险", Some("Sly"), "😈"),
+    ("亲亲", Some("Kiss"), "😚"),
+    ("可怜", Some("Whimper"), "🥺"),
+    ("笑脸", None, "😀"),
+    ("生病", None, "😷"),
+    ("脸红", None, "😳"),
+    ("笑哭", None, "😂"),
+    ("破涕为笑", None, "😂"),
+    ("恐惧", None, "😨"),
+    ("失望", None, "😞"),
+    ("无语", None, "😑"),
+    ("菜刀", Some("Cleaver"), "🔪"),
+    ("西瓜", Some("Watermelon"), "🍉"),
+    ("啤酒", Some("Beer"), "🍺"),
+    ("咖啡", Some("Coffee"), "☕"),
+    ("猪头", Some("Pig"), "🐷"),
+    ("玫瑰", Some("Rose"), "🌹"),
+    ("凋谢", Some("Wilt"), "🥀"),
+    ("嘴唇", Some("Lips"), "👄"),
+    ("爱心", Some("Heart"), "❤️"),
+    ("心碎", Some("BrokenHeart"), "💔"),
+    ("蛋糕", Some("Cake"), "🎂"),
+    ("炸弹", Some("Bomb"), "💣"),
+    ("便便", Some("Poop"), "💩"),
+    ("月亮", Some("Moon"), "🌙"),
+    ("太阳", Some("Sun"), "☀️"),
+    ("拥抱", Some("Hug"), "🤗"),
+    ("强", Some("ThumbsUp"), "👍"),
+    ("弱", Some("ThumbsDown"), "👎"),
+    ("握手", Some("Shake"), "🤝"),
+    ("胜利", Some("Peace"), "✌️"),
+    ("抱拳", Some("Fight"), "🙏"),
+    ("勾引", Some("Beckon"), "👈"),
+    ("拳头", Some("Fist"), "👊"),
+    ("OK", None, "👌"),
+    ("合十", None, "🙏"),
+    ("红包", Some("Packet"), "🧧"),
+    ("發", None, "🀅"),
+    ("福", None, "🧧"),
+    ("烟花", None, "🎆"),
+    ("爆竹", None, "🧨"),
+    ("礼物", Some("Gift"), "🎁"),
+    ("庆祝", None, "🎉"),
+    ("鸡", Some("Chicken"), "🐥"),
+    ("跳跳", Some("Waddle"), "💃"),
+    ("发抖", Some("Tremble"), "🥶"),
+    ("转圈", Some("Twirl"), "💫"),
+    ("嘿哈", Some("Hey"), "🤪"),
+    ("捂脸", Some("Facepalm"), "🤦"),
+    ("奸笑", Some("Smirk"), "😏"),
+    ("机智", Some("Smart"), "🤓"),
+    ("皱眉", Some("Concerned"), "😟"),
+    ("耶", Some("Yeah!"), "✌️"),
+    ("吃瓜", Some("Onlooker"), "🍿"),
+    ("加油", Some("GoForIt"), "💪"),
+    ("汗", Some("Sweats"), "😓"),
+    ("天啊", Some("OMG"), "😱"),
+    ("Emm", None, "🤔"),
+    ("社会社会", Some("Respect"), "🫡"),
+    ("旺柴", Some("Doge"), "🐶"),
+    ("好的", Some("NoProb"), "👌"),
+    ("打脸", Some("MyBad"), "🤕"),
+    ("哇", Some("Wow"), "🤩"),
+    ("翻白眼", Some("Boring"), "🙄"),
+    ("666", Some("Awesome"), "👍"),
+    ("让我看看", Some("LetMeSee"), "👀"),
+    ("叹气", Some("Sigh"), "😮‍💨"),
+    ("苦涩", Some("Hurt"), "😣"),
+    ("裂开", Some("Broken"), "💔"),
+];
+
+/// closest unicode emoji of a bracket code without brackets, e.g. "捂脸" or "Facepalm"
+pub fn emoji_of(code: &str) -> Option<&'static str> {
+    EMOTICONS
+        .iter()
+        .find(|(zh, en, _)| *zh == code || *en == Some(code))
+        .map(|(_, _, emoji)| *emoji)
+}
+
+/// chinese bracket code of an emoji with brackets, e.g. "[笑哭]". It is the reverse of `emoji_of`
+pub fn code_of(emoji: &str) -> Option<String> {
+    let emoji = emoji.trim_end_matches('\u{fe0f}');
+    EMOTICONS
+        .iter()
+        .find(|(_, _, e)| e.trim_end_matches('\u{fe0f}') == emoji)
+        .map(|(zh, _, _)| format!("[{}]", zh))
+}
+
+/// replace bracket emoticons in text with emoji. Unknown codes are kept as they are
+pub fn to_emoji(text: &str) -> Cow<'_, str> {
+    if !text.contains('[') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        result.push_str(&rest[..start]);
+        let candidate = &rest[start + 1..];
+        // a nested '[' starts another candidate
+        let emoji = candidate
+            .find([']', '['])
+            .filter(|end| candidate[*end..].starts_with(']'))
+            .and_then(|end| emoji_of(&candidate[..end]).map(|emoji| (end, emoji)));
+        match emoji {
+            Some((end, emoji)) => {
+                result.push_str(emoji);
+                rest = &candidate[end + 1..];
+            }
+            None => {
+                result.push('[');
+                rest = candidate;
+            }
+        }
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
+
+///
+/// replace emoji in the table with bracket codes for old clients rendering them as boxes.
+/// Words starting with '@' are mention placeholders and words with "://" are urls, which are kept
+/// untouched. Emoji joined with modifiers or other emoji are kept as well
+///
+pub fn to_codes(text: &str) -> Cow<'_, str> {
+    if text.is_ascii() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut word_start = true;
+    let mut protected = false;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            word_start = true;
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        if word_start {
+            let word = rest.split(char::is_whitespace).next().unwrap_or_default();
+            protected = word.starts_with('@') || word.contains("://");
+            word_start = false;
+        }
+
+        let matched = match protected || result.ends_with(ZWJ) {
+            true => None,
+            false => longest_emoji(rest),
+        };
+        match matched {
+            Some((len, code)) => {
+                result.push_str(&code);
+                rest = rest[len..].trim_start_matches(VARIATION_SELECTOR);
+            }
+            None => {
+                result.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    Cow::Owned(result)
+}
+
+const ZWJ: char = '\u{200d}';
+const VARIATION_SELECTOR: char = '\u{fe0f}';
+
+// byte length and code of the longest emoji at the beginning of text. Emoji followed by skin tone
+// modifiers or zwj are parts of larger emoji which are not in the table
+fn longest_emoji(text: &str) -> Option<(usize, String)> {
+    let emoji = EMOTICONS
+        .iter()
+        .map(|(_, _, emoji)| emoji.trim_end_matches(VARIATION_SELECTOR))
+        .filter(|emoji| text.starts_with(emoji))
+        .max_by_key(|emoji| emoji.len())?;
+    let next = text[emoji.len()..]
+        .trim_start_matches(VARIATION_SELECTOR)
+        .chars()
+        .next();
+    if matches!(next, Some(c) if c == ZWJ || ('\u{1f3fb}'..='\u{1f3ff}').contains(&c)) {
+        return None;
+    }
+    Some((emoji.len(), code_of(emoji)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chinese_and_english_codes_become_emoji() {
+        assert_eq!(to_emoji("好的[捂脸]"), "好的🤦");
+        assert_eq!(to_emoji("ok [Facepalm][Smile]"), "ok 🤦🙂");
+        assert_eq!(to_emoji("[爱心]"), "❤️");
+    }
+
+    #[test]
+    fn text_without_known_codes_is_kept() {
+        assert!(matches!(to_emoji("no codes"), Cow::Borrowed(_)));
+        assert_eq!(to_emoji("[不存在] [facepalm] []"), "[不存在] [facepalm] []");
+        assert_eq!(to_emoji("a[1] ]["), "a[1] ][");
+        assert_eq!(to_emoji("[微笑"), "[微笑");
+    }
+
+    #[test]
+    fn nested_brackets_start_a_new_code() {
+        assert_eq!(to_emoji("[[微笑]"), "[🙂");
+        assert_eq!(to_emoji("[a[微笑]b]"), "[a🙂b]");
+        assert_eq!(to_emoji("[微[笑]"), "[微[笑]");
+    }
+
+    #[test]
+    fn code_of_reverses_emoji_of() {
+        assert_eq!(code_of("🤦").as_deref(), Some("[捂脸]"));
+        assert_eq!(emoji_of("捂脸"), Some("🤦"));
+        // the first entry wins for emoji shared by several codes
+        assert_eq!(code_of("😂").as_deref(), Some("[笑哭]"));
+        assert_eq!(code_of("🦀"), None);
+    }
+
+    #[test]
+    fn code_of_ignores_variation_selector() {
+        assert_eq!(code_of("❤️").as_deref(), Some("[爱心]"));
+        assert_eq!(code_of("❤").as_deref(), Some("[爱心]"));
+        assert_eq!(code_of("☺").as_deref(), Some("[害羞]"));
+        assert_eq!(to_emoji(&code_of("☀").unwrap()), "☀️");
+    }
+
+    #[test]
+    fn emoji_become_codes() {
+        assert_eq!(to_codes("好的🤦"), "好的[捂脸]");
+        // variation selectors are part of the emoji
+        assert_eq!(to_codes("❤️❤"), "[爱心][爱心]");
+        assert!(matches!(to_codes("plain ascii"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn urls_and_mentions_are_kept() {
+        assert_eq!(
+            to_codes("see https://example.com/🙂 🙂"),
+            "see https://example.com/🙂 [微笑]"
+        );
+        assert_eq!(to_codes("@🙂name 🙂"), "@🙂name [微笑]");
+    }
+
+    #[test]
+    fn zwj_and_skin_tone_sequences_are_kept() {
+        assert_eq!(to_codes("🤦\u{200d}♂\u{fe0f}"), "🤦\u{200d}♂\u{fe0f}");
+        assert_eq!(to_codes("❤\u{fe0f}\u{200d}🔥"), "❤\u{fe0f}\u{200d}🔥");
+        assert_eq!(
+            to_codes("👨\u{200d}❤\u{fe0f}\u{200d}👨"),
+            "👨\u{200d}❤\u{fe0f}\u{200d}👨"
+        );
+        assert_eq!(to_codes("👍🏻 👍"), "👍🏻 [强]");
+    }
+}
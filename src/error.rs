@@ -0,0 +1,113 @@
+use thiserror::Error;
+
+use crate::ws::send::ErrorCode;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot find wechat instance: {0}")]
+    InstanceNotFound(String),
+    #[error("wechat instance crashed. pid = {0}")]
+    InstanceCrashed(u32),
+    #[error("wechat is not logged in")]
+    NotLoggedIn,
+    #[error("inject wechat failed: {0}")]
+    Injection(String),
+    #[error("hook api on port {0} never responds after injection")]
+    HookApiNotReady(u32),
+    #[error("hook api of wechat[pid={0}] refuses connection and recovering it failed: {1}")]
+    HookRecovery(u32, String),
+    #[error("request wechat hook failed: {0}")]
+    Hook(#[from] reqwest::Error),
+    #[error("wechat hook returns error: {0}")]
+    HookResult(String),
+    #[error("media too large: {0}")]
+    TooLarge(String),
+    #[error("download media failed: {0}")]
+    Download(String),
+    #[error("media is incomplete: {0}")]
+    Incomplete(String),
+    #[error("query database failed: {0}")]
+    Db(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("parse failed: {0}")]
+    Parse(String),
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("{0} is not supported by wechat driver")]
+    Unsupported(String),
+    #[error("unsupported command {0} of req {1}. the bridge may be newer than agent")]
+    UnknownCommand(String, i32),
+    #[error("command {0} does not finish in {1} seconds")]
+    CommandTimeout(String, u64),
+    #[error("state db failed: {0}")]
+    Store(String),
+    #[error("write to websocket channel failed: {0}")]
+    Delivery(String),
+    #[error("{0}")]
+    Internal(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Hook(e) if e.is_timeout() || e.is_connect() => ErrorCode::HookUnreachable,
+            Error::HookApiNotReady(_) | Error::HookRecovery(..) => ErrorCode::HookUnreachable,
+            Error::HookResult(_) => ErrorCode::HookFailed,
+            Error::InstanceNotFound(_) | Error::InstanceCrashed(_) => ErrorCode::InstanceNotFound,
+            Error::NotLoggedIn => ErrorCode::NotLoggedIn,
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            Error::TooLarge(_) => ErrorCode::TooLarge,
+            Error::Unsupported(_) | Error::UnknownCommand(..) => ErrorCode::Unsupported,
+            Error::CommandTimeout(..) => ErrorCode::Timeout,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// transport errors may succeed after retrying while validation errors never do
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            Error::Hook(_)
+                | Error::Download(_)
+                | Error::Incomplete(_)
+                | Error::Delivery(_)
+                | Error::Io(_)
+        )
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Self {
+        Error::Store(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<quick_xml::DeError> for Error {
+    fn from(err: quick_xml::DeError) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for Error {
+    fn from(err: std::num::ParseFloatError) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<libloading::Error> for Error {
+    fn from(err: libloading::Error) -> Self {
+        Error::Injection(err.to_string())
+    }
+}
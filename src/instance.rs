@@ -0,0 +1,152 @@
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{error, warn};
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+
+use crate::error::{Error, Result};
+
+const LOCK_FILE: &str = "agent.lock";
+pub const PID_FILE: &str = "wechat.pids";
+
+/// name of the agent instance. Agents with different names can share a machine since their
+/// default paths are namespaced by the name
+pub fn is_valid_instance_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// join name to dir if the instance is named. Unnamed instance keeps the legacy layout
+pub fn namespaced(dir: &Path, name: Option<&str>) -> PathBuf {
+    match name {
+        Some(name) => dir.join(name),
+        None => dir.to_path_buf(),
+    }
+}
+
+/// lock file held by the running agent of an instance name. It records the pid of agent so that
+/// a lock left by a crashed agent is taken over. The file is removed when the lock is dropped
+pub struct InstanceLock {
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(run_dir: &Path) -> Result<InstanceLock> {
+        std::fs::create_dir_all(run_dir)?;
+        let path = run_dir.join(LOCK_FILE);
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    return Ok(InstanceLock { path });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let holder = std::fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|s| s.trim().parse::<u32>().ok());
+                    if let Some(pid) = holder.filter(|pid| is_agent_alive(*pid)) {
+                        return Err(Error::Internal(format!(
+                            "another agent[pid={}] is running with the same instance name. lock file: {}",
+                            pid,
+                            path.display()
+                        )));
+                    }
+                    warn!("take over stale lock file {}", path.display());
+                    std::fs::remove_file(&path)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("remove lock file {} failed: {}", self.path.display(), e);
+        }
+    }
+}
+
+// the pid may be reused by another process after agent crashed
+fn is_agent_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return false;
+    }
+    let s = System::new_all();
+    let current = s
+        .process(Pid::from_u32(std::process::id()))
+        .map(|p| p.name().to_string());
+    match (s.process(Pid::from_u32(pid)), current) {
+        (Some(p), Some(current)) => p.name() == current,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// pids of wechat processes started by the agent instance. They are killed at startup instead of
+/// every wechat process, which may belong to another agent
+#[derive(Clone, Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pids: Arc<Mutex<BTreeSet<u32>>>,
+}
+
+impl PidFile {
+    pub fn open(path: &Path) -> PidFile {
+        let pids = std::fs::read_to_string(path)
+            .map(|s| s.lines().filter_map(|l| l.trim().parse().ok()).collect())
+            .unwrap_or_default();
+        PidFile {
+            path: path.to_path_buf(),
+            pids: Arc::new(Mutex::new(pids)),
+        }
+    }
+
+    pub fn pids(&self) -> Vec<u32> {
+        match self.pids.lock() {
+            Ok(pids) => pids.iter().cloned().collect(),
+            Err(err) => {
+                error!("lock pid file failed: {}", err);
+                vec![]
+            }
+        }
+    }
+
+    pub fn add(&self, pid: u32) {
+        self.update(|pids| {
+            pids.insert(pid);
+        })
+    }
+
+    pub fn remove(&self, pid: u32) {
+        self.update(|pids| {
+            pids.remove(&pid);
+        })
+    }
+
+    pub fn clear(&self) {
+        self.update(|pids| pids.clear())
+    }
+
+    fn update<F: FnOnce(&mut BTreeSet<u32>)>(&self, f: F) {
+        let mut pids = match self.pids.lock() {
+            Ok(pids) => pids,
+            Err(err) => {
+                error!("lock pid file failed: {}", err);
+                return;
+            }
+        };
+        f(&mut pids);
+        let content: String = pids.iter().map(|pid| format!("{}\n", pid)).collect();
+        if let Err(e) = std::fs::write(&self.path, content) {
+            error!("write pid file {} failed: {}", self.path.display(), e);
+        }
+    }
+}
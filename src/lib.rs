@@ -1,4 +1,10 @@
+pub mod config;
 pub mod constants;
+pub mod diagnose;
+pub mod emoticon;
+pub mod error;
+pub mod instance;
+pub mod locale;
 pub mod manager;
 pub mod utils;
 pub mod ws;
@@ -0,0 +1,236 @@
+use std::str::FromStr;
+
+use crate::error::Error;
+
+/// language of the text generated by agent, e.g. placeholders of failed downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    Zh,
+    En,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::Zh, Locale::En];
+
+    pub fn texts(&self) -> &'static Texts {
+        match self {
+            Locale::Zh => &ZH,
+            Locale::En => &EN,
+        }
+    }
+}
+
+impl FromStr for Locale {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zh" | "zh-cn" | "zh_cn" => Ok(Locale::Zh),
+            "en" | "en-us" | "en_us" => Ok(Locale::En),
+            _ => Err(Error::InvalidRequest(format!("unsupported locale {}", s))),
+        }
+    }
+}
+
+pub struct Texts {
+    pub image_download_failed: &'static str,
+    pub voice_download_failed: &'static str,
+    pub video_download_failed: &'static str,
+    pub sticker_download_failed: &'static str,
+    pub file_download_failed: &'static str,
+    pub media_too_large: &'static str,
+    // media which is still being written by wechat when the wait ends
+    pub media_incomplete: &'static str,
+    // media which failed to deliver during outage and cannot be resent
+    pub media_resend_failed: &'static str,
+    pub location_parse_failed: &'static str,
+    pub app_parse_failed: &'static str,
+    pub voip_parse_failed: &'static str,
+    pub revoke_parse_failed: &'static str,
+    pub system_parse_failed: &'static str,
+
+    pub voip_started: &'static str,
+    pub voip_ended: &'static str,
+    // followed by the duration
+    pub voip_completed: &'static str,
+    pub voip_missed: &'static str,
+    pub voip_declined: &'static str,
+    pub voip_cancelled: &'static str,
+    // followed by the status code
+    pub voip_unknown_status: &'static str,
+    // followed by the bubble message
+    pub voip_prefix: &'static str,
+
+    // followed by the amount of money
+    pub transfer_sent: &'static str,
+    pub transfer_accepted: &'static str,
+    pub transfer_refunded: &'static str,
+    pub transfer_expired: &'static str,
+    pub transfer_updated: &'static str,
+
+    // descriptors of quoted media messages
+    pub quote_image: &'static str,
+    pub quote_voice: &'static str,
+    pub quote_video: &'static str,
+    pub quote_sticker: &'static str,
+    pub quote_location: &'static str,
+    pub quote_app: &'static str,
+
+    pub system_message: &'static str,
+    // readable content of known sysmsg types
+    pub system_contents: &'static [(&'static str, &'static str)],
+    pub hook_broken: &'static str,
+    pub logged_out: &'static str,
+    // pat(拍一拍) notice like "A patted B"
+    pub patted: &'static str,
+    pub pat_self: &'static str,
+}
+
+static ZH: Texts = Texts {
+    image_download_failed: "[图片下载失败]",
+    voice_download_failed: "[语音下载失败]",
+    video_download_failed: "[视频下载失败]",
+    sticker_download_failed: "[表情下载失败]",
+    file_download_failed: "[文件下载失败]",
+    media_too_large: "[文件过大]",
+    media_incomplete: "[文件未接收完整]",
+    media_resend_failed: "[媒体消息因连接中断转发失败]",
+    location_parse_failed: "[位置解析失败]",
+    app_parse_failed: "[应用解析失败]",
+    voip_parse_failed: "[VoIP状态解析失败]",
+    revoke_parse_failed: "[撤回消息解析失败]",
+    system_parse_failed: "[系统消息解析失败]",
+
+    voip_started: "VoIP: 发起通话",
+    voip_ended: "VoIP: 通话结束",
+    voip_completed: "VoIP: 通话时长 ",
+    voip_missed: "VoIP: 未接来电",
+    voip_declined: "VoIP: 已拒绝",
+    voip_cancelled: "VoIP: 已取消",
+    voip_unknown_status: "VoIP: 未知状态: ",
+    voip_prefix: "VoIP: ",
+
+    transfer_sent: "[转账] ",
+    transfer_accepted: "[已收款] ",
+    transfer_refunded: "[已退还] ",
+    transfer_expired: "[已过期] ",
+    transfer_updated: "[转账状态更新] ",
+
+    quote_image: "[图片]",
+    quote_voice: "[语音]",
+    quote_video: "[视频]",
+    quote_sticker: "[表情]",
+    quote_location: "[位置]",
+    quote_app: "[文件/链接]",
+
+    system_message: "[系统消息]",
+    system_contents: &[
+        ("sysmsgtemplate", "[群通知]"),
+        ("delchatroommember", "[群成员被移出]"),
+        ("NewXmlChatRoomAccessVerifyApplication", "[入群申请]"),
+        ("mmchatroombarannouncememt", "[群公告已更新]"),
+        ("roomtoolstips", "[群工具已更新]"),
+        ("paymsg", "[支付通知]"),
+        ("functionmsg", "[功能消息]"),
+    ],
+    hook_broken: "[微信钩子失效，微信可能已自动更新，请更新 wxDriver64.dll]",
+    logged_out: "[微信已下线，可能在其他设备登录或被风控，请重新扫码登录]",
+    patted: "拍了拍",
+    pat_self: "我",
+};
+
+static EN: Texts = Texts {
+    image_download_failed: "[image download failed]",
+    voice_download_failed: "[voice download failed]",
+    video_download_failed: "[video download failed]",
+    sticker_download_failed: "[sticker download failed]",
+    file_download_failed: "[file download failed]",
+    media_too_large: "[file too large]",
+    media_incomplete: "[file incomplete]",
+    media_resend_failed: "[media lost while the connection was down]",
+    location_parse_failed: "[location parse failed]",
+    app_parse_failed: "[app message parse failed]",
+    voip_parse_failed: "[VoIP status parse failed]",
+    revoke_parse_failed: "[recall parse failed]",
+    system_parse_failed: "[system message parse failed]",
+
+    voip_started: "VoIP: Started a call",
+    voip_ended: "VoIP: Call ended",
+    voip_completed: "VoIP: Call duration ",
+    voip_missed: "VoIP: Missed call",
+    voip_declined: "VoIP: Call declined",
+    voip_cancelled: "VoIP: Call cancelled",
+    voip_unknown_status: "VoIP: Unknown status: ",
+    voip_prefix: "VoIP: ",
+
+    transfer_sent: "[transfer] ",
+    transfer_accepted: "[transfer accepted] ",
+    transfer_refunded: "[transfer refunded] ",
+    transfer_expired: "[transfer expired] ",
+    transfer_updated: "[transfer updated] ",
+
+    quote_image: "[image]",
+    quote_voice: "[voice]",
+    quote_video: "[video]",
+    quote_sticker: "[sticker]",
+    quote_location: "[location]",
+    quote_app: "[file/link]",
+
+    system_message: "[system message]",
+    system_contents: &[
+        ("sysmsgtemplate", "[group notification]"),
+        ("delchatroommember", "[group member removed]"),
+        (
+            "NewXmlChatRoomAccessVerifyApplication",
+            "[group join request]",
+        ),
+        ("mmchatroombarannouncememt", "[group announcement updated]"),
+        ("roomtoolstips", "[group tools updated]"),
+        ("paymsg", "[payment notification]"),
+        ("functionmsg", "[function message]"),
+    ],
+    hook_broken: "[WeChat hook stopped working. WeChat may have been auto-updated, please update wxDriver64.dll]",
+    logged_out: "[WeChat went offline. It may be logged in on another device or restricted by risk control. Please log in by QR code again]",
+    patted: "patted",
+    pat_self: "you",
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_locale_tags_case_insensitively() {
+        assert_eq!("zh".parse::<Locale>().unwrap(), Locale::Zh);
+        assert_eq!("zh-CN".parse::<Locale>().unwrap(), Locale::Zh);
+        assert_eq!("EN_us".parse::<Locale>().unwrap(), Locale::En);
+        assert!(matches!(
+            "fr".parse::<Locale>(),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn texts_follow_the_locale() {
+        assert_eq!(Locale::Zh.texts().image_download_failed, "[图片下载失败]");
+        assert_eq!(
+            Locale::En.texts().image_download_failed,
+            "[image download failed]"
+        );
+    }
+
+    #[test]
+    fn every_locale_describes_the_same_system_messages() {
+        let kinds = |locale: Locale| {
+            locale
+                .texts()
+                .system_contents
+                .iter()
+                .map(|(kind, _)| *kind)
+                .collect::<Vec<_>>()
+        };
+        for locale in Locale::ALL {
+            assert_eq!(kinds(locale), kinds(Locale::Zh), "{:?}", locale);
+        }
+    }
+}
@@ -1,75 +1,412 @@
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::time::Duration;
 
-use chrono::Utc;
-use log::{warn, LevelFilter};
+use chrono::{Local, Utc};
+use chrono_tz::Tz;
+use log::{warn, LevelFilter, Record};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
 use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
 use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
 use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::config::{Appender, Config, Logger, Root};
-use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::{self, pattern::PatternEncoder, Encode};
 
 use futures_util::{SinkExt, StreamExt};
 use matrix_wechat_agent::utils;
 use tokio::time::sleep;
 use tokio_tungstenite::{
     connect_async,
-    tungstenite::{self, handshake, http::Request, Message},
+    tungstenite::{
+        self, handshake,
+        http::Request,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
 };
 
 use futures_util::{future, pin_mut};
 use log::{debug, error, info};
 use matrix_wechat_agent::{
+    config::ManagerConfig,
     constants,
+    diagnose::{self, DiagnoseOptions},
+    instance::{self, InstanceLock, PidFile},
+    locale::Locale,
     manager::{self, WechatManager},
-    ws::recv::WebsocketMatrixRequest,
+    ws::{self, recv::WebsocketMatrixRequest, CommandType},
 };
 use tokio::sync::broadcast::{self, Receiver};
 
-use clap::Parser;
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, Subcommand};
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    token: String,
-    #[arg(short, long)]
-    addr: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        short,
+        long,
+        help = "yaml file of options keyed by their long names like max_inbound_media_size. command line flags override it"
+    )]
+    config: Option<String>,
+    #[arg(short, long, required = true)]
+    token: Option<String>,
+    #[arg(short, long, required = true)]
+    addr: Option<String>,
     #[arg(short, long, default_value = "23333")]
     port: u32,
+    #[arg(
+        long,
+        help = "name of the agent so that several agents can run on one machine. default save path, log dir and state dir are namespaced by it"
+    )]
+    instance_name: Option<String>,
     #[arg(
         short,
         long,
-        help = "image save path default to $CURRENT_DIR/hook_media"
+        help = "image save path default to $CURRENT_DIR/hook_media, or $CURRENT_DIR/hook_media/$INSTANCE_NAME if named"
     )]
     save_path: Option<String>,
     #[arg(short, long, default_value = "5")]
     buffer_size: u32,
+    #[arg(
+        long,
+        help = "buffer events until the bridge acks them and redeliver them after reconnecting"
+    )]
+    ack_events: bool,
+    #[arg(long, default_value = "1024")]
+    replay_buffer_size: usize,
+    #[arg(long, help = "directory to persist agent state like muted chats")]
+    state_dir: Option<String>,
+    #[arg(
+        long,
+        help = "allow debug commands like raw database queries and callback replay"
+    )]
+    enable_raw_query: bool,
+    #[arg(
+        long,
+        help = "WeChat Files directory. default to the one detected from registry or documents dir"
+    )]
+    wechat_files_dir: Option<String>,
+    #[arg(long, default_value = "5")]
+    http_connect_timeout_secs: u64,
+    #[arg(
+        long,
+        default_value = "30",
+        help = "timeout of wechat hook api requests"
+    )]
+    http_timeout_secs: u64,
+    #[arg(long, default_value = "8")]
+    http_pool_max_idle_per_host: usize,
+    #[arg(long, help = "check avatar urls by HEAD requests and blank dead ones")]
+    verify_avatars: bool,
+    #[arg(
+        long,
+        default_value = "zh",
+        help = "language of placeholders and notices generated by agent. zh or en"
+    )]
+    locale: Locale,
+    #[arg(
+        long,
+        help = "timezone like Asia/Shanghai to display log timestamps in. default to local timezone"
+    )]
+    timezone: Option<Tz>,
+    #[arg(
+        long,
+        default_value = "60",
+        help = "interval to detect hooks broken by wechat auto update and logouts. 0 to disable both"
+    )]
+    health_check_interval_secs: u64,
+    #[arg(
+        long,
+        help = "wechat version like 3.7.0.30 to pretend when hooks are broken"
+    )]
+    spoof_wechat_version: Option<String>,
+    #[arg(
+        long,
+        help = "kill wechat instance forced offline instead of keeping it for re-login by qr code"
+    )]
+    drop_on_logout: bool,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "comma separated hosts which outbound media can be fetched from. subdomains are included. default to any host"
+    )]
+    media_hosts: Vec<String>,
+    #[arg(
+        long,
+        default_value_t = constants::DEFAULT_MAX_INBOUND_MEDIA_SIZE,
+        help = "max bytes of media received from wechat. larger media is replaced by a placeholder"
+    )]
+    max_inbound_media_size: usize,
+    #[arg(
+        long,
+        default_value_t = constants::DEFAULT_MAX_OUTBOUND_MEDIA_SIZE,
+        help = "max bytes of media sent to wechat. larger media is rejected"
+    )]
+    max_outbound_media_size: usize,
+    #[arg(
+        long,
+        default_value = "30",
+        help = "days to keep message ids persisted in state dir for resolving revokes after restart"
+    )]
+    msg_id_retention_days: u64,
+    #[arg(
+        long,
+        default_value = "100000",
+        help = "max message ids persisted in state dir. 0 to disable persisting"
+    )]
+    msg_id_max_count: usize,
+    #[arg(
+        long,
+        help = "journal events in state dir before delivery and redeliver undelivered ones after crash"
+    )]
+    journal_events: bool,
+    #[arg(
+        long,
+        default_value = "86400",
+        help = "drop wechat messages whose ids are seen in the window, like the ones replayed after restart. 0 to disable"
+    )]
+    dedup_window_secs: u64,
+    #[arg(
+        long,
+        default_value = "60",
+        help = "answer user and group info queries from cache within it unless forceRefresh is set. 0 to disable"
+    )]
+    info_cache_ttl_secs: u64,
+    #[arg(
+        long,
+        default_value = "600",
+        help = "answer a resent message with the same idempotency key in the window by the first ack instead of sending it again. 0 to disable"
+    )]
+    send_idempotency_ttl_secs: u64,
+    #[arg(
+        long,
+        default_value = "3000",
+        help = "warn about hook api calls and sql queries slower than it. 0 to disable"
+    )]
+    slow_op_threshold_ms: u64,
+    #[arg(
+        long,
+        default_value = "86400",
+        help = "age after which avatars cached in save path are revalidated. 0 to disable the cache"
+    )]
+    avatar_cache_max_age_secs: u64,
+    #[arg(
+        long,
+        help = "log raw wechat callbacks and the events emitted or drop reasons to rotated files in save path"
+    )]
+    audit_callbacks: bool,
+    #[arg(
+        long,
+        default_value = "3600",
+        help = "resend media which failed to deliver during outage from its file after reconnecting if it is younger than it. 0 to disable"
+    )]
+    media_resend_max_age_secs: u64,
+    #[arg(
+        long,
+        help = "translate wechat emoticons like [捂脸] in received text to unicode emoji"
+    )]
+    translate_emoticons: bool,
+    #[arg(
+        long,
+        help = "convert common emoji in sent text to wechat emoticons like [笑哭] for old clients. urls and mentions are kept"
+    )]
+    convert_outgoing_emoji: bool,
+    #[arg(
+        long,
+        default_value = "0",
+        help = "poll members of groups watched by the bridge in the interval and emit join and leave events. 0 to disable"
+    )]
+    group_member_poll_interval_secs: u64,
+    #[arg(
+        long,
+        default_value = "0",
+        help = "poll contacts of logged in accounts in the interval and emit friend added and removed events. 0 to disable"
+    )]
+    contact_poll_interval_secs: u64,
+    #[arg(
+        long,
+        default_value = "120",
+        help = "answer commands running longer with a timeout error so that the bridge stops waiting. 0 to disable"
+    )]
+    command_timeout_secs: u64,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_command_timeout,
+        default_value = "connect=300,restart_instance=300,send_message=600,get_media_manifest=300",
+        help = "comma separated command=secs overriding command timeout secs for commands which take longer. 0 to disable"
+    )]
+    command_timeouts: Vec<(String, u64)>,
+    #[arg(
+        long,
+        help = "static map url template with {lat} and {lon}. The map of a received location is sent as an image replying to it. disabled by default"
+    )]
+    location_thumbnail_url: Option<String>,
+    #[arg(
+        long,
+        default_value = "300",
+        help = "wait up to it for wechat to finish downloading a received file or video so that it is not sent truncated. 0 to check only briefly"
+    )]
+    file_complete_timeout_secs: u64,
+    #[arg(
+        long,
+        default_value = "2000",
+        help = "received media of unknown size is read after its size stays unchanged for it, so that it is not sent truncated. in ms"
+    )]
+    media_stable_window_ms: u64,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_db_names,
+        default_value = "MicroMsg.db=MicroMsg.db|MicroMsg,OpenIMContact.db=OpenIMContact.db|OpenIMContact",
+        help = "comma separated db=name|name... candidate names of the databases queried by agent, for wechat versions naming them differently"
+    )]
+    db_names: Vec<(String, Vec<String>)>,
+    #[arg(
+        long,
+        default_value = "8",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "wechat callbacks of different chats handled at the same time, so that slow media of a chat does not hold others up. callbacks of a chat are always handled in order"
+    )]
+    callback_concurrency: u16,
+    #[arg(
+        long,
+        default_value_t = constants::MAX_WS_RECONNECT_COUNT,
+        help = "max websocket reconnections in 5 minutes before giving up"
+    )]
+    max_ws_reconnect_count: u32,
+    #[arg(
+        long,
+        help = "exit with non-zero code after giving up reconnecting so that a supervisor can restart agent. Otherwise agent keeps running without websocket"
+    )]
+    exit_on_ws_failure: bool,
+    #[arg(
+        long,
+        help = "url to POST a json notification to after giving up reconnecting"
+    )]
+    failure_webhook: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// check prerequisites like driver lib and callback port without connecting to the bridge
+    Diagnose {
+        #[arg(long, help = "start and kill a wechat instance to check injection")]
+        inject: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() {
-    utils::kill_by_name("WeChat");
-    init_logger();
+    let arg = parse_args();
+    let name = arg.instance_name.as_deref();
+    let cwd = std::env::current_dir().unwrap();
+    let default_save_path = instance::namespaced(&cwd.join("hook_media"), name)
+        .into_os_string()
+        .into_string()
+        .unwrap();
+    let save_path = arg.save_path.clone().unwrap_or(default_save_path);
+    let state_dir = arg
+        .state_dir
+        .as_ref()
+        .map(|dir| instance::namespaced(Path::new(dir), name));
+    // lock and pid files live in state dir, or a per instance run dir if state is not persisted
+    let run_dir = state_dir
+        .clone()
+        .unwrap_or_else(|| cwd.join("run").join(name.unwrap_or("default")));
 
-    let arg = Args::parse();
-    let url = url::Url::parse(&arg.addr).unwrap();
-    info!("parse url {} successfully", arg.addr);
+    if let Some(Command::Diagnose { inject }) = arg.command {
+        let ok = diagnose::run(&DiagnoseOptions {
+            message_hook_port: arg.port,
+            save_path,
+            wechat_files_dir: arg.wechat_files_dir.clone(),
+            inject,
+        });
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    init_logger(arg.timezone, &instance::namespaced(Path::new("log"), name));
+    let _lock = match InstanceLock::acquire(&run_dir) {
+        Ok(lock) => lock,
+        Err(err) => {
+            error!("!!! refuse to start: {}", err);
+            std::process::exit(1);
+        }
+    };
+    // only wechat processes left by the previous run of this instance are killed
+    let pid_file_path = run_dir.join(instance::PID_FILE);
+    let pid_file = PidFile::open(&pid_file_path);
+    let killed = utils::kill_pids(&pid_file.pids(), "WeChat").await;
+    if !killed.is_empty() {
+        info!("kill running wechat processes {:?} successfully", killed);
+    }
+    pid_file.clear();
+
+    // token and addr are required by clap unless a subcommand is given
+    let addr = arg.addr.clone().unwrap();
+    let token = arg.token.clone().unwrap();
+    let url = url::Url::parse(&addr).unwrap();
+    info!("parse url {} successfully", addr);
 
     info!("construct wss request successfully");
 
     let (tx, _) = broadcast::channel::<String>(arg.buffer_size.try_into().unwrap());
 
-    let default_save_path = std::env::current_dir()
-        .unwrap()
-        .join("hook_media")
-        .into_os_string()
-        .into_string()
-        .unwrap();
-    let save_path = arg.save_path.unwrap_or(default_save_path);
-    let manager: WechatManager = manager::WechatManager::new(arg.port, save_path, tx.clone());
+    let manager: WechatManager = manager::WechatManager::new(
+        ManagerConfig {
+            message_hook_port: arg.port,
+            save_path,
+            ack_events: arg.ack_events,
+            replay_buffer_size: arg.replay_buffer_size,
+            state_dir: state_dir.map(|dir| dir.to_string_lossy().to_string()),
+            enable_raw_query: arg.enable_raw_query,
+            wechat_files_dir: arg.wechat_files_dir.clone(),
+            http_connect_timeout_secs: arg.http_connect_timeout_secs,
+            http_timeout_secs: arg.http_timeout_secs,
+            http_pool_max_idle_per_host: arg.http_pool_max_idle_per_host,
+            verify_avatars: arg.verify_avatars,
+            locale: arg.locale,
+            timezone: arg.timezone,
+            health_check_interval_secs: arg.health_check_interval_secs,
+            spoof_wechat_version: arg.spoof_wechat_version.clone(),
+            drop_on_logout: arg.drop_on_logout,
+            media_hosts: arg.media_hosts.clone(),
+            max_inbound_media_size: arg.max_inbound_media_size,
+            max_outbound_media_size: arg.max_outbound_media_size,
+            msg_id_retention_days: arg.msg_id_retention_days,
+            msg_id_max_count: arg.msg_id_max_count,
+            journal_events: arg.journal_events,
+            dedup_window_secs: arg.dedup_window_secs,
+            info_cache_ttl_secs: arg.info_cache_ttl_secs,
+            send_idempotency_ttl_secs: arg.send_idempotency_ttl_secs,
+            slow_op_threshold_ms: arg.slow_op_threshold_ms,
+            avatar_cache_max_age_secs: arg.avatar_cache_max_age_secs,
+            audit_callbacks: arg.audit_callbacks,
+            media_resend_max_age_secs: arg.media_resend_max_age_secs,
+            pid_file: Some(pid_file_path.to_string_lossy().to_string()),
+            translate_emoticons: arg.translate_emoticons,
+            convert_outgoing_emoji: arg.convert_outgoing_emoji,
+            group_member_poll_interval_secs: arg.group_member_poll_interval_secs,
+            contact_poll_interval_secs: arg.contact_poll_interval_secs,
+            command_timeout_secs: arg.command_timeout_secs,
+            command_timeouts: arg.command_timeouts.iter().cloned().collect(),
+            location_thumbnail_url: arg.location_thumbnail_url.clone(),
+            file_complete_timeout_secs: arg.file_complete_timeout_secs,
+            media_stable_window_ms: arg.media_stable_window_ms,
+            db_names: arg.db_names.iter().cloned().collect(),
+            callback_concurrency: arg.callback_concurrency as usize,
+        },
+        tx.clone(),
+    )
+    .unwrap();
     let inner_manager = manager.clone();
+    let client = manager.http_client();
+    let health_manager = manager.clone();
+    let member_manager = manager.clone();
+    let contact_manager = manager.clone();
 
     let ws = tokio::spawn(async move {
         let inner_tx = tx;
@@ -77,26 +414,37 @@ async fn main() {
         let mut last_err = Utc::now();
         let wait = Duration::from_secs(5);
         loop {
-            connect_ws(
+            if let Err(reason) = connect_ws(
                 url.clone(),
-                arg.token.clone(),
+                token.clone(),
                 &inner_manager,
                 inner_tx.subscribe(),
             )
-            .await;
+            .await
+            {
+                // reconnecting never helps until either side is upgraded
+                error!("!!! refuse to talk to bridge: {}", reason);
+                if let Some(webhook) = &arg.failure_webhook {
+                    notify_failure(&client, webhook, &reason).await;
+                }
+                break;
+            }
             if Utc::now() - last_err < chrono::Duration::minutes(5) {
                 err_cnt += 1;
             } else {
                 err_cnt = 1;
             };
 
-            if err_cnt > constants::MAX_WS_RECONNECT_COUNT {
-                error!(
+            if err_cnt > arg.max_ws_reconnect_count {
+                let reason = format!(
                     "err cnt {} exceeds max ws reconnect count {} in last 5 minutes",
-                    err_cnt,
-                    constants::MAX_WS_RECONNECT_COUNT
+                    err_cnt, arg.max_ws_reconnect_count
                 );
-                return;
+                error!("!!! give up reconnecting websocket: {}", reason);
+                if let Some(webhook) = &arg.failure_webhook {
+                    notify_failure(&client, webhook, &reason).await;
+                }
+                break;
             }
 
             last_err = Utc::now();
@@ -106,22 +454,260 @@ async fn main() {
             );
             sleep(wait * err_cnt).await;
         }
+        // ending the task exits agent below
+        if !arg.exit_on_ws_failure {
+            warn!("keep agent running without websocket");
+            future::pending::<()>().await;
+        }
     });
 
     let write_wechat_event = tokio::spawn(async move {
         manager.start_server().await;
     });
 
-    future::select(ws, write_wechat_event).await;
+    tokio::spawn(async move {
+        health_manager.start_health_check().await;
+    });
+
+    tokio::spawn(async move {
+        member_manager.start_member_polling().await;
+    });
+
+    tokio::spawn(async move {
+        contact_manager.start_contact_polling().await;
+    });
+
+    // both tasks are supposed to run forever unless websocket is given up with --exit-on-ws-failure.
+    // If either ends, exit so that a supervisor restarts the whole agent instead of leaving the other half running
+    let (task, result) = match future::select(ws, write_wechat_event).await {
+        future::Either::Left((result, _)) => ("websocket", result),
+        future::Either::Right((result, _)) => ("wechat event server", result),
+    };
+    match result {
+        Ok(_) => error!("!!! {} task ended unexpectedly, exit agent", task),
+        Err(err) => error!("!!! {} task died: {}, exit agent", task, err),
+    }
+    std::process::exit(1);
+}
+
+// options from the config file are inserted before command line ones which are not overridden
+fn parse_args() -> Args {
+    let cli_args: Vec<String> = std::env::args().collect();
+    let path = cli_args
+        .iter()
+        .enumerate()
+        .find_map(|(i, a)| match a.as_str() {
+            "--config" | "-c" => cli_args.get(i + 1).cloned(),
+            // -cpath and -c=path like clap
+            _ => a
+                .strip_prefix("--config=")
+                .or_else(|| {
+                    a.strip_prefix("-c")
+                        .map(|v| v.strip_prefix('=').unwrap_or(v))
+                })
+                .map(str::to_string),
+        });
+    let path = match path {
+        Some(path) => path,
+        None => return validated(Args::parse()),
+    };
+
+    let file_args = match load_config_file(&path, &cli_args[1..]) {
+        Ok(args) => args,
+        Err(err) => Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("invalid config file {}: {}", path, err),
+            )
+            .exit(),
+    };
+    let mut merged = vec![cli_args[0].clone()];
+    merged.extend(file_args);
+    merged.extend(cli_args[1..].iter().cloned());
+    validated(Args::parse_from(merged))
+}
+
+fn validated(args: Args) -> Args {
+    if let Err(err) = args.validate() {
+        Args::command()
+            .error(ErrorKind::ValueValidation, err)
+            .exit();
+    }
+    args
+}
+
+// convert options in config file to flags. Options given on command line are skipped
+fn load_config_file(path: &str, cli_args: &[String]) -> Result<Vec<String>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let options: BTreeMap<String, serde_yaml::Value> =
+        serde_yaml::from_str(&content).map_err(|e| e.to_string())?;
+
+    let command = Args::command();
+    let mut args = vec![];
+    for (key, value) in options {
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_id() == key.as_str() && a.get_long().is_some())
+            .filter(|_| key != "config")
+            .ok_or_else(|| format!("unknown option `{}`", key))?;
+        let long = format!("--{}", arg.get_long().unwrap_or_default());
+        let short = arg.get_short().map(|s| format!("-{}", s));
+        if is_given(cli_args, &long) || matches!(&short, Some(s) if is_given(cli_args, s)) {
+            continue;
+        }
+
+        let takes_value = matches!(arg.get_action(), ArgAction::Set | ArgAction::Append);
+        let value = match value {
+            serde_yaml::Value::Null => continue,
+            serde_yaml::Value::Bool(b) if !takes_value => {
+                if b {
+                    args.push(long);
+                }
+                continue;
+            }
+            serde_yaml::Value::Bool(b) => b.to_string(),
+            serde_yaml::Value::Number(n) => n.to_string(),
+            serde_yaml::Value::String(s) => s,
+            serde_yaml::Value::Sequence(seq) => seq
+                .iter()
+                .map(|v| match v {
+                    serde_yaml::Value::String(s) => Ok(s.clone()),
+                    serde_yaml::Value::Number(n) => Ok(n.to_string()),
+                    _ => Err(format!("`{}` must be a list of scalars", key)),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            serde_yaml::Value::Mapping(_) => return Err(format!("`{}` must be a scalar", key)),
+        };
+        if !takes_value {
+            return Err(format!("`{}` is a flag and must be true or false", key));
+        }
+        args.push(long);
+        args.push(value);
+    }
+    Ok(args)
+}
+
+// a long flag is given as itself or with =value, while --token-file is not --token. A short flag
+// is also given with its value attached, since clap reads -p30000 as -p 30000
+fn is_given(cli_args: &[String], flag: &str) -> bool {
+    let is_short = flag.len() == 2 && !flag.starts_with("--");
+    cli_args.iter().any(|a| match a.strip_prefix(flag) {
+        Some(rest) => rest.is_empty() || rest.starts_with('=') || is_short,
+        None => false,
+    })
+}
+
+// db=name|name pair of db names. The db must be one queried by agent
+fn parse_db_names(s: &str) -> Result<(String, Vec<String>), String> {
+    let (db, names) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{} must be like MicroMsg.db=MicroMsg.db|MicroMsg", s))?;
+    let db = db.trim();
+    if ![constants::DB_MICRO_MSG, constants::DB_OPEN_IM_CONTACT].contains(&db) {
+        return Err(format!("unknown db {}", db));
+    }
+    let names: Vec<String> = names
+        .split('|')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return Err(format!("no candidate names of {}", db));
+    }
+    Ok((db.to_string(), names))
+}
+
+// command=secs pair of command timeouts. The command must be a known one
+fn parse_command_timeout(s: &str) -> Result<(String, u64), String> {
+    let (name, secs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{} must be like send_message=600", s))?;
+    let name = name.trim();
+    match serde_json::from_value(serde_json::Value::String(name.to_string())) {
+        Ok(CommandType::Unknown(_)) | Err(_) => return Err(format!("unknown command {}", name)),
+        Ok(_) => {}
+    }
+    let secs = secs
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid timeout of {}: {}", name, e))?;
+    Ok((name.to_string(), secs))
+}
+
+impl Args {
+    // checks beyond types which clap cannot express
+    fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.instance_name {
+            if !instance::is_valid_instance_name(name) {
+                return Err(format!(
+                    "instance_name {} must be 1 to 64 ascii letters, digits, '-' or '_'",
+                    name
+                ));
+            }
+        }
+        if self.command.is_some() {
+            return Ok(());
+        }
+        if let Some(addr) = &self.addr {
+            url::Url::parse(addr).map_err(|e| format!("invalid addr {}: {}", addr, e))?;
+        }
+        if self.port == 0 || self.port >= u16::MAX as u32 {
+            return Err(format!(
+                "port {} must be in 1..{} since instances listen at the following ports",
+                self.port,
+                u16::MAX
+            ));
+        }
+        if let Some(template) = &self.location_thumbnail_url {
+            url::Url::parse(template)
+                .map_err(|e| format!("invalid location_thumbnail_url {}: {}", template, e))?;
+            if !template.contains("{lat}") || !template.contains("{lon}") {
+                return Err("location_thumbnail_url must contain {lat} and {lon}".to_string());
+            }
+        }
+        if self.buffer_size == 0 {
+            return Err("buffer_size must be greater than 0".to_string());
+        }
+        if self.drop_on_logout && self.health_check_interval_secs == 0 {
+            return Err(
+                "drop_on_logout requires health_check_interval_secs since logouts are detected by health check"
+                    .to_string(),
+            );
+        }
+        if self.ack_events && self.replay_buffer_size == 0 {
+            return Err("replay_buffer_size must be greater than 0 to ack events".to_string());
+        }
+        Ok(())
+    }
+}
+
+// best-effort notification since websocket, the usual channel, is down
+async fn notify_failure(client: &reqwest::Client, webhook: &str, reason: &str) {
+    let resp = client
+        .post(webhook)
+        .timeout(Duration::from_secs(constants::FAILURE_WEBHOOK_TIMEOUT_SECS))
+        .json(&serde_json::json!({
+            "agent": "matrix-wechat-agent",
+            "error": reason,
+        }))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    match resp {
+        Ok(_) => info!("notify failure to webhook successfully"),
+        Err(err) => error!("notify failure to webhook failed: {}", err),
+    }
 }
 
+/// connect websocket and serve until it is closed. Err means the bridge speaks an incompatible protocol
 async fn connect_ws(
     url: url::Url,
     token: String,
     manager: &WechatManager,
     mut rx: Receiver<String>,
-) {
-    let request = Request::builder()
+) -> Result<(), String> {
+    let mut builder = Request::builder()
         .method("GET")
         .header("Host", url.host_str().unwrap())
         .header("Connection", "Upgrade")
@@ -129,14 +715,73 @@ async fn connect_ws(
         .header("Sec-WebSocket-Version", "13")
         .header("Sec-WebSocket-Key", handshake::client::generate_key())
         .header("Authorization", format!("Basic {}", token))
-        .uri(url.as_str())
-        .body(())
-        .unwrap();
-    let (ws_stream, _) = connect_async(request).await.expect("Failed to connect");
+        .header(
+            constants::PROTOCOL_VERSION_HEADER,
+            constants::PROTOCOL_VERSION,
+        )
+        .header(
+            constants::MIN_PROTOCOL_VERSION_HEADER,
+            constants::MIN_BRIDGE_PROTOCOL_VERSION,
+        );
+    if let Some(version) = manager.wechat_version() {
+        builder = builder.header(constants::WECHAT_VERSION_HEADER, version);
+    }
+    let request = builder.uri(url.as_str()).body(()).unwrap();
+    let (mut ws_stream, resp) = match connect_async(request).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            error!("connect websocket failed: {}", err);
+            return Ok(());
+        }
+    };
     info!("WebSocket handshake has been successfully completed");
+
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v: &tungstenite::http::HeaderValue| v.to_str().ok())
+    };
+    let bridge_version = header(constants::PROTOCOL_VERSION_HEADER);
+    if let Err(reason) = ws::check_bridge_protocol(
+        bridge_version,
+        header(constants::MIN_PROTOCOL_VERSION_HEADER),
+    ) {
+        let close = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "incompatible protocol version".into(),
+        };
+        if let Err(err) = ws_stream.close(Some(close)).await {
+            warn!("close websocket failed: {}", err);
+        }
+        return Err(reason);
+    }
+    match bridge_version {
+        Some(v) => info!(
+            "bridge speaks protocol version {}, agent speaks {}",
+            v,
+            constants::PROTOCOL_VERSION
+        ),
+        None => warn!("bridge announces no protocol version. assume it is compatible"),
+    }
     let (mut writer, reader) = ws_stream.split();
 
+    let unacked_events = manager.unacked_events();
+    let resend_manager = manager.clone();
     let write_message = tokio::spawn(async move {
+        if !unacked_events.is_empty() {
+            info!("redeliver {} unacked events", unacked_events.len());
+        }
+        for msg in unacked_events {
+            if let Err(err) = writer.send(Message::Text(msg)).await {
+                error!("redeliver unacked event failed: {}", err);
+                return;
+            }
+        }
+        // media is read from files again and written to the channel consumed below
+        tokio::spawn(async move {
+            resend_manager.resend_failed_media().await;
+        });
+
         while let Ok(msg) = rx.recv().await {
             match writer.send(Message::Text(msg)).await {
                 Ok(_) => debug!("write message to ws successfully"),
@@ -160,6 +805,7 @@ async fn connect_ws(
     };
     pin_mut!(read_message, write_message);
     future::select(read_message, write_message).await;
+    Ok(())
 }
 
 async fn recv_message(
@@ -187,13 +833,21 @@ async fn recv_message(
 
     debug!("recv ws command: {}", s);
 
-    let msg = match serde_json::from_str::<WebsocketMatrixRequest>(s.as_str()) {
+    let msg = match WebsocketMatrixRequest::parse(&s) {
         Ok(msg) => {
-            info!("parse recv message as json successfully");
+            match &msg.command {
+                CommandType::Unknown(command) => {
+                    warn!("recv unknown command {}. req = {}", command, msg.req_id)
+                }
+                _ => info!("parse recv message as json successfully"),
+            }
             msg
         }
         Err(err) => {
             error!("parse recv message as json failed: {}", err);
+            if let Err(e) = manager.reject_malformed_command(&s, err.to_string()).await {
+                error!("answer malformed command failed: {}", e);
+            }
             return;
         }
     };
@@ -203,23 +857,40 @@ async fn recv_message(
     };
 }
 
-fn init_logger() {
-    let pattern =
-        PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} | {I:5.5} | {({l}):5.5} | {f}:{L} — {m}{n}");
+/// prefix the log line with timestamp in the given timezone since pattern encoder only supports local and utc
+#[derive(Debug, Clone)]
+struct TzEncoder {
+    timezone: Option<Tz>,
+    pattern: PatternEncoder,
+}
+
+impl Encode for TzEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+        let format = "%Y-%m-%d %H:%M:%S";
+        match self.timezone {
+            Some(tz) => write!(w, "{}", Utc::now().with_timezone(&tz).format(format))?,
+            None => write!(w, "{}", Local::now().format(format))?,
+        }
+        self.pattern.encode(w, record)
+    }
+}
+
+fn init_logger(timezone: Option<Tz>, log_dir: &Path) {
+    let pattern = TzEncoder {
+        timezone,
+        pattern: PatternEncoder::new(" | {I:5.5} | {({l}):5.5} | {f}:{L} — {m}{n}"),
+    };
     let rollingfile = RollingFileAppender::builder()
         .encoder(Box::new(pattern.clone()))
         .build(
-            Path::new("log").join("matrix_wechat_agent.log"),
+            log_dir.join("matrix_wechat_agent.log"),
             Box::new(CompoundPolicy::new(
                 Box::new(SizeTrigger::new(16 * 1024 * 1024)), // max size is 16M for each log file
                 Box::new(
                     FixedWindowRoller::builder()
                         .base(1)
                         .build(
-                            Path::new("log")
-                                .join("matrix_wechat_agent_{}.log")
-                                .to_str()
-                                .unwrap(),
+                            log_dir.join("matrix_wechat_agent_{}.log").to_str().unwrap(),
                             5,
                         )
                         .unwrap(),
@@ -252,3 +923,49 @@ fn init_logger() {
         .unwrap();
     log4rs::init_config(config).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn flags_are_given_as_clap_reads_them() {
+        assert!(is_given(&args(&["agent", "-t", "x"]), "-t"));
+        assert!(is_given(&args(&["agent", "-t=x"]), "-t"));
+        assert!(is_given(&args(&["agent", "--token=x"]), "--token"));
+        assert!(!is_given(&args(&["agent", "--token-file", "x"]), "--token"));
+        assert!(!is_given(&args(&["agent", "--token"]), "-t"));
+
+        // clap takes the rest of a short flag as its value
+        assert!(is_given(&args(&["agent", "-tz"]), "-t"));
+        let parsed = Args::try_parse_from(["agent", "-tz", "-a", "ws://x", "-p30000"]).unwrap();
+        assert_eq!(parsed.token.as_deref(), Some("z"));
+        assert_eq!(parsed.port, 30000);
+        assert!(is_given(&args(&["agent", "-p30000"]), "-p"));
+    }
+
+    #[test]
+    fn config_file_options_are_skipped_only_if_given_on_command_line() {
+        let path = std::env::temp_dir().join(format!("agent-config-{}.yaml", std::process::id()));
+        std::fs::write(&path, "token: from-file\nport: 30000\nack_events: true\n").unwrap();
+        let loaded = load_config_file(
+            path.to_str().unwrap(),
+            &args(&["agent", "-p30001", "--ack-events"]),
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded, args(&["--token", "from-file"]));
+
+        // the options of the file and the command line are accepted together by clap
+        let mut all = args(&["agent", "-p30001", "--ack-events", "-a", "ws://x"]);
+        all.extend(loaded);
+        let parsed = Args::try_parse_from(all).unwrap();
+        assert_eq!(parsed.port, 30001);
+        assert_eq!(parsed.token.as_deref(), Some("from-file"));
+    }
+}
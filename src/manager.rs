@@ -1,164 +1,726 @@
+use crate::config::ManagerConfig;
+use crate::error::{Error, Result};
+use crate::instance::PidFile;
 use crate::wechat::WechatInstance;
-use crate::ws::send::{WebsocketCommand, WebsocketMessage};
-use anyhow::bail;
-use log::debug;
+use crate::ws::send::{ErrorCode, WebsocketCommand, WebsocketMessage};
+use log::{debug, error, info, warn};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
-use std::sync::atomic::AtomicU32;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc::UnboundedSender;
 
-use crate::utils;
 use crate::ws::{send::WebsocketEvent, CommandType};
+use crate::{constants, utils};
 
+use self::audit::CallbackAudit;
+use self::avatar::AvatarCache;
+use self::chat_queue::ChatQueues;
+use self::contacts::ContactWatch;
+use self::dedup::SeenMessages;
+use self::health::HookHealthMap;
+use self::journal::EventJournal;
+use self::members::GroupMemberWatch;
+use self::msgstore::MsgIdStore;
+use self::pending::PendingEvents;
+use self::recent::{RecentEvent, RecentEvents};
+use self::responses::RecentResponses;
+use self::sent::SentMessages;
+
+mod audit;
+mod avatar;
+mod capabilities;
+mod chat_queue;
+mod contacts;
+mod dedup;
+mod group_avatar;
+mod health;
+mod journal;
+mod login;
 mod matrix;
+mod media;
+mod members;
+mod msgstore;
+mod pending;
+mod recent;
+mod resend;
+mod responses;
+mod sent;
+mod stats;
 mod wechat;
 
 pub struct WechatManager {
-    message_hook_port: u32,
-    save_path: String,
+    config: Arc<ManagerConfig>,
+    client: reqwest::Client,
+    // for urls from messages and the bridge
+    media_client: utils::GuardedClient,
     wechat_listen_port: Arc<AtomicU32>,
     pid_instance_map: Arc<Mutex<HashMap<u32, WechatInstance>>>,
     mxid_pid_map: Arc<Mutex<HashMap<String, u32>>>,
     sender_chan: Sender<String>,
+    undelivered_event_count: Arc<AtomicU64>,
+    pending_events: Arc<Mutex<PendingEvents>>,
+    recent_events: Arc<Mutex<RecentEvents>>,
+    muted_chats: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    connecting_mxids: Arc<Mutex<HashSet<String>>>,
+    avatar_checker: Option<utils::UrlChecker>,
+    avatars: Option<AvatarCache>,
+    // port of the callback listener. It can be changed at runtime by set_hook_port command
+    message_hook_port: Arc<AtomicU32>,
+    // hand a new listener over to the callback server. It is set after the server starts
+    hook_listener_chan: Arc<Mutex<Option<UnboundedSender<TcpListener>>>>,
+    hook_health: Arc<Mutex<HookHealthMap>>,
+    // mxids whose login qr codes are being polled
+    polling_mxids: Arc<Mutex<HashSet<String>>>,
+    msg_ids: Option<MsgIdStore>,
+    journal: Option<EventJournal>,
+    // journaled events before restart which are redelivered once without acks. (journal key, data)
+    recovered_events: Arc<Mutex<Vec<(u64, String)>>>,
+    seen_messages: Option<SeenMessages>,
+    callback_audit: Option<CallbackAudit>,
+    recent_responses: Arc<Mutex<RecentResponses>>,
+    // pids of spawned wechat processes persisted for the startup kill of next run
+    pid_file: Option<PidFile>,
+    group_members: Option<GroupMemberWatch>,
+    contacts: Option<ContactWatch>,
+    // wechat callbacks are handled in order per chat and concurrently across chats
+    chat_queues: ChatQueues,
+    sent_messages: Option<SentMessages>,
 }
 
 impl Clone for WechatManager {
     fn clone(&self) -> Self {
         Self {
-            message_hook_port: self.message_hook_port,
-            save_path: self.save_path.clone(),
+            config: self.config.clone(),
+            client: self.client.clone(),
+            media_client: self.media_client.clone(),
             wechat_listen_port: self.wechat_listen_port.clone(),
             pid_instance_map: self.pid_instance_map.clone(),
             mxid_pid_map: self.mxid_pid_map.clone(),
             sender_chan: self.sender_chan.clone(),
+            undelivered_event_count: self.undelivered_event_count.clone(),
+            pending_events: self.pending_events.clone(),
+            recent_events: self.recent_events.clone(),
+            muted_chats: self.muted_chats.clone(),
+            connecting_mxids: self.connecting_mxids.clone(),
+            avatar_checker: self.avatar_checker.clone(),
+            avatars: self.avatars.clone(),
+            message_hook_port: self.message_hook_port.clone(),
+            hook_listener_chan: self.hook_listener_chan.clone(),
+            hook_health: self.hook_health.clone(),
+            polling_mxids: self.polling_mxids.clone(),
+            msg_ids: self.msg_ids.clone(),
+            journal: self.journal.clone(),
+            recovered_events: self.recovered_events.clone(),
+            seen_messages: self.seen_messages.clone(),
+            callback_audit: self.callback_audit.clone(),
+            recent_responses: self.recent_responses.clone(),
+            pid_file: self.pid_file.clone(),
+            group_members: self.group_members.clone(),
+            contacts: self.contacts.clone(),
+            chat_queues: self.chat_queues.clone(),
+            sent_messages: self.sent_messages.clone(),
         }
     }
 }
 
 impl WechatManager {
-    pub fn new(
-        msg_hook_port: u32,
-        save_path: String,
-        sender_chan: Sender<String>,
-    ) -> WechatManager {
-        WechatManager {
-            message_hook_port: msg_hook_port,
-            save_path,
-            wechat_listen_port: Arc::new(AtomicU32::new(msg_hook_port + 1)),
+    pub fn new(config: ManagerConfig, sender_chan: Sender<String>) -> Result<WechatManager> {
+        let client_builder = || {
+            reqwest::Client::builder()
+                .user_agent(constants::USER_AGENT)
+                .gzip(true)
+                .connect_timeout(Duration::from_secs(config.http_connect_timeout_secs))
+                .timeout(Duration::from_secs(config.http_timeout_secs))
+                .pool_max_idle_per_host(config.http_pool_max_idle_per_host)
+        };
+        let client = client_builder().build()?;
+        let media_client = utils::GuardedClient::new(client_builder())?;
+
+        let avatar_checker = match config.verify_avatars {
+            true => Some(utils::UrlChecker::new(media_client.clone())),
+            false => None,
+        };
+
+        let state_db = open_state_db(&config);
+        let journal = open_journal(&config, state_db.as_ref());
+        let mut pending = PendingEvents::new(config.replay_buffer_size);
+        let mut recovered = vec![];
+        if let Some(journal) = &journal {
+            match journal.last_seqs() {
+                Ok(seqs) => seqs
+                    .iter()
+                    .for_each(|(mxid, seq)| pending.restore_seq(mxid, *seq)),
+                Err(err) => error!("load last seqs from journal failed: {}", err),
+            }
+            match journal.load() {
+                Ok(entries) => {
+                    for (key, entry) in entries {
+                        match config.ack_events {
+                            true => pending.restore(entry.mxid, entry.seq, entry.data),
+                            false => recovered.push((key, entry.data)),
+                        }
+                    }
+                }
+                Err(err) => error!("load undelivered events from journal failed: {}", err),
+            }
+        }
+
+        Ok(WechatManager {
+            msg_ids: open_msg_id_store(&config, state_db.as_ref()),
+            journal,
+            recovered_events: Arc::new(Mutex::new(recovered)),
+            seen_messages: match config.dedup_window_secs {
+                0 => None,
+                window => Some(SeenMessages::new(window, state_db.as_ref())),
+            },
+            recent_responses: Arc::new(Mutex::new(RecentResponses::new(
+                constants::RECENT_RESPONSE_CACHE_SIZE,
+                Duration::from_secs(constants::RECENT_RESPONSE_TTL_SECS),
+            ))),
+            pid_file: config
+                .pid_file
+                .as_ref()
+                .map(|path| PidFile::open(Path::new(path))),
+            group_members: match config.group_member_poll_interval_secs {
+                0 => None,
+                _ => Some(GroupMemberWatch::new(state_db.as_ref())),
+            },
+            contacts: match config.contact_poll_interval_secs {
+                0 => None,
+                _ => Some(ContactWatch::default()),
+            },
+            chat_queues: ChatQueues::new(
+                config.callback_concurrency,
+                constants::CHAT_QUEUE_CAPACITY,
+            ),
+            sent_messages: match config.send_idempotency_ttl_secs {
+                0 => None,
+                ttl => Some(SentMessages::new(ttl)),
+            },
+            callback_audit: match config.audit_callbacks {
+                true => Some(CallbackAudit::new(&config.save_path)),
+                false => None,
+            },
+            avatars: match config.avatar_cache_max_age_secs {
+                0 => None,
+                max_age => Some(AvatarCache::new(
+                    &config.save_path,
+                    max_age,
+                    media_client.clone(),
+                )),
+            },
+            client,
+            media_client,
+            avatar_checker,
+            message_hook_port: Arc::new(AtomicU32::new(load_hook_port(&config))),
+            hook_listener_chan: Arc::new(Mutex::new(None)),
+            hook_health: Arc::new(Mutex::new(HashMap::new())),
+            polling_mxids: Arc::new(Mutex::new(HashSet::new())),
+            wechat_listen_port: Arc::new(AtomicU32::new(config.message_hook_port + 1)),
             pid_instance_map: Arc::new(Mutex::new(HashMap::new())),
             mxid_pid_map: Arc::new(Mutex::new(HashMap::new())),
             sender_chan,
+            undelivered_event_count: Arc::new(AtomicU64::new(0)),
+            pending_events: Arc::new(Mutex::new(pending)),
+            recent_events: Arc::new(Mutex::new(RecentEvents::new(
+                constants::RECENT_EVENT_CACHE_SIZE,
+            ))),
+            muted_chats: Arc::new(Mutex::new(load_muted_chats(&config))),
+            connecting_mxids: Arc::new(Mutex::new(HashSet::new())),
+            config: Arc::new(config),
+        })
+    }
+
+    /// client with the configured timeouts and proxy, for requests to operator given urls
+    pub fn http_client(&self) -> reqwest::Client {
+        self.client.clone()
+    }
+
+    /// number of wechat events which failed to be written to websocket channel
+    pub fn undelivered_event_count(&self) -> u64 {
+        self.undelivered_event_count.load(Ordering::SeqCst)
+    }
+
+    /// serialized events which are not acked by the bridge yet. They should be redelivered after reconnecting
+    pub fn unacked_events(&self) -> Vec<String> {
+        if !self.config.ack_events {
+            return self.take_recovered_events();
+        }
+        match self.pending_events.lock() {
+            Ok(pending) => pending.unacked(),
+            Err(err) => {
+                error!("lock pending events failed: {}", err);
+                vec![]
+            }
         }
     }
+
+    // events recovered from journal are delivered once when acks are not negotiated
+    fn take_recovered_events(&self) -> Vec<String> {
+        let recovered = match self.recovered_events.lock() {
+            Ok(mut recovered) => std::mem::take(&mut *recovered),
+            Err(err) => {
+                error!("lock recovered events failed: {}", err);
+                return vec![];
+            }
+        };
+        recovered
+            .into_iter()
+            .map(|(key, data)| {
+                if let Some(journal) = &self.journal {
+                    if let Err(e) = journal.remove(key) {
+                        error!("remove redelivered event from journal failed: {}", e);
+                    }
+                }
+                data
+            })
+            .collect()
+    }
+
+    fn ack_events(&self, mxid: &str, seq: u64) -> Result<()> {
+        let mut pending = match self.pending_events.lock() {
+            Ok(pending) => pending,
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock pending events failed: {}",
+                    err
+                )))
+            }
+        };
+        let cnt = pending.ack(mxid, seq);
+        if let Some(store) = &self.msg_ids {
+            if let Err(e) = store.record_ack(mxid, seq) {
+                error!("persist acked seq of {} failed: {}", mxid, e);
+            }
+        }
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.ack(mxid, seq) {
+                error!("remove acked events of {} from journal failed: {}", mxid, e);
+            }
+        }
+        info!(
+            "bridge acked events of {} until seq {}. remove {} events",
+            mxid, seq, cnt
+        );
+        Ok(())
+    }
 }
 ///
 /// lock related methods
 ///
 impl WechatManager {
-    fn get_instance_by_pid(&self, pid: u32) -> anyhow::Result<WechatInstance> {
+    fn get_instance_by_pid(&self, pid: u32) -> Result<WechatInstance> {
         let db = match self.pid_instance_map.lock() {
             Ok(db) => db,
-            Err(err) => {
-                bail!("lock db failed: {}", err)
-            }
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
         };
         let instance = match db.get(&pid) {
             Some(ins) => ins.clone(),
-            None => {
-                bail!("cannot get wechat instance by pid: {}", pid)
-            }
+            None => return Err(Error::InstanceNotFound(format!("pid = {}", pid))),
         };
         Ok(instance)
     }
 
-    fn get_instance_by_mxid(&self, mxid: String) -> anyhow::Result<WechatInstance> {
+    fn get_instance_by_mxid(&self, mxid: String) -> Result<WechatInstance> {
         let mxid_map = match self.mxid_pid_map.lock() {
             Ok(db) => db,
-            Err(err) => {
-                bail!("lock db failed: {}", err)
-            }
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
         };
         let pid = match mxid_map.get(&mxid) {
             Some(ins) => ins,
-            None => {
-                bail!("cannot get wechat pid by mxid: {}", mxid)
-            }
+            None => return Err(Error::InstanceNotFound(format!("mxid = {}", mxid))),
         };
 
         let ins = self.get_instance_by_pid(*pid)?;
         if !ins.is_alive()? {
-            bail!("instance crashed. pid = {}", pid)
+            return Err(Error::InstanceCrashed(*pid));
         }
         Ok(ins)
     }
 
-    fn store_instance(&self, mxid: String, instance: WechatInstance) -> anyhow::Result<()> {
+    fn store_instance(&self, mxid: String, instance: WechatInstance) -> Result<()> {
         let mut db = match self.pid_instance_map.lock() {
             Ok(db) => db,
-            Err(err) => {
-                bail!("lock db failed: {}", err)
-            }
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
         };
         let mut mxid_map = match self.mxid_pid_map.lock() {
             Ok(db) => db,
-            Err(err) => {
-                bail!("lock db failed: {}", err)
-            }
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
         };
 
-        mxid_map.insert(mxid, instance.pid);
+        // the old instance of mxid is replaced and would be orphaned
+        if let Some(old_pid) = mxid_map.insert(mxid.clone(), instance.pid) {
+            if old_pid != instance.pid {
+                if let Some(old) = db.remove(&old_pid) {
+                    warn!(
+                        "instance of {} is replaced. kill old instance[pid={}]",
+                        mxid, old_pid
+                    );
+                    match old.kill_self_process() {
+                        Ok(true) => self.forget_pid(old_pid),
+                        Ok(false) => warn!("kill old instance[pid={}] failed", old_pid),
+                        Err(err) => warn!("kill old instance[pid={}] failed: {}", old_pid, err),
+                    }
+                }
+            }
+        }
         db.insert(instance.pid, instance);
 
         Ok(())
     }
 
-    fn drop_instance(&self, mxid: String) -> anyhow::Result<()> {
-        let mut db = match self.pid_instance_map.lock() {
-            Ok(db) => db,
+    fn start_connecting(&self, mxid: &str) -> Result<ConnectingGuard> {
+        let mut connecting = match self.connecting_mxids.lock() {
+            Ok(connecting) => connecting,
             Err(err) => {
-                bail!("lock db failed: {}", err)
+                return Err(Error::Internal(format!(
+                    "lock connecting mxids failed: {}",
+                    err
+                )))
             }
         };
+        if !connecting.insert(mxid.to_string()) {
+            return Err(Error::InvalidRequest(format!(
+                "another connect of {} is in progress",
+                mxid
+            )));
+        }
+        Ok(ConnectingGuard {
+            connecting_mxids: self.connecting_mxids.clone(),
+            mxid: mxid.to_string(),
+        })
+    }
+
+    fn drop_instance(&self, mxid: String) -> Result<()> {
+        let mut db = match self.pid_instance_map.lock() {
+            Ok(db) => db,
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
+        };
         let mut mxid_map = match self.mxid_pid_map.lock() {
             Ok(db) => db,
-            Err(err) => {
-                bail!("lock db failed: {}", err)
-            }
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
         };
 
         let pid = mxid_map.get(&mxid);
         if pid.is_none() {
-            bail!("get pid by mxid[{}] failed", mxid)
+            return Err(Error::InstanceNotFound(format!("mxid = {}", mxid)));
         }
         let pid = pid.unwrap();
 
         let ins = db.get(pid);
         if ins.is_none() {
-            bail!("can not get instance by pid[{}]", pid)
+            return Err(Error::InstanceNotFound(format!("pid = {}", pid)));
         }
         let ins = ins.unwrap();
 
         // kill old instance process
         ins.kill_self_process()?;
+        self.forget_pid(*pid);
 
         db.remove(pid);
         mxid_map.remove(&mxid);
 
         Ok(())
     }
+
+    fn record_pid(&self, pid: u32) {
+        if let Some(pid_file) = &self.pid_file {
+            pid_file.add(pid);
+        }
+    }
+
+    fn forget_pid(&self, pid: u32) {
+        if let Some(pid_file) = &self.pid_file {
+            pid_file.remove(pid);
+        }
+    }
+
+    fn instances(&self) -> Result<Vec<WechatInstance>> {
+        let db = match self.pid_instance_map.lock() {
+            Ok(db) => db,
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
+        };
+        Ok(db.values().cloned().collect())
+    }
+
+    /// Versions of the hooked wechat. Instances spawned before an update of wechat run the old
+    /// one, so distinct versions are joined by `,`. The installed version is announced before any
+    /// instance is hooked
+    pub fn wechat_version(&self) -> Option<String> {
+        let hooked = self
+            .instances()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|ins| ins.version)
+            .collect::<BTreeSet<String>>();
+        match hooked.is_empty() {
+            true => utils::get_wechat_version().ok(),
+            false => Some(hooked.into_iter().collect::<Vec<_>>().join(",")),
+        }
+    }
+
+    /// update callback port of all instances and return them to be hooked again
+    fn set_instances_hook_port(&self, port: u32) -> Result<Vec<WechatInstance>> {
+        let mut db = match self.pid_instance_map.lock() {
+            Ok(db) => db,
+            Err(err) => return Err(Error::Internal(format!("lock db failed: {}", err))),
+        };
+        Ok(db
+            .values_mut()
+            .map(|ins| {
+                ins.message_hook_port = port;
+                ins.clone()
+            })
+            .collect())
+    }
+}
+
+/// remove mxid from connecting set when connect finished or failed
+struct ConnectingGuard {
+    connecting_mxids: Arc<Mutex<HashSet<String>>>,
+    mxid: String,
+}
+
+impl Drop for ConnectingGuard {
+    fn drop(&mut self) {
+        match self.connecting_mxids.lock() {
+            Ok(mut connecting) => {
+                connecting.remove(&self.mxid);
+            }
+            Err(err) => error!("lock connecting mxids failed: {}", err),
+        }
+    }
+}
+
+// mute related methods
+impl WechatManager {
+    fn is_chat_muted(&self, mxid: &str, chat_id: &str) -> bool {
+        match self.muted_chats.lock() {
+            Ok(muted) => muted
+                .get(mxid)
+                .map(|chats| chats.contains(chat_id))
+                .unwrap_or(false),
+            Err(err) => {
+                error!("lock muted chats failed: {}", err);
+                false
+            }
+        }
+    }
+
+    fn set_chat_muted(&self, mxid: String, chat_id: String, mute: bool) -> Result<()> {
+        let mut muted = match self.muted_chats.lock() {
+            Ok(muted) => muted,
+            Err(err) => return Err(Error::Internal(format!("lock muted chats failed: {}", err))),
+        };
+        let chats = muted.entry(mxid).or_default();
+        match mute {
+            true => chats.insert(chat_id),
+            false => chats.remove(&chat_id),
+        };
+
+        if let Some(dir) = &self.config.state_dir {
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(
+                Path::new(dir).join(MUTED_CHATS_FILE),
+                serde_json::to_vec(&*muted)?,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+const MUTED_CHATS_FILE: &str = "muted_chats.json";
+// port set by set_hook_port command, which is kept across restarts
+const HOOK_PORT_FILE: &str = "hook_port";
+
+// embedded db in state_dir shared by the message id store, the event journal, dedup and member snapshots
+fn open_state_db(config: &ManagerConfig) -> Option<sled::Db> {
+    let dir = config.state_dir.as_ref()?;
+    if config.msg_id_max_count == 0
+        && !config.journal_events
+        && config.dedup_window_secs == 0
+        && config.group_member_poll_interval_secs == 0
+    {
+        return None;
+    }
+    match sled::open(Path::new(dir).join(constants::STATE_DB_DIR)) {
+        Ok(db) => Some(db),
+        Err(err) => {
+            error!("open state db in {} failed: {}", dir, err);
+            None
+        }
+    }
+}
+
+// the store is optional. Agent works with the in-memory recent event cache only without it
+fn open_msg_id_store(config: &ManagerConfig, db: Option<&sled::Db>) -> Option<MsgIdStore> {
+    if config.msg_id_max_count == 0 {
+        return None;
+    }
+    match MsgIdStore::new(
+        db?,
+        config.msg_id_retention_days * 24 * 3600,
+        config.msg_id_max_count,
+    ) {
+        Ok(store) => Some(store),
+        Err(err) => {
+            error!("open message id store failed: {}", err);
+            None
+        }
+    }
+}
+
+fn open_journal(config: &ManagerConfig, db: Option<&sled::Db>) -> Option<EventJournal> {
+    if !config.journal_events {
+        return None;
+    }
+    let (db, dir) = match (db, &config.state_dir) {
+        (Some(db), Some(dir)) => (db, dir),
+        _ => {
+            warn!("event journal requires state dir. events are not journaled");
+            return None;
+        }
+    };
+    match EventJournal::new(db, Path::new(dir), config.replay_buffer_size) {
+        Ok(journal) => Some(journal),
+        Err(err) => {
+            error!("open event journal failed: {}", err);
+            None
+        }
+    }
+}
+
+fn load_muted_chats(config: &ManagerConfig) -> HashMap<String, HashSet<String>> {
+    let path = match &config.state_dir {
+        Some(dir) => Path::new(dir).join(MUTED_CHATS_FILE),
+        None => return HashMap::new(),
+    };
+    if !path.exists() {
+        return HashMap::new();
+    }
+    match std::fs::read(&path)
+        .map_err(Error::from)
+        .and_then(|data| Ok(serde_json::from_slice(&data)?))
+    {
+        Ok(muted) => muted,
+        Err(err) => {
+            error!("load muted chats from {} failed: {}", path.display(), err);
+            HashMap::new()
+        }
+    }
+}
+
+fn load_hook_port(config: &ManagerConfig) -> u32 {
+    let path = match &config.state_dir {
+        Some(dir) => Path::new(dir).join(HOOK_PORT_FILE),
+        None => return config.message_hook_port,
+    };
+    if !path.exists() {
+        return config.message_hook_port;
+    }
+    match std::fs::read_to_string(&path)
+        .map_err(Error::from)
+        .and_then(|port| {
+            port.trim()
+                .parse::<u32>()
+                .map_err(|e| Error::Parse(e.to_string()))
+        }) {
+        Ok(port) => {
+            info!("use message hook port {} set before restart", port);
+            port
+        }
+        Err(err) => {
+            error!("load hook port from {} failed: {}", path.display(), err);
+            config.message_hook_port
+        }
+    }
 }
 
 // utils methods
 impl WechatManager {
-    async fn write_to_sender<T: Serialize>(&self, data: T) -> anyhow::Result<()> {
-        let writer = self.sender_chan.clone();
-        utils::retriable_write(writer, data, None).await
+    // the response is kept so that the command resent by the bridge is answered again
+    async fn write_command<T: Serialize>(&self, cmd: WebsocketCommand<T>) -> Result<()> {
+        let (mxid, req_id) = (cmd.mxid.clone(), cmd.req_id);
+        let data = serde_json::to_string(&WebsocketMessage::Command(cmd))?;
+        match self.recent_responses.lock() {
+            Ok(mut responses) => responses.finish(&mxid, req_id, &data),
+            Err(err) => error!("lock recent responses failed: {}", err),
+        }
+        utils::retriable_write_raw(self.sender_chan.clone(), data, None).await
     }
 
-    async fn write_event_resp<T: Serialize>(&self, event: WebsocketEvent<T>) -> anyhow::Result<()> {
-        self.write_to_sender(event).await
+    async fn write_event_resp<T: Serialize>(&self, mut event: WebsocketEvent<T>) -> Result<()> {
+        let (data, journal_key) = self.prepare_event(&mut event)?;
+        self.deliver_event(event.base.id, data, journal_key).await
+    }
+
+    // assign seq to event, then buffer and journal it before delivery. return the serialized event and journal key
+    fn prepare_event<T: Serialize>(
+        &self,
+        event: &mut WebsocketEvent<T>,
+    ) -> Result<(String, Option<u64>)> {
+        let msg_id = event.base.id;
+        let data = {
+            let mut pending = match self.pending_events.lock() {
+                Ok(pending) => pending,
+                Err(err) => {
+                    return Err(Error::Internal(format!(
+                        "lock pending events failed: {}",
+                        err
+                    )))
+                }
+            };
+            event.base.seq = pending.next_seq(&event.base.mxid);
+            // events generated by agent have no wechat msg id
+            if let (Some(store), true) = (&self.msg_ids, msg_id != 0) {
+                if let Err(e) = store.record(&event.base) {
+                    error!("persist msg id {} failed: {}", msg_id, e);
+                }
+            }
+            let data = serde_json::to_string(&event)?;
+            if self.config.ack_events {
+                pending.push(event.base.mxid.clone(), event.base.seq, data.clone());
+            }
+            data
+        };
+        let journal_key = self.journal.as_ref().and_then(|journal| {
+            journal
+                .append(&event.base.mxid, event.base.seq, &data)
+                .map_err(|e| error!("journal event failed: {}. msg_id = {}", e, msg_id))
+                .ok()
+        });
+        Ok((data, journal_key))
+    }
+
+    async fn deliver_event(
+        &self,
+        msg_id: u64,
+        data: String,
+        journal_key: Option<u64>,
+    ) -> Result<()> {
+        if let Err(e) = utils::retriable_write_raw(self.sender_chan.clone(), data, None).await {
+            let cnt = self.undelivered_event_count.fetch_add(1, Ordering::SeqCst) + 1;
+            error!(
+                "write event to ws channel failed: {}. msg_id = {} undelivered count = {}",
+                e, msg_id, cnt
+            );
+            return Err(e);
+        }
+        // without acks, writing to the channel is the only delivery signal
+        if let (Some(journal), Some(key), false) =
+            (&self.journal, journal_key, self.config.ack_events)
+        {
+            if let Err(e) = journal.remove(key) {
+                error!("remove delivered event from journal failed: {}", e);
+            }
+        }
+        Ok(())
     }
 
     async fn write_command_resp<T: Serialize + Debug>(
@@ -166,34 +728,30 @@ impl WechatManager {
         mxid: String,
         req_id: i32,
         data: Option<T>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         let cmd = WebsocketCommand {
-            mxid: mxid.clone(),
+            mxid,
             req_id,
             command: CommandType::Response,
             data,
         };
         debug!("write command to ws channel: {:?}", cmd);
-        let resp = self.write_to_sender(WebsocketMessage::Command(cmd)).await;
-        if let Err(e) = resp {
-            self.write_command_error(mxid, req_id, e.to_string())
-                .await?
-        }
-        Ok(())
+        self.write_command(cmd).await
     }
 
     async fn write_command_error(
         &self,
         mxid: String,
         req_id: i32,
+        code: ErrorCode,
         message: String,
-    ) -> anyhow::Result<()> {
-        self.write_to_sender(WebsocketMessage::Command(WebsocketCommand {
+    ) -> Result<()> {
+        self.write_command(WebsocketCommand {
             mxid,
             req_id,
             command: CommandType::Error,
-            data: serde_json::json!({ "message": message }),
-        }))
+            data: serde_json::json!({ "code": code, "message": message }),
+        })
         .await
     }
 }
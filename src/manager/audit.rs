@@ -0,0 +1,150 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use log::error;
+use serde::Serialize;
+
+use crate::constants;
+use crate::error::Result;
+
+const AUDIT_DIR: &str = "audit";
+const AUDIT_FILE: &str = "callbacks.log";
+
+/// what finally happened to a wechat callback message
+pub enum CallbackOutcome {
+    // id of the event written to websocket channel
+    Emitted(u64),
+    Dropped(String),
+}
+
+/// append-only log of raw wechat callback lines and their outcomes. The file is rotated when it
+/// exceeds the max size and only the latest rotated files are kept
+#[derive(Clone)]
+pub struct CallbackAudit {
+    dir: PathBuf,
+    writer: Arc<Mutex<Option<(File, u64)>>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind")]
+enum AuditRecord<'a> {
+    #[serde(rename = "raw")]
+    Raw {
+        ts: i64,
+        peer: String,
+        line: &'a str,
+    },
+    #[serde(rename = "outcome")]
+    Outcome {
+        ts: i64,
+        peer: String,
+        #[serde(rename = "msgId")]
+        msg_id: Option<u64>,
+        #[serde(rename = "eventId")]
+        event_id: Option<u64>,
+        drop: Option<&'a str>,
+    },
+}
+
+impl CallbackAudit {
+    pub fn new(save_path: &str) -> CallbackAudit {
+        CallbackAudit {
+            dir: Path::new(save_path).join(AUDIT_DIR),
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// record a callback line before it is parsed
+    pub fn raw(&self, peer: Option<SocketAddr>, line: &str) {
+        self.append(&AuditRecord::Raw {
+            ts: Utc::now().timestamp_millis(),
+            peer: format_peer(peer),
+            line,
+        });
+    }
+
+    pub fn outcome(
+        &self,
+        peer: Option<SocketAddr>,
+        msg_id: Option<u64>,
+        outcome: &CallbackOutcome,
+    ) {
+        let (event_id, drop) = match outcome {
+            CallbackOutcome::Emitted(id) => (Some(*id), None),
+            CallbackOutcome::Dropped(reason) => (None, Some(reason.as_str())),
+        };
+        self.append(&AuditRecord::Outcome {
+            ts: Utc::now().timestamp_millis(),
+            peer: format_peer(peer),
+            msg_id,
+            event_id,
+            drop,
+        });
+    }
+
+    // auditing never breaks message handling. failures are logged only
+    fn append(&self, record: &AuditRecord) {
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(err) => {
+                error!("lock callback audit log failed: {}", err);
+                return;
+            }
+        };
+        if let Err(e) = self.write_record(&mut writer, record) {
+            error!("write callback audit log failed: {}", e);
+            // reopen next time
+            *writer = None;
+        }
+    }
+
+    fn write_record(&self, writer: &mut Option<(File, u64)>, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        if matches!(writer, Some((_, size)) if *size + line.len() as u64 > constants::AUDIT_LOG_MAX_SIZE)
+        {
+            *writer = None;
+            self.rotate()?;
+        }
+        let (file, size) = match writer {
+            Some(w) => w,
+            None => {
+                std::fs::create_dir_all(&self.dir)?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(AUDIT_FILE))?;
+                let size = file.metadata()?.len();
+                writer.insert((file, size))
+            }
+        };
+        file.write_all(&line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    // callbacks.log -> callbacks.log.1 -> ... The oldest one beyond max files is removed
+    fn rotate(&self) -> Result<()> {
+        let rotated = |i: usize| self.dir.join(format!("{}.{}", AUDIT_FILE, i));
+        let oldest = rotated(constants::AUDIT_LOG_MAX_FILES);
+        if oldest.exists() {
+            std::fs::remove_file(oldest)?;
+        }
+        for i in (1..constants::AUDIT_LOG_MAX_FILES).rev() {
+            if rotated(i).exists() {
+                std::fs::rename(rotated(i), rotated(i + 1))?;
+            }
+        }
+        std::fs::rename(self.dir.join(AUDIT_FILE), rotated(1))?;
+        Ok(())
+    }
+}
+
+fn format_peer(peer: Option<SocketAddr>) -> String {
+    peer.map(|p| p.to_string()).unwrap_or_default()
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::constants;
+use crate::error::{Error, Result};
+use crate::utils::{self, DownloadOptions};
+
+const AVATAR_DIR: &str = "avatars";
+const INDEX_FILE: &str = "index.json";
+
+/// avatar bytes cached under save_path/avatars/<md5 of url>. Entries older than max age
+/// are revalidated with the validators recorded in the index
+#[derive(Clone)]
+pub struct AvatarCache {
+    dir: PathBuf,
+    max_age_secs: i64,
+    client: utils::GuardedClient,
+    // md5 of url -> entry
+    index: Arc<Mutex<HashMap<String, AvatarEntry>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AvatarEntry {
+    url: String,
+    etag: Option<String>,
+    #[serde(rename = "lastModified")]
+    last_modified: Option<String>,
+    // epoch secs when the entry is downloaded or revalidated
+    #[serde(rename = "checkedAt")]
+    checked_at: i64,
+    size: u64,
+}
+
+impl AvatarCache {
+    pub fn new(save_path: &str, max_age_secs: u64, client: utils::GuardedClient) -> AvatarCache {
+        let dir = Path::new(save_path).join(AVATAR_DIR);
+        let index = match std::fs::read(dir.join(INDEX_FILE)) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!(
+                    "parse avatar cache index failed, start with an empty one: {}",
+                    e
+                );
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        AvatarCache {
+            dir,
+            max_age_secs: max_age_secs as i64,
+            client,
+            index: Arc::new(Mutex::new(index)),
+        }
+    }
+
+    /// avatar bytes of url. A stale entry is still served if revalidation fails
+    pub async fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let key = utils::calculate_md5(url.as_bytes());
+        let path = self.dir.join(&key);
+        let cached = self.entry(&key)?.filter(|_| path.exists());
+
+        let now = Utc::now().timestamp();
+        if let Some(entry) = &cached {
+            if now - entry.checked_at < self.max_age_secs {
+                debug!("avatar cache hit: {}", url);
+                return Ok(tokio::fs::read(&path).await?);
+            }
+        }
+
+        let resp = utils::get_file_conditional(
+            &self.client,
+            url,
+            cached.as_ref().and_then(|e| e.etag.as_deref()),
+            cached.as_ref().and_then(|e| e.last_modified.as_deref()),
+            DownloadOptions {
+                max_size: constants::MAX_AVATAR_SIZE,
+                content_type: Some("image/"),
+            },
+        )
+        .await;
+        let resp = match (resp, cached) {
+            (Ok(resp), _) => resp,
+            (Err(e), Some(_)) => {
+                warn!(
+                    "revalidate avatar {} failed, serve the stale one: {}",
+                    url, e
+                );
+                return Ok(tokio::fs::read(&path).await?);
+            }
+            (Err(e), None) => return Err(e),
+        };
+
+        let blob = match resp.body {
+            Some(blob) => {
+                tokio::fs::create_dir_all(&self.dir).await?;
+                tokio::fs::write(&path, &blob).await?;
+                blob
+            }
+            None => tokio::fs::read(&path).await?,
+        };
+        self.update(
+            key,
+            AvatarEntry {
+                url: url.to_string(),
+                etag: resp.etag,
+                last_modified: resp.last_modified,
+                checked_at: now,
+                size: blob.len() as u64,
+            },
+        )?;
+        Ok(blob)
+    }
+
+    fn entry(&self, key: &str) -> Result<Option<AvatarEntry>> {
+        match self.index.lock() {
+            Ok(index) => Ok(index.get(key).cloned()),
+            Err(err) => Err(Error::Internal(format!(
+                "lock avatar cache index failed: {}",
+                err
+            ))),
+        }
+    }
+
+    fn update(&self, key: String, entry: AvatarEntry) -> Result<()> {
+        let mut index = match self.index.lock() {
+            Ok(index) => index,
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock avatar cache index failed: {}",
+                    err
+                )))
+            }
+        };
+        index.insert(key, entry);
+        std::fs::write(self.dir.join(INDEX_FILE), serde_json::to_vec(&*index)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+
+use crate::constants;
+use crate::ws::{send::EventType, CommandType};
+
+use super::WechatManager;
+
+/// what this agent build supports, so that the bridge can negotiate behavior
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: u32,
+    #[serde(rename = "agentVersion")]
+    pub agent_version: &'static str,
+    // hooked wechat like 3.7.0.30, the installed one before any instance is hooked. None if it
+    // cannot be detected
+    #[serde(rename = "wechatVersion")]
+    pub wechat_version: Option<String>,
+    pub commands: Vec<CommandType>,
+    pub events: Vec<EventType>,
+    pub features: Features,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Features {
+    #[serde(rename = "ackEvents")]
+    pub ack_events: bool,
+    #[serde(rename = "eventJournal")]
+    pub event_journal: bool,
+    #[serde(rename = "msgIdStore")]
+    pub msg_id_store: bool,
+    pub dedup: bool,
+    #[serde(rename = "avatarCache")]
+    pub avatar_cache: bool,
+    #[serde(rename = "rawQuery")]
+    pub raw_query: bool,
+    // command data and message data may carry "kind" instead of being matched by shape
+    #[serde(rename = "taggedData")]
+    pub tagged_data: bool,
+    // sends carrying the same idempotency key are answered by the first ack
+    #[serde(rename = "idempotentSend")]
+    pub idempotent_send: bool,
+    #[serde(rename = "maxInboundMediaSize")]
+    pub max_inbound_media_size: usize,
+    #[serde(rename = "maxOutboundMediaSize")]
+    pub max_outbound_media_size: usize,
+}
+
+impl WechatManager {
+    /// It only reads config and the wechat version, therefore, it is cheap and works without any
+    /// instance
+    pub(super) fn capabilities(&self) -> Capabilities {
+        let mut commands = vec![
+            CommandType::Capabilities,
+            CommandType::Connect,
+            CommandType::Disconnect,
+            CommandType::RestartInstance,
+            CommandType::LoginWithQRCode,
+            CommandType::IsLogin,
+            CommandType::GetSelf,
+            CommandType::GetUserInfo,
+            CommandType::BatchGetUserInfo,
+            CommandType::GetGroupInfo,
+            CommandType::GetGroupMembers,
+            CommandType::GetGroupMemberNickname,
+            CommandType::GetGroupMemberNicknames,
+            CommandType::GetFriendList,
+            CommandType::GetGroupList,
+            CommandType::GetUnreadCounts,
+            CommandType::SendMessage,
+            CommandType::Ack,
+            CommandType::MuteChat,
+            CommandType::UnmuteChat,
+            CommandType::GetAvatar,
+            CommandType::GetMediaManifest,
+            CommandType::GetHistoryImage,
+            CommandType::SetHookPort,
+            CommandType::GetStats,
+            CommandType::AcceptFriendRequest,
+        ];
+        if self.config.enable_raw_query {
+            commands.push(CommandType::GetRawContact);
+            commands.push(CommandType::Replay);
+        }
+        let mut events = vec![
+            EventType::Text,
+            EventType::Image,
+            EventType::Audio,
+            EventType::Video,
+            EventType::File,
+            EventType::Location,
+            EventType::Notice,
+            EventType::App,
+            EventType::Revoke,
+            EventType::VoIP,
+            EventType::System,
+            EventType::Alert,
+            EventType::LoginQRCode,
+            EventType::Login,
+            EventType::LoginTimeout,
+            EventType::LoginCancelled,
+            EventType::Logout,
+        ];
+        if self.group_members.is_some() {
+            commands.extend([
+                CommandType::WatchGroupMembers,
+                CommandType::UnwatchGroupMembers,
+            ]);
+            events.extend([EventType::MemberJoin, EventType::MemberLeave]);
+        }
+        if self.contacts.is_some() {
+            events.extend([EventType::FriendAdd, EventType::FriendRemove]);
+        }
+
+        Capabilities {
+            protocol_version: constants::PROTOCOL_VERSION,
+            agent_version: env!("CARGO_PKG_VERSION"),
+            wechat_version: self.wechat_version(),
+            commands,
+            events,
+            features: Features {
+                ack_events: self.config.ack_events,
+                event_journal: self.journal.is_some(),
+                msg_id_store: self.msg_ids.is_some(),
+                dedup: self.seen_messages.is_some(),
+                avatar_cache: self.avatars.is_some(),
+                raw_query: self.config.enable_raw_query,
+                tagged_data: true,
+                idempotent_send: self.sent_messages.is_some(),
+                max_inbound_media_size: self.config.max_inbound_media_size,
+                max_outbound_media_size: self.config.max_outbound_media_size,
+            },
+        }
+    }
+}
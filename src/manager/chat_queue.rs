@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use log::{debug, error};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::constants;
+
+type Job = BoxFuture<'static, ()>;
+
+///
+/// work queues keyed by chat. Jobs of a chat run one by one in the order they are pushed, while
+/// jobs of different chats run concurrently up to the cap. A queue exits once it is idle for a while
+///
+#[derive(Clone)]
+pub struct ChatQueues {
+    queues: Arc<Mutex<HashMap<String, Sender<Job>>>>,
+    permits: Arc<Semaphore>,
+    capacity: usize,
+}
+
+impl ChatQueues {
+    pub fn new(concurrency: usize, capacity: usize) -> ChatQueues {
+        ChatQueues {
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// run job after the jobs of the same key pushed before. It waits while the queue of the key
+    /// is full, so that a wedged chat slows down the pusher instead of piling up jobs
+    pub async fn push<F>(&self, key: String, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut job: Job = Box::pin(job);
+        loop {
+            // jobs are only sent under the lock, under which the worker also checks that its queue
+            // is empty before exiting idle. Therefore, no job is sent to an exiting worker
+            let tx = {
+                let mut queues = match self.queues.lock() {
+                    Ok(queues) => queues,
+                    Err(err) => {
+                        error!("lock chat queues failed: {}. run the job directly", err);
+                        tokio::spawn(job);
+                        return;
+                    }
+                };
+                let tx = match queues.get(&key) {
+                    Some(tx) => tx.clone(),
+                    None => {
+                        let (tx, rx) = mpsc::channel(self.capacity);
+                        queues.insert(key.clone(), tx.clone());
+                        tokio::spawn(self.clone().work(key.clone(), rx));
+                        tx
+                    }
+                };
+                match tx.try_send(job) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(returned)) => {
+                        job = returned;
+                        tx
+                    }
+                    // the worker panicked. A new one is spawned by the next try
+                    Err(TrySendError::Closed(returned)) => {
+                        queues.remove(&key);
+                        job = returned;
+                        continue;
+                    }
+                }
+            };
+            // wait for room outside the lock
+            let _ = tx.reserve().await;
+        }
+    }
+
+    async fn work(self, key: String, mut rx: Receiver<Job>) {
+        let idle = Duration::from_secs(constants::CHAT_QUEUE_IDLE_SECS);
+        loop {
+            let job = match timeout(idle, rx.recv()).await {
+                Ok(Some(job)) => job,
+                Ok(None) => return,
+                Err(_) => match self.queues.lock() {
+                    // a job may be pushed right before the lock is taken
+                    Ok(mut queues) => match rx.try_recv() {
+                        Ok(job) => job,
+                        Err(_) => {
+                            queues.remove(&key);
+                            debug!("chat queue of {} is idle, exit", key);
+                            return;
+                        }
+                    },
+                    Err(err) => {
+                        error!("lock chat queues failed: {}", err);
+                        continue;
+                    }
+                },
+            };
+            let _permit = match self.permits.acquire().await {
+                Ok(permit) => permit,
+                Err(err) => {
+                    error!("acquire chat queue permit failed: {}", err);
+                    return;
+                }
+            };
+            job.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::{oneshot, Notify};
+
+    #[tokio::test]
+    async fn jobs_of_a_chat_run_in_order() {
+        let queues = ChatQueues::new(4, 16);
+        let done = Arc::new(Mutex::new(vec![]));
+        for i in 0..10u64 {
+            let done = done.clone();
+            queues
+                .push("a".to_string(), async move {
+                    // earlier jobs are slower, so that they would finish last if run concurrently
+                    tokio::time::sleep(Duration::from_millis(10 - i)).await;
+                    done.lock().unwrap().push(i);
+                })
+                .await;
+        }
+        let (tx, rx) = oneshot::channel();
+        queues
+            .push("a".to_string(), async move {
+                let _ = tx.send(());
+            })
+            .await;
+        rx.await.unwrap();
+        assert_eq!(*done.lock().unwrap(), (0..10).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn slow_chat_does_not_delay_other_chats() {
+        let queues = ChatQueues::new(2, 16);
+        let release = Arc::new(Notify::new());
+        let wedged = release.clone();
+        queues
+            .push("a".to_string(), async move { wedged.notified().await })
+            .await;
+        let (tx, rx) = oneshot::channel();
+        queues
+            .push("b".to_string(), async move {
+                let _ = tx.send(());
+            })
+            .await;
+        timeout(Duration::from_secs(1), rx).await.unwrap().unwrap();
+        release.notify_one();
+    }
+
+    #[tokio::test]
+    async fn push_waits_while_the_chat_is_full() {
+        let queues = ChatQueues::new(2, 1);
+        let release = Arc::new(Notify::new());
+        let wedged = release.clone();
+        queues
+            .push("a".to_string(), async move { wedged.notified().await })
+            .await;
+        // the wedged job is taken by the worker, so one more fits
+        timeout(
+            Duration::from_secs(1),
+            queues.push("a".to_string(), async {}),
+        )
+        .await
+        .unwrap();
+        let full = timeout(
+            Duration::from_millis(100),
+            queues.push("a".to_string(), async {}),
+        )
+        .await;
+        assert!(full.is_err());
+
+        release.notify_one();
+        timeout(
+            Duration::from_secs(1),
+            queues.push("a".to_string(), async {}),
+        )
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::time::sleep;
+
+use crate::constants;
+use crate::error::{Error, Result};
+use crate::wechat::{WechatInstance, WechatUserInfo};
+use crate::ws::send::{EventType, WebsocketEvent};
+
+use super::WechatManager;
+
+// mxid -> (wxid, contact ids)
+type Snapshots = HashMap<String, (String, BTreeSet<String>)>;
+
+/// contact ids of logged in accounts keyed by mxid, along with the wxid they belong to. They are
+/// kept in memory, therefore, the first poll after start or relogin only takes the baseline
+#[derive(Clone, Default)]
+pub struct ContactWatch {
+    snapshots: Arc<Mutex<Snapshots>>,
+}
+
+impl ContactWatch {
+    fn lock(&self) -> Result<MutexGuard<'_, Snapshots>> {
+        self.snapshots
+            .lock()
+            .map_err(|err| Error::Internal(format!("lock contact snapshots failed: {}", err)))
+    }
+
+    /// contact ids of mxid, or None if there is no snapshot of the account self_id yet
+    fn snapshot(&self, mxid: &str, self_id: &str) -> Result<Option<BTreeSet<String>>> {
+        Ok(match self.lock()?.get(mxid) {
+            Some((wxid, ids)) if wxid == self_id => Some(ids.clone()),
+            _ => None,
+        })
+    }
+
+    fn replace(&self, mxid: &str, self_id: &str, ids: BTreeSet<String>) -> Result<()> {
+        self.lock()?
+            .insert(mxid.to_string(), (self_id.to_string(), ids));
+        Ok(())
+    }
+
+    // apply a delivered friend event to the snapshot. Nothing changes if the account of mxid is another one
+    fn apply(&self, mxid: &str, self_id: &str, wxid: &str, added: bool) -> Result<()> {
+        if let Some((owner, ids)) = self.lock()?.get_mut(mxid) {
+            if owner == self_id {
+                match added {
+                    true => ids.insert(wxid.to_string()),
+                    false => ids.remove(wxid),
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WechatManager {
+    ///
+    /// diff contacts of logged in instances and emit friend events. It never returns unless polling is disabled
+    ///
+    pub async fn start_contact_polling(&self) {
+        let contacts = match &self.contacts {
+            Some(contacts) => contacts,
+            None => {
+                info!("contact polling is disabled");
+                return;
+            }
+        };
+        let interval = Duration::from_secs(self.config.contact_poll_interval_secs);
+        loop {
+            sleep(interval).await;
+            let instances = match self.instances() {
+                Ok(instances) => instances,
+                Err(e) => {
+                    error!("list instances failed: {}", e);
+                    continue;
+                }
+            };
+            for ins in instances {
+                if let Err(e) = self.poll_contacts(contacts, &ins).await {
+                    debug!("poll contacts of {} failed: {}", ins.mxid, e);
+                }
+            }
+        }
+    }
+
+    /// poll contacts of the instance now instead of waiting for the interval, e.g. after accepting
+    /// a friend request
+    pub(super) fn poll_contacts_soon(&self, ins: WechatInstance) {
+        if self.contacts.is_none() {
+            return;
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(
+                constants::ACCEPTED_FRIEND_POLL_DELAY_MS,
+            ))
+            .await;
+            if let Some(contacts) = &manager.contacts {
+                if let Err(e) = manager.poll_contacts(contacts, &ins).await {
+                    warn!("poll contacts of {} failed: {}", ins.mxid, e);
+                }
+            }
+        });
+    }
+
+    async fn poll_contacts(&self, contacts: &ContactWatch, ins: &WechatInstance) -> Result<()> {
+        self.ensure_not_logged_out(ins.pid)?;
+        let self_id = match ins.cached_self_wxid() {
+            Some(wxid) => wxid,
+            None => ins.get_self().await?.id,
+        };
+        if self_id.is_empty() {
+            return Err(Error::NotLoggedIn);
+        }
+        let ids = ins.get_contact_ids().await?;
+        // contact db is unavailable if nothing is returned. It must not be taken as everyone removed
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let old = match contacts.snapshot(&ins.mxid, &self_id)? {
+            Some(old) => old,
+            // the first poll of the account takes the baseline
+            None => return contacts.replace(&ins.mxid, &self_id, ids),
+        };
+        let added: Vec<String> = ids.difference(&old).cloned().collect();
+        let removed: Vec<String> = old.difference(&ids).cloned().collect();
+        if !added.is_empty() || !removed.is_empty() {
+            info!(
+                "contacts of {} changed: {} added, {} removed",
+                ins.mxid,
+                added.len(),
+                removed.len()
+            );
+        }
+        let changes = added
+            .into_iter()
+            .map(|wxid| (EventType::FriendAdd, wxid))
+            .chain(
+                removed
+                    .into_iter()
+                    .map(|wxid| (EventType::FriendRemove, wxid)),
+            );
+        for (event_type, wxid) in changes {
+            // fall back to the id instead of holding the event back
+            let info = match ins.get_user_info(wxid.clone()).await {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!("get user info of contact {} failed: {}", wxid, e);
+                    WechatUserInfo {
+                        id: wxid.clone(),
+                        nickname: wxid.clone(),
+                        avatar: None,
+                        remark: None,
+                        is_enterprise: wxid.ends_with("@openim"),
+                    }
+                }
+            };
+            let mut event = WebsocketEvent::<WechatUserInfo>::agent(
+                ins.mxid.clone(),
+                event_type,
+                String::new(),
+                Some(info),
+            );
+            event.base.sender = wxid.clone();
+            self.write_event_resp(event).await?;
+            // the snapshot follows delivered events only, so that undelivered ones are found by the next poll
+            contacts.apply(
+                &ins.mxid,
+                &self_id,
+                &wxid,
+                matches!(event_type, EventType::FriendAdd),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn snapshot_follows_applied_changes_only() {
+        let watch = ContactWatch::default();
+        watch
+            .replace("@a:x", "wxid_self", ids(&["wxid_a", "wxid_b"]))
+            .unwrap();
+
+        // wxid_c is added and wxid_a removed, but only the add is delivered yet
+        watch.apply("@a:x", "wxid_self", "wxid_c", true).unwrap();
+        assert_eq!(
+            watch.snapshot("@a:x", "wxid_self").unwrap(),
+            Some(ids(&["wxid_a", "wxid_b", "wxid_c"]))
+        );
+        watch.apply("@a:x", "wxid_self", "wxid_a", false).unwrap();
+        assert_eq!(
+            watch.snapshot("@a:x", "wxid_self").unwrap(),
+            Some(ids(&["wxid_b", "wxid_c"]))
+        );
+    }
+
+    #[test]
+    fn another_account_of_the_mxid_takes_a_new_baseline() {
+        let watch = ContactWatch::default();
+        watch.replace("@a:x", "wxid_old", ids(&["wxid_a"])).unwrap();
+
+        assert_eq!(watch.snapshot("@a:x", "wxid_new").unwrap(), None);
+        // events of the old account delivered late do not touch it
+        watch.replace("@a:x", "wxid_new", ids(&["wxid_b"])).unwrap();
+        watch.apply("@a:x", "wxid_old", "wxid_c", true).unwrap();
+        assert_eq!(
+            watch.snapshot("@a:x", "wxid_new").unwrap(),
+            Some(ids(&["wxid_b"]))
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use log::{error, info};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::constants;
+use crate::error::Result;
+
+const SEEN_TREE: &str = "seen";
+
+/// wechat msg ids already delivered, per self wxid. wechat replays recent messages after restart,
+/// therefore, ids are persisted to the state db if it is available. Persisting is batched in background
+#[derive(Clone)]
+pub struct SeenMessages {
+    window_secs: i64,
+    // (self wxid, msg id) -> epoch secs when it is seen
+    seen: Arc<Mutex<HashMap<(String, u64), i64>>>,
+    persist_chan: Option<UnboundedSender<(Vec<u8>, i64)>>,
+}
+
+impl SeenMessages {
+    pub fn new(window_secs: u64, db: Option<&sled::Db>) -> SeenMessages {
+        let mut seen = HashMap::new();
+        let persist_chan = match db.map(|db| db.open_tree(SEEN_TREE)) {
+            Some(Ok(tree)) => {
+                let now = Utc::now().timestamp();
+                if let Err(e) = load_seen(&tree, now - window_secs as i64, &mut seen) {
+                    error!("load seen messages failed: {}", e);
+                }
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(persist_seen(tree, rx, window_secs as i64));
+                Some(tx)
+            }
+            Some(Err(e)) => {
+                error!("open seen messages tree failed: {}", e);
+                None
+            }
+            None => None,
+        };
+        SeenMessages {
+            window_secs: window_secs as i64,
+            seen: Arc::new(Mutex::new(seen)),
+            persist_chan,
+        }
+    }
+
+    /// whether msg id is seen in the window. It is checked before the message is handled
+    pub fn is_seen(&self, self_id: &str, id: u64) -> bool {
+        let now = Utc::now().timestamp();
+        match self.seen.lock() {
+            Ok(seen) => matches!(
+                seen.get(&(self_id.to_string(), id)),
+                Some(ts) if now - ts < self.window_secs
+            ),
+            Err(err) => {
+                error!("lock seen messages failed: {}", err);
+                false
+            }
+        }
+    }
+
+    /// record msg id once the message is delivered, so that a failed delivery is not dropped as seen
+    /// when wechat replays it
+    pub fn record(&self, self_id: &str, id: u64) {
+        let now = Utc::now().timestamp();
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(err) => {
+                error!("lock seen messages failed: {}", err);
+                return;
+            }
+        };
+        seen.insert((self_id.to_string(), id), now);
+        if seen
+            .len()
+            .is_multiple_of(constants::SEEN_MESSAGE_EXPIRE_EVERY)
+        {
+            let cutoff = now - self.window_secs;
+            seen.retain(|_, ts| *ts >= cutoff);
+        }
+        drop(seen);
+
+        if let Some(chan) = &self.persist_chan {
+            if let Err(e) = chan.send((seen_key(self_id, id), now)) {
+                error!("queue seen message {} failed: {}", id, e);
+            }
+        }
+    }
+}
+
+fn seen_key(self_id: &str, id: u64) -> Vec<u8> {
+    let mut key = self_id.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn load_seen(tree: &sled::Tree, cutoff: i64, seen: &mut HashMap<(String, u64), i64>) -> Result<()> {
+    for item in tree.iter() {
+        let (key, value) = item?;
+        let ts = value
+            .as_ref()
+            .try_into()
+            .map(i64::from_be_bytes)
+            .unwrap_or(0);
+        if ts < cutoff || key.len() < 9 {
+            tree.remove(key)?;
+            continue;
+        }
+        let (self_id, id) = key.split_at(key.len() - 9);
+        if let Ok(id) = id[1..].try_into().map(u64::from_be_bytes) {
+            seen.insert((String::from_utf8_lossy(self_id).to_string(), id), ts);
+        }
+    }
+    info!("load {} seen messages", seen.len());
+    Ok(())
+}
+
+// write queued ids in batches and drop expired ones from time to time
+async fn persist_seen(
+    tree: sled::Tree,
+    mut rx: UnboundedReceiver<(Vec<u8>, i64)>,
+    window_secs: i64,
+) {
+    let mut written = 0;
+    while let Some((key, ts)) = rx.recv().await {
+        let mut batch = sled::Batch::default();
+        batch.insert(key, &ts.to_be_bytes());
+        while let Ok((key, ts)) = rx.try_recv() {
+            batch.insert(key, &ts.to_be_bytes());
+            written += 1;
+        }
+        written += 1;
+        if let Err(e) = tree.apply_batch(batch) {
+            error!("persist seen messages failed: {}", e);
+        }
+
+        if written >= constants::SEEN_MESSAGE_EXPIRE_EVERY {
+            written = 0;
+            let cutoff = Utc::now().timestamp() - window_secs;
+            let mut expired = sled::Batch::default();
+            for (key, value) in tree.iter().flatten() {
+                let ts = value.as_ref().try_into().map(i64::from_be_bytes);
+                if !matches!(ts, Ok(ts) if ts >= cutoff) {
+                    expired.remove(key);
+                }
+            }
+            if let Err(e) = tree.apply_batch(expired) {
+                error!("expire seen messages failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_seen_once_recorded() {
+        let seen = SeenMessages::new(60, None);
+        assert!(!seen.is_seen("wxid_self", 1));
+        // e.g. delivery failed, so that the replay is handled again
+        assert!(!seen.is_seen("wxid_self", 1));
+
+        seen.record("wxid_self", 1);
+        assert!(seen.is_seen("wxid_self", 1));
+        assert!(!seen.is_seen("wxid_other", 1));
+        assert!(!seen.is_seen("wxid_self", 2));
+    }
+
+    #[test]
+    fn messages_are_forgotten_after_the_window() {
+        let seen = SeenMessages::new(0, None);
+        seen.record("wxid_self", 1);
+        assert!(!seen.is_seen("wxid_self", 1));
+    }
+
+    #[test]
+    fn persisted_messages_in_the_window_are_loaded() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(SEEN_TREE).unwrap();
+        let now = Utc::now().timestamp();
+        tree.insert(seen_key("wxid_self", 1), &now.to_be_bytes())
+            .unwrap();
+        tree.insert(seen_key("wxid_self", 2), &(now - 120).to_be_bytes())
+            .unwrap();
+
+        let mut seen = HashMap::new();
+        load_seen(&tree, now - 60, &mut seen).unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(seen.contains_key(&("wxid_self".to_string(), 1)));
+        // expired ones are removed from db as well
+        assert!(tree.get(seen_key("wxid_self", 2)).unwrap().is_none());
+    }
+}
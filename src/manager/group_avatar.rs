@@ -0,0 +1,202 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{imageops, DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use log::{debug, info, warn};
+
+use crate::constants;
+use crate::error::{Error, Result};
+use crate::utils::{self, DownloadOptions};
+use crate::wechat::{WechatGroupInfo, WechatInstance};
+
+use super::WechatManager;
+
+const GROUP_AVATAR_DIR: &str = "group_avatars";
+// composite avatars are served by the avatar command with urls of this scheme, followed by
+// <group id>/<md5 of member list>
+const GROUP_AVATAR_SCHEME: &str = "wechat-group-avatar://";
+
+impl WechatManager {
+    /// wechat composites group avatars on the client. Do the same with avatars of the first
+    /// members if the group has no avatar url. The composite is cached by the member list
+    pub(super) async fn fill_group_avatar(
+        &self,
+        ins: &WechatInstance,
+        group: &mut WechatGroupInfo,
+    ) {
+        if !group.avatar.is_empty() || group.member_ids.is_empty() {
+            return;
+        }
+        match self
+            .composite_group_avatar(ins, &group.id, &group.member_ids)
+            .await
+        {
+            Ok(key) => group.avatar = format!("{}{}/{}", GROUP_AVATAR_SCHEME, group.id, key),
+            Err(e) => warn!("composite avatar of group {} failed: {}", group.id, e),
+        }
+    }
+
+    /// bytes of an avatar url, which may be a composite group avatar
+    pub(super) async fn get_avatar(&self, mxid: String, url: String) -> Result<Vec<u8>> {
+        match url.strip_prefix(GROUP_AVATAR_SCHEME) {
+            Some(path) => self.read_group_avatar(mxid, path).await,
+            None => self.download_avatar(url).await,
+        }
+    }
+
+    async fn download_avatar(&self, url: String) -> Result<Vec<u8>> {
+        match &self.avatars {
+            Some(avatars) => avatars.get(&url).await,
+            None => {
+                utils::get_file_maybe_gzip_decompress(
+                    &self.media_client,
+                    url,
+                    DownloadOptions {
+                        max_size: constants::MAX_AVATAR_SIZE,
+                        content_type: Some("image/"),
+                    },
+                )
+                .await
+            }
+        }
+    }
+
+    // the composite is generated again from current members if the cached one is gone
+    async fn read_group_avatar(&self, mxid: String, path: &str) -> Result<Vec<u8>> {
+        let (group_id, key) = match path.rsplit_once('/') {
+            Some((group_id, key))
+                if group_id.ends_with("@chatroom")
+                    && !key.is_empty()
+                    && key.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                (group_id, key)
+            }
+            _ => {
+                return Err(Error::InvalidRequest(format!(
+                    "invalid group avatar url {}{}",
+                    GROUP_AVATAR_SCHEME, path
+                )))
+            }
+        };
+        match tokio::fs::read(self.group_avatar_path(key)).await {
+            Ok(blob) => Ok(blob),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(
+                    "avatar of group {} is not cached, composite it again",
+                    group_id
+                );
+                let ins = self.get_instance_by_mxid(mxid)?;
+                let member_ids = ins.get_group_members(group_id.to_string()).await?;
+                let key = self
+                    .composite_group_avatar(&ins, group_id, &member_ids)
+                    .await?;
+                Ok(tokio::fs::read(self.group_avatar_path(&key)).await?)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // return the cache key of the composite
+    async fn composite_group_avatar(
+        &self,
+        ins: &WechatInstance,
+        group_id: &str,
+        member_ids: &[String],
+    ) -> Result<String> {
+        let key = utils::calculate_md5(member_ids.join("\n").as_bytes());
+        let path = self.group_avatar_path(&key);
+        if path.exists() {
+            return Ok(key);
+        }
+
+        let mut avatars = vec![];
+        for member_id in member_ids {
+            if avatars.len() >= constants::MAX_GROUP_AVATAR_MEMBERS {
+                break;
+            }
+            let url = match ins.get_user_info(member_id.clone()).await {
+                Ok(info) => info.avatar,
+                Err(e) => {
+                    debug!("get user info of member {} failed: {}", member_id, e);
+                    None
+                }
+            };
+            if let Some(url) = url {
+                match self.download_avatar(url).await {
+                    Ok(blob) => avatars.push(blob),
+                    Err(e) => debug!("download avatar of member {} failed: {}", member_id, e),
+                }
+            }
+        }
+
+        // decoding and resizing are cpu bound
+        let jpeg = tokio::task::spawn_blocking(move || composite(&avatars))
+            .await
+            .map_err(|e| Error::Internal(format!("composite task failed: {}", e)))??;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, jpeg).await?;
+        info!(
+            "composite avatar of group {} into {}",
+            group_id,
+            path.display()
+        );
+        Ok(key)
+    }
+
+    fn group_avatar_path(&self, key: &str) -> PathBuf {
+        Path::new(&self.config.save_path)
+            .join(GROUP_AVATAR_DIR)
+            .join(format!("{}.jpg", key))
+    }
+}
+
+// jpeg of avatars in a grid of up to 3x3 on a gray background. The last row is centered if it is
+// not full. Avatars which cannot be decoded are skipped
+fn composite(avatars: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let images: Vec<DynamicImage> = avatars
+        .iter()
+        .filter_map(|blob| image::load_from_memory(blob).ok())
+        .collect();
+    if images.is_empty() {
+        return Err(Error::NotFound("decodable member avatar".to_string()));
+    }
+
+    let count = images.len() as u32;
+    let cols = match count {
+        1 => 1,
+        2..=4 => 2,
+        _ => 3,
+    };
+    let rows = count.div_ceil(cols);
+    let size = constants::GROUP_AVATAR_SIZE;
+    let gap = constants::GROUP_AVATAR_GAP;
+    let cell = (size - gap * (cols + 1)) / cols;
+    let top = (size - rows * cell - (rows - 1) * gap) / 2;
+
+    let mut canvas = RgbImage::from_pixel(size, size, Rgb([221, 221, 221]));
+    for (i, image) in images.iter().enumerate() {
+        let (row, col) = (i as u32 / cols, i as u32 % cols);
+        let row_len = cols.min(count - row * cols);
+        let left = (size - row_len * cell - (row_len - 1) * gap) / 2;
+        let tile = image
+            .resize_to_fill(cell, cell, imageops::FilterType::Triangle)
+            .to_rgb8();
+        imageops::overlay(
+            &mut canvas,
+            &tile,
+            (left + col * (cell + gap)) as i64,
+            (top + row * (cell + gap)) as i64,
+        );
+    }
+
+    let mut jpeg = Cursor::new(vec![]);
+    DynamicImage::ImageRgb8(canvas)
+        .write_to(
+            &mut jpeg,
+            ImageOutputFormat::Jpeg(constants::GROUP_AVATAR_QUALITY),
+        )
+        .map_err(|e| Error::Internal(format!("encode group avatar failed: {}", e)))?;
+    Ok(jpeg.into_inner())
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::time::sleep;
+
+use crate::constants;
+use crate::error::{Error, Result};
+use crate::wechat::WechatInstance;
+use crate::ws::send::{EventType, WebsocketEvent};
+use crate::ws::MatrixMessageDataField;
+
+use super::WechatManager;
+
+pub type HookHealthMap = HashMap<u32, HookHealth>;
+
+/// hook state of an instance observed by health check
+pub struct HookHealth {
+    logged_in: bool,
+    last_callback: Instant,
+    alerted: bool,
+    // forced offline by wechat. cleared after re-login
+    logged_out: bool,
+}
+
+impl Default for HookHealth {
+    fn default() -> Self {
+        HookHealth {
+            logged_in: false,
+            last_callback: Instant::now(),
+            alerted: false,
+            logged_out: false,
+        }
+    }
+}
+
+impl WechatManager {
+    ///
+    /// detect hooks broken by wechat auto update, and logouts and re-logins. It never returns
+    /// unless health check is disabled, which disables logout detection as well
+    ///
+    pub async fn start_health_check(&self) {
+        if self.config.health_check_interval_secs == 0 {
+            info!("hook health check is disabled");
+            return;
+        }
+        let interval = Duration::from_secs(self.config.health_check_interval_secs);
+        loop {
+            sleep(interval).await;
+            let instances = match self.instances() {
+                Ok(instances) => instances,
+                Err(e) => {
+                    error!("list instances for health check failed: {}", e);
+                    continue;
+                }
+            };
+            if let Ok(mut health) = self.hook_health.lock() {
+                health.retain(|pid, _| instances.iter().any(|ins| ins.pid == *pid));
+            }
+            for ins in instances {
+                self.check_hook_health(ins).await;
+            }
+        }
+    }
+
+    pub(super) fn record_callback(&self, pid: u32) {
+        match self.hook_health.lock() {
+            Ok(mut health) => {
+                let h = health.entry(pid).or_default();
+                h.last_callback = Instant::now();
+                h.alerted = false;
+            }
+            Err(err) => error!("lock hook health failed: {}", err),
+        }
+    }
+
+    async fn check_hook_health(&self, ins: WechatInstance) {
+        // crashed instance is reported when it is used
+        if !ins.is_alive().unwrap_or(false) {
+            return;
+        }
+
+        let (was_logged_in, was_logged_out, silent, alerted) = match self.hook_health.lock() {
+            Ok(mut health) => {
+                let h = health.entry(ins.pid).or_default();
+                let silent = h.last_callback.elapsed()
+                    > Duration::from_secs(constants::HOOK_SILENCE_THRESHOLD_SECS);
+                (h.logged_in, h.logged_out, silent, h.alerted)
+            }
+            Err(err) => {
+                error!("lock hook health failed: {}", err);
+                return;
+            }
+        };
+
+        // a clean answer means the hook works, even if a quiet account is logged out. The hook is
+        // broken when the driver rejects the call, or keeps failing while callbacks stop arriving
+        let (logged_in, broken) = match ins.is_login().await {
+            Ok(logged_in) => (logged_in, false),
+            Err(e) => {
+                warn!("check login of instance[pid={}] failed: {}", ins.pid, e);
+                (
+                    was_logged_in,
+                    is_version_mismatch(&e) || (was_logged_in && silent),
+                )
+            }
+        };
+        self.set_hook_health(ins.pid, |h| {
+            h.logged_in = logged_in;
+            h.logged_out &= !logged_in;
+        });
+        if was_logged_in && !logged_in {
+            self.handle_logout(ins).await;
+            return;
+        }
+        // logged in again without the qr code flow, e.g. confirmed on the phone
+        if was_logged_out && logged_in {
+            self.handle_relogin(ins).await;
+            return;
+        }
+        if !broken || alerted {
+            return;
+        }
+
+        warn!(
+            "hook of instance[pid={}] seems broken. wechat may be updated",
+            ins.pid
+        );
+        if let Some(version) = &self.config.spoof_wechat_version {
+            match ins.set_version(version.clone()).await {
+                Ok(()) if ins.is_login().await.unwrap_or(false) => {
+                    info!(
+                        "recover hook of instance[pid={}] by setting version {}",
+                        ins.pid, version
+                    );
+                    self.set_hook_health(ins.pid, |h| h.logged_in = true);
+                    return;
+                }
+                Ok(()) => warn!("set version of instance[pid={}] does not help", ins.pid),
+                Err(e) => error!("set version of instance[pid={}] failed: {}", ins.pid, e),
+            }
+        }
+
+        let event = WebsocketEvent::<MatrixMessageDataField>::agent(
+            ins.mxid.clone(),
+            EventType::Alert,
+            self.config.locale.texts().hook_broken.to_string(),
+            None,
+        );
+        match self.write_event_resp(event).await {
+            Ok(()) => self.set_hook_health(ins.pid, |h| h.alerted = true),
+            Err(e) => error!("deliver hook broken alert of {} failed: {}", ins.mxid, e),
+        }
+    }
+
+    async fn handle_logout(&self, ins: WechatInstance) {
+        warn!("instance[pid={}] of {} is logged out", ins.pid, ins.mxid);
+        self.set_hook_health(ins.pid, |h| h.logged_out = true);
+        ins.clear_info_cache();
+        // the next login may be another account
+        ins.forget_self_wxid();
+        if let Err(e) = ins.unhook_wechat_media().await {
+            warn!("unhook media of instance[pid={}] failed: {}", ins.pid, e);
+        }
+
+        let event = WebsocketEvent::<MatrixMessageDataField>::agent(
+            ins.mxid.clone(),
+            EventType::Logout,
+            self.config.locale.texts().logged_out.to_string(),
+            None,
+        );
+        if let Err(e) = self.write_event_resp(event).await {
+            error!("deliver logout event of {} failed: {}", ins.mxid, e);
+        }
+
+        if self.config.drop_on_logout {
+            match self.drop_instance(ins.mxid.clone()) {
+                Ok(()) => info!("drop logged out instance[pid={}]", ins.pid),
+                Err(e) => error!("drop logged out instance[pid={}] failed: {}", ins.pid, e),
+            }
+        }
+    }
+
+    // media hooks are stopped on logout, therefore, they are set again
+    async fn handle_relogin(&self, ins: WechatInstance) {
+        info!(
+            "instance[pid={}] of {} is logged in again",
+            ins.pid, ins.mxid
+        );
+        if let Err(e) = ins.hook_wechat_message(self.config.save_path.clone()).await {
+            warn!("rehook {} after login failed: {}", ins.mxid, e);
+        }
+    }
+
+    pub(super) fn mark_logged_in(&self, pid: u32) {
+        self.set_hook_health(pid, |h| {
+            h.logged_in = true;
+            h.logged_out = false;
+        });
+    }
+
+    /// fail fast for instances forced offline instead of calling the hook
+    pub(super) fn ensure_not_logged_out(&self, pid: u32) -> Result<()> {
+        match self.hook_health.lock() {
+            Ok(health) => match health.get(&pid) {
+                Some(h) if h.logged_out => Err(Error::NotLoggedIn),
+                _ => Ok(()),
+            },
+            Err(err) => Err(Error::Internal(format!("lock hook health failed: {}", err))),
+        }
+    }
+
+    fn set_hook_health(&self, pid: u32, f: impl FnOnce(&mut HookHealth)) {
+        match self.hook_health.lock() {
+            Ok(mut health) => f(health.entry(pid).or_default()),
+            Err(err) => error!("lock hook health failed: {}", err),
+        }
+    }
+}
+
+// driver dll of another wechat version rejects the calls
+fn is_version_mismatch(e: &Error) -> bool {
+    matches!(e, Error::HookResult(msg) if msg.to_lowercase().contains("version"))
+}
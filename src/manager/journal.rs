@@ -0,0 +1,273 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use crate::error::{Error, Result};
+
+const JOURNAL_TREE: &str = "journal";
+// journal id -> (mxid, seq) key of the journal, i.e. events in the order they are journaled
+const ORDER_TREE: &str = "journal_order";
+const SEQS_TREE: &str = "seqs";
+const MEDIA_DIR: &str = "journal_media";
+
+/// append-only journal of serialized events which are not delivered yet. Events are journaled
+/// before delivery and replayed after a crash. Media blobs are kept in files beside the db
+#[derive(Clone)]
+pub struct EventJournal {
+    // keyed by (mxid, seq) so that acks remove a range
+    events: sled::Tree,
+    order: sled::Tree,
+    // last seq of each mxid. seq keeps increasing across restarts so that acks of replayed events still work
+    seqs: sled::Tree,
+    db: sled::Db,
+    media_dir: PathBuf,
+    capacity: usize,
+    // sled counts a tree by scanning it
+    count: Arc<AtomicUsize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct JournalEntry {
+    // unique id of the entry, which also names its media file
+    pub id: u64,
+    pub mxid: String,
+    pub seq: u64,
+    pub data: String,
+}
+
+// mxids never contain NUL, therefore, the keys of one mxid are a contiguous range ordered by seq
+fn entry_key(mxid: &str, seq: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(mxid.len() + 9);
+    key.extend_from_slice(mxid.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+fn decode_id(id: &[u8]) -> Result<u64> {
+    id.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| Error::Store(format!("invalid journal id of {} bytes", id.len())))
+}
+
+impl EventJournal {
+    pub fn new(db: &sled::Db, dir: &Path, capacity: usize) -> Result<EventJournal> {
+        let media_dir = dir.join(MEDIA_DIR);
+        std::fs::create_dir_all(&media_dir)?;
+        let order = db.open_tree(ORDER_TREE)?;
+        Ok(EventJournal {
+            events: db.open_tree(JOURNAL_TREE)?,
+            count: Arc::new(AtomicUsize::new(order.len())),
+            order,
+            seqs: db.open_tree(SEQS_TREE)?,
+            db: db.clone(),
+            media_dir,
+            capacity,
+        })
+    }
+
+    /// journal event and return its id. The oldest entries are dropped if the journal is full
+    pub fn append(&self, mxid: &str, seq: u64, data: &str) -> Result<u64> {
+        let id = self.db.generate_id()?;
+        let entry = JournalEntry {
+            id,
+            mxid: mxid.to_string(),
+            seq,
+            data: self.externalize_media(id, data)?,
+        };
+        let key = entry_key(mxid, seq);
+        self.events.insert(&key, serde_json::to_vec(&entry)?)?;
+        self.order.insert(id.to_be_bytes(), key)?;
+        self.seqs.insert(mxid.as_bytes(), &seq.to_be_bytes())?;
+
+        let mut count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        while count > self.capacity {
+            match self.order.first()? {
+                Some((old, _)) => {
+                    warn!("event journal is full, drop the oldest event");
+                    self.remove(decode_id(&old)?)?;
+                    count = self.count.load(Ordering::Relaxed);
+                }
+                None => break,
+            }
+        }
+        Ok(id)
+    }
+
+    pub fn remove(&self, id: u64) -> Result<()> {
+        if let Some(key) = self.order.remove(id.to_be_bytes())? {
+            self.events.remove(key)?;
+            self.count.fetch_sub(1, Ordering::Relaxed);
+        }
+        let path = self.media_path(id);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// remove events of mxid whose seq is less than or equal to the acked seq
+    pub fn ack(&self, mxid: &str, seq: u64) -> Result<()> {
+        let mut ids = vec![];
+        for item in self.events.range(entry_key(mxid, 0)..=entry_key(mxid, seq)) {
+            let (_, value) = item?;
+            let entry: JournalEntry = serde_json::from_slice(&value)?;
+            ids.push(entry.id);
+        }
+        for id in ids {
+            self.remove(id)?;
+        }
+        Ok(())
+    }
+
+    /// journaled events in the order of delivery, with media inlined again
+    pub fn load(&self) -> Result<Vec<(u64, JournalEntry)>> {
+        let mut entries = vec![];
+        for item in self.order.iter() {
+            let (id, key) = item?;
+            let id = decode_id(&id)?;
+            let value = match self.events.get(key)? {
+                Some(value) => value,
+                None => {
+                    warn!("journal entry {} is gone, drop it", id);
+                    self.order.remove(id.to_be_bytes())?;
+                    continue;
+                }
+            };
+            let mut entry: JournalEntry = serde_json::from_slice(&value)?;
+            entry.data = self.internalize_media(id, &entry.data)?;
+            entries.push((id, entry));
+        }
+        if !entries.is_empty() {
+            info!("load {} undelivered events from journal", entries.len());
+        }
+        Ok(entries)
+    }
+
+    pub fn last_seqs(&self) -> Result<Vec<(String, u64)>> {
+        let mut seqs = vec![];
+        for item in self.seqs.iter() {
+            let (mxid, seq) = item?;
+            let seq = seq
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| Error::Store("invalid journal seq".to_string()))?;
+            seqs.push((String::from_utf8_lossy(&mxid).to_string(), seq));
+        }
+        Ok(seqs)
+    }
+
+    fn media_path(&self, id: u64) -> PathBuf {
+        self.media_dir.join(format!("{}.bin", id))
+    }
+
+    // move extra.binary of blob events into a file and keep the file path instead
+    fn externalize_media(&self, id: u64, data: &str) -> Result<String> {
+        let mut event: serde_json::Value = serde_json::from_str(data)?;
+        let binary = match event.pointer_mut("/extra/binary") {
+            Some(binary) => binary.take(),
+            None => return Ok(data.to_string()),
+        };
+        let bytes: Vec<u8> = serde_json::from_value(binary)?;
+        let path = self.media_path(id);
+        std::fs::write(&path, bytes)?;
+        if let Some(extra) = event.pointer_mut("/extra").and_then(|e| e.as_object_mut()) {
+            extra.remove("binary");
+            extra.insert(
+                "binaryFile".to_string(),
+                serde_json::Value::String(path.display().to_string()),
+            );
+        }
+        Ok(serde_json::to_string(&event)?)
+    }
+
+    fn internalize_media(&self, id: u64, data: &str) -> Result<String> {
+        let mut event: serde_json::Value = serde_json::from_str(data)?;
+        let extra = match event.pointer_mut("/extra").and_then(|e| e.as_object_mut()) {
+            Some(extra) if extra.contains_key("binaryFile") => extra,
+            _ => return Ok(data.to_string()),
+        };
+        extra.remove("binaryFile");
+        let bytes = std::fs::read(self.media_path(id))?;
+        extra.insert("binary".to_string(), serde_json::to_value(bytes)?);
+        Ok(serde_json::to_string(&event)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(capacity: usize) -> (EventJournal, PathBuf) {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "journal-test-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        (EventJournal::new(&db, &dir, capacity).unwrap(), dir)
+    }
+
+    fn seqs_of(journal: &EventJournal) -> Vec<(String, u64)> {
+        journal
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| (entry.mxid, entry.seq))
+            .collect()
+    }
+
+    #[test]
+    fn ack_removes_acked_events_of_the_mxid_only() {
+        let (journal, dir) = open(10);
+        journal.append("@a:x", 1, r#"{"id": 1}"#).unwrap();
+        // its mxid starts with the other one
+        journal.append("@a:xy", 1, r#"{"id": 2}"#).unwrap();
+        journal.append("@a:x", 2, r#"{"id": 3}"#).unwrap();
+        journal.append("@a:x", 3, r#"{"id": 4}"#).unwrap();
+
+        journal.ack("@a:x", 2).unwrap();
+        assert_eq!(
+            seqs_of(&journal),
+            vec![("@a:xy".to_string(), 1), ("@a:x".to_string(), 3)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn full_journal_drops_the_oldest_event() {
+        let (journal, dir) = open(2);
+        journal.append("@b:x", 7, r#"{"id": 1}"#).unwrap();
+        journal.append("@a:x", 1, r#"{"id": 2}"#).unwrap();
+        journal.append("@a:x", 2, r#"{"id": 3}"#).unwrap();
+
+        assert_eq!(
+            seqs_of(&journal),
+            vec![("@a:x".to_string(), 1), ("@a:x".to_string(), 2)]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn media_is_kept_in_a_file_until_removed() {
+        let (journal, dir) = open(10);
+        let id = journal
+            .append("@a:x", 1, r#"{"id": 5, "extra": {"binary": [1, 2, 3]}}"#)
+            .unwrap();
+        let path = dir.join(MEDIA_DIR).join(format!("{}.bin", id));
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1, 2, 3]);
+
+        let entries = journal.load().unwrap();
+        let event: serde_json::Value = serde_json::from_str(&entries[0].1.data).unwrap();
+        assert_eq!(event["extra"]["binary"], serde_json::json!([1, 2, 3]));
+
+        journal.remove(id).unwrap();
+        assert!(!path.exists());
+        assert!(journal.load().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use tokio::time::sleep;
+
+use crate::constants;
+use crate::utils;
+use crate::wechat::WechatInstance;
+use crate::ws::send::{EventType, WebsocketEvent};
+use crate::ws::{MatrixMessageDataBlob, MatrixMessageDataField, MatrixMessageDataLogin};
+
+use super::WechatManager;
+
+impl WechatManager {
+    /// push refreshed login qr codes to matrix in background until logged in or timeout
+    pub(super) fn start_qrcode_polling(&self, ins: WechatInstance, qrcode: &[u8]) {
+        match self.polling_mxids.lock() {
+            Ok(mut polling) => {
+                if !polling.insert(ins.mxid.clone()) {
+                    info!("login qr code of {} is already being polled", ins.mxid);
+                    return;
+                }
+            }
+            Err(err) => {
+                error!("lock polling mxids failed: {}", err);
+                return;
+            }
+        }
+
+        let manager = self.clone();
+        let last_md5 = utils::calculate_md5(qrcode);
+        tokio::spawn(async move {
+            let mxid = ins.mxid.clone();
+            manager.poll_login_qrcode(ins, last_md5).await;
+            if let Ok(mut polling) = manager.polling_mxids.lock() {
+                polling.remove(&mxid);
+            }
+        });
+    }
+
+    async fn poll_login_qrcode(&self, ins: WechatInstance, mut last_md5: String) {
+        let deadline = Instant::now() + Duration::from_secs(constants::LOGIN_QRCODE_TIMEOUT_SECS);
+        while Instant::now() < deadline {
+            sleep(Duration::from_secs(
+                constants::LOGIN_QRCODE_POLL_INTERVAL_SECS,
+            ))
+            .await;
+
+            if self.is_login_cancelled(&ins) {
+                info!("login of {} is cancelled", ins.mxid);
+                self.write_agent_event(&ins.mxid, EventType::LoginCancelled, None)
+                    .await;
+                return;
+            }
+
+            match ins.is_login().await {
+                Ok(true) => {
+                    self.complete_login(&ins).await;
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("check login status of {} failed: {}", ins.mxid, e);
+                    continue;
+                }
+            }
+
+            match ins.fetch_login_qrcode().await {
+                Ok(qrcode) => {
+                    let md5 = utils::calculate_md5(&qrcode);
+                    if md5 == last_md5 {
+                        continue;
+                    }
+                    last_md5 = md5;
+                    debug!("push refreshed login qr code of {}", ins.mxid);
+                    let blob = MatrixMessageDataField::Blob(MatrixMessageDataBlob {
+                        name: None,
+                        binary: qrcode,
+                        source: None,
+                    });
+                    self.write_agent_event(&ins.mxid, EventType::LoginQRCode, Some(blob))
+                        .await;
+                }
+                Err(e) => debug!("fetch login qr code of {} failed: {}", ins.mxid, e),
+            }
+        }
+        warn!("wait for {} to scan login qr code timeout", ins.mxid);
+        self.write_agent_event(&ins.mxid, EventType::LoginTimeout, None)
+            .await;
+    }
+
+    // the instance being polled is disconnected, replaced or exited
+    fn is_login_cancelled(&self, ins: &WechatInstance) -> bool {
+        match self.get_instance_by_mxid(ins.mxid.clone()) {
+            Ok(current) if current.pid == ins.pid => !ins.is_alive().unwrap_or(false),
+            _ => true,
+        }
+    }
+
+    async fn complete_login(&self, ins: &WechatInstance) {
+        self.mark_logged_in(ins.pid);
+        // hooks set before login are sometimes lost
+        if let Err(e) = ins.hook_wechat_message(self.config.save_path.clone()).await {
+            warn!("rehook {} after login failed: {}", ins.mxid, e);
+        }
+
+        // bridge can still query GetSelf if self info is not ready yet
+        let login = match ins.get_self().await {
+            Ok(info) => {
+                info!("{} logged in by qr code as {}", ins.mxid, info.id);
+                Some(MatrixMessageDataField::Login(MatrixMessageDataLogin {
+                    wxid: info.id,
+                    nickname: info.nickname,
+                }))
+            }
+            Err(e) => {
+                error!("get self info of {} after login failed: {}", ins.mxid, e);
+                None
+            }
+        };
+        self.write_agent_event(&ins.mxid, EventType::Login, login)
+            .await;
+    }
+
+    async fn write_agent_event(
+        &self,
+        mxid: &str,
+        event_type: EventType,
+        extra: Option<MatrixMessageDataField>,
+    ) {
+        let event = WebsocketEvent::agent(mxid.to_string(), event_type, String::new(), extra);
+        if let Err(e) = self.write_event_resp(event).await {
+            error!("deliver agent event of {} failed: {}", mxid, e);
+        }
+    }
+}
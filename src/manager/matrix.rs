@@ -1,55 +1,262 @@
-use anyhow::bail;
-use log::info;
+use log::{error, info, warn};
 
 use crate::{
-    wechat::WechatInstance,
-    ws::{recv::MatrixRequestDataField, recv::WebsocketMatrixRequest, CommandType},
+    constants, emoticon,
+    error::{Error, Result},
+    utils,
+    wechat::{WechatInstance, WechatMessage},
+    ws::{
+        recv::{
+            MatrixMessageType, MatrixRequestDataAck, MatrixRequestDataAvatar,
+            MatrixRequestDataChat, MatrixRequestDataField, MatrixRequestDataFriendRequest,
+            MatrixRequestDataHistory, MatrixRequestDataLogin, MatrixRequestDataMembers,
+            MatrixRequestDataMessage, MatrixRequestDataPage, MatrixRequestDataPort,
+            MatrixRequestDataQuery, MatrixRequestDataReplay, RequestData, WebsocketMatrixRequest,
+        },
+        send::{BatchResult, ErrorCode},
+        CommandType, MatrixMessageDataField,
+    },
 };
 use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time::timeout;
 
+use super::responses::CommandState;
 use super::WechatManager;
 
 impl WechatManager {
+    /// create a new wechat instance for mxid and hook it. The process is killed if hooking failed
+    async fn spawn_instance(&self, mxid: String) -> Result<WechatInstance> {
+        let port = self.next_listen_port()?;
+        let mut ins = WechatInstance::new(
+            port,
+            self.config.save_path.clone(),
+            self.message_hook_port.load(Ordering::SeqCst),
+            mxid,
+            self.client.clone(),
+            self.media_client.clone(),
+            self.avatar_checker.clone(),
+            self.config.db_names.clone(),
+        )?;
+        self.record_pid(ins.pid);
+        ins.detect_version();
+        ins.slow_threshold = match self.config.slow_op_threshold_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        if self.config.info_cache_ttl_secs > 0 {
+            ins.enable_info_cache(Duration::from_secs(self.config.info_cache_ttl_secs));
+        }
+        let hooked = match ins.wait_hook_api().await {
+            Ok(()) => ins.hook_wechat_message(self.config.save_path.clone()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = hooked {
+            match ins.kill_self_process() {
+                Ok(true) => self.forget_pid(ins.pid),
+                Ok(false) => error!("kill unhooked instance[pid={}] failed", ins.pid),
+                Err(err) => error!("kill unhooked instance[pid={}] failed: {}", ins.pid, err),
+            }
+            return Err(e);
+        }
+        Ok(ins)
+    }
+
+    // ports in use, e.g. by instances of another agent on the same machine, are skipped
+    fn next_listen_port(&self) -> Result<u32> {
+        for _ in 0..constants::LISTEN_PORT_PROBE_COUNT {
+            let port = self.wechat_listen_port.fetch_add(1, Ordering::SeqCst);
+            let port_u16 = u16::try_from(port)
+                .map_err(|_| Error::Internal(format!("listen port {} is out of range", port)))?;
+            match std::net::TcpListener::bind(("127.0.0.1", port_u16)) {
+                Ok(_) => return Ok(port),
+                Err(e) => warn!("skip listen port {} of new instance: {}", port, e),
+            }
+        }
+        Err(Error::Internal(format!(
+            "no free listen port for new instance in {} attempts",
+            constants::LISTEN_PORT_PROBE_COUNT
+        )))
+    }
+
     ///
     /// handle matrix events(WebsocketMatrixRequest) and send resp(WebsocketCommand) to websocket
     ///
-    pub async fn handle_matrix_events(&self, msg: WebsocketMatrixRequest) -> anyhow::Result<()> {
+    pub async fn handle_matrix_events(&self, msg: WebsocketMatrixRequest) -> Result<()> {
         let mxid = msg.mxid.clone();
         let req_id = msg.req_id;
-        if let Err(e) = self._handle_matrix_events(msg).await {
-            self.write_command_error(mxid, req_id, e.to_string())
+
+        let fingerprint =
+            utils::calculate_md5(format!("{:?} {:?}", msg.command, msg.data).as_bytes());
+        let state = match self.recent_responses.lock() {
+            Ok(mut responses) => responses.begin(&mxid, req_id, fingerprint),
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock recent responses failed: {}",
+                    err
+                )))
+            }
+        };
+        match state {
+            CommandState::New => {}
+            CommandState::Running => {
+                info!(
+                    "command {} of {} is still running, skip the resent one",
+                    req_id, mxid
+                );
+                return Ok(());
+            }
+            CommandState::Answered(data) => {
+                info!(
+                    "answer resent command {} of {} with the kept response",
+                    req_id, mxid
+                );
+                return utils::retriable_write_raw(self.sender_chan.clone(), data, None).await;
+            }
+        }
+
+        let command = msg.command.clone();
+        let rerun_on_retry = rerun_on_retry(&msg, self.sent_messages.is_some());
+        let result = match self.command_timeout(&command) {
+            Some(limit) => match timeout(limit, self._handle_matrix_events(msg)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    let name = command_name(&command);
+                    warn!("command {} of {} times out after {:?}", req_id, mxid, limit);
+                    Err(Error::CommandTimeout(name, limit.as_secs()))
+                }
+            },
+            None => self._handle_matrix_events(msg).await,
+        };
+        if let Err(e) = result {
+            let timed_out = matches!(e, Error::CommandTimeout(..));
+            self.write_command_error(mxid.clone(), req_id, e.code(), e.to_string())
                 .await?;
+            // a retry of the timed out command is executed again instead of answered from the kept error
+            if timed_out && rerun_on_retry {
+                match self.recent_responses.lock() {
+                    Ok(mut responses) => responses.forget(&mxid, req_id),
+                    Err(err) => error!("lock recent responses failed: {}", err),
+                }
+            }
         }
 
         Ok(())
     }
 
-    pub async fn _handle_matrix_events(&self, msg: WebsocketMatrixRequest) -> anyhow::Result<()> {
+    // None if the command never times out
+    fn command_timeout(&self, command: &CommandType) -> Option<Duration> {
+        let secs = self
+            .config
+            .command_timeouts
+            .get(&command_name(command))
+            .copied()
+            .unwrap_or(self.config.command_timeout_secs);
+        match secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    ///
+    /// answer a command which fails to parse with an error carrying the raw payload, so that the
+    /// bridge can surface it instead of waiting forever. It fails if the target cannot be found
+    ///
+    pub async fn reject_malformed_command(&self, raw: &str, reason: String) -> Result<()> {
+        let (mxid, req_id) = WebsocketMatrixRequest::peek_target(raw).ok_or_else(|| {
+            Error::Parse(format!(
+                "no mxid and req id in malformed command: {}",
+                reason
+            ))
+        })?;
+        let payload = utils::truncate_for_error(raw);
+        warn!(
+            "reject malformed command {} of {}: {}",
+            req_id, mxid, reason
+        );
+        self.write_command_error(
+            mxid,
+            req_id,
+            ErrorCode::InvalidRequest,
+            format!("parse command failed: {}. payload: {}", reason, payload),
+        )
+        .await
+    }
+
+    pub async fn _handle_matrix_events(&self, msg: WebsocketMatrixRequest) -> Result<()> {
         // let ins = self.get_instance_by_mxid(msg.mxid)?;
         let mxid = msg.mxid;
         let req_id = msg.req_id;
+        let raw_data = msg.raw_data;
 
         info!("recv matrix event: command type: {:?}", msg.command);
 
+        if let Some(data) = &msg.data {
+            data.validate().map_err(|e| {
+                Error::InvalidRequest(format!(
+                    "invalid data of command {}: {}",
+                    command_name(&msg.command),
+                    e
+                ))
+            })?;
+        }
+
+        if requires_login(&msg.command) {
+            if let Ok(ins) = self.get_instance_by_mxid(mxid.clone()) {
+                self.ensure_not_logged_out(ins.pid)?;
+            }
+        }
+
         match msg.command {
+            CommandType::Unknown(command) => return Err(Error::UnknownCommand(command, req_id)),
+
+            CommandType::Capabilities => {
+                self.write_command_resp(mxid, req_id, Some(self.capabilities()))
+                    .await?
+            }
+
+            CommandType::GetStats => {
+                let stats = self.stats(mxid.clone())?;
+                self.write_command_resp(mxid, req_id, Some(stats)).await?
+            }
+
             CommandType::Connect => {
-               let ins = match self.get_instance_by_mxid(mxid.clone()) {
-                    Ok(ins) => ins,
-                    Err(_) => {
-                        let port = self.wechat_listen_port.fetch_add(1, Ordering::SeqCst);
-                        WechatInstance::new(
-                            port,
-                            self.save_path.clone(),
-                            self.message_hook_port,
-                            mxid.clone(),
-                        )?
+                // reject duplicated connect for the same mxid to avoid orphaned wechat process
+                let _connecting = self.start_connecting(&mxid)?;
+                let ins = match self.get_instance_by_mxid(mxid.clone()) {
+                    Ok(ins) => {
+                        ins.hook_wechat_message(self.config.save_path.clone())
+                            .await?;
+                        ins
                     }
+                    Err(_) => self.spawn_instance(mxid.clone()).await?,
                 };
-                ins.hook_wechat_message(self.save_path.clone()).await?;
+                let resp = serde_json::json!({
+                    "pid": ins.pid,
+                    "wechatVersion": ins.version,
+                });
                 self.store_instance(mxid.clone(), ins)?;
 
-                self.write_command_resp::<String>(mxid, req_id, None)
-                    .await?;
+                self.write_command_resp(mxid, req_id, Some(resp)).await?;
+            }
+
+            CommandType::RestartInstance => {
+                let _connecting = self.start_connecting(&mxid)?;
+                if let Err(e) = self.drop_instance(mxid.clone()) {
+                    warn!("drop instance of {} before restart failed: {}", mxid, e);
+                }
+                let ins = self.spawn_instance(mxid.clone()).await?;
+                self.store_instance(mxid.clone(), ins.clone())?;
+                info!(
+                    "restart instance of {} successfully. pid = {}",
+                    mxid, ins.pid
+                );
+
+                let qrcode = match ins.is_login().await.unwrap_or(false) {
+                    true => None,
+                    false => Some(ins.get_login_qrcode().await?),
+                };
+                self.write_command_resp(mxid, req_id, qrcode).await?;
             }
 
             CommandType::Disconnect => {
@@ -59,20 +266,33 @@ impl WechatManager {
             }
 
             CommandType::LoginWithQRCode => {
-                self.write_command_resp(
-                    mxid.clone(),
-                    req_id,
-                    Some(self.get_instance_by_mxid(mxid)?.get_login_qrcode().await?),
-                )
-                .await?;
+                // refreshed qr codes are pushed as events unless the bridge opts out
+                let poll = match msg.data {
+                    Some(MatrixRequestDataField::Login(login)) => login.poll,
+                    None => true,
+                    _ => {
+                        return Err(mismatched_data::<MatrixRequestDataLogin>(
+                            &msg.command,
+                            raw_data.as_ref(),
+                        ))
+                    }
+                };
+                let ins = self.get_instance_by_mxid(mxid.clone())?;
+                let qrcode = ins.get_login_qrcode().await?;
+                if poll {
+                    self.start_qrcode_polling(ins, &qrcode);
+                }
+                self.write_command_resp(mxid, req_id, Some(qrcode)).await?;
             }
 
             CommandType::IsLogin => {
+                let ins = self.get_instance_by_mxid(mxid.clone())?;
                 self.write_command_resp(
-                    mxid.clone(),
+                    mxid,
                     req_id,
                     Some(serde_json::json!({
-                        "status": self.get_instance_by_mxid(mxid)?.is_login().await.unwrap_or(false) ,
+                        "status": ins.is_login().await.unwrap_or(false),
+                        "wechatVersion": ins.version,
                     })),
                 )
                 .await?
@@ -84,36 +304,87 @@ impl WechatManager {
                     .await?;
             }
 
+            CommandType::GetSelfQRCode => {
+                let ins = self.get_instance_by_mxid(mxid.clone())?;
+                self.write_command_resp(mxid, req_id, Some(ins.get_self_qrcode().await?))
+                    .await?;
+            }
+
             CommandType::GetUserInfo => match msg.data {
                 Some(MatrixRequestDataField::Query(q)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    let info = match q.force_refresh {
+                        true => ins.refresh_user_info(q.wechat_id).await?,
+                        false => ins.get_user_info(q.wechat_id).await?,
+                    };
+                    self.write_command_resp(mxid, req_id, Some(info)).await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataQuery>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::BatchGetUserInfo => match msg.data {
+                Some(MatrixRequestDataField::Members(m)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    let mut result = BatchResult::new();
+                    for wxid in m.wechat_ids {
+                        let info = ins.get_user_info(wxid.clone()).await;
+                        result.record(wxid, info)?;
+                    }
+                    self.write_command_resp(mxid, req_id, Some(result)).await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataMembers>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::GetRawContact => match msg.data {
+                Some(MatrixRequestDataField::Query(q)) => {
+                    if !self.config.enable_raw_query {
+                        return Err(Error::InvalidRequest("raw query is disabled".to_string()));
+                    }
                     self.write_command_resp(
                         mxid.clone(),
                         req_id,
                         Some(
                             self.get_instance_by_mxid(mxid)?
-                                .get_user_info(q.wechat_id)
+                                .get_raw_contact(q.wechat_id)
                                 .await?,
                         ),
                     )
                     .await?
                 }
-                _ => bail!("deserialize matrix message failed"),
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataQuery>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
             },
 
             CommandType::GetGroupInfo => match msg.data {
                 Some(MatrixRequestDataField::Query(q)) => {
-                    self.write_command_resp(
-                        mxid.clone(),
-                        req_id,
-                        Some(
-                            self.get_instance_by_mxid(mxid)?
-                                .get_group_info(q.group_id)
-                                .await?,
-                        ),
-                    )
-                    .await?
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    let mut info = match q.force_refresh {
+                        true => ins.refresh_group_info(q.group_id).await?,
+                        false => ins.get_group_info(q.group_id).await?,
+                    };
+                    self.fill_group_avatar(&ins, &mut info).await;
+                    self.write_command_resp(mxid, req_id, Some(info)).await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataQuery>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
                 }
-                _ => bail!("deserialize matrix message failed"),
             },
 
             CommandType::GetGroupMembers => match msg.data {
@@ -129,7 +400,12 @@ impl WechatManager {
                     )
                     .await?
                 }
-                _ => bail!("deserialize matrix message failed"),
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataQuery>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
             },
 
             CommandType::GetGroupMemberNickname => match msg.data {
@@ -145,7 +421,78 @@ impl WechatManager {
                     )
                     .await?
                 }
-                _ => bail!("deserialize matrix message failed"),
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataQuery>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::GetGroupMemberNicknames => match msg.data {
+                Some(MatrixRequestDataField::Chat(c)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    self.write_command_resp(
+                        mxid,
+                        req_id,
+                        Some(ins.get_group_member_nicknames(c.chat_id).await?),
+                    )
+                    .await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataChat>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::GetGroupQRCode => match msg.data {
+                Some(MatrixRequestDataField::Chat(c)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    self.write_command_resp(
+                        mxid,
+                        req_id,
+                        Some(ins.get_group_qrcode(c.chat_id).await?),
+                    )
+                    .await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataChat>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::CreateGroup => match msg.data {
+                Some(MatrixRequestDataField::Members(m)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    let group_id = ins.create_group(m.wechat_ids).await?;
+                    self.write_command_resp(mxid, req_id, Some(ins.get_group_info(group_id).await?))
+                        .await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataMembers>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::QuitGroup => match msg.data {
+                Some(MatrixRequestDataField::Chat(c)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    ins.quit_group(c.chat_id).await?;
+                    self.write_command_resp::<String>(mxid, req_id, None)
+                        .await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataChat>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
             },
 
             CommandType::GetFriendList => {
@@ -166,22 +513,316 @@ impl WechatManager {
                 .await?
             }
 
+            CommandType::GetUnreadCounts => {
+                let ins = self.get_instance_by_mxid(mxid.clone())?;
+                // badges are a nicety. The bridge gets nothing rather than an error if db is locked
+                let counts = ins.get_unread_counts().await.unwrap_or_else(|e| {
+                    warn!("get unread counts of {} failed: {}", mxid, e);
+                    Default::default()
+                });
+                self.write_command_resp(mxid, req_id, Some(counts)).await?
+            }
+
             CommandType::SendMessage => match msg.data {
-                Some(MatrixRequestDataField::Message(msg)) => {
-                    self.write_command_resp(
-                        mxid.clone(),
-                        req_id,
-                        Some(self.get_instance_by_mxid(mxid)?.send_message(msg).await?),
+                Some(MatrixRequestDataField::Message(mut msg)) => {
+                    if let Some(MatrixMessageDataField::Media(media)) = &msg.data {
+                        utils::check_media_url(&media.url, &self.config.media_hosts)?;
+                    }
+                    if self.config.convert_outgoing_emoji
+                        && matches!(msg.message_type, MatrixMessageType::Text)
+                    {
+                        msg.content = emoticon::to_codes(&msg.content).into_owned();
+                    }
+                    let ack = self.send_message_once(mxid.clone(), msg).await?;
+                    self.write_command_resp(mxid, req_id, Some(ack)).await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataMessage>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::Ack => match msg.data {
+                Some(MatrixRequestDataField::Ack(ack)) => {
+                    self.ack_events(&mxid, ack.seq)?;
+                    self.write_command_resp::<String>(mxid, req_id, None)
+                        .await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataAck>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::MuteChat | CommandType::UnmuteChat => match msg.data {
+                Some(MatrixRequestDataField::Chat(chat)) => {
+                    let mute = matches!(msg.command, CommandType::MuteChat);
+                    self.set_chat_muted(mxid.clone(), chat.chat_id, mute)?;
+                    self.write_command_resp::<String>(mxid, req_id, None)
+                        .await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataChat>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::WatchGroupMembers | CommandType::UnwatchGroupMembers => match msg.data {
+                Some(MatrixRequestDataField::Chat(chat)) => {
+                    let watch = matches!(msg.command, CommandType::WatchGroupMembers);
+                    self.watch_group_members(mxid.clone(), chat.chat_id, watch)
+                        .await?;
+                    self.write_command_resp::<String>(mxid, req_id, None)
+                        .await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataChat>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::AcceptFriendRequest => match msg.data {
+                Some(MatrixRequestDataField::FriendRequest(request)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    ins.accept_friend_request(request.v3, request.v4).await?;
+                    self.poll_contacts_soon(ins);
+                    self.write_command_resp::<String>(mxid, req_id, None)
+                        .await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataFriendRequest>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::Replay => match msg.data {
+                Some(MatrixRequestDataField::Replay(replay)) => {
+                    if !self.config.enable_raw_query {
+                        return Err(Error::InvalidRequest("raw query is disabled".to_string()));
+                    }
+                    let callback: WechatMessage = serde_json::from_str(&replay.line)?;
+                    let event = self.replay_wechat_event(mxid.clone(), callback)?;
+                    self.write_command_resp(mxid, req_id, event).await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataReplay>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::SetHookPort => match msg.data {
+                Some(MatrixRequestDataField::Port(p)) => {
+                    self.set_hook_port(p.port).await?;
+                    self.write_command_resp::<String>(mxid, req_id, None)
+                        .await?
+                }
+
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataPort>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::GetAvatar => match msg.data {
+                Some(MatrixRequestDataField::Avatar(a)) => {
+                    let blob = self.get_avatar(mxid.clone(), a.url).await?;
+                    self.write_command_resp(mxid, req_id, Some(blob)).await?
+                }
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataAvatar>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
+            },
+
+            CommandType::GetMediaManifest => {
+                let (offset, limit) = match msg.data {
+                    Some(MatrixRequestDataField::Page(page)) => (page.offset, page.limit),
+                    None => (None, None),
+                    _ => {
+                        return Err(mismatched_data::<MatrixRequestDataPage>(
+                            &msg.command,
+                            raw_data.as_ref(),
+                        ))
+                    }
+                };
+                let ins = self.get_instance_by_mxid(mxid.clone())?;
+                let self_id = ins.get_self().await?.id;
+                let manifest = self
+                    .media_manifest(
+                        &self_id,
+                        offset.unwrap_or(0),
+                        limit.unwrap_or(constants::DEFAULT_MEDIA_MANIFEST_PAGE_SIZE),
                     )
+                    .await?;
+                self.write_command_resp(mxid, req_id, Some(manifest))
                     .await?
+            }
+
+            CommandType::GetHistoryImage => match msg.data {
+                Some(MatrixRequestDataField::History(history)) => {
+                    let ins = self.get_instance_by_mxid(mxid.clone())?;
+                    let self_id = match ins.cached_self_wxid() {
+                        Some(wxid) => wxid,
+                        None => ins.get_self().await?.id,
+                    };
+                    let image = self
+                        .fetch_history_image(&ins, self_id, history.chat_id, history.msg_id)
+                        .await?;
+                    self.write_command_resp(mxid, req_id, Some(image)).await?
                 }
 
-                _ => bail!("deserialize matrix message failed"),
+                _ => {
+                    return Err(mismatched_data::<MatrixRequestDataHistory>(
+                        &msg.command,
+                        raw_data.as_ref(),
+                    ))
+                }
             },
 
-            _ => bail!("deserialize matrix message failed"),
+            _ => return Err(unhandled_command(&msg.command)),
         }
 
         Ok(())
     }
 }
+
+// commands calling wechat apis which are unavailable before login
+fn requires_login(command: &CommandType) -> bool {
+    matches!(
+        command,
+        CommandType::GetSelf
+            | CommandType::GetSelfQRCode
+            | CommandType::GetUserInfo
+            | CommandType::BatchGetUserInfo
+            | CommandType::GetRawContact
+            | CommandType::GetGroupInfo
+            | CommandType::GetGroupMembers
+            | CommandType::GetGroupMemberNickname
+            | CommandType::GetGroupMemberNicknames
+            | CommandType::GetGroupQRCode
+            | CommandType::CreateGroup
+            | CommandType::QuitGroup
+            | CommandType::GetFriendList
+            | CommandType::GetGroupList
+            | CommandType::GetUnreadCounts
+            | CommandType::GetHistoryImage
+            | CommandType::AcceptFriendRequest
+            | CommandType::SendMessage
+    )
+}
+
+// a timed out command may have taken effect in wechat. A send is run again only if its idempotency
+// key answers the retry with the first ack, a group creation is never run again
+fn rerun_on_retry(msg: &WebsocketMatrixRequest, dedup_sends: bool) -> bool {
+    match (&msg.command, &msg.data) {
+        (CommandType::SendMessage, Some(MatrixRequestDataField::Message(data))) => {
+            dedup_sends && data.idempotency_key.is_some()
+        }
+        (CommandType::SendMessage | CommandType::CreateGroup, _) => false,
+        _ => true,
+    }
+}
+
+// name of command in the protocol, e.g. send_message
+fn command_name(command: &CommandType) -> String {
+    match serde_json::to_value(command) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{:?}", command),
+    }
+}
+
+// error naming the command, why its data does not fit and the shape it expects
+fn mismatched_data<T: RequestData>(
+    command: &CommandType,
+    data: Option<&serde_json::Value>,
+) -> Error {
+    let reason = match data {
+        None => "no data".to_string(),
+        Some(data) => match serde_json::from_value::<T>(data.clone()) {
+            Err(e) => e.to_string(),
+            // untagged data is matched by shape. It fits but is taken as data of another command
+            Ok(_) => format!("data {} is taken as another kind", data),
+        },
+    };
+    Error::InvalidRequest(format!(
+        "invalid data of command {}: {}. expected {}",
+        command_name(command),
+        reason,
+        T::SHAPE
+    ))
+}
+
+fn unhandled_command(command: &CommandType) -> Error {
+    Error::InvalidRequest(format!(
+        "command {} is not accepted from the bridge",
+        command_name(command)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(command: &str, data: serde_json::Value) -> WebsocketMatrixRequest {
+        let raw = serde_json::json!({"mxid": "@a:x", "req": 1, "command": command, "data": data});
+        WebsocketMatrixRequest::parse(&raw.to_string()).unwrap()
+    }
+
+    #[test]
+    fn timed_out_sends_are_rerun_only_if_deduplicated() {
+        let keyed = request(
+            "send_message",
+            serde_json::json!({"target": "wxid_a", "type": "m.text", "content": "hi", "idempotencyKey": "k"}),
+        );
+        assert!(rerun_on_retry(&keyed, true));
+        assert!(!rerun_on_retry(&keyed, false));
+
+        let unkeyed = request(
+            "send_message",
+            serde_json::json!({"target": "wxid_a", "type": "m.text", "content": "hi"}),
+        );
+        assert!(!rerun_on_retry(&unkeyed, true));
+    }
+
+    #[test]
+    fn timed_out_queries_are_rerun() {
+        let query = request("get_user_info", serde_json::json!({"wxid": "wxid_a"}));
+        assert!(rerun_on_retry(&query, false));
+    }
+
+    #[test]
+    fn mismatched_data_names_the_command_reason_and_shape() {
+        let data = serde_json::json!({"type": "m.text", "content": "hi"});
+        let err =
+            mismatched_data::<MatrixRequestDataMessage>(&CommandType::SendMessage, Some(&data));
+        let err = err.to_string();
+        assert!(err.contains("send_message"), "{}", err);
+        assert!(err.contains("missing field `target`"), "{}", err);
+        assert!(err.contains(MatrixRequestDataMessage::SHAPE), "{}", err);
+
+        let err = mismatched_data::<MatrixRequestDataPort>(&CommandType::Connect, None);
+        assert!(err.to_string().contains("no data"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tokio::fs::File;
+
+use crate::constants;
+use crate::error::Result;
+use crate::utils;
+
+use super::WechatManager;
+
+#[derive(Serialize, Debug)]
+pub struct MediaManifest {
+    pub files: Vec<MediaManifestEntry>,
+    pub total: usize,
+    // offset of the next page. None if it is the last page
+    #[serde(rename = "nextOffset")]
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MediaManifestEntry {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub md5: String,
+}
+
+impl WechatManager {
+    /// list media files saved by hook for the wechat account. md5 is calculated only for files in the page
+    pub(super) async fn media_manifest(
+        &self,
+        self_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<MediaManifest> {
+        let dir = Path::new(&self.config.save_path).join(utils::sanitize_component(self_id));
+        let mut paths = vec![];
+        if dir.is_dir() {
+            collect_files(&dir, &mut paths).await?;
+        }
+        paths.sort();
+
+        let total = paths.len();
+        let limit = limit.clamp(1, constants::MAX_MEDIA_MANIFEST_PAGE_SIZE);
+        let mut files = vec![];
+        for path in paths.iter().skip(offset).take(limit) {
+            let mut file = File::open(path).await?;
+            files.push(MediaManifestEntry {
+                name: utils::get_filename(path)?,
+                path: path.display().to_string(),
+                size: file.metadata().await?.len(),
+                md5: utils::calculate_md5_from_reader(&mut file).await?,
+            });
+        }
+
+        let next_offset = match offset + files.len() < total {
+            true => Some(offset + files.len()),
+            false => None,
+        };
+        Ok(MediaManifest {
+            files,
+            total,
+            next_offset,
+        })
+    }
+}
+
+async fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                paths.push(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::time::sleep;
+
+use crate::constants;
+use crate::error::{Error, Result};
+use crate::ws::send::{EventType, WebsocketEvent};
+use crate::ws::MatrixMessageDataField;
+
+use super::WechatManager;
+
+const MEMBERS_TREE: &str = "group_members";
+
+// (mxid, group id) -> wxids of members
+type Snapshots = BTreeMap<(String, String), BTreeSet<String>>;
+
+/// member snapshots of groups watched by the bridge, keyed by (mxid, group id). They are mirrored
+/// to the state db if it is available so that changes during downtime are detected after restart
+#[derive(Clone)]
+pub struct GroupMemberWatch {
+    snapshots: Arc<Mutex<Snapshots>>,
+    tree: Option<sled::Tree>,
+}
+
+impl GroupMemberWatch {
+    pub fn new(db: Option<&sled::Db>) -> GroupMemberWatch {
+        let tree = match db.map(|db| db.open_tree(MEMBERS_TREE)) {
+            Some(Ok(tree)) => Some(tree),
+            Some(Err(e)) => {
+                error!("open group members tree failed: {}", e);
+                None
+            }
+            None => None,
+        };
+        let mut snapshots = BTreeMap::new();
+        if let Some(tree) = &tree {
+            for (key, value) in tree.iter().flatten() {
+                let key = String::from_utf8_lossy(&key).to_string();
+                let members = serde_json::from_slice(&value).unwrap_or_default();
+                if let Some((mxid, group_id)) = key.split_once('\0') {
+                    snapshots.insert((mxid.to_string(), group_id.to_string()), members);
+                }
+            }
+            info!("load member snapshots of {} groups", snapshots.len());
+        }
+        GroupMemberWatch {
+            snapshots: Arc::new(Mutex::new(snapshots)),
+            tree,
+        }
+    }
+
+    fn watched(&self) -> Result<Vec<(String, String)>> {
+        match self.snapshots.lock() {
+            Ok(snapshots) => Ok(snapshots.keys().cloned().collect()),
+            Err(err) => Err(Error::Internal(format!(
+                "lock group member snapshots failed: {}",
+                err
+            ))),
+        }
+    }
+
+    // an empty snapshot marks the group watched until its members are polled
+    fn register(&self, mxid: &str, group_id: &str) -> Result<()> {
+        self.modify(mxid, group_id, |snapshot| match snapshot {
+            Some(_) => None,
+            None => Some(BTreeSet::new()),
+        })
+    }
+
+    fn snapshot(&self, mxid: &str, group_id: &str) -> Result<Option<BTreeSet<String>>> {
+        match self.snapshots.lock() {
+            Ok(snapshots) => Ok(snapshots
+                .get(&(mxid.to_string(), group_id.to_string()))
+                .cloned()),
+            Err(err) => Err(Error::Internal(format!(
+                "lock group member snapshots failed: {}",
+                err
+            ))),
+        }
+    }
+
+    // nothing changes if the group is unwatched meanwhile
+    fn replace(&self, mxid: &str, group_id: &str, members: BTreeSet<String>) -> Result<()> {
+        self.modify(mxid, group_id, |snapshot| snapshot.map(|_| members))
+    }
+
+    // apply a delivered join or leave to the snapshot
+    fn apply(&self, mxid: &str, group_id: &str, wxid: &str, joined: bool) -> Result<()> {
+        self.modify(mxid, group_id, |snapshot| {
+            let mut members = snapshot?.clone();
+            match joined {
+                true => members.insert(wxid.to_string()),
+                false => members.remove(wxid),
+            };
+            Some(members)
+        })
+    }
+
+    // f returns the new snapshot, or None to keep the current one
+    fn modify<F>(&self, mxid: &str, group_id: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&BTreeSet<String>>) -> Option<BTreeSet<String>>,
+    {
+        let mut snapshots = match self.snapshots.lock() {
+            Ok(snapshots) => snapshots,
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock group member snapshots failed: {}",
+                    err
+                )))
+            }
+        };
+        let key = (mxid.to_string(), group_id.to_string());
+        if let Some(members) = f(snapshots.get(&key)) {
+            if let Some(tree) = &self.tree {
+                tree.insert(tree_key(mxid, group_id), serde_json::to_vec(&members)?)?;
+            }
+            snapshots.insert(key, members);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, mxid: &str, group_id: &str) -> Result<()> {
+        let mut snapshots = match self.snapshots.lock() {
+            Ok(snapshots) => snapshots,
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock group member snapshots failed: {}",
+                    err
+                )))
+            }
+        };
+        snapshots.remove(&(mxid.to_string(), group_id.to_string()));
+        if let Some(tree) = &self.tree {
+            tree.remove(tree_key(mxid, group_id))?;
+        }
+        Ok(())
+    }
+}
+
+fn tree_key(mxid: &str, group_id: &str) -> Vec<u8> {
+    format!("{}\0{}", mxid, group_id).into_bytes()
+}
+
+impl WechatManager {
+    /// watch or unwatch membership of the group. Watching takes the current members as the baseline
+    pub(super) async fn watch_group_members(
+        &self,
+        mxid: String,
+        group_id: String,
+        watch: bool,
+    ) -> Result<()> {
+        let members = match &self.group_members {
+            Some(members) => members,
+            None => {
+                return Err(Error::InvalidRequest(
+                    "group member polling is disabled".to_string(),
+                ))
+            }
+        };
+        if !group_id.ends_with("@chatroom") {
+            return Err(Error::InvalidRequest(format!(
+                "{} is not a group",
+                group_id
+            )));
+        }
+        if !watch {
+            return members.remove(&mxid, &group_id);
+        }
+        members.register(&mxid, &group_id)?;
+        // polling retries later if the baseline cannot be taken now
+        if let Err(e) = self.poll_group_members(members, &mxid, &group_id).await {
+            warn!(
+                "take member baseline of {} for {} failed: {}",
+                group_id, mxid, e
+            );
+        }
+        Ok(())
+    }
+
+    ///
+    /// diff members of watched groups and emit join and leave events. It never returns unless polling is disabled
+    ///
+    pub async fn start_member_polling(&self) {
+        let members = match &self.group_members {
+            Some(members) => members,
+            None => {
+                info!("group member polling is disabled");
+                return;
+            }
+        };
+        let interval = Duration::from_secs(self.config.group_member_poll_interval_secs);
+        loop {
+            sleep(interval).await;
+            let watched = match members.watched() {
+                Ok(watched) => watched,
+                Err(e) => {
+                    error!("list watched groups failed: {}", e);
+                    continue;
+                }
+            };
+            for (mxid, group_id) in watched {
+                if let Err(e) = self.poll_group_members(members, &mxid, &group_id).await {
+                    debug!("poll members of {} for {} failed: {}", group_id, mxid, e);
+                }
+                // member lists are read from wechat db. space the queries out
+                sleep(Duration::from_millis(constants::GROUP_MEMBER_POLL_GAP_MS)).await;
+            }
+        }
+    }
+
+    async fn poll_group_members(
+        &self,
+        members: &GroupMemberWatch,
+        mxid: &str,
+        group_id: &str,
+    ) -> Result<()> {
+        let ins = self.get_instance_by_mxid(mxid.to_string())?;
+        self.ensure_not_logged_out(ins.pid)?;
+        let current: BTreeSet<String> = ins
+            .get_group_members(group_id.to_string())
+            .await?
+            .into_iter()
+            .collect();
+        // wechat returns nothing while the db is unavailable. It must not be taken as everyone left
+        if current.is_empty() {
+            warn!("no members of group {} for {}, skip it", group_id, mxid);
+            return Ok(());
+        }
+
+        let old = match members.snapshot(mxid, group_id)? {
+            Some(old) => old,
+            // unwatched meanwhile
+            None => return Ok(()),
+        };
+        // the first poll takes the baseline
+        if old.is_empty() {
+            return members.replace(mxid, group_id, current);
+        }
+        let joined: Vec<String> = current.difference(&old).cloned().collect();
+        let left: Vec<String> = old.difference(&current).cloned().collect();
+        if !joined.is_empty() || !left.is_empty() {
+            info!(
+                "members of {} for {} changed: {} joined, {} left",
+                group_id,
+                mxid,
+                joined.len(),
+                left.len()
+            );
+        }
+        let changes = joined
+            .into_iter()
+            .map(|wxid| (EventType::MemberJoin, wxid))
+            .chain(left.into_iter().map(|wxid| (EventType::MemberLeave, wxid)));
+        // the snapshot follows delivered events only, so that undelivered ones are found by the next poll
+        for (event_type, wxid) in changes {
+            let joined = matches!(event_type, EventType::MemberJoin);
+            let mut event = WebsocketEvent::<MatrixMessageDataField>::agent(
+                mxid.to_string(),
+                event_type,
+                String::new(),
+                None,
+            );
+            event.base.sender = wxid.clone();
+            event.base.target = group_id.to_string();
+            self.write_event_resp(event).await?;
+            members.apply(mxid, group_id, &wxid, joined)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn snapshot_follows_applied_changes_only() {
+        let watch = GroupMemberWatch::new(None);
+        watch.register("@a:x", "1@chatroom").unwrap();
+        watch
+            .replace("@a:x", "1@chatroom", ids(&["wxid_a", "wxid_b"]))
+            .unwrap();
+
+        // wxid_c joined and wxid_a left, but only the join is delivered yet
+        watch.apply("@a:x", "1@chatroom", "wxid_c", true).unwrap();
+        assert_eq!(
+            watch.snapshot("@a:x", "1@chatroom").unwrap(),
+            Some(ids(&["wxid_a", "wxid_b", "wxid_c"]))
+        );
+        watch.apply("@a:x", "1@chatroom", "wxid_a", false).unwrap();
+        assert_eq!(
+            watch.snapshot("@a:x", "1@chatroom").unwrap(),
+            Some(ids(&["wxid_b", "wxid_c"]))
+        );
+    }
+
+    #[test]
+    fn unwatched_groups_are_not_watched_again_by_late_polls() {
+        let watch = GroupMemberWatch::new(None);
+        watch.register("@a:x", "1@chatroom").unwrap();
+        watch.remove("@a:x", "1@chatroom").unwrap();
+
+        watch
+            .replace("@a:x", "1@chatroom", ids(&["wxid_a"]))
+            .unwrap();
+        watch.apply("@a:x", "1@chatroom", "wxid_b", true).unwrap();
+        assert_eq!(watch.snapshot("@a:x", "1@chatroom").unwrap(), None);
+        assert!(watch.watched().unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshots_survive_restart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let watch = GroupMemberWatch::new(Some(&db));
+        watch.register("@a:x", "1@chatroom").unwrap();
+        watch
+            .replace("@a:x", "1@chatroom", ids(&["wxid_a"]))
+            .unwrap();
+        watch.apply("@a:x", "1@chatroom", "wxid_b", true).unwrap();
+
+        let watch = GroupMemberWatch::new(Some(&db));
+        assert_eq!(
+            watch.snapshot("@a:x", "1@chatroom").unwrap(),
+            Some(ids(&["wxid_a", "wxid_b"]))
+        );
+    }
+}
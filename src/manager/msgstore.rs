@@ -0,0 +1,262 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::constants;
+use crate::error::Result;
+use crate::ws::send::{EventType, WebsocketEventBase};
+
+use super::recent::RecentEvent;
+
+const EVENTS_TREE: &str = "events";
+const ACKS_TREE: &str = "acks";
+// (ts, msg id) -> empty, i.e. events in the order they are recorded
+const ORDER_TREE: &str = "events_order";
+
+/// durable wechat msg id -> emitted event mapping. It outlives the in-memory recent event cache
+/// so that revokes and replies of old messages can still be resolved after restart
+#[derive(Clone)]
+pub struct MsgIdStore {
+    events: sled::Tree,
+    acks: sled::Tree,
+    order: sled::Tree,
+    max_age_secs: u64,
+    max_count: usize,
+    inserts: Arc<AtomicU64>,
+    // sled counts a tree by scanning it
+    count: Arc<AtomicUsize>,
+    pruning: Arc<AtomicBool>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredEvent {
+    mxid: String,
+    seq: u64,
+    event_type: EventType,
+    sender: String,
+    // epoch millis when the event is emitted
+    ts: i64,
+}
+
+fn order_key(ts: i64, id: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&ts.to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+impl MsgIdStore {
+    pub fn new(db: &sled::Db, max_age_secs: u64, max_count: usize) -> Result<MsgIdStore> {
+        let store = MsgIdStore {
+            events: db.open_tree(EVENTS_TREE)?,
+            acks: db.open_tree(ACKS_TREE)?,
+            order: db.open_tree(ORDER_TREE)?,
+            max_age_secs,
+            max_count,
+            inserts: Arc::new(AtomicU64::new(0)),
+            count: Arc::new(AtomicUsize::new(0)),
+            pruning: Arc::new(AtomicBool::new(false)),
+        };
+        store.rebuild_order()?;
+        store.prune()?;
+        Ok(store)
+    }
+
+    // stores written before the order index have events only
+    fn rebuild_order(&self) -> Result<()> {
+        let count = self.events.len();
+        if self.order.len() != count {
+            info!("rebuild order index of {} stored message ids", count);
+            self.order.clear()?;
+            for entry in self.events.iter() {
+                let (key, value) = entry?;
+                match serde_json::from_slice::<StoredEvent>(&value) {
+                    Ok(event) => {
+                        let mut id = [0; 8];
+                        id.copy_from_slice(&key);
+                        self.order
+                            .insert(order_key(event.ts, u64::from_be_bytes(id)), &[])?;
+                    }
+                    Err(e) => {
+                        warn!("remove corrupted stored event: {}", e);
+                        self.events.remove(key)?;
+                    }
+                }
+            }
+        }
+        self.count.store(self.order.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn record(&self, base: &WebsocketEventBase) -> Result<()> {
+        self.record_at(base, Utc::now().timestamp_millis())?;
+
+        let inserts = self.inserts.fetch_add(1, Ordering::Relaxed) + 1;
+        if inserts.is_multiple_of(constants::MSG_ID_STORE_PRUNE_EVERY)
+            && !self.pruning.swap(true, Ordering::AcqRel)
+        {
+            // records come from the async callback path, while pruning blocks on disk io
+            let store = self.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = store.prune() {
+                    error!("prune message id store failed: {}", e);
+                }
+                store.pruning.store(false, Ordering::Release);
+            });
+        }
+        Ok(())
+    }
+
+    // ts is epoch millis when the event is emitted
+    fn record_at(&self, base: &WebsocketEventBase, ts: i64) -> Result<()> {
+        let event = StoredEvent {
+            mxid: base.mxid.clone(),
+            seq: base.seq,
+            event_type: base.event_type,
+            sender: base.sender.clone(),
+            ts,
+        };
+        let previous = self
+            .events
+            .insert(base.id.to_be_bytes(), serde_json::to_vec(&event)?)?;
+        match previous.and_then(|p| serde_json::from_slice::<StoredEvent>(&p).ok()) {
+            Some(previous) => {
+                self.order.remove(order_key(previous.ts, base.id))?;
+            }
+            None => {
+                self.count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.order.insert(order_key(ts, base.id), &[])?;
+        Ok(())
+    }
+    pub fn get(&self, id: u64) -> Result<Option<RecentEvent>> {
+        let value = match self.events.get(id.to_be_bytes())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let event: StoredEvent = serde_json::from_slice(&value)?;
+        Ok(Some(RecentEvent {
+            event_type: event.event_type,
+            sender: event.sender,
+        }))
+    }
+
+    /// the last seq acked by the bridge for mxid
+    pub fn record_ack(&self, mxid: &str, seq: u64) -> Result<()> {
+        self.acks.insert(mxid.as_bytes(), &seq.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// remove events older than max age, then the oldest ones beyond max count
+    pub fn prune(&self) -> Result<()> {
+        let cutoff = Utc::now().timestamp_millis() - (self.max_age_secs * 1000) as i64;
+        let (mut expired, mut overflow) = (0, 0);
+        // the order index is sorted by ts, so only the removed entries are visited
+        for entry in self.order.iter() {
+            let (key, _) = entry?;
+            let mut ts = [0; 8];
+            ts.copy_from_slice(&key[..8]);
+            let is_expired = i64::from_be_bytes(ts) < cutoff;
+            let is_overflow = self.count.load(Ordering::Relaxed) > self.max_count;
+            if !is_expired && !is_overflow {
+                break;
+            }
+            self.events.remove(&key[8..])?;
+            self.order.remove(key)?;
+            self.count.fetch_sub(1, Ordering::Relaxed);
+            match is_expired {
+                true => expired += 1,
+                false => overflow += 1,
+            }
+        }
+        if expired + overflow > 0 {
+            info!(
+                "prune message id store: {} expired, {} over capacity",
+                expired, overflow
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::send::WebsocketEvent;
+
+    fn open(max_count: usize) -> MsgIdStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        MsgIdStore::new(&db, 3600, max_count).unwrap()
+    }
+
+    fn event(id: u64) -> WebsocketEventBase {
+        let mut base = WebsocketEvent::<()>::agent(
+            "@a:example.org".to_string(),
+            EventType::Image,
+            String::new(),
+            None,
+        )
+        .base;
+        base.id = id;
+        base.sender = format!("wxid_{}", id);
+        base
+    }
+
+    #[test]
+    fn oldest_events_beyond_count_are_pruned() {
+        let store = open(2);
+        let now = Utc::now().timestamp_millis();
+        // recorded out of id order, so that pruning must follow the time of recording
+        store.record_at(&event(3), now - 30).unwrap();
+        store.record_at(&event(1), now - 20).unwrap();
+        store.record_at(&event(2), now - 10).unwrap();
+        store.prune().unwrap();
+
+        assert!(store.get(3).unwrap().is_none());
+        assert_eq!(store.get(1).unwrap().unwrap().sender, "wxid_1");
+        assert!(store.get(2).unwrap().is_some());
+    }
+
+    #[test]
+    fn expired_events_are_pruned() {
+        let store = open(10);
+        let now = Utc::now().timestamp_millis();
+        store.record_at(&event(1), now - 3601 * 1000).unwrap();
+        store.record_at(&event(2), now).unwrap();
+        store.prune().unwrap();
+
+        assert!(store.get(1).unwrap().is_none());
+        assert!(store.get(2).unwrap().is_some());
+    }
+
+    #[test]
+    fn recording_an_id_again_moves_it_to_the_newest() {
+        let store = open(2);
+        let now = Utc::now().timestamp_millis();
+        store.record_at(&event(1), now - 30).unwrap();
+        store.record_at(&event(2), now - 20).unwrap();
+        store.record_at(&event(1), now - 10).unwrap();
+        store.record_at(&event(3), now).unwrap();
+        store.prune().unwrap();
+
+        assert!(store.get(2).unwrap().is_none());
+        assert!(store.get(1).unwrap().is_some());
+        assert!(store.get(3).unwrap().is_some());
+    }
+
+    #[test]
+    fn order_index_is_rebuilt_for_stores_without_it() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = MsgIdStore::new(&db, 3600, 10).unwrap();
+        store.record(&event(1)).unwrap();
+        store.record(&event(2)).unwrap();
+        db.drop_tree(ORDER_TREE).unwrap();
+
+        let store = MsgIdStore::new(&db, 3600, 1).unwrap();
+        assert!(store.get(1).unwrap().is_none());
+        assert!(store.get(2).unwrap().is_some());
+    }
+}
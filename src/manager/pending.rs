@@ -0,0 +1,154 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use log::warn;
+
+use crate::constants;
+use crate::ws::{send::WebsocketEvent, MatrixMessageDataField};
+
+/// per-mxid event sequence numbers and the bounded buffer of serialized events
+/// which have not been acked by the bridge yet
+pub struct PendingEvents {
+    capacity: usize,
+    last_seq: HashMap<String, u64>,
+    events: VecDeque<PendingEvent>,
+    failed_media: VecDeque<FailedMedia>,
+}
+
+/// media event which failed to deliver. Its binary is dropped to save memory during outages
+/// and read from source again when it is resent
+pub struct FailedMedia {
+    pub event: WebsocketEvent<MatrixMessageDataField>,
+    pub source: PathBuf,
+    pub failed_at: Instant,
+    pub journal_key: Option<u64>,
+}
+
+struct PendingEvent {
+    mxid: String,
+    seq: u64,
+    data: String,
+}
+
+impl PendingEvents {
+    pub fn new(capacity: usize) -> PendingEvents {
+        PendingEvents {
+            capacity,
+            last_seq: HashMap::new(),
+            events: VecDeque::new(),
+            failed_media: VecDeque::new(),
+        }
+    }
+
+    /// sequence number is monotonically increasing for each mxid and starts from 1
+    pub fn next_seq(&mut self, mxid: &str) -> u64 {
+        let seq = self.last_seq.entry(mxid.to_string()).or_insert(0);
+        *seq += 1;
+        *seq
+    }
+
+    pub fn push(&mut self, mxid: String, seq: u64, data: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.events.len() >= self.capacity {
+            if let Some(dropped) = self.events.pop_front() {
+                warn!(
+                    "replay buffer is full, drop unacked event. mxid = {} seq = {}",
+                    dropped.mxid, dropped.seq
+                );
+            }
+        }
+        self.events.push_back(PendingEvent { mxid, seq, data });
+    }
+
+    /// remove all events of mxid whose seq is less than or equal to the acked seq
+    pub fn ack(&mut self, mxid: &str, seq: u64) -> usize {
+        let before = self.events.len();
+        self.events.retain(|e| e.mxid != mxid || e.seq > seq);
+        before - self.events.len()
+    }
+
+    /// put back an event journaled before restart. seq continues from the restored ones
+    pub fn restore(&mut self, mxid: String, seq: u64, data: String) {
+        self.restore_seq(&mxid, seq);
+        self.push(mxid, seq, data);
+    }
+
+    pub fn restore_seq(&mut self, mxid: &str, seq: u64) {
+        let last = self.last_seq.entry(mxid.to_string()).or_insert(0);
+        *last = (*last).max(seq);
+    }
+
+    /// remove a buffered event, e.g. the one replaced by a reference to its media
+    pub fn remove(&mut self, mxid: &str, seq: u64) {
+        self.events.retain(|e| e.mxid != mxid || e.seq != seq);
+    }
+
+    pub fn stash_media(&mut self, media: FailedMedia) {
+        while self.failed_media.len() >= constants::MAX_FAILED_MEDIA {
+            if let Some(dropped) = self.failed_media.pop_front() {
+                warn!(
+                    "failed media buffer is full, drop media of {}. msg_id = {}",
+                    dropped.event.base.mxid, dropped.event.base.id
+                );
+            }
+        }
+        self.failed_media.push_back(media);
+    }
+
+    pub fn take_media(&mut self) -> Vec<FailedMedia> {
+        self.failed_media.drain(..).collect()
+    }
+
+    pub fn unacked(&self) -> Vec<String> {
+        self.events.iter().map(|e| e.data.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seq_increases_per_mxid_from_one() {
+        let mut pending = PendingEvents::new(8);
+        assert_eq!(pending.next_seq("@a:hs"), 1);
+        assert_eq!(pending.next_seq("@a:hs"), 2);
+        assert_eq!(pending.next_seq("@b:hs"), 1);
+        assert_eq!(pending.next_seq("@a:hs"), 3);
+    }
+
+    #[test]
+    fn ack_removes_acked_events_of_the_mxid_only() {
+        let mut pending = PendingEvents::new(8);
+        for seq in 1..=3 {
+            pending.push("@a:hs".to_string(), seq, format!("a{}", seq));
+            pending.push("@b:hs".to_string(), seq, format!("b{}", seq));
+        }
+
+        assert_eq!(pending.ack("@a:hs", 2), 2);
+        assert_eq!(pending.unacked(), vec!["b1", "b2", "a3", "b3"]);
+        // acking again or below the acked seq removes nothing
+        assert_eq!(pending.ack("@a:hs", 1), 0);
+    }
+
+    #[test]
+    fn full_buffer_drops_the_oldest_event() {
+        let mut pending = PendingEvents::new(2);
+        for seq in 1..=3 {
+            pending.push("@a:hs".to_string(), seq, seq.to_string());
+        }
+        assert_eq!(pending.unacked(), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn restored_events_keep_seq_gapless_after_restart() {
+        let mut pending = PendingEvents::new(8);
+        pending.restore("@a:hs".to_string(), 7, "a7".to_string());
+        pending.restore("@a:hs".to_string(), 5, "a5".to_string());
+        assert_eq!(pending.next_seq("@a:hs"), 8);
+        assert_eq!(pending.unacked(), vec!["a7", "a5"]);
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::ws::send::EventType;
+
+/// bounded cache of recently delivered events, keyed by wechat msg id.
+/// It is used to resolve the event which a revoke refers to
+pub struct RecentEvents {
+    capacity: usize,
+    events: HashMap<u64, RecentEvent>,
+    order: VecDeque<u64>,
+    // transfer id -> msg id of the message which sends the transfer
+    transfers: HashMap<String, u64>,
+    transfer_order: VecDeque<String>,
+}
+
+#[derive(Clone)]
+pub struct RecentEvent {
+    pub event_type: EventType,
+    pub sender: String,
+}
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> RecentEvents {
+        RecentEvents {
+            capacity,
+            events: HashMap::new(),
+            order: VecDeque::new(),
+            transfers: HashMap::new(),
+            transfer_order: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, id: u64, event: RecentEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.insert(id, event).is_some() {
+            return;
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.events.remove(&old);
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<RecentEvent> {
+        self.events.get(&id).cloned()
+    }
+
+    pub fn record_transfer(&mut self, transfer_id: String, id: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.transfers.insert(transfer_id.clone(), id).is_some() {
+            return;
+        }
+        self.transfer_order.push_back(transfer_id);
+        while self.transfer_order.len() > self.capacity {
+            if let Some(old) = self.transfer_order.pop_front() {
+                self.transfers.remove(&old);
+            }
+        }
+    }
+
+    /// msg id and the event which sends the transfer
+    pub fn transfer(&self, transfer_id: &str) -> Option<(u64, RecentEvent)> {
+        let id = *self.transfers.get(transfer_id)?;
+        self.get(id).map(|e| (id, e))
+    }
+}
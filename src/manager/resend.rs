@@ -0,0 +1,137 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::error::{Error, Result};
+use crate::utils;
+use crate::ws::send::{EventType, WebsocketEvent};
+use crate::ws::MatrixMessageDataField;
+
+use super::pending::FailedMedia;
+use super::WechatManager;
+
+impl WechatManager {
+    /// deliver event of wechat message. If media read from a local file fails to deliver,
+    /// a reference to the file is kept so that it can be resent after reconnecting
+    pub(super) async fn write_message_event(
+        &self,
+        event: WebsocketEvent<MatrixMessageDataField>,
+    ) -> Result<()> {
+        self.deliver_message_event(event, Instant::now()).await
+    }
+
+    /// resend media which failed to deliver during outage. Media which is too old or cannot be
+    /// read again is replaced by a placeholder
+    pub async fn resend_failed_media(&self) {
+        let media = match self.pending_events.lock() {
+            Ok(mut pending) => pending.take_media(),
+            Err(err) => {
+                error!("lock pending events failed: {}", err);
+                return;
+            }
+        };
+        if media.is_empty() {
+            return;
+        }
+        info!("resend {} media failed to deliver", media.len());
+
+        let max_age = Duration::from_secs(self.config.media_resend_max_age_secs);
+        for m in media {
+            // the resent event is journaled again with a new seq
+            if let (Some(journal), Some(key)) = (&self.journal, m.journal_key) {
+                if let Err(e) = journal.remove(key) {
+                    error!("remove failed media from journal failed: {}", e);
+                }
+            }
+
+            let mut event = m.event;
+            let msg_id = event.base.id;
+            let binary = match m.failed_at.elapsed() > max_age {
+                true => Err(Error::Internal(format!(
+                    "media failed {} secs ago",
+                    m.failed_at.elapsed().as_secs()
+                ))),
+                false => self.read_media_again(&m.source).await,
+            };
+            match (binary, &mut event.extra) {
+                (Ok(binary), Some(MatrixMessageDataField::Blob(blob))) => blob.binary = binary,
+                (binary, _) => {
+                    if let Err(e) = binary {
+                        warn!(
+                            "give up resending media {}: {}. msg_id = {}",
+                            m.source.display(),
+                            e,
+                            msg_id
+                        );
+                    }
+                    event.base.event_type = EventType::Text;
+                    event.base.content = self.config.locale.texts().media_resend_failed.to_string();
+                    event.extra = None;
+                }
+            }
+            if let Err(e) = self.deliver_message_event(event, m.failed_at).await {
+                error!("resend media failed: {}. msg_id = {}", e, msg_id);
+            }
+        }
+    }
+
+    async fn deliver_message_event(
+        &self,
+        mut event: WebsocketEvent<MatrixMessageDataField>,
+        failed_at: Instant,
+    ) -> Result<()> {
+        let source = match &event.extra {
+            Some(MatrixMessageDataField::Blob(blob)) => blob.source.clone(),
+            _ => None,
+        };
+        let source = match (source, self.config.media_resend_max_age_secs) {
+            (Some(source), age) if age > 0 => source,
+            _ => return self.write_event_resp(event).await,
+        };
+
+        let (data, journal_key) = self.prepare_event(&mut event)?;
+        let result = self.deliver_event(event.base.id, data, journal_key).await;
+        if result.is_err() {
+            self.stash_failed_media(event, source, failed_at, journal_key);
+        }
+        result
+    }
+
+    // replace the buffered event with a reference to its media to avoid holding the binary
+    fn stash_failed_media(
+        &self,
+        mut event: WebsocketEvent<MatrixMessageDataField>,
+        source: PathBuf,
+        failed_at: Instant,
+        journal_key: Option<u64>,
+    ) {
+        let mut pending = match self.pending_events.lock() {
+            Ok(pending) => pending,
+            Err(err) => {
+                error!("lock pending events failed: {}", err);
+                return;
+            }
+        };
+        pending.remove(&event.base.mxid, event.base.seq);
+        if let Some(MatrixMessageDataField::Blob(blob)) = &mut event.extra {
+            blob.binary = vec![];
+        }
+        info!(
+            "keep media {} to resend after reconnecting. msg_id = {}",
+            source.display(),
+            event.base.id
+        );
+        pending.stash_media(FailedMedia {
+            event,
+            source,
+            failed_at,
+            journal_key,
+        });
+    }
+
+    async fn read_media_again(&self, path: &Path) -> Result<Vec<u8>> {
+        let file = tokio::fs::File::open(path).await?;
+        utils::read_file_limited(file, path, self.config.max_inbound_media_size).await
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// responses of recent commands keyed by (mxid, req id). A command resent by the bridge, e.g. after
+/// reconnecting, is answered from here instead of being executed again
+pub struct RecentResponses {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<(String, i32), RecentResponse>,
+    order: VecDeque<((String, i32), Instant)>,
+}
+
+struct RecentResponse {
+    // tells a resent command from a different one reusing the req id, e.g. after bridge restart
+    fingerprint: String,
+    started_at: Instant,
+    // serialized response. None while the command is running
+    data: Option<String>,
+}
+
+pub enum CommandState {
+    New,
+    Running,
+    Answered(String),
+}
+
+impl RecentResponses {
+    pub fn new(capacity: usize, ttl: Duration) -> RecentResponses {
+        RecentResponses {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// state of the command before it is executed. A new command is marked as running
+    pub fn begin(&mut self, mxid: &str, req_id: i32, fingerprint: String) -> CommandState {
+        self.expire();
+        if self.capacity == 0 {
+            return CommandState::New;
+        }
+        let key = (mxid.to_string(), req_id);
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.fingerprint == fingerprint {
+                return match &entry.data {
+                    Some(data) => CommandState::Answered(data.clone()),
+                    None => CommandState::Running,
+                };
+            }
+        }
+
+        let now = Instant::now();
+        self.entries.insert(
+            key.clone(),
+            RecentResponse {
+                fingerprint,
+                started_at: now,
+                data: None,
+            },
+        );
+        self.order.push_back((key, now));
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some((old, started_at)) => self.remove(&old, started_at),
+                None => break,
+            }
+        }
+        CommandState::New
+    }
+
+    /// keep the response of a running command. Responses of unknown commands are ignored
+    pub fn finish(&mut self, mxid: &str, req_id: i32, data: &str) {
+        if let Some(entry) = self.entries.get_mut(&(mxid.to_string(), req_id)) {
+            entry.data = Some(data.to_string());
+        }
+    }
+
+    /// drop the kept state of a command so that it is executed again when resent
+    pub fn forget(&mut self, mxid: &str, req_id: i32) {
+        self.entries.remove(&(mxid.to_string(), req_id));
+    }
+
+    fn expire(&mut self) {
+        while let Some((_, started_at)) = self.order.front() {
+            if started_at.elapsed() < self.ttl {
+                break;
+            }
+            if let Some((old, started_at)) = self.order.pop_front() {
+                self.remove(&old, started_at);
+            }
+        }
+    }
+
+    // the key may be reused by a newer command which must be kept
+    fn remove(&mut self, key: &(String, i32), started_at: Instant) {
+        if matches!(self.entries.get(key), Some(e) if e.started_at == started_at) {
+            self.entries.remove(key);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+use tokio::sync::watch;
+
+use crate::error::{Error, Result};
+use crate::wechat::SendMessageAck;
+use crate::ws::recv::MatrixRequestDataMessage;
+
+use super::WechatManager;
+
+// (mxid, idempotency key) -> (started at, ack once the send succeeds)
+type Entries = HashMap<(String, String), (Instant, watch::Receiver<Option<SendMessageAck>>)>;
+
+/// sends with idempotency keys in the ttl. A send retried by the bridge, e.g. after it times out
+/// while wechat has sent it, is answered with the first ack instead of being sent twice
+#[derive(Clone)]
+pub struct SentMessages {
+    ttl: Duration,
+    entries: Arc<Mutex<Entries>>,
+}
+
+enum SendState {
+    // the ack is published through the sender. Dropping it without an ack marks the send failed
+    New(watch::Sender<Option<SendMessageAck>>),
+    Sending(watch::Receiver<Option<SendMessageAck>>),
+    Sent(SendMessageAck),
+}
+
+impl SentMessages {
+    pub fn new(ttl_secs: u64) -> SentMessages {
+        SentMessages {
+            ttl: Duration::from_secs(ttl_secs),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // a failed send is taken as new so that the retry sends it again
+    fn begin(&self, mxid: &str, key: &str) -> Result<SendState> {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock sent messages failed: {}",
+                    err
+                )))
+            }
+        };
+        entries.retain(|_, (started_at, _)| started_at.elapsed() < self.ttl);
+        let entry = (mxid.to_string(), key.to_string());
+        if let Some((_, rx)) = entries.get(&entry) {
+            if let Some(ack) = rx.borrow().clone() {
+                return Ok(SendState::Sent(ack));
+            }
+            if rx.has_changed().is_ok() {
+                return Ok(SendState::Sending(rx.clone()));
+            }
+        }
+        let (tx, rx) = watch::channel(None);
+        entries.insert(entry, (Instant::now(), rx));
+        Ok(SendState::New(tx))
+    }
+}
+
+impl WechatManager {
+    /// send the message unless one with the same idempotency key is sent or being sent
+    pub(super) async fn send_message_once(
+        &self,
+        mxid: String,
+        msg: MatrixRequestDataMessage,
+    ) -> Result<SendMessageAck> {
+        let ins = self.get_instance_by_mxid(mxid.clone())?;
+        let max_size = self.config.max_outbound_media_size;
+        let (sent, key) = match (&self.sent_messages, msg.idempotency_key.clone()) {
+            (Some(sent), Some(key)) => (sent, key),
+            // the send goes on even if the command times out, so that it is never cut half way
+            _ => {
+                return tokio::spawn(async move { ins.send_message(msg, max_size).await })
+                    .await
+                    .map_err(|e| Error::Internal(format!("send task failed: {}", e)))?
+            }
+        };
+        loop {
+            match sent.begin(&mxid, &key)? {
+                SendState::Sent(ack) => {
+                    info!("message {} of {} is sent already, skip it", key, mxid);
+                    return Ok(ack);
+                }
+                SendState::Sending(mut rx) => {
+                    info!("message {} of {} is being sent, wait for it", key, mxid);
+                    // it fails once the send fails, which is retried by the next round
+                    let _ = rx.changed().await;
+                    if let Some(ack) = rx.borrow().clone() {
+                        return Ok(ack);
+                    }
+                }
+                SendState::New(tx) => {
+                    // the send goes on even if the command times out, so that a retry gets its ack
+                    let task = tokio::spawn(async move {
+                        let ack = ins.send_message(msg, max_size).await?;
+                        let _ = tx.send(Some(ack.clone()));
+                        Ok(ack)
+                    });
+                    return task
+                        .await
+                        .map_err(|e| Error::Internal(format!("send task failed: {}", e)))?;
+                }
+            }
+        }
+    }
+}
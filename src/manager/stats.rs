@@ -0,0 +1,41 @@
+use serde::Serialize;
+
+use crate::error::Result;
+
+use super::WechatManager;
+
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl From<(u64, u64)> for CacheStats {
+    fn from((hits, misses): (u64, u64)) -> Self {
+        CacheStats { hits, misses }
+    }
+}
+
+/// counters of an instance for the bridge to tell whether caches pay off
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    // user and group infos. Both are 0 if the cache is disabled
+    #[serde(rename = "infoCache")]
+    pub info_cache: CacheStats,
+    #[serde(rename = "dbHandleCache")]
+    pub db_handle_cache: CacheStats,
+    // of all instances since agent starts
+    #[serde(rename = "undeliveredEvents")]
+    pub undelivered_events: u64,
+}
+
+impl WechatManager {
+    pub(super) fn stats(&self, mxid: String) -> Result<Stats> {
+        let ins = self.get_instance_by_mxid(mxid)?;
+        Ok(Stats {
+            info_cache: ins.info_cache_stats().into(),
+            db_handle_cache: ins.db_handle_cache_stats().into(),
+            undelivered_events: self.undelivered_event_count(),
+        })
+    }
+}
@@ -1,50 +1,158 @@
-use crate::wechat::{WechatMessage, WechatMessageAppType, WechatMessageType};
-use crate::ws::{MatrixMessageDataBlob, MatrixMessageDataField, MatrixMessageDataLink};
-use anyhow::bail;
+use crate::wechat::{WechatInstance, WechatMessage, WechatMessageAppType, WechatMessageType};
+use crate::ws::{
+    MatrixMessageDataBlob, MatrixMessageDataField, MatrixMessageDataLink,
+    MatrixMessageDataLocation, MatrixMessageDataTransfer, MatrixMessageDataVoip, TransferStatus,
+    VoipOutcome,
+};
 use chrono::Utc;
 use futures_util::StreamExt;
 use log::{debug, error, info, warn};
-use tokio::io::AsyncReadExt;
+use tokio::fs::File;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
 use tokio_util::codec::{Framed, LinesCodec};
 
-use crate::ws::send::{EventType, ReplyInfo, WebsocketEvent, WebsocketEventBase};
-use crate::{constants, utils};
+use crate::error::{Error, Result};
+use crate::locale::Texts;
+use crate::ws::send::{EventType, ReplyInfo, RevokeInfo, WebsocketEvent, WebsocketEventBase};
+use crate::{constants, emoticon, utils};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use super::WechatManager;
+use super::audit::CallbackOutcome;
+use super::{RecentEvent, WechatManager, HOOK_PORT_FILE};
 
 impl WechatManager {
     ///
     /// handle events sended by wechat and send them to matrix
     ///
     pub async fn start_server(&self) {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.message_hook_port))
+        let port = self.message_hook_port.load(Ordering::SeqCst);
+        let mut listener = TcpListener::bind(format!("127.0.0.1:{}", port))
             .await
-            .unwrap_or_else(|_| panic!("bind to port[{}] failed", self.message_hook_port));
+            .unwrap_or_else(|_| panic!("bind to port[{}] failed", port));
         info!(
             "start listen tcp at {} to recv wechat callback event successfully",
-            self.message_hook_port
+            port
         );
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<TcpListener>();
+        match self.hook_listener_chan.lock() {
+            Ok(mut chan) => *chan = Some(tx),
+            Err(err) => error!("lock hook listener channel failed: {}", err),
+        }
+
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
-            let local_self = self.clone();
-            tokio::spawn(async move {
-                if let Err(e) = local_self.process(stream).await {
-                    error!("{}", e);
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted.unwrap();
+                    let local_self = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = local_self.process(stream).await {
+                            error!("{}", e);
+                        }
+                    });
                 }
-            });
+                // connections accepted by the old listener are kept until wechat closes them
+                Some(new_listener) = rx.recv() => {
+                    info!("switch wechat callback listener to {:?}", new_listener.local_addr());
+                    listener = new_listener;
+                }
+            }
         }
     }
 
-    async fn process(&self, stream: TcpStream) -> anyhow::Result<()> {
+    /// rebind the callback listener to port and hook all instances to it again
+    pub(super) async fn set_hook_port(&self, port: u32) -> Result<()> {
+        let chan = match self.hook_listener_chan.lock() {
+            Ok(chan) => chan.clone(),
+            Err(err) => {
+                return Err(Error::Internal(format!(
+                    "lock hook listener channel failed: {}",
+                    err
+                )))
+            }
+        };
+        let chan = match chan {
+            Some(chan) => chan,
+            None => {
+                return Err(Error::Internal(
+                    "wechat callback server is not started".to_string(),
+                ))
+            }
+        };
+
+        // bind before switching so that a port conflict is reported to the caller
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+        if chan.send(listener).is_err() {
+            return Err(Error::Internal(
+                "wechat callback server is stopped".to_string(),
+            ));
+        }
+        let old_port = self.message_hook_port.swap(port, Ordering::SeqCst);
+        info!("change message hook port from {} to {}", old_port, port);
+        if let Some(dir) = &self.config.state_dir {
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(Path::new(dir).join(HOOK_PORT_FILE), port.to_string())?;
+        }
+
+        let mut failed = vec![];
+        for ins in self.set_instances_hook_port(port)? {
+            // the hook keeps calling back to the old port until it is stopped
+            if let Err(e) = ins.unhook_wechat_message().await {
+                warn!(
+                    "unhook instance[pid={}] from port {} failed: {}",
+                    ins.pid, old_port, e
+                );
+            }
+            if let Err(e) = ins.hook_wechat_message(self.config.save_path.clone()).await {
+                error!(
+                    "hook instance[pid={}] to port {} failed: {}",
+                    ins.pid, port, e
+                );
+                failed.push(ins.mxid);
+            }
+        }
+        if !failed.is_empty() {
+            return Err(Error::Internal(format!(
+                "hook instances of {:?} to port {} failed",
+                failed, port
+            )));
+        }
+        Ok(())
+    }
+
+    async fn process(&self, stream: TcpStream) -> Result<()> {
+        let peer = stream.peer_addr().ok();
         let mut lines = Framed::new(stream, LinesCodec::new());
         let mut err_cnt = 0;
+        // callbacks are handled by chat queues, which report failures back
+        let (fail_tx, mut fail_rx) = mpsc::unbounded_channel::<Error>();
         loop {
-            match lines.next().await {
+            let line = tokio::select! {
+                biased;
+                Some(e) = fail_rx.recv() => {
+                    err_cnt += 1;
+                    if err_cnt > constants::MAX_WECHAT_CALLBACK_FAIL_COUNT {
+                        return Err(Error::Internal(format!(
+                            "handle wechat callback failed: failure time exceeds {} the max failure time: {}. last error: {}",
+                            err_cnt,
+                            constants::MAX_WECHAT_CALLBACK_FAIL_COUNT,
+                            e
+                        )));
+                    }
+                    continue;
+                }
+                line = lines.next() => line,
+            };
+            match line {
                 Some(Ok(line)) => {
                     debug!("recv a new wechat callback event: {}", line);
+                    if let Some(audit) = &self.callback_audit {
+                        audit.raw(peer, &line);
+                    }
                     let msg = match serde_json::from_str::<WechatMessage>(line.as_str()) {
                         Ok(m) => {
                             info!(
@@ -55,22 +163,35 @@ impl WechatManager {
                         }
                         Err(e) => {
                             error!("parse wechat callback message failed: {}", e);
+                            if let Some(audit) = &self.callback_audit {
+                                let reason = format!("parse failed: {}", e);
+                                audit.outcome(peer, None, &CallbackOutcome::Dropped(reason));
+                            }
                             continue;
                         }
                     };
 
-                    if let Err(e) = self.handle_wechat_callback(msg).await {
-                        error!("handle wechat callback failed: {}", e);
-                        err_cnt += 1;
-                    };
-
-                    if err_cnt > constants::MAX_WECHAT_CALLBACK_FAIL_COUNT {
-                        bail!(
-                            "handle wechat callback failed: failure time exceeds {} the max failure time: {}",
-                            err_cnt,
-                            constants::MAX_WECHAT_CALLBACK_FAIL_COUNT
-                        )
-                    }
+                    let key = format!("{}/{}", msg.pid, msg.sender);
+                    let manager = self.clone();
+                    let fail_tx = fail_tx.clone();
+                    self.chat_queues
+                        .push(key, async move {
+                            let msg_id = msg.message_id;
+                            let outcome = match manager.handle_wechat_callback(msg).await {
+                                Ok(outcome) => outcome,
+                                Err(e) => {
+                                    error!("handle wechat callback failed: {}", e);
+                                    let outcome = CallbackOutcome::Dropped(e.to_string());
+                                    // the connection may be closed already
+                                    let _ = fail_tx.send(e);
+                                    outcome
+                                }
+                            };
+                            if let Some(audit) = &manager.callback_audit {
+                                audit.outcome(peer, Some(msg_id), &outcome);
+                            }
+                        })
+                        .await;
                 }
                 Some(Err(e)) => {
                     error!("recv a new wechat callback line failed: {}", e);
@@ -83,266 +204,695 @@ impl WechatManager {
         Ok(())
     }
 
-    async fn handle_wechat_callback(&self, msg: WechatMessage) -> anyhow::Result<()> {
-        // TODO(xylonx): deduplicate message by msg_id
+    async fn handle_wechat_callback(&self, msg: WechatMessage) -> Result<CallbackOutcome> {
+        let ins = self.get_instance_by_pid(msg.pid)?;
+        self.record_callback(ins.pid);
+        let ins = match self.route_callback(ins, &msg.self_id) {
+            Some(ins) => ins,
+            None => return Ok(CallbackOutcome::Dropped("self id mismatch".to_string())),
+        };
 
-        if matches!(msg.is_send_by_phone, Some(0))
-            && !matches!(msg.msg_type, WechatMessageType::Hint)
-        {
-            info!("duplicated message. msg_id = {}", msg.message_id);
-            return Ok(());
+        // system messages tell renames, remark edits and member changes of the chat
+        if matches!(msg.msg_type, WechatMessageType::System) {
+            ins.invalidate_info(&msg.sender);
         }
 
-        let ins = self.get_instance_by_pid(msg.pid)?;
+        // checked before building since building downloads and saves media. Replays of one message
+        // are in one chat, whose callbacks are handled in order, so that it is recorded before the next
+        if let Some(seen) = &self.seen_messages {
+            if seen.is_seen(&msg.self_id, msg.message_id) {
+                info!("skip seen message. msg_id = {}", msg.message_id);
+                return Ok(CallbackOutcome::Dropped("seen before".to_string()));
+            }
+        }
 
-        let mut base = WebsocketEventBase {
-            mxid: ins.mxid.clone(),
-            id: msg.message_id,
-            event_type: EventType::Text,
-            timestamp: Utc::now(),
-            sender: msg.self_id.clone(),
-            target: msg.sender.clone(),
-            content: msg.message.clone(),
-            reply: None,
+        let self_id = msg.self_id.clone();
+        let chat_id = msg.sender.clone();
+        let mxid = ins.mxid.clone();
+        let mut event = match self.build_wechat_event(mxid.clone(), msg).await? {
+            Some(event) => event,
+            None => return Ok(CallbackOutcome::Dropped("skipped".to_string())),
         };
+        if self.config.translate_emoticons {
+            translate_emoticons(&mut event.base);
+        }
 
-        if msg.is_send_message == 0 {
-            base.sender = msg.wechat_id.clone();
-            if !msg.sender.ends_with("@chatroom") {
-                base.target = msg.self_id.clone();
+        if let Some(MatrixMessageDataField::Transfer(t)) = &event.extra {
+            if t.status == TransferStatus::Sent {
+                self.record_transfer(t.transfer_id.clone(), event.base.id);
             }
         }
-        let mut event = WebsocketEvent::<MatrixMessageDataField> { base, extra: None };
+        if !matches!(event.base.event_type, EventType::Revoke) {
+            self.record_event(
+                event.base.id,
+                RecentEvent {
+                    event_type: event.base.event_type,
+                    sender: event.base.sender.clone(),
+                },
+            );
+        }
 
-        match msg.msg_type {
-            WechatMessageType::Unknown => info!("recv unknown wechat message"),
+        // muted messages are recorded like delivered ones, so that they are neither emitted when
+        // wechat replays them nor unknown to revokes and replies after the chat is unmuted
+        if self.is_chat_muted(&mxid, &chat_id) {
+            info!(
+                "skip message of muted chat {}. msg_id = {}",
+                chat_id, event.base.id
+            );
+            if let Some(store) = &self.msg_ids {
+                if let Err(e) = store.record(&event.base) {
+                    error!("persist msg id {} failed: {}", event.base.id, e);
+                }
+            }
+            if let Some(seen) = &self.seen_messages {
+                seen.record(&self_id, event.base.id);
+            }
+            return Ok(CallbackOutcome::Dropped("muted chat".to_string()));
+        }
+
+        if chat_id.ends_with("@chatroom") && event.base.sender != self_id {
+            event.base.sender_name = self
+                .group_sender_name(&ins, &chat_id, &event.base.sender)
+                .await;
+        }
+        let thumbnail = self.location_thumbnail(&event);
+        // delivery failure is not caused by wechat, therefore, do not close the callback connection
+        let msg_id = event.base.id;
+        if let Err(e) = self.write_message_event(event).await {
+            error!("deliver wechat event failed: {} msg_id: {}", e, msg_id);
+            return Ok(CallbackOutcome::Dropped(format!("delivery failed: {}", e)));
+        }
+        if let Some(seen) = &self.seen_messages {
+            seen.record(&self_id, msg_id);
+        }
+        if let Some((url, thumbnail)) = thumbnail {
+            self.spawn_location_thumbnail(url, thumbnail);
+        }
+        Ok(CallbackOutcome::Emitted(msg_id))
+    }
 
-            WechatMessageType::Text => {
-                event.extra = self.get_mentions(msg.extra_info).await?;
+    // a missing name is not worth dropping the message, the bridge falls back to its own profile
+    async fn group_sender_name(
+        &self,
+        ins: &WechatInstance,
+        group_id: &str,
+        sender: &str,
+    ) -> Option<String> {
+        match ins.get_group_member_display_name(group_id, sender).await {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("get name of {} in {} failed: {}", sender, group_id, e);
+                None
             }
+        }
+    }
 
-            // TODO(xylonx): upload media to matrix in place instead of sending blob to ws to avoid high-traffic problem
-            WechatMessageType::Image => match self.fetch_image(msg.self_id, msg.file_path).await {
-                Ok(blob) => {
-                    event.base.event_type = EventType::Image;
-                    event.extra = Some(blob);
-                }
-                Err(e) => {
-                    error!("download image failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[图片下载失败]".to_string();
-                }
-            },
+    // the callback of one account must never be emitted as another's. A callback whose self id
+    // differs from the logged in wxid of the instance is rerouted to the instance logged in as it
+    fn route_callback(&self, ins: WechatInstance, self_id: &str) -> Option<WechatInstance> {
+        if self_id.is_empty() {
+            return Some(ins);
+        }
+        // unknown until get_self succeeds, e.g. on login or the bridge querying self info
+        let wxid = match ins.cached_self_wxid() {
+            Some(wxid) => wxid,
+            None => return Some(ins),
+        };
+        if wxid == self_id {
+            return Some(ins);
+        }
 
-            WechatMessageType::Voice => match self.fetch_voice(msg.self_id, msg.message).await {
-                Ok(blob) => {
-                    event.base.event_type = EventType::Audio;
-                    event.extra = Some(blob);
-                }
-                Err(e) => {
-                    error!("download voice failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[语音下载失败]".to_string();
-                }
-            },
+        let owner = self.instances().ok().and_then(|instances| {
+            instances
+                .into_iter()
+                .find(|other| other.cached_self_wxid().as_deref() == Some(self_id))
+        });
+        match owner {
+            Some(owner) => {
+                warn!(
+                    "callback of {} arrives at instance[pid={}] logged in as {}. reroute it to {}",
+                    self_id, ins.pid, wxid, owner.mxid
+                );
+                Some(owner)
+            }
+            None => {
+                warn!(
+                    "drop callback of {} which arrives at instance[pid={}] logged in as {}",
+                    self_id, ins.pid, wxid
+                );
+                None
+            }
+        }
+    }
+    ///
+    /// convert a wechat callback message to the event sent to matrix. None means the message is skipped.
+    /// Media is downloaded and copied to save path on the way
+    ///
+    async fn build_wechat_event(
+        &self,
+        mxid: String,
+        msg: WechatMessage,
+    ) -> Result<Option<WebsocketEvent<MatrixMessageDataField>>> {
+        let msg_id = msg.message_id;
+        let tz = self.config.timezone.map(|tz| tz.name().to_string());
+        let (mut event, pending) = match parse_wechat_event(self.texts(), tz, self, mxid, msg)? {
+            Some((event, Some(pending))) => (event, pending),
+            Some((event, None)) => return Ok(Some(event)),
+            None => return Ok(None),
+        };
 
-            WechatMessageType::Video => match self.fetch_video(msg.file_path, msg.thumb_path).await
-            {
-                Ok(blob) => {
-                    event.base.event_type = EventType::Video;
-                    event.extra = Some(blob);
-                }
-                Err(e) => {
-                    error!("download video failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[视频下载失败]".to_string();
-                }
-            },
+        let texts = self.texts();
+        let (fetched, kind, download_failed) = match pending {
+            Pending::Image { self_id, file_path } => (
+                self.fetch_image(self_id, file_path).await,
+                "image",
+                texts.image_download_failed,
+            ),
+            Pending::Voice { self_id, message } => (
+                self.fetch_voice(self_id, message).await,
+                "voice",
+                texts.voice_download_failed,
+            ),
+            Pending::Video {
+                self_id,
+                file_path,
+                thumb_path,
+                message,
+            } => (
+                self.fetch_video(self_id, file_path, thumb_path, message)
+                    .await,
+                "video",
+                texts.video_download_failed,
+            ),
+            Pending::Sticker(message) => (
+                self.fetch_sticker(message).await,
+                "sticker",
+                texts.sticker_download_failed,
+            ),
+            Pending::File {
+                self_id,
+                file_path,
+                size,
+            } => (
+                self.fetch_file(self_id, file_path, size).await,
+                "file",
+                texts.file_download_failed,
+            ),
+            Pending::Pat { self_id, pat } => {
+                event.base.content = self.format_pat(&event.base.mxid, &self_id, pat).await;
+                return Ok(Some(event));
+            }
+        };
+        match fetched {
+            Ok(blob) => event.extra = Some(blob),
+            Err(e) => {
+                error!("download {} failed: {} msg_id: {}", kind, e, msg_id);
+                event.base.event_type = EventType::Text;
+                event.base.content = self.media_failed_text(&e, download_failed);
+            }
+        }
+        Ok(Some(event))
+    }
 
-            WechatMessageType::Sticker => match self.fetch_sticker(msg.message).await {
-                Ok(blob) => {
-                    event.base.event_type = EventType::Image;
-                    event.extra = Some(blob);
-                }
-                Err(e) => {
-                    error!("download sticker failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[表情下载失败]".to_string();
-                }
-            },
+    /// the event a captured callback message is emitted as. Nothing is fetched from wechat and
+    /// nothing is recorded, therefore, media and names are left as placeholders
+    pub(super) fn replay_wechat_event(
+        &self,
+        mxid: String,
+        msg: WechatMessage,
+    ) -> Result<Option<WebsocketEvent<MatrixMessageDataField>>> {
+        let tz = self.config.timezone.map(|tz| tz.name().to_string());
+        let (mut event, pending) = match parse_wechat_event(self.texts(), tz, self, mxid, msg)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if let Some(pending) = pending {
+            event.base.content = pending.placeholder(self.texts());
+        }
+        if self.config.translate_emoticons {
+            translate_emoticons(&mut event.base);
+        }
+        Ok(Some(event))
+    }
+}
 
-            WechatMessageType::Location => match self.parse_location(msg.message).await {
-                Ok(location) => {
-                    event.base.event_type = EventType::Location;
-                    event.extra = Some(location);
-                }
-                Err(e) => {
-                    error!("parse location failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[位置解析失败]".to_string();
-                }
-            },
+// what building an event reads about earlier events. It is answered from memory and the msg id
+// store, so that building does not wait on wechat
+trait EventLookup {
+    fn recent_event(&self, id: u64) -> Option<RecentEvent>;
+    fn recent_transfer(&self, transfer_id: &str) -> Option<(u64, RecentEvent)>;
+}
 
-            WechatMessageType::App => match self.parse_app(msg.message.clone()).await {
-                Ok(EnumAppMessage::File) => match self.fetch_file(msg.file_path).await {
-                    Ok(blob) => {
-                        event.base.event_type = EventType::File;
-                        event.extra = Some(blob);
-                    }
-                    Err(e) => {
-                        error!("download file failed: {} msg_id: {}", e, msg.message_id);
-                        event.base.content = "[文件下载失败]".to_string();
-                    }
-                },
-                Ok(EnumAppMessage::Sticker) => match self.fetch_sticker(msg.message).await {
-                    Ok(blob) => {
-                        event.base.event_type = EventType::Image;
-                        event.extra = Some(blob);
-                    }
-                    Err(e) => {
-                        error!("download sticker failed: {} msg_id: {}", e, msg.message_id);
-                        event.base.content = "[表情下载失败]".to_string();
-                    }
-                },
-                Ok(EnumAppMessage::Reply(r)) => {
-                    event.base.content = r.content;
-                    let sender = r.chat_sender.or(r.user_sender);
-                    if sender.is_none() {
-                        bail!("cannot find sender. msg_id: {}", msg.message_id)
-                    }
-                    event.base.reply = Some(ReplyInfo {
-                        id: r.refer_msg_id,
-                        sender: sender.unwrap(),
-                    })
-                }
-                Ok(EnumAppMessage::Announcement(a)) => {
-                    event.base.event_type = EventType::Notice;
-                    event.base.content = a;
-                }
-                Ok(EnumAppMessage::Link(l)) => {
-                    event.base.event_type = EventType::App;
-                    event.extra = Some(MatrixMessageDataField::Link(l));
-                }
-                _ => {
-                    error!("parse app failed. msg_id: {}", msg.message_id);
-                    event.base.content = "[应用解析失败]".to_string();
-                }
-            },
+// media and names of an event which are fetched from wechat after it is built
+enum Pending {
+    Image {
+        self_id: String,
+        file_path: String,
+    },
+    Voice {
+        self_id: String,
+        message: String,
+    },
+    Video {
+        self_id: String,
+        file_path: String,
+        thumb_path: String,
+        message: String,
+    },
+    Sticker(String),
+    File {
+        self_id: String,
+        file_path: String,
+        size: Option<u64>,
+    },
+    Pat {
+        self_id: String,
+        pat: PatContent,
+    },
+}
+
+impl Pending {
+    // content of an event which is not fetched. Pats name everyone else by wxid
+    fn placeholder(&self, texts: &Texts) -> String {
+        match self {
+            Pending::Image { .. } => texts.quote_image.to_string(),
+            Pending::Voice { .. } => texts.quote_voice.to_string(),
+            Pending::Video { .. } => texts.quote_video.to_string(),
+            Pending::Sticker(_) => texts.quote_sticker.to_string(),
+            Pending::File { .. } => texts.quote_app.to_string(),
+            Pending::Pat { self_id, pat } => {
+                let name = |wxid: &str| match wxid == self_id {
+                    true => texts.pat_self.to_string(),
+                    false => wxid.to_string(),
+                };
+                pat_text(texts, &name(&pat.from), &name(&pat.patted), pat)
+            }
+        }
+    }
+}
+
+///
+/// convert a wechat callback message to the event sent to matrix without fetching anything from
+/// wechat. None means the message is skipped. The event is typed already for what is pending,
+/// its content is the one of the callback until it is fetched
+///
+fn parse_wechat_event(
+    texts: &Texts,
+    tz: Option<String>,
+    lookup: &impl EventLookup,
+    mxid: String,
+    msg: WechatMessage,
+) -> Result<Option<(WebsocketEvent<MatrixMessageDataField>, Option<Pending>)>> {
+    if is_duplicated(&msg) {
+        info!("duplicated message. msg_id = {}", msg.message_id);
+        return Ok(None);
+    }
+
+    let mut base = new_event_base(mxid, &msg);
+    base.tz = tz;
+    let mut event = WebsocketEvent::<MatrixMessageDataField> { base, extra: None };
+    let mut pending = None;
+
+    match msg.msg_type {
+        WechatMessageType::Unknown => info!("recv unknown wechat message"),
+
+        WechatMessageType::Text => {
+            event.extra = get_mentions(msg.extra_info)?;
+        }
 
-            WechatMessageType::PrivateVoIP => match self.parse_private_voip(msg.message).await {
-                Ok(status) => {
-                    event.base.event_type = EventType::VoIP;
-                    event.base.content = status;
+        // TODO(xylonx): upload media to matrix in place instead of sending blob to ws to avoid high-traffic problem
+        WechatMessageType::Image => {
+            event.base.event_type = EventType::Image;
+            pending = Some(Pending::Image {
+                self_id: msg.self_id,
+                file_path: msg.file_path,
+            });
+        }
+
+        WechatMessageType::Voice => {
+            event.base.event_type = EventType::Audio;
+            pending = Some(Pending::Voice {
+                self_id: msg.self_id,
+                message: msg.message,
+            });
+        }
+
+        WechatMessageType::Video => {
+            event.base.event_type = EventType::Video;
+            pending = Some(Pending::Video {
+                self_id: msg.self_id,
+                file_path: msg.file_path,
+                thumb_path: msg.thumb_path,
+                message: msg.message,
+            });
+        }
+
+        WechatMessageType::Sticker => {
+            event.base.event_type = EventType::Image;
+            pending = Some(Pending::Sticker(msg.message));
+        }
+
+        WechatMessageType::Location => match parse_location(msg.message) {
+            Ok(location) => {
+                event.base.event_type = EventType::Location;
+                event.base.content = location_content(&location);
+                event.extra = Some(location);
+            }
+            Err(e) => {
+                error!("parse location failed: {} msg_id: {}", e, msg.message_id);
+                event.base.content = texts.location_parse_failed.to_string();
+            }
+        },
+
+        WechatMessageType::App => match parse_app(msg.message.clone()) {
+            Ok(EnumAppMessage::File(size)) => {
+                event.base.event_type = EventType::File;
+                pending = Some(Pending::File {
+                    self_id: msg.self_id,
+                    file_path: msg.file_path,
+                    size,
+                });
+            }
+            Ok(EnumAppMessage::Sticker) => {
+                event.base.event_type = EventType::Image;
+                pending = Some(Pending::Sticker(msg.message));
+            }
+            Ok(EnumAppMessage::Reply(r)) => {
+                event.base.content = r.content;
+                let sender = r.chat_sender.or(r.user_sender);
+                if sender.is_none() {
+                    return Err(Error::Parse(format!(
+                        "cannot find sender. msg_id: {}",
+                        msg.message_id
+                    )));
                 }
-                Err(e) => {
-                    error!("parse voip failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[VoIP状态解析失败]".to_string();
+                // quote without refer type. The quoted event is looked up in recorded events instead
+                event.base.reply = Some(ReplyInfo {
+                    id: r.refer_msg_id,
+                    sender: sender.unwrap(),
+                    preview: r
+                        .refer_type
+                        .and_then(|t| quote_preview(texts, t))
+                        .or_else(|| {
+                            let recorded = lookup.recent_event(r.refer_msg_id)?;
+                            recorded_preview(texts, recorded.event_type)
+                        }),
+                })
+            }
+            Ok(EnumAppMessage::Announcement(a)) => {
+                event.base.event_type = EventType::Notice;
+                event.base.content = a;
+            }
+            Ok(EnumAppMessage::Link(l)) => {
+                event.base.event_type = EventType::App;
+                event.extra = Some(MatrixMessageDataField::Link(l));
+            }
+            Ok(EnumAppMessage::Transfer(t)) => {
+                let prefix = match t.status {
+                    TransferStatus::Sent => texts.transfer_sent,
+                    TransferStatus::Accepted => texts.transfer_accepted,
+                    TransferStatus::Refunded => texts.transfer_refunded,
+                    TransferStatus::Expired => texts.transfer_expired,
+                    TransferStatus::Unknown => texts.transfer_updated,
+                };
+                event.base.event_type = EventType::Notice;
+                event.base.content = format!("{}{}", prefix, t.fee);
+                // status update replies to the original transfer so that the bridge can correlate them
+                if t.status != TransferStatus::Sent {
+                    if let Some((id, original)) = lookup.recent_transfer(&t.transfer_id) {
+                        event.base.reply = Some(ReplyInfo {
+                            id,
+                            sender: original.sender,
+                            preview: None,
+                        });
+                    }
                 }
-            },
+                event.extra = Some(MatrixMessageDataField::Transfer(t));
+            }
+            _ => {
+                error!("parse app failed. msg_id: {}", msg.message_id);
+                event.base.content = texts.app_parse_failed.to_string();
+            }
+        },
 
-            WechatMessageType::LastMessage => {
-                info!("recv last wechat message");
-                return Ok(());
+        WechatMessageType::PrivateVoIP => match parse_private_voip(msg.message) {
+            Ok(voip) => {
+                event.base.event_type = EventType::VoIP;
+                event.base.content = voip_content(texts, &voip);
+                event.extra = Some(MatrixMessageDataField::Voip(voip));
+            }
+            Err(e) => {
+                error!("parse voip failed: {} msg_id: {}", e, msg.message_id);
+                event.base.content = texts.voip_parse_failed.to_string();
             }
+        },
 
-            WechatMessageType::Hint => match self.parse_hint(msg.message).await {
-                Ok(status) => {
-                    event.base.event_type = EventType::Revoke;
-                    event.base.content = status;
-                }
-                Err(e) => {
-                    error!("parse revoke failed: {} msg_id: {}", e, msg.message_id);
-                    event.base.content = "[撤回消息解析失败]".to_string();
-                }
-            },
+        WechatMessageType::LastMessage => {
+            info!("recv last wechat message");
+            return Ok(None);
+        }
 
-            WechatMessageType::System => match msg.sender == "weixin" || msg.is_send_message == 1 {
-                true => {
-                    info!("skip wechat system message msg_id: {}", msg.message_id);
-                    return Ok(());
-                }
-                false => match self.parse_system_message(msg.message).await {
-                    Ok(status) => {
-                        event.base.event_type = EventType::System;
-                        event.base.content = status;
-
-                        if (event.base.content == "You recalled a message"
-                            || event.base.content == "你撤回了一条消息")
-                            && !msg.sender.ends_with("@chatroom")
-                        {
-                            event.base.target = msg.wechat_id;
-                        }
-                    }
-                    Err(e) => {
-                        error!("parse system failed: {} msg_id: {}", e, msg.message_id);
-                        event.base.content = "[系统消息解析失败]".to_string();
-                    }
-                },
-            },
+        WechatMessageType::Hint => match parse_hint(msg.message) {
+            Ok(hint) => {
+                event.base.event_type = EventType::Revoke;
+                event.base.content = hint.content;
+                event.base.revoke = hint.revoked_id.map(|id| RevokeInfo {
+                    id,
+                    event_type: lookup.recent_event(id).map(|e| e.event_type),
+                });
+            }
+            Err(e) => {
+                error!("parse revoke failed: {} msg_id: {}", e, msg.message_id);
+                event.base.content = texts.revoke_parse_failed.to_string();
+            }
+        },
+
+        WechatMessageType::System if msg.sender == "weixin" => {
+            info!("skip wechat system message msg_id: {}", msg.message_id);
+            return Ok(None);
         }
 
-        self.write_event_resp(event).await
+        WechatMessageType::System => match parse_system_message(texts, msg.message) {
+            // system messages caused by self are skipped except recalls, which the chat shows
+            Ok(sys_msg) if msg.is_send_message == 1 && sys_msg.revoked_id.is_none() => {
+                info!("skip self system message msg_id: {}", msg.message_id);
+                return Ok(None);
+            }
+            Ok(SystemMessage { pat: Some(pat), .. }) => {
+                event.base.event_type = EventType::Notice;
+                pending = Some(Pending::Pat {
+                    self_id: msg.self_id,
+                    pat,
+                });
+            }
+            Ok(sys_msg) => {
+                event.base.event_type = EventType::System;
+                event.base.content = sys_msg.content;
+                event.base.raw = sys_msg.raw;
+
+                if let Some(id) = sys_msg.revoked_id {
+                    event.base.revoke = Some(RevokeInfo {
+                        id,
+                        event_type: lookup.recent_event(id).map(|e| e.event_type),
+                    });
+                    // a recall by self may be called back as received with self as its sender.
+                    // It belongs to the chat instead of self in private chats
+                    if msg.wechat_id == msg.self_id && !msg.sender.ends_with("@chatroom") {
+                        event.base.target = msg.sender;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("parse system failed: {} msg_id: {}", e, msg.message_id);
+                event.base.content = texts.system_parse_failed.to_string();
+            }
+        },
+    }
+
+    Ok(Some((event, pending)))
+}
+
+// replies are text events as well. media placeholders and system content are left untouched
+fn translate_emoticons(base: &mut WebsocketEventBase) {
+    if matches!(base.event_type, EventType::Text | EventType::Notice) {
+        base.content = emoticon::to_emoji(&base.content).into_owned();
+    }
+    if let Some(ReplyInfo {
+        preview: Some(preview),
+        ..
+    }) = &mut base.reply
+    {
+        *preview = emoticon::to_emoji(preview).into_owned();
     }
 }
 
-impl WechatManager {
-    async fn get_mentions(&self, extra: String) -> anyhow::Result<Option<MatrixMessageDataField>> {
-        #[derive(serde::Deserialize)]
-        struct Mentions {
-            #[serde(rename = "atuserlist")]
-            at_user_list: Option<String>,
-        }
+// message sent by self is called back twice. Only the one sent by phone is kept except the hint
+fn is_duplicated(msg: &WechatMessage) -> bool {
+    matches!(msg.is_send_by_phone, Some(0)) && !matches!(msg.msg_type, WechatMessageType::Hint)
+}
 
-        if extra.is_empty() {
-            bail!("no data in extra info")
+// event base of text message. sender and target are swapped for received private messages
+fn new_event_base(mxid: String, msg: &WechatMessage) -> WebsocketEventBase {
+    let mut base = WebsocketEventBase {
+        mxid,
+        id: msg.message_id,
+        seq: 0,
+        event_type: EventType::Text,
+        timestamp: Utc::now(),
+        tz: None,
+        sender: msg.self_id.clone(),
+        sender_name: None,
+        target: msg.sender.clone(),
+        content: msg.message.clone(),
+        reply: None,
+        revoke: None,
+        raw: None,
+    };
+
+    if msg.is_send_message == 0 {
+        base.sender = msg.wechat_id.clone();
+        if !msg.sender.ends_with("@chatroom") {
+            base.target = msg.self_id.clone();
         }
+    }
+    base
+}
 
-        let mentions: Mentions = quick_xml::de::from_reader(extra.as_bytes())?;
-        let mentions = mentions.at_user_list;
+// descriptor of a quoted media message since its content is not shown in the quote
+fn quote_preview(texts: &Texts, refer_type: u32) -> Option<String> {
+    let preview = match refer_type {
+        t if t == WechatMessageType::Image as u32 => texts.quote_image,
+        t if t == WechatMessageType::Voice as u32 => texts.quote_voice,
+        t if t == WechatMessageType::Video as u32 => texts.quote_video,
+        t if t == WechatMessageType::Sticker as u32 => texts.quote_sticker,
+        t if t == WechatMessageType::Location as u32 => texts.quote_location,
+        t if t == WechatMessageType::App as u32 => texts.quote_app,
+        _ => return None,
+    };
+    Some(preview.to_string())
+}
 
-        if mentions.is_none() {
-            return Ok(Option::<MatrixMessageDataField>::None);
+fn recorded_preview(texts: &Texts, event_type: EventType) -> Option<String> {
+    let preview = match event_type {
+        EventType::Image => texts.quote_image,
+        EventType::Audio => texts.quote_voice,
+        EventType::Video => texts.quote_video,
+        EventType::Location => texts.quote_location,
+        EventType::App => texts.quote_app,
+        _ => return None,
+    };
+    Some(preview.to_string())
+}
+
+impl WechatManager {
+    fn texts(&self) -> &'static Texts {
+        self.config.locale.texts()
+    }
+
+    // placeholder of media which cannot be emitted
+    fn media_failed_text(&self, e: &Error, download_failed: &'static str) -> String {
+        match e {
+            Error::TooLarge(_) => self.texts().media_too_large.to_string(),
+            Error::Incomplete(_) => self.texts().media_incomplete.to_string(),
+            _ => download_failed.to_string(),
         }
+    }
 
-        Ok(Some(MatrixMessageDataField::Mentions(
-            mentions
-                .unwrap()
-                .trim()
-                .split(',')
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>(),
-        )))
+    fn wechat_files_dir(&self) -> Result<PathBuf> {
+        match &self.config.wechat_files_dir {
+            Some(dir) => Ok(PathBuf::from(dir)),
+            None => utils::get_wechat_document_dir(),
+        }
     }
 
     async fn fetch_image(
         &self,
         self_id: String,
         file_path: String,
-    ) -> anyhow::Result<MatrixMessageDataField> {
+    ) -> Result<MatrixMessageDataField> {
         let path = Path::new(&file_path);
-        let filename = utils::get_filename(path)?;
-
-        let base_image = Path::new(&self.save_path)
-            .join(self_id)
-            .join(filename.clone());
-        let png_image = base_image.clone().with_extension("png");
-        let gif_image = base_image.clone().with_extension("gif");
-        let jpg_image = base_image.clone().with_extension("jpg");
-
-        // retry 3 times to wait wechat hook
-        let mut file =
-            utils::retriable_open_file(vec![base_image, png_image, gif_image, jpg_image], 3)
-                .await?;
-
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).await?;
+        let filename = utils::sanitize_component(&utils::get_filename(path)?);
+
+        // the hook may still be writing it
+        let (file, path) = utils::wait_file_complete(
+            &self.decoded_image_paths(&self_id, &filename),
+            None,
+            self.media_stable_window(),
+            Duration::from_secs(constants::MEDIA_COMPLETE_TIMEOUT_SECS),
+        )
+        .await?;
+        let buffer =
+            utils::read_file_limited(file, &path, self.config.max_inbound_media_size).await?;
 
         Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
             name: Some(filename),
             binary: buffer,
+            source: Some(path),
         }))
     }
 
-    async fn fetch_voice(
+    /// image of an old message. The copy decoded by the hook is preferred, or the one stored by
+    /// wechat is decoded. It is not found if wechat has cleaned it up
+    pub(super) async fn fetch_history_image(
         &self,
+        ins: &WechatInstance,
         self_id: String,
-        msg: String,
-    ) -> anyhow::Result<MatrixMessageDataField> {
+        chat_id: String,
+        msg_id: u64,
+    ) -> Result<MatrixMessageDataField> {
+        let file_path = ins.get_image_path(chat_id, msg_id).await?;
+        let filename = utils::sanitize_component(&utils::get_filename(Path::new(&file_path))?);
+        for path in self.decoded_image_paths(&self_id, &filename) {
+            if let Ok(file) = File::open(&path).await {
+                let buffer =
+                    utils::read_file_limited(file, &path, self.config.max_inbound_media_size)
+                        .await?;
+                return Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
+                    name: utils::get_filename(&path).ok(),
+                    binary: buffer,
+                    source: Some(path),
+                }));
+            }
+        }
+
+        let path = match utils::resolve_wechat_file(
+            &self.wechat_files_dir()?,
+            &self_id,
+            Path::new(&file_path),
+        ) {
+            Ok(path) => path,
+            Err(e) => {
+                debug!("image of message {} is gone: {}", msg_id, e);
+                return Err(Error::NotFound(format!("image of message {}", msg_id)));
+            }
+        };
+        let file = File::open(&path).await?;
+        let data =
+            utils::read_file_limited(file, &path, self.config.max_inbound_media_size).await?;
+        let (buffer, ext) = utils::decode_dat_image(data)?;
+        Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
+            name: Some(
+                Path::new(&filename)
+                    .with_extension(ext)
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            binary: buffer,
+            source: None,
+        }))
+    }
+
+    // the hook saves images decoded under the account dir with the extension of their format
+    fn decoded_image_paths(&self, self_id: &str, filename: &str) -> Vec<PathBuf> {
+        let base_image = Path::new(&self.config.save_path)
+            .join(utils::sanitize_component(self_id))
+            .join(filename);
+        let mut paths = vec![base_image.clone()];
+        paths.extend(["png", "gif", "jpg"].map(|ext| base_image.with_extension(ext)));
+        paths
+    }
+
+    async fn fetch_voice(&self, self_id: String, msg: String) -> Result<MatrixMessageDataField> {
         #[derive(serde::Deserialize)]
         struct Message {
             #[serde(rename = "voicemsg")]
@@ -355,76 +905,154 @@ impl WechatManager {
         }
 
         if msg.is_empty() {
-            bail!("no data in extra info")
+            return Err(Error::Parse("no data in extra info".to_string()));
         }
 
         let msg: Message = quick_xml::de::from_reader(msg.as_bytes())?;
 
-        let voice_path = msg.message.client_message_id;
-        let path = Path::new(&self.save_path)
-            .join(self_id)
-            .join(voice_path + ".amr");
+        // driver versions differ in the extension and whether voices are put under voice/
+        let voice_name = utils::sanitize_component(&msg.message.client_message_id);
+        let base_dir = Path::new(&self.config.save_path).join(utils::sanitize_component(&self_id));
+        let candidates: Vec<PathBuf> = [base_dir.clone(), base_dir.join("voice")]
+            .iter()
+            .flat_map(|dir| {
+                constants::VOICE_FILE_EXTENSIONS
+                    .iter()
+                    .map(|ext| dir.join(&voice_name).with_extension(ext))
+                    .collect::<Vec<PathBuf>>()
+            })
+            .collect();
+
+        let (file, path) = match utils::wait_file_complete(
+            &candidates,
+            None,
+            self.media_stable_window(),
+            Duration::from_secs(constants::MEDIA_COMPLETE_TIMEOUT_SECS),
+        )
+        .await
+        {
+            Ok(matched) => matched,
+            Err(e) => {
+                error!("voice file not found. attempted paths: {:?}", candidates);
+                return Err(e);
+            }
+        };
+        debug!("voice file matched: {}", path.display());
         let filename = utils::get_filename(path.as_path())?;
-        if !path.exists() {
-            bail!("voice file {} not found", path.display())
-        }
-
-        let mut file = utils::retriable_open_file(vec![path], 3).await?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).await?;
+        let buffer =
+            utils::read_file_limited(file, &path, self.config.max_inbound_media_size).await?;
 
         Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
             name: Some(filename),
             binary: buffer,
+            source: Some(path),
         }))
     }
 
+    // videos are downloaded by wechat after the message like files. The size is in the message
     async fn fetch_video(
         &self,
+        self_id: String,
         file_path: String,
         thumbnail: String,
-    ) -> anyhow::Result<MatrixMessageDataField> {
-        let path = match file_path.len() {
-            0 => utils::get_wechat_document_dir()?
-                .join(thumbnail)
-                .with_extension("mp4"),
-            _ => utils::get_wechat_document_dir()?.join(file_path),
+        msg: String,
+    ) -> Result<MatrixMessageDataField> {
+        #[derive(serde::Deserialize)]
+        struct Message {
+            #[serde(rename = "videomsg")]
+            message: VideoMessage,
+        }
+        #[derive(serde::Deserialize)]
+        struct VideoMessage {
+            #[serde(rename = "@length")]
+            length: Option<u64>,
+        }
+
+        let relative_path = match file_path.len() {
+            0 => Path::new(&thumbnail).with_extension("mp4"),
+            _ => Path::new(&file_path).to_path_buf(),
         };
+        let path = utils::resolve_wechat_file(&self.wechat_files_dir()?, &self_id, &relative_path)?;
         let filename = utils::get_filename(path.as_path())?;
+        let size = match quick_xml::de::from_reader::<_, Message>(msg.as_bytes()) {
+            Ok(msg) => msg.message.length.filter(|length| *length > 0),
+            Err(e) => {
+                debug!("parse video message failed: {}", e);
+                None
+            }
+        };
 
-        if !path.exists() {
-            bail!("video file {} not found", path.display())
-        }
-
-        let mut file = utils::retriable_open_file(vec![path], 3).await?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).await?;
+        let file = self.wait_downloaded(&path, size).await?;
+        let buffer =
+            utils::read_file_limited(file, &path, self.config.max_inbound_media_size).await?;
 
         Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
             name: Some(filename),
             binary: buffer,
+            source: Some(path),
         }))
     }
 
-    async fn fetch_file(&self, file_path: String) -> anyhow::Result<MatrixMessageDataField> {
-        let path = utils::get_wechat_document_dir()?.join(file_path);
+    // large files are still being downloaded by wechat when the message arrives. Wait until it
+    // has the size in the message, or stops growing if the size is unknown
+    async fn fetch_file(
+        &self,
+        self_id: String,
+        file_path: String,
+        size: Option<u64>,
+    ) -> Result<MatrixMessageDataField> {
+        let path =
+            utils::resolve_wechat_file(&self.wechat_files_dir()?, &self_id, Path::new(&file_path))?;
         let filename = utils::get_filename(path.as_path())?;
 
-        if !path.exists() {
-            bail!("file {} not found", path.display())
-        }
-
-        let mut file = utils::retriable_open_file(vec![path], 3).await?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).await?;
+        let file = self.wait_downloaded(&path, size).await?;
+        let buffer =
+            utils::read_file_limited(file, &path, self.config.max_inbound_media_size).await?;
 
         Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
             name: Some(filename),
             binary: buffer,
+            source: Some(path),
         }))
     }
 
-    async fn fetch_sticker(&self, msg: String) -> anyhow::Result<MatrixMessageDataField> {
+    // wait for wechat to download the file of the announced size. It is rejected early if it is
+    // too large anyway
+    async fn wait_downloaded(&self, path: &Path, size: Option<u64>) -> Result<File> {
+        let max_size = self.config.max_inbound_media_size;
+        if let Some(size) = size.filter(|size| *size > max_size as u64) {
+            return Err(Error::TooLarge(format!(
+                "{}: size {} exceeds max size {}",
+                path.display(),
+                size,
+                max_size
+            )));
+        }
+
+        let candidates = [path.to_path_buf()];
+        let (file, _) = match self.config.file_complete_timeout_secs {
+            0 => {
+                utils::retriable_open_file(candidates.to_vec(), 3, Duration::from_secs(1), true)
+                    .await?
+            }
+            secs => {
+                utils::wait_file_complete(
+                    &candidates,
+                    size,
+                    self.media_stable_window(),
+                    Duration::from_secs(secs),
+                )
+                .await?
+            }
+        };
+        Ok(file)
+    }
+
+    fn media_stable_window(&self) -> Duration {
+        Duration::from_millis(self.config.media_stable_window_ms)
+    }
+
+    async fn fetch_sticker(&self, msg: String) -> Result<MatrixMessageDataField> {
         #[derive(serde::Deserialize)]
         struct Message {
             #[serde(rename = "emoji")]
@@ -439,143 +1067,531 @@ impl WechatManager {
         }
 
         if msg.is_empty() {
-            bail!("no data in extra info")
+            return Err(Error::Parse("no data in extra info".to_string()));
         }
 
         let msg: Message = quick_xml::de::from_reader(msg.as_bytes())?;
 
         Ok(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
-            name: Some(msg.message.key),
-            binary: utils::get_file_maybe_gzip_decompress(msg.message.cnd_url).await?,
+            name: Some(utils::sanitize_component(&msg.message.key)),
+            binary: utils::get_file_maybe_gzip_decompress(
+                &self.media_client,
+                msg.message.cnd_url,
+                utils::DownloadOptions {
+                    max_size: constants::MAX_STICKER_SIZE.min(self.config.max_inbound_media_size),
+                    content_type: Some("image/"),
+                },
+            )
+            .await?,
+            source: None,
         }))
     }
 
-    async fn parse_location(&self, msg: String) -> anyhow::Result<MatrixMessageDataField> {
-        #[derive(serde::Deserialize)]
-        struct Message {
-            #[serde(rename = "location")]
-            message: LocationMessage,
+    // url of the static map and the image event replying to the location. None if it is disabled
+    fn location_thumbnail(
+        &self,
+        location: &WebsocketEvent<MatrixMessageDataField>,
+    ) -> Option<(String, WebsocketEvent<MatrixMessageDataField>)> {
+        let template = self.config.location_thumbnail_url.as_ref()?;
+        let (name, latitude, longitude) = match &location.extra {
+            Some(MatrixMessageDataField::Location(location)) => {
+                (&location.name, location.latitude, location.longitude)
+            }
+            _ => return None,
+        };
+        let url = template
+            .replace("{lat}", &latitude.to_string())
+            .replace("{lon}", &longitude.to_string());
+
+        let mut event = WebsocketEvent::agent(
+            location.base.mxid.clone(),
+            EventType::Image,
+            name.clone(),
+            None,
+        );
+        event.base.tz = location.base.tz.clone();
+        event.base.sender = location.base.sender.clone();
+        event.base.target = location.base.target.clone();
+        event.base.reply = Some(ReplyInfo {
+            id: location.base.id,
+            sender: location.base.sender.clone(),
+            preview: Some(self.texts().quote_location.to_string()),
+        });
+        Some((url, event))
+    }
+
+    // the location is already delivered. A map which fails to fetch is only logged
+    fn spawn_location_thumbnail(
+        &self,
+        url: String,
+        mut event: WebsocketEvent<MatrixMessageDataField>,
+    ) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let location_id = event.base.reply.as_ref().map(|r| r.id).unwrap_or_default();
+            let binary = match utils::get_file_maybe_gzip_decompress(
+                &manager.media_client,
+                url.clone(),
+                utils::DownloadOptions {
+                    max_size: constants::MAX_LOCATION_THUMBNAIL_SIZE,
+                    content_type: Some("image/"),
+                },
+            )
+            .await
+            {
+                Ok(binary) => binary,
+                Err(e) => {
+                    warn!(
+                        "fetch map of location {} from {} failed: {}",
+                        location_id, url, e
+                    );
+                    return;
+                }
+            };
+            event.extra = Some(MatrixMessageDataField::Blob(MatrixMessageDataBlob {
+                name: Some(constants::LOCATION_THUMBNAIL_NAME.to_string()),
+                binary,
+                source: None,
+            }));
+            if let Err(e) = manager.write_event_resp(event).await {
+                error!("deliver map of location {} failed: {}", location_id, e);
+            }
+        });
+    }
+
+    fn record_event(&self, id: u64, event: RecentEvent) {
+        match self.recent_events.lock() {
+            Ok(mut recent) => recent.record(id, event),
+            Err(err) => error!("lock recent events failed: {}", err),
         }
-        #[derive(serde::Deserialize)]
-        struct LocationMessage {
-            #[serde(rename = "@x")]
-            x: String,
-            #[serde(rename = "@y")]
-            y: String,
-            #[serde(rename = "@poiname")]
-            position_name: String,
-            #[serde(rename = "@label")]
-            label: String,
+    }
+
+    fn record_transfer(&self, transfer_id: String, id: u64) {
+        match self.recent_events.lock() {
+            Ok(mut recent) => recent.record_transfer(transfer_id, id),
+            Err(err) => error!("lock recent events failed: {}", err),
         }
+    }
 
-        if msg.is_empty() {
-            bail!("no data in extra info")
+    // "A 拍了拍 B 的后脑勺" with wxids resolved to nicknames. Group aliases are preferred in groups
+    async fn format_pat(&self, mxid: &str, self_id: &str, pat: PatContent) -> String {
+        let ins = self.get_instance_by_mxid(mxid.to_string()).ok();
+        let from = self
+            .pat_name(ins.as_ref(), self_id, &pat.chat, &pat.from)
+            .await;
+        let patted = self
+            .pat_name(ins.as_ref(), self_id, &pat.chat, &pat.patted)
+            .await;
+        pat_text(self.texts(), &from, &patted, &pat)
+    }
+
+    async fn pat_name(
+        &self,
+        ins: Option<&WechatInstance>,
+        self_id: &str,
+        chat: &str,
+        wxid: &str,
+    ) -> String {
+        if wxid == self_id {
+            return self.texts().pat_self.to_string();
         }
-        let msg: Message = quick_xml::de::from_reader(msg.as_bytes())?;
+        let ins = match ins {
+            Some(ins) => ins,
+            None => return wxid.to_string(),
+        };
+        if chat.ends_with("@chatroom") {
+            match ins
+                .get_group_member_nickname(chat.to_string(), wxid.to_string())
+                .await
+            {
+                Ok(nickname) if !nickname.is_empty() => return nickname,
+                Ok(_) => {}
+                Err(e) => warn!("get nickname of {} in {} failed: {}", wxid, chat, e),
+            }
+        }
+        match ins.get_user_info(wxid.to_string()).await {
+            Ok(info) => info.nickname,
+            Err(e) => {
+                warn!("get user info of {} failed: {}", wxid, e);
+                wxid.to_string()
+            }
+        }
+    }
+}
 
-        Ok(MatrixMessageDataField::Location {
+impl EventLookup for WechatManager {
+    fn recent_transfer(&self, transfer_id: &str) -> Option<(u64, RecentEvent)> {
+        match self.recent_events.lock() {
+            Ok(recent) => recent.transfer(transfer_id),
+            Err(err) => {
+                error!("lock recent events failed: {}", err);
+                None
+            }
+        }
+    }
+
+    fn recent_event(&self, id: u64) -> Option<RecentEvent> {
+        let recent = match self.recent_events.lock() {
+            Ok(recent) => recent.get(id),
+            Err(err) => {
+                error!("lock recent events failed: {}", err);
+                None
+            }
+        };
+        // events evicted from memory or emitted before restart
+        match (recent, &self.msg_ids) {
+            (Some(event), _) => Some(event),
+            (None, Some(store)) => store.get(id).unwrap_or_else(|e| {
+                error!("look up msg id {} in store failed: {}", id, e);
+                None
+            }),
+            (None, None) => None,
+        }
+    }
+}
+
+// pat notice of resolved names. The suffix is cut from the template when wechat does not tell it
+fn pat_text(texts: &Texts, from: &str, patted: &str, pat: &PatContent) -> String {
+    let suffix = match pat.suffix.trim() {
+        "" => pat
+            .template
+            .split(&format!("${{{}}}", pat.patted))
+            .nth(1)
+            .map(|s| s.trim_start_matches('"').trim())
+            .unwrap_or_default()
+            .to_string(),
+        suffix => suffix.to_string(),
+    };
+    match suffix.is_empty() {
+        true => format!("{} {} {}", from, texts.patted, patted),
+        false => format!("{} {} {} {}", from, texts.patted, patted, suffix),
+    }
+}
+
+// parsers of the xml wechat calls back with. They only read the message, so that they are
+// tested without a running wechat
+fn get_mentions(extra: String) -> Result<Option<MatrixMessageDataField>> {
+    #[derive(serde::Deserialize)]
+    struct Mentions {
+        #[serde(rename = "atuserlist")]
+        at_user_list: Option<String>,
+    }
+
+    if extra.is_empty() {
+        return Err(Error::Parse("no data in extra info".to_string()));
+    }
+
+    let mentions: Mentions = quick_xml::de::from_reader(extra.as_bytes())?;
+    let mentions = mentions.at_user_list;
+
+    if mentions.is_none() {
+        return Ok(Option::<MatrixMessageDataField>::None);
+    }
+
+    Ok(Some(MatrixMessageDataField::Mentions(
+        mentions
+            .unwrap()
+            .trim()
+            .split(',')
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>(),
+    )))
+}
+
+fn parse_location(msg: String) -> Result<MatrixMessageDataField> {
+    #[derive(serde::Deserialize)]
+    struct Message {
+        #[serde(rename = "location")]
+        message: LocationMessage,
+    }
+    #[derive(serde::Deserialize)]
+    struct LocationMessage {
+        #[serde(rename = "@x")]
+        x: String,
+        #[serde(rename = "@y")]
+        y: String,
+        #[serde(rename = "@poiname")]
+        position_name: String,
+        #[serde(rename = "@label")]
+        label: String,
+    }
+
+    if msg.is_empty() {
+        return Err(Error::Parse("no data in extra info".to_string()));
+    }
+    let msg: Message = quick_xml::de::from_reader(msg.as_bytes())?;
+
+    Ok(MatrixMessageDataField::Location(
+        MatrixMessageDataLocation {
             name: msg.message.position_name,
             address: msg.message.label,
             longitude: msg.message.y.parse::<f64>()?,
             latitude: msg.message.x.parse::<f64>()?,
-        })
-    }
+        },
+    ))
+}
 
-    async fn parse_app(&self, msg: String) -> anyhow::Result<EnumAppMessage> {
-        if msg.is_empty() {
-            bail!("no data in extra info")
-        }
-        let msg: AppMessage = quick_xml::de::from_reader(msg.as_bytes())?;
-        match msg.message.message_type {
-            WechatMessageAppType::File => Ok(EnumAppMessage::File),
-            WechatMessageAppType::Sticker => Ok(EnumAppMessage::Sticker),
-            WechatMessageAppType::Reply if msg.message.reply.is_some() => {
-                let mut reply = msg.message.reply.unwrap();
-                reply.content = msg.message.title;
-                Ok(EnumAppMessage::Reply(reply))
-            }
-            WechatMessageAppType::Notice if msg.message.announcement.is_some() => Ok(
-                EnumAppMessage::Announcement(msg.message.announcement.unwrap()),
-            ),
-            _ => Ok(EnumAppMessage::Link(MatrixMessageDataLink {
-                title: msg.message.title,
-                des: msg.message.des,
-                url: msg.message.url.unwrap_or_default(),
-            })),
-        }
+fn parse_app(msg: String) -> Result<EnumAppMessage> {
+    if msg.is_empty() {
+        return Err(Error::Parse("no data in extra info".to_string()));
     }
-
-    async fn parse_private_voip(&self, msg: String) -> anyhow::Result<String> {
-        #[derive(serde::Deserialize, Debug)]
-        struct InviteMessage {
-            status: u32,
+    let msg: AppMessage = quick_xml::de::from_reader(msg.as_bytes())?;
+    match msg.message.message_type {
+        WechatMessageAppType::File => Ok(EnumAppMessage::File(
+            msg.message
+                .attach
+                .map(|a| a.total_len)
+                .filter(|len| *len > 0),
+        )),
+        WechatMessageAppType::Sticker => Ok(EnumAppMessage::Sticker),
+        WechatMessageAppType::Reply if msg.message.reply.is_some() => {
+            let mut reply = msg.message.reply.unwrap();
+            reply.content = msg.message.title;
+            Ok(EnumAppMessage::Reply(reply))
         }
-        #[derive(serde::Deserialize, Debug)]
-        struct BubbleMessage {
-            #[serde(rename = "VoIPBubbleMsg")]
-            bubble: BubbleContent,
-        }
-        #[derive(serde::Deserialize, Debug)]
-        struct BubbleContent {
-            msg: String,
+        WechatMessageAppType::Notice if msg.message.announcement.is_some() => Ok(
+            EnumAppMessage::Announcement(msg.message.announcement.unwrap()),
+        ),
+        WechatMessageAppType::Transfer if msg.message.pay_info.is_some() => {
+            let info = msg.message.pay_info.unwrap();
+            Ok(EnumAppMessage::Transfer(MatrixMessageDataTransfer {
+                // the transfer id is shared by the transfer and all its status updates
+                transfer_id: info.transfer_id.or(info.transaction_id).unwrap_or_default(),
+                status: match info.pay_sub_type {
+                    1 => TransferStatus::Sent,
+                    3 => TransferStatus::Accepted,
+                    4 => TransferStatus::Refunded,
+                    5 => TransferStatus::Expired,
+                    _ => TransferStatus::Unknown,
+                },
+                fee: info.fee_desc,
+                memo: info.memo,
+            }))
         }
+        app_type => Ok(EnumAppMessage::Link(MatrixMessageDataLink {
+            title: msg.message.title,
+            des: msg.message.des,
+            url: msg.message.url.unwrap_or_default(),
+            subtype: app_type.subtype(),
+        })),
+    }
+}
 
-        let bytes = msg.as_bytes();
-        match quick_xml::de::from_reader(bytes) {
-            Ok(InviteMessage { status }) => match status {
-                1 => {
-                    return Ok(String::from("VoIP: Started a call"));
-                }
-                2 => {
-                    return Ok(String::from("VoIP: Call ended"));
-                }
-                _ => {
-                    return Ok(format!("VoIP: Unknown status: {}", status));
-                }
-            },
-            Err(_) => {
-                if let Ok(BubbleMessage { bubble }) = quick_xml::de::from_reader(bytes) {
-                    return Ok(format!("VoIP: {}", bubble.msg));
+fn parse_private_voip(msg: String) -> Result<MatrixMessageDataVoip> {
+    #[derive(serde::Deserialize, Debug)]
+    struct InviteMessage {
+        status: u32,
+    }
+    #[derive(serde::Deserialize, Debug)]
+    struct BubbleMessage {
+        #[serde(rename = "VoIPBubbleMsg")]
+        bubble: BubbleContent,
+    }
+    #[derive(serde::Deserialize, Debug)]
+    struct BubbleContent {
+        msg: String,
+    }
+
+    let mut voip = MatrixMessageDataVoip {
+        outcome: VoipOutcome::Unknown,
+        duration: None,
+        status: None,
+        message: None,
+    };
+    let bytes = msg.as_bytes();
+    match quick_xml::de::from_reader(bytes) {
+        Ok(InviteMessage { status }) => match status {
+            1 => voip.outcome = VoipOutcome::Started,
+            2 => voip.outcome = VoipOutcome::Ended,
+            _ => voip.status = Some(status),
+        },
+        Err(_) => {
+            if let Ok(BubbleMessage { bubble }) = quick_xml::de::from_reader(bytes) {
+                match voip_outcome(&bubble.msg) {
+                    Some((outcome, duration)) => {
+                        voip.outcome = outcome;
+                        voip.duration = duration;
+                    }
+                    None => voip.message = Some(bubble.msg),
                 }
             }
-        };
+        }
+    };
+
+    Ok(voip)
+}
 
-        Ok("".to_string())
+fn voip_content(texts: &Texts, voip: &MatrixMessageDataVoip) -> String {
+    match voip.outcome {
+        VoipOutcome::Started => texts.voip_started.to_string(),
+        VoipOutcome::Ended => texts.voip_ended.to_string(),
+        VoipOutcome::Completed => match voip.duration {
+            Some(secs) => format!("{}{}", texts.voip_completed, format_duration(secs)),
+            None => texts.voip_ended.to_string(),
+        },
+        VoipOutcome::Missed => texts.voip_missed.to_string(),
+        VoipOutcome::Declined => texts.voip_declined.to_string(),
+        VoipOutcome::Cancelled => texts.voip_cancelled.to_string(),
+        VoipOutcome::Unknown => match (voip.status, &voip.message) {
+            (Some(status), _) => format!("{}{}", texts.voip_unknown_status, status),
+            (None, Some(message)) => format!("{}{}", texts.voip_prefix, message),
+            (None, None) => String::new(),
+        },
     }
+}
 
-    async fn parse_hint(&self, msg: String) -> anyhow::Result<String> {
-        if msg.is_empty() {
-            bail!("no data in extra info")
-        }
-        Ok(quick_xml::de::from_reader(msg.as_bytes()).unwrap_or(msg))
+fn parse_hint(msg: String) -> Result<Hint> {
+    if msg.is_empty() {
+        return Err(Error::Parse("no data in extra info".to_string()));
     }
 
-    async fn parse_system_message(&self, msg: String) -> anyhow::Result<String> {
-        #[derive(serde::Deserialize)]
-        struct Message {
-            #[serde(rename = "@type")]
-            msg_type: String,
-        }
+    if let Ok(RevokeMessage { revoke }) = quick_xml::de::from_reader(msg.as_bytes()) {
+        return Ok(Hint {
+            content: revoke.replace_msg,
+            revoked_id: Some(revoke.new_msg_id),
+        });
+    }
 
-        if msg.is_empty() {
-            bail!("no data in extra info")
-        }
-        let sys_msg: Message = match quick_xml::de::from_reader(msg.as_bytes()) {
-            Ok(m) => m,
-            Err(_) => {
-                warn!("unknown system message: {}", msg);
-                return Ok("".to_string());
-            }
-        };
+    Ok(Hint {
+        content: quick_xml::de::from_reader(msg.as_bytes()).unwrap_or(msg),
+        revoked_id: None,
+    })
+}
+
+fn parse_system_message(texts: &Texts, msg: String) -> Result<SystemMessage> {
+    #[derive(serde::Deserialize)]
+    struct Message {
+        #[serde(rename = "@type")]
+        msg_type: String,
+    }
 
-        match sys_msg.msg_type.as_str() {
-            // tickle and revoke hint will be resend by Hint, therefore, ignore it in sysmsg block
-            "pat" | "revokemsg" => Ok("".to_string()),
-            _ => Ok(msg),
+    if msg.is_empty() {
+        return Err(Error::Parse("no data in extra info".to_string()));
+    }
+
+    // plain text system message is already human-readable
+    if !msg.trim_start().starts_with('<') {
+        return Ok(SystemMessage {
+            content: msg,
+            raw: None,
+            revoked_id: None,
+            pat: None,
+        });
+    }
+
+    let sys_msg: Message = match quick_xml::de::from_reader(msg.as_bytes()) {
+        Ok(m) => m,
+        Err(_) => {
+            warn!("unknown system message: {}", msg);
+            return Ok(SystemMessage {
+                content: texts.system_message.to_string(),
+                raw: Some(msg),
+                revoked_id: None,
+                pat: None,
+            });
         }
+    };
+
+    match sys_msg.msg_type.as_str() {
+        // pat content is rendered after resolving nicknames
+        "pat" => match quick_xml::de::from_reader(msg.as_bytes()) {
+            Ok(PatMessage { pat }) => Ok(SystemMessage {
+                content: "".to_string(),
+                raw: None,
+                revoked_id: None,
+                pat: Some(pat),
+            }),
+            Err(e) => {
+                warn!("parse pat failed: {}: {}", e, msg);
+                Ok(SystemMessage {
+                    content: texts.system_message.to_string(),
+                    raw: Some(msg),
+                    revoked_id: None,
+                    pat: None,
+                })
+            }
+        },
+        "revokemsg" => Ok(SystemMessage {
+            content: "".to_string(),
+            raw: None,
+            revoked_id: quick_xml::de::from_reader(msg.as_bytes())
+                .ok()
+                .map(|m: RevokeMessage| m.revoke.new_msg_id),
+            pat: None,
+        }),
+        t => match texts.system_contents.iter().find(|(k, _)| *k == t) {
+            Some((_, content)) => Ok(SystemMessage {
+                content: content.to_string(),
+                raw: None,
+                revoked_id: None,
+                pat: None,
+            }),
+            None => {
+                warn!("unknown system message type {}: {}", t, msg);
+                Ok(SystemMessage {
+                    content: texts.system_message.to_string(),
+                    raw: Some(msg),
+                    revoked_id: None,
+                    pat: None,
+                })
+            }
+        },
     }
 }
 
+#[derive(serde::Deserialize)]
+#[serde(rename = "sysmsg")]
+struct RevokeMessage {
+    #[serde(rename = "revokemsg")]
+    revoke: RevokeContent,
+}
+
+#[derive(serde::Deserialize)]
+struct RevokeContent {
+    #[serde(rename = "newmsgid")]
+    new_msg_id: u64,
+    #[serde(rename = "replacemsg")]
+    replace_msg: String,
+}
+
+struct Hint {
+    content: String,
+    // msg id of the recalled message if the hint is a revoke
+    revoked_id: Option<u64>,
+}
+
+struct SystemMessage {
+    content: String,
+    // raw xml of unknown system message for debugging
+    raw: Option<String>,
+    // msg id of the recalled message if it is a revokemsg
+    revoked_id: Option<u64>,
+    pat: Option<PatContent>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "sysmsg")]
+struct PatMessage {
+    pat: PatContent,
+}
+
+#[derive(serde::Deserialize)]
+struct PatContent {
+    #[serde(rename = "fromusername")]
+    from: String,
+    // group id, or wxid of the other side in private chat
+    #[serde(rename = "chatusername")]
+    chat: String,
+    #[serde(rename = "pattedusername")]
+    patted: String,
+    // custom text set by the patted user like "的后脑勺"
+    #[serde(rename = "patsuffix", default)]
+    suffix: String,
+    // like `"${wxid_a}" 拍了拍 "${wxid_b}" 的后脑勺`. used when suffix is absent
+    #[serde(default)]
+    template: String,
+}
+
 // FIXME(xylonx): move below wechat message type definition to another module
 #[derive(serde::Deserialize)]
 #[serde(rename = "msg")]
@@ -585,11 +1601,13 @@ struct AppMessage {
 }
 
 enum EnumAppMessage {
-    File,
+    // size of the file if wechat tells
+    File(Option<u64>),
     Sticker,
     Announcement(String),
     Reply(AppReply),
     Link(MatrixMessageDataLink),
+    Transfer(MatrixMessageDataTransfer),
 }
 
 #[derive(serde::Deserialize)]
@@ -607,6 +1625,34 @@ struct AppMessageContent {
 
     #[serde(rename = "refermsg")]
     reply: Option<AppReply>,
+
+    #[serde(rename = "wcpayinfo")]
+    pay_info: Option<AppPayInfo>,
+
+    #[serde(rename = "appattach")]
+    attach: Option<AppAttach>,
+}
+
+#[derive(serde::Deserialize)]
+struct AppAttach {
+    #[serde(rename = "totallen", default)]
+    total_len: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct AppPayInfo {
+    // 1: sent, 3: accepted, 4: refunded, 5: expired
+    #[serde(rename = "paysubtype")]
+    pay_sub_type: u32,
+    #[serde(rename = "feedesc", default)]
+    fee_desc: String,
+    #[serde(rename = "pay_memo", default)]
+    memo: String,
+    #[serde(rename = "transferid")]
+    transfer_id: Option<String>,
+    // typo of wechat
+    #[serde(rename = "transcationid")]
+    transaction_id: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -620,4 +1666,468 @@ struct AppReply {
     chat_sender: Option<String>,
     #[serde(rename = "fromusr")]
     user_sender: Option<String>,
+    // wechat message type of the quoted message
+    #[serde(rename = "type")]
+    refer_type: Option<u32>,
+}
+
+// outcome and seconds of a call from its bubble, which is written in the language of wechat. The
+// other side cancelling is a missed call of self while self cancelling is a cancelled one
+fn voip_outcome(bubble: &str) -> Option<(VoipOutcome, Option<u32>)> {
+    let bubble = bubble.trim();
+    if let Some(secs) = bubble.rsplit(' ').next().and_then(parse_duration) {
+        if bubble.starts_with("通话时长") || bubble.starts_with("Duration") {
+            return Some((VoipOutcome::Completed, Some(secs)));
+        }
+    }
+    let outcome = match bubble {
+        "对方已取消" | "未接听" | "对方无应答" | "Canceled by caller" | "No answer" | "Missed" => {
+            VoipOutcome::Missed
+        }
+        "已拒绝" | "对方已拒绝" | "Declined" | "Declined by callee" => {
+            VoipOutcome::Declined
+        }
+        "已取消" | "Canceled" | "Cancelled" => VoipOutcome::Cancelled,
+        _ => return None,
+    };
+    Some((outcome, None))
+}
+
+// seconds of mm:ss or hh:mm:ss
+fn parse_duration(text: &str) -> Option<u32> {
+    let parts = text
+        .split(':')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    match parts.as_slice() {
+        [m, s] => Some(m * 60 + s),
+        [h, m, s] => Some(h * 3600 + m * 60 + s),
+        _ => None,
+    }
+}
+
+fn format_duration(secs: u32) -> String {
+    match secs >= 3600 {
+        true => format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+        false => format!("{:02}:{:02}", secs / 60, secs % 60),
+    }
+}
+
+// geo uri which clients can open in a map, after the place name if any
+fn location_content(location: &MatrixMessageDataField) -> String {
+    match location {
+        MatrixMessageDataField::Location(MatrixMessageDataLocation {
+            name,
+            address,
+            longitude,
+            latitude,
+        }) => {
+            let place = match name.is_empty() {
+                true => address,
+                false => name,
+            };
+            let uri = format!("geo:{},{}", latitude, longitude);
+            match place.is_empty() {
+                true => uri,
+                false => format!("{} {}", place, uri),
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locale::Locale;
+    use crate::ws::AppSubtype;
+
+    fn callback(msg_type: u32, is_send_message: i8, sender: &str, message: &str) -> WechatMessage {
+        serde_json::from_value(serde_json::json!({
+            "pid": 1,
+            "msgid": 42,
+            "wxid": "wxid_friend",
+            "sender": sender,
+            "self": "wxid_self",
+            "isSendMsg": is_send_message,
+            "isSendByPhone": null,
+            "type": msg_type,
+            "message": message,
+            "filepath": "",
+            "thumb_path": "",
+            "extrainfo": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn received_private_message_is_sent_to_self() {
+        let msg = callback(1, 0, "wxid_friend", "hi");
+        let base = new_event_base("@alice:matrix".to_string(), &msg);
+        assert_eq!(base.id, 42);
+        assert_eq!(base.sender, "wxid_friend");
+        assert_eq!(base.target, "wxid_self");
+        assert_eq!(base.content, "hi");
+        assert_eq!(base.event_type, EventType::Text);
+    }
+
+    #[test]
+    fn group_message_targets_the_group() {
+        let received = callback(1, 0, "123@chatroom", "hi");
+        let base = new_event_base("@alice:matrix".to_string(), &received);
+        assert_eq!(base.sender, "wxid_friend");
+        assert_eq!(base.target, "123@chatroom");
+
+        let sent = callback(1, 1, "123@chatroom", "hi");
+        let base = new_event_base("@alice:matrix".to_string(), &sent);
+        assert_eq!(base.sender, "wxid_self");
+        assert_eq!(base.target, "123@chatroom");
+    }
+
+    #[test]
+    fn self_message_not_sent_by_phone_is_duplicated_except_hints() {
+        let mut msg = callback(1, 1, "wxid_friend", "hi");
+        assert!(!is_duplicated(&msg));
+        msg.is_send_by_phone = Some(1);
+        assert!(!is_duplicated(&msg));
+        msg.is_send_by_phone = Some(0);
+        assert!(is_duplicated(&msg));
+
+        let mut hint = callback(10000, 1, "wxid_friend", "");
+        hint.is_send_by_phone = Some(0);
+        assert!(!is_duplicated(&hint));
+    }
+
+    #[test]
+    fn parse_location_swaps_xy_to_coordinates() {
+        let xml =
+            r#"<msg><location x="39.9" y="116.4" poiname="Tiananmen" label="Beijing" /></msg>"#;
+        let location = parse_location(xml.to_string()).unwrap();
+        match &location {
+            MatrixMessageDataField::Location(MatrixMessageDataLocation {
+                name,
+                address,
+                longitude,
+                latitude,
+            }) => {
+                assert_eq!(name, "Tiananmen");
+                assert_eq!(address, "Beijing");
+                assert_eq!(*latitude, 39.9);
+                assert_eq!(*longitude, 116.4);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(location_content(&location), "Tiananmen geo:39.9,116.4");
+        assert!(parse_location(String::new()).is_err());
+    }
+
+    #[test]
+    fn parse_app_classifies_files_replies_and_links() {
+        let file = "<msg><appmsg><type>6</type><title>a.pdf</title><des></des>\
+            <appattach><totallen>1024</totallen></appattach></appmsg></msg>";
+        assert!(matches!(
+            parse_app(file.to_string()),
+            Ok(EnumAppMessage::File(Some(1024)))
+        ));
+
+        let reply = "<msg><appmsg><type>57</type><title>sure</title><des></des>\
+            <refermsg><type>1</type><svrid>7</svrid><fromusr>wxid_friend</fromusr>\
+            <chatusr>wxid_member</chatusr></refermsg></appmsg></msg>";
+        match parse_app(reply.to_string()) {
+            Ok(EnumAppMessage::Reply(r)) => {
+                assert_eq!(r.content, "sure");
+                assert_eq!(r.refer_msg_id, 7);
+                assert_eq!(r.chat_sender.as_deref(), Some("wxid_member"));
+                assert_eq!(r.refer_type, Some(1));
+            }
+            _ => panic!("reply is not parsed"),
+        }
+
+        let link = "<msg><appmsg><type>5</type><title>news</title><des>today</des>\
+            <url>https://example.com</url></appmsg></msg>";
+        match parse_app(link.to_string()) {
+            Ok(EnumAppMessage::Link(l)) => {
+                assert_eq!(l.title, "news");
+                assert_eq!(l.url, "https://example.com");
+                assert_eq!(l.subtype, AppSubtype::Link);
+            }
+            _ => panic!("link is not parsed"),
+        }
+    }
+
+    #[test]
+    fn parse_app_tags_app_messages_with_their_subtype() {
+        let cases = [
+            (3, AppSubtype::Music, "music"),
+            (4, AppSubtype::Video, "video"),
+            (19, AppSubtype::ChatHistory, "chat_history"),
+            (33, AppSubtype::MiniProgram, "mini_program"),
+            (36, AppSubtype::MiniProgram, "mini_program"),
+            (51, AppSubtype::Channels, "channels"),
+            (1234, AppSubtype::Other, "other"),
+            // a reply without the quoted message is emitted as an app message too
+            (57, AppSubtype::Other, "other"),
+        ];
+        for (app_type, subtype, name) in cases {
+            let xml = format!(
+                "<msg><appmsg><type>{}</type><title>t</title><des>d</des>\
+                <url>https://example.com</url></appmsg></msg>",
+                app_type
+            );
+            match parse_app(xml) {
+                Ok(EnumAppMessage::Link(l)) => {
+                    assert_eq!(l.subtype, subtype, "app type {}", app_type);
+                    assert_eq!(serde_json::to_value(l).unwrap()["subtype"], name);
+                }
+                _ => panic!("app type {} is not parsed as a link", app_type),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_app_reads_transfer_status_and_id() {
+        let transfer = "<msg><appmsg><type>2000</type><title>transfer</title><des></des>\
+            <wcpayinfo><paysubtype>3</paysubtype><feedesc>¥1.00</feedesc>\
+            <transcationid>t1</transcationid></wcpayinfo></appmsg></msg>";
+        match parse_app(transfer.to_string()) {
+            Ok(EnumAppMessage::Transfer(t)) => {
+                assert_eq!(t.transfer_id, "t1");
+                assert_eq!(t.status, TransferStatus::Accepted);
+                assert_eq!(t.fee, "¥1.00");
+            }
+            _ => panic!("transfer is not parsed"),
+        }
+    }
+
+    #[test]
+    fn parse_hint_tells_revokes_from_plain_hints() {
+        let revoke = "<sysmsg type=\"revokemsg\"><revokemsg><newmsgid>7</newmsgid>\
+            <replacemsg>\"friend\" recalled a message</replacemsg></revokemsg></sysmsg>";
+        let hint = parse_hint(revoke.to_string()).unwrap();
+        assert_eq!(hint.revoked_id, Some(7));
+        assert_eq!(hint.content, "\"friend\" recalled a message");
+
+        let hint = parse_hint("you are now friends".to_string()).unwrap();
+        assert_eq!(hint.revoked_id, None);
+        assert_eq!(hint.content, "you are now friends");
+    }
+
+    #[test]
+    fn parse_system_message_by_type() {
+        let texts = Locale::En.texts();
+
+        let plain = parse_system_message(texts, "friend joined".to_string()).unwrap();
+        assert_eq!(plain.content, "friend joined");
+        assert!(plain.raw.is_none());
+
+        let revoke = "<sysmsg type=\"revokemsg\"><revokemsg><newmsgid>7</newmsgid>\
+            <replacemsg>recalled</replacemsg></revokemsg></sysmsg>";
+        let sys_msg = parse_system_message(texts, revoke.to_string()).unwrap();
+        assert_eq!(sys_msg.revoked_id, Some(7));
+
+        let pat = "<sysmsg type=\"pat\"><pat><fromusername>wxid_a</fromusername>\
+            <chatusername>wxid_b</chatusername><pattedusername>wxid_b</pattedusername>\
+            <patsuffix>on the head</patsuffix></pat></sysmsg>";
+        let sys_msg = parse_system_message(texts, pat.to_string()).unwrap();
+        let pat = sys_msg.pat.unwrap();
+        assert_eq!(pat.from, "wxid_a");
+        assert_eq!(pat.patted, "wxid_b");
+        assert_eq!(pat.suffix, "on the head");
+
+        let unknown = "<sysmsg type=\"whatever\"></sysmsg>";
+        let sys_msg = parse_system_message(texts, unknown.to_string()).unwrap();
+        assert_eq!(sys_msg.content, texts.system_message);
+        assert_eq!(sys_msg.raw.as_deref(), Some(unknown));
+
+        assert!(parse_system_message(texts, String::new()).is_err());
+    }
+
+    #[test]
+    fn quoted_media_is_previewed_by_its_refer_type() {
+        let texts = Locale::En.texts();
+        let image_reply = "<msg><appmsg><type>57</type><title>nice photo</title><des></des>\
+            <refermsg><type>3</type><svrid>1234567890123</svrid><fromusr>wxid_friend</fromusr>\
+            <displayname>friend</displayname><content>&lt;msg&gt;&lt;img length=\"1\" /&gt;&lt;/msg&gt;</content>\
+            </refermsg></appmsg></msg>";
+        let reply = match parse_app(image_reply.to_string()) {
+            Ok(EnumAppMessage::Reply(r)) => r,
+            _ => panic!("image reply is not parsed"),
+        };
+        assert_eq!(reply.content, "nice photo");
+        assert_eq!(reply.refer_msg_id, 1234567890123);
+        assert_eq!(reply.user_sender.as_deref(), Some("wxid_friend"));
+        assert_eq!(
+            reply.refer_type.and_then(|t| quote_preview(texts, t)),
+            Some(texts.quote_image.to_string())
+        );
+
+        let file_reply = "<msg><appmsg><type>57</type><title>got it</title><des></des>\
+            <refermsg><type>49</type><svrid>9</svrid><chatusr>wxid_member</chatusr></refermsg>\
+            </appmsg></msg>";
+        let reply = match parse_app(file_reply.to_string()) {
+            Ok(EnumAppMessage::Reply(r)) => r,
+            _ => panic!("file reply is not parsed"),
+        };
+        assert_eq!(
+            reply.refer_type.and_then(|t| quote_preview(texts, t)),
+            Some(texts.quote_app.to_string())
+        );
+    }
+
+    #[test]
+    fn quoted_text_has_no_preview() {
+        let texts = Locale::Zh.texts();
+        assert_eq!(quote_preview(texts, WechatMessageType::Text as u32), None);
+        assert_eq!(recorded_preview(texts, EventType::Text), None);
+        assert_eq!(
+            recorded_preview(texts, EventType::Audio).as_deref(),
+            Some("[语音]")
+        );
+    }
+
+    // bubble of a private call as captured from the hook
+    fn voip_bubble(text: &str) -> String {
+        format!(
+            "<voipmsg type=\"VoIPBubbleMsg\"><VoIPBubbleMsg><msg><![CDATA[{}]]></msg>\
+            <room_type>1</room_type><red_dot>false</red_dot><roomid>1234567890</roomid>\
+            <roomkey>0</roomkey><inviteid>1690000000</inviteid><msg_type>100</msg_type>\
+            <timestamp>1690000000123</timestamp><identity><![CDATA[6512345678901234567]]></identity>\
+            <duration>0</duration><inviteid64>1690000000123</inviteid64><business>1</business>\
+            <caller_memberid>0</caller_memberid><callee_memberid>1</callee_memberid>\
+            </VoIPBubbleMsg></voipmsg>",
+            text
+        )
+    }
+
+    #[test]
+    fn parse_private_voip_tells_call_outcomes() {
+        let cases = [
+            ("未接听", VoipOutcome::Missed),
+            ("对方无应答", VoipOutcome::Missed),
+            ("Missed", VoipOutcome::Missed),
+            ("已拒绝", VoipOutcome::Declined),
+            ("Declined by callee", VoipOutcome::Declined),
+            ("已取消", VoipOutcome::Cancelled),
+            ("Canceled", VoipOutcome::Cancelled),
+        ];
+        for (text, outcome) in cases {
+            let voip = parse_private_voip(voip_bubble(text)).unwrap();
+            assert_eq!(voip.outcome, outcome, "{}", text);
+            assert_eq!(voip.duration, None);
+        }
+    }
+
+    #[test]
+    fn parse_private_voip_reads_durations() {
+        let texts = Locale::En.texts();
+        let voip = parse_private_voip(voip_bubble("通话时长 01:05")).unwrap();
+        assert_eq!(voip.outcome, VoipOutcome::Completed);
+        assert_eq!(voip.duration, Some(65));
+        assert_eq!(voip_content(texts, &voip), "VoIP: Call duration 01:05");
+
+        let voip = parse_private_voip(voip_bubble("Duration 1:02:03")).unwrap();
+        assert_eq!(voip.outcome, VoipOutcome::Completed);
+        assert_eq!(voip.duration, Some(3723));
+        assert_eq!(voip_content(texts, &voip), "VoIP: Call duration 1:02:03");
+    }
+
+    #[test]
+    fn parse_private_voip_keeps_unknown_bubbles_and_invites() {
+        let voip = parse_private_voip(voip_bubble("通话中断")).unwrap();
+        assert_eq!(voip.outcome, VoipOutcome::Unknown);
+        assert_eq!(voip.message.as_deref(), Some("通话中断"));
+
+        let invite = "<voipinvitemsg><roomid>1234567890</roomid><key>0</key>\
+            <status>1</status><invitetype>0</invitetype></voipinvitemsg>";
+        let voip = parse_private_voip(invite.to_string()).unwrap();
+        assert_eq!(voip.outcome, VoipOutcome::Started);
+
+        let invite = invite.replace("<status>1</status>", "<status>9</status>");
+        let voip = parse_private_voip(invite).unwrap();
+        assert_eq!(voip.outcome, VoipOutcome::Unknown);
+        assert_eq!(voip.status, Some(9));
+    }
+
+    // earlier events a replay reads, without a manager
+    struct Recorded(Vec<(u64, EventType)>);
+
+    impl EventLookup for Recorded {
+        fn recent_event(&self, id: u64) -> Option<RecentEvent> {
+            self.0
+                .iter()
+                .find(|(recorded, _)| *recorded == id)
+                .map(|(_, event_type)| RecentEvent {
+                    event_type: *event_type,
+                    sender: "wxid_friend".to_string(),
+                })
+        }
+
+        fn recent_transfer(&self, _: &str) -> Option<(u64, RecentEvent)> {
+            None
+        }
+    }
+
+    fn parse(
+        msg: WechatMessage,
+        recorded: &Recorded,
+    ) -> Option<(WebsocketEvent<MatrixMessageDataField>, Option<Pending>)> {
+        parse_wechat_event(
+            Locale::En.texts(),
+            None,
+            recorded,
+            "@alice:matrix".to_string(),
+            msg,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn media_is_typed_and_left_pending_without_fetching() {
+        let texts = Locale::En.texts();
+        let mut msg = callback(3, 0, "wxid_friend", "");
+        msg.file_path = "wxid_self\\FileStorage\\Image\\a.dat".to_string();
+        let (event, pending) = parse(msg, &Recorded(vec![])).unwrap();
+        assert_eq!(event.base.event_type, EventType::Image);
+        assert!(event.extra.is_none());
+        let pending = pending.unwrap();
+        assert!(
+            matches!(&pending, Pending::Image { file_path, .. } if file_path.ends_with("a.dat"))
+        );
+        assert_eq!(pending.placeholder(texts), texts.quote_image);
+
+        let mut duplicated = callback(3, 1, "wxid_friend", "");
+        duplicated.is_send_by_phone = Some(0);
+        assert!(parse(duplicated, &Recorded(vec![])).is_none());
+    }
+
+    #[test]
+    fn revoke_reads_the_type_of_the_recorded_event() {
+        let revoke = "<sysmsg type=\"revokemsg\"><revokemsg><newmsgid>7</newmsgid>\
+            <replacemsg>recalled</replacemsg></revokemsg></sysmsg>";
+        let (event, pending) = parse(
+            callback(10000, 0, "wxid_friend", revoke),
+            &Recorded(vec![(7, EventType::Video)]),
+        )
+        .unwrap();
+        assert!(pending.is_none());
+        assert_eq!(event.base.event_type, EventType::Revoke);
+        let revoke = event.base.revoke.unwrap();
+        assert_eq!(revoke.id, 7);
+        assert_eq!(revoke.event_type, Some(EventType::Video));
+    }
+
+    #[test]
+    fn pending_pat_names_self_and_others_by_wxid() {
+        let texts = Locale::En.texts();
+        let pat = "<sysmsg type=\"pat\"><pat><fromusername>wxid_friend</fromusername>\
+            <chatusername>wxid_friend</chatusername><pattedusername>wxid_self</pattedusername>\
+            <patsuffix>on the head</patsuffix></pat></sysmsg>";
+        let (event, pending) =
+            parse(callback(10002, 0, "wxid_friend", pat), &Recorded(vec![])).unwrap();
+        assert_eq!(event.base.event_type, EventType::Notice);
+        assert_eq!(
+            pending.unwrap().placeholder(texts),
+            "wxid_friend patted you on the head"
+        );
+    }
 }
@@ -1,136 +1,1188 @@
-extern crate crypto;
 extern crate dirs;
 
-use crypto::digest::Digest;
-use crypto::md5::Md5;
+use md5::{Digest, Md5};
+use rand::Rng;
 use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use sysinfo::{Pid, PidExt, ProcessExt, Signal, System, SystemExt};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    time::sleep,
 };
-use sysinfo::{ProcessExt, System, SystemExt};
-use tokio::{fs::File, time::sleep};
 
-use anyhow::bail;
 use log::{debug, error, info, warn};
 use serde::Serialize;
 use tokio::sync::broadcast::Sender;
+use unicode_normalization::UnicodeNormalization;
 
 use crate::constants;
+use crate::error::{Error, Result};
 
 pub async fn retriable_write<T: Serialize>(
     writer: Sender<String>,
     data: T,
     retry_time: Option<u8>,
-) -> anyhow::Result<()> {
+) -> Result<()> {
+    retriable_write_raw(writer, serde_json::to_string(&data)?, retry_time).await
+}
+
+pub async fn retriable_write_raw(
+    writer: Sender<String>,
+    data: String,
+    retry_time: Option<u8>,
+) -> Result<()> {
     let retry_time = retry_time.unwrap_or(constants::DEFAULT_WRITE_WS_RETRY_TIME);
-    let data = serde_json::to_string(&data)?;
     debug!("write message to channel: {}", data);
-    for _ in 0..retry_time {
+    let mut wait = Duration::from_millis(constants::DEFAULT_WRITE_WS_RETRY_DELAY_MS);
+    for i in 0..retry_time {
         match writer.send(data.clone()) {
-            Ok(_) => break,
+            Ok(_) => return Ok(()),
             Err(e) => {
                 error!("send message to channel failed: {}", e);
             }
         };
+        if i + 1 < retry_time {
+            warn!(
+                "write message to channel failed. will wait {} ms and retry",
+                wait.as_millis()
+            );
+            sleep(wait).await;
+            wait *= 2;
+        }
+    }
+
+    Err(Error::Delivery(format!(
+        "write message to channel with {} times failed",
+        retry_time
+    )))
+}
+
+/// read the whole file unless it is larger than max_size. Reading stops once the limit is crossed
+/// so that a file growing after the size check cannot exhaust memory either
+pub async fn read_file_limited(file: File, path: &Path, max_size: usize) -> Result<Vec<u8>> {
+    let len = file.metadata().await?.len();
+    if len > max_size as u64 {
+        return Err(Error::TooLarge(format!(
+            "{}: size {} exceeds max size {}",
+            path.display(),
+            len,
+            max_size
+        )));
     }
-    Ok(())
+
+    let mut buffer = Vec::with_capacity(len as usize);
+    file.take(max_size as u64 + 1)
+        .read_to_end(&mut buffer)
+        .await?;
+    if buffer.len() > max_size {
+        return Err(Error::TooLarge(format!(
+            "{}: body exceeds max size {}",
+            path.display(),
+            max_size
+        )));
+    }
+    Ok(buffer)
 }
 
+/// open the first existing file of filename_seq and return it with its path
 pub async fn retriable_open_file(
     filename_seq: Vec<PathBuf>,
     retry_time: u32,
-) -> anyhow::Result<File> {
-    let mut wait = Duration::from_secs(1);
+    base_delay: Duration,
+    wait_stable: bool,
+) -> Result<(File, PathBuf)> {
+    let mut wait = base_delay;
     for _ in 0..retry_time {
         for filename in &filename_seq {
             if let Ok(f) = File::open(filename).await {
-                return Ok(f);
+                // wechat may still be writing the file
+                if !wait_stable || is_file_size_stable(&f).await? {
+                    return Ok((f, filename.clone()));
+                }
+                warn!("file {} is still growing", filename.display());
+                break;
             }
         }
+        let delay = with_jitter(wait);
         warn!(
-            "open file failed. will wait {} seconds and retry",
-            wait.as_secs()
+            "open file failed. will wait {} ms and retry",
+            delay.as_millis()
         );
-        sleep(wait).await;
+        sleep(delay).await;
         wait *= 2;
     }
 
-    bail!(
-        "open file {:?} with {} times failed",
-        filename_seq,
-        retry_time
-    )
+    Err(Error::NotFound(format!(
+        "file {:?} after {} attempts",
+        filename_seq, retry_time
+    )))
+}
+
+///
+/// open the first existing file of candidates once it is completely written and return it with its
+/// path. It is complete when it reaches the expected size, or does not grow within stable_window
+/// if the size is unknown. Fail if it is still missing or incomplete after timeout
+///
+pub async fn wait_file_complete(
+    candidates: &[PathBuf],
+    expected_size: Option<u64>,
+    stable_window: Duration,
+    timeout: Duration,
+) -> Result<(File, PathBuf)> {
+    let start = Instant::now();
+    // (path, size, since when the size is unchanged)
+    let mut last: Option<(PathBuf, u64, Instant)> = None;
+    loop {
+        if let Some((f, path)) = open_first(candidates).await {
+            let len = f.metadata().await?.len();
+            let stable_since = match &last {
+                Some((p, l, since)) if *p == path && *l == len => *since,
+                _ => Instant::now(),
+            };
+            let complete = match expected_size {
+                Some(size) => len >= size,
+                None => len > 0 && stable_since.elapsed() >= stable_window,
+            };
+            if complete {
+                if start.elapsed() > Duration::from_secs(1) {
+                    info!(
+                        "file {} is complete after {:?}",
+                        path.display(),
+                        start.elapsed()
+                    );
+                }
+                return Ok((f, path));
+            }
+            last = Some((path, len, stable_since));
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(match last {
+                Some((path, len, _)) => Error::Incomplete(format!(
+                    "{} after {:?}: {} of {} bytes",
+                    path.display(),
+                    timeout,
+                    len,
+                    expected_size.map_or("unknown".to_string(), |s| s.to_string())
+                )),
+                None => Error::NotFound(format!("file {:?} within {:?}", candidates, timeout)),
+            });
+        }
+        debug!(
+            "wait for file {:?} to complete. size = {:?}",
+            candidates,
+            last.as_ref().map(|(_, len, _)| len)
+        );
+        sleep(Duration::from_millis(
+            constants::FILE_STABLE_PROBE_INTERVAL_MS,
+        ))
+        .await;
+    }
+}
+
+async fn open_first(candidates: &[PathBuf]) -> Option<(File, PathBuf)> {
+    for path in candidates {
+        if let Ok(f) = File::open(path).await {
+            return Some((f, path.clone()));
+        }
+    }
+    None
+}
+
+async fn is_file_size_stable(file: &File) -> Result<bool> {
+    let before = file.metadata().await?.len();
+    sleep(Duration::from_millis(
+        constants::FILE_STABLE_PROBE_INTERVAL_MS,
+    ))
+    .await;
+    let after = file.metadata().await?.len();
+    Ok(before == after && after > 0)
+}
+
+// add up to 50% random delay to avoid retrying in lockstep
+fn with_jitter(delay: Duration) -> Duration {
+    let max_jitter = delay.as_millis() as u64 / 2;
+    if max_jitter == 0 {
+        return delay;
+    }
+    delay + Duration::from_millis(rand::thread_rng().gen_range(0..=max_jitter))
+}
+
+// windows reserves the superscript digits of COM and LPT as well
+const WINDOWS_RESERVED_NAMES: [&str; 28] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "COM¹", "COM²", "COM³", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8",
+    "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+///
+/// make a string provided by wechat hook safe to be used as a single path component.
+/// separators, control chars and chars forbidden by windows are replaced with `_`,
+/// and reserved windows device names are prefixed with `_`. The name is normalized to NFC, so
+/// that a name composed or decomposed, like é or e + U+0301, maps to the same file
+///
+pub fn sanitize_component(name: &str) -> String {
+    let mut component: String = name
+        .nfc()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // windows silently drops trailing dots and spaces
+    component = component.trim_end_matches(['.', ' ']).to_string();
+    if component.is_empty() {
+        return "_".to_string();
+    }
+
+    // windows drops the spaces before the extension too, so `CON .txt` is CON
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        component = format!("_{}", component);
+    }
+
+    truncate_on_char_boundary(&mut component, constants::MAX_PATH_COMPONENT_LEN);
+    component
+}
+
+/// raw payload echoed in an error, truncated to MAX_ERROR_PAYLOAD_LEN with "..." appended
+pub fn truncate_for_error(raw: &str) -> String {
+    let mut payload = raw.to_string();
+    if truncate_on_char_boundary(&mut payload, constants::MAX_ERROR_PAYLOAD_LEN) {
+        payload.push_str("...");
+    }
+    payload
+}
+
+// true if text is longer than max bytes and truncated
+fn truncate_on_char_boundary(text: &mut String, max: usize) -> bool {
+    if text.len() <= max {
+        return false;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    true
+}
+
+pub fn is_valid_wxid(wxid: &str) -> bool {
+    !wxid.is_empty()
+        && wxid.len() <= constants::MAX_WXID_LEN
+        && wxid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '@' | '.'))
+}
+
+/// kind of a wechat id, told by its suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdKind {
+    User,
+    Group,
+    Enterprise,
+}
+
+pub fn classify_id(wxid: &str) -> IdKind {
+    match wxid {
+        id if id.ends_with("@chatroom") => IdKind::Group,
+        id if id.ends_with("@openim") => IdKind::Enterprise,
+        _ => IdKind::User,
+    }
+}
+
+/// quote wxid as a sqlite string literal. wxid is limited to a conservative character set so that
+/// the request cannot break out of the literal even if the escaping is bypassed
+pub fn sql_quote_wxid(wxid: &str) -> Result<String> {
+    if !is_valid_wxid(wxid) {
+        return Err(Error::InvalidRequest(format!("invalid wxid {:?}", wxid)));
+    }
+    Ok(format!("'{}'", wxid.replace('\'', "''")))
+}
+
+pub fn is_http_url(s: &str) -> bool {
+    match url::Url::parse(s) {
+        Ok(u) => matches!(u.scheme(), "http" | "https") && u.host().is_some(),
+        Err(_) => false,
+    }
+}
+
+/// outbound media url must be http(s) and hosted by one of allowed hosts or their subdomains.
+/// any host is allowed if allowed_hosts is empty
+pub fn check_media_url(s: &str, allowed_hosts: &[String]) -> Result<()> {
+    let host = match url::Url::parse(s) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") => u.host_str().map(|h| h.to_lowercase()),
+        _ => None,
+    };
+    let host = match host {
+        Some(host) => host,
+        None => {
+            return Err(Error::InvalidRequest(format!(
+                "media url {} is not http(s)",
+                s
+            )))
+        }
+    };
+    if allowed_hosts.is_empty() {
+        return Ok(());
+    }
+    let allowed = allowed_hosts.iter().any(|h| {
+        let h = h.to_lowercase();
+        host == h || host.ends_with(&format!(".{}", h))
+    });
+    match allowed {
+        true => Ok(()),
+        false => Err(Error::InvalidRequest(format!(
+            "media host {} is not allowed",
+            host
+        ))),
+    }
+}
+
+/// check whether urls are reachable by HEAD requests. Results are cached for a while
+/// since avatars are queried again and again
+#[derive(Clone, Debug)]
+pub struct UrlChecker {
+    client: GuardedClient,
+    cache: Arc<Mutex<HashMap<String, (bool, Instant)>>>,
 }
 
-pub fn get_filename(path: &Path) -> anyhow::Result<String> {
+impl UrlChecker {
+    pub fn new(client: GuardedClient) -> UrlChecker {
+        UrlChecker {
+            client,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn is_alive(&self, url: &str) -> bool {
+        let ttl = Duration::from_secs(constants::URL_CHECK_CACHE_SECS);
+        if let Ok(cache) = self.cache.lock() {
+            if let Some((alive, checked_at)) = cache.get(url) {
+                if checked_at.elapsed() < ttl {
+                    return *alive;
+                }
+            }
+        }
+
+        let req = self
+            .client
+            .0
+            .head(url)
+            .timeout(Duration::from_secs(constants::URL_CHECK_TIMEOUT_SECS));
+        let alive = match guarded_send(url, req).await {
+            Ok(resp) => resp.status().is_success(),
+            Err(e) => {
+                debug!("check url {} failed: {}", url, e);
+                false
+            }
+        };
+
+        if let Ok(mut cache) = self.cache.lock() {
+            if cache.len() >= constants::MAX_URL_CHECK_CACHE_SIZE {
+                cache.retain(|_, (_, checked_at)| checked_at.elapsed() < ttl);
+            }
+            if cache.len() < constants::MAX_URL_CHECK_CACHE_SIZE {
+                cache.insert(url.to_string(), (alive, Instant::now()));
+            }
+        }
+        alive
+    }
+}
+
+pub fn get_filename(path: &Path) -> Result<String> {
     match path.file_name() {
         Some(fs) => match fs.to_str() {
             Some(f) => Ok(f.to_string()),
-            None => {
-                bail!("file_path[{}] contains invalid UTF8 char", path.display())
-            }
+            None => Err(Error::Parse(format!(
+                "file_path[{}] contains invalid UTF8 char",
+                path.display()
+            ))),
         },
-        None => bail!(
+        None => Err(Error::Parse(format!(
             "file_path[{}] does not contain a filename and extensions",
             path.display()
-        ),
+        ))),
+    }
+}
+
+pub struct DownloadOptions {
+    pub max_size: usize,
+    // expected content-type prefix like "image/". untyped binary response is always accepted
+    pub content_type: Option<&'static str>,
+}
+
+/// http client for untrusted urls, like media urls in messages. It only connects to public
+/// addresses: host names are resolved to public addresses only, and the connection is made to the
+/// addresses checked, while ip hosts are checked for the url and every redirect
+#[derive(Clone, Debug)]
+pub struct GuardedClient(reqwest::Client);
+
+impl GuardedClient {
+    pub fn new(builder: reqwest::ClientBuilder) -> Result<GuardedClient> {
+        let client = builder
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= constants::MAX_DOWNLOAD_REDIRECTS {
+                    return attempt.error("too many redirects");
+                }
+                match ensure_public_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            }))
+            // a proxy would resolve the host instead
+            .no_proxy()
+            .build()?;
+        Ok(GuardedClient(client))
     }
 }
 
-pub async fn get_file_maybe_gzip_decompress(url: String) -> anyhow::Result<Vec<u8>> {
-    let client = reqwest::Client::builder()
-        .user_agent(constants::USER_AGENT)
-        .gzip(true)
-        .build()?;
-    let resp = client.get(url).send().await?;
-    Ok(Vec::from(resp.bytes().await?))
+// resolver of GuardedClient which fails names resolved to any non-public address
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs = tokio::net::lookup_host((host, 0))
+                .await?
+                .collect::<Vec<SocketAddr>>();
+            if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+                return Err(Error::InvalidRequest(format!(
+                    "{} resolves to non-public address {}",
+                    host,
+                    addr.ip()
+                ))
+                .into());
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// send request built by GuardedClient
+async fn guarded_send(url: &str, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    match url::Url::parse(url) {
+        Ok(u) => ensure_public_url(&u)?,
+        Err(e) => return Err(Error::InvalidRequest(format!("{}: {}", url, e))),
+    }
+    req.send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| match rejected_address(&e) {
+            Some(reason) => Error::InvalidRequest(format!("{}: {}", url, reason)),
+            None => Error::Download(format!("{}: {}", url, e)),
+        })
+}
+
+// the scheme must be http(s). Host names are checked when they are resolved
+fn ensure_public_url(u: &url::Url) -> Result<()> {
+    if !matches!(u.scheme(), "http" | "https") {
+        return Err(Error::InvalidRequest(format!("{} is not http(s)", u)));
+    }
+    let ip = match u.host() {
+        Some(url::Host::Domain(_)) => return Ok(()),
+        Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+        None => return Err(Error::InvalidRequest(format!("{} has no host", u))),
+    };
+    match is_public_ip(ip) {
+        true => Ok(()),
+        false => Err(Error::InvalidRequest(format!(
+            "{} is a non-public address",
+            ip
+        ))),
+    }
+}
+
+// why the resolver or the redirect policy refuses the request, which is wrapped in reqwest errors
+fn rejected_address(e: &reqwest::Error) -> Option<String> {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if let Some(Error::InvalidRequest(reason)) = err.downcast_ref::<Error>() {
+            return Some(reason.clone());
+        }
+        source = err.source();
+    }
+    None
+}
+
+// loopback, private, link-local and other special-purpose addresses are not public
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                // shared address space 100.64.0.0/10
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                || octets[0] == 0)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let segment = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // unique local fc00::/7
+                || (segment & 0xfe00) == 0xfc00
+                // link local fe80::/10
+                || (segment & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+pub async fn get_file_maybe_gzip_decompress(
+    client: &GuardedClient,
+    url: String,
+    options: DownloadOptions,
+) -> Result<Vec<u8>> {
+    let req = client
+        .0
+        .get(url.clone())
+        // downloading media takes much longer than calling hook api
+        .timeout(Duration::from_secs(constants::DOWNLOAD_TIMEOUT_SECS));
+    let resp = guarded_send(&url, req).await?;
+    read_body(resp, &url, &options).await
+}
+
+/// response of a conditional GET. body is None if the server replies 304 Not Modified
+pub struct ConditionalResponse {
+    pub body: Option<Vec<u8>>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// GET url with If-None-Match and If-Modified-Since built from validators of a cached copy
+pub async fn get_file_conditional(
+    client: &GuardedClient,
+    url: &str,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+    options: DownloadOptions,
+) -> Result<ConditionalResponse> {
+    let mut req = client
+        .0
+        .get(url)
+        .timeout(Duration::from_secs(constants::DOWNLOAD_TIMEOUT_SECS));
+    if let Some(etag) = etag {
+        req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let resp = guarded_send(url, req).await?;
+
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+    let body = match resp.status() {
+        reqwest::StatusCode::NOT_MODIFIED => None,
+        _ => Some(read_body(resp, url, &options).await?),
+    };
+    Ok(ConditionalResponse {
+        body,
+        etag,
+        last_modified,
+    })
+}
+
+// check content-type and size of response, then read the body into memory
+async fn read_body(
+    mut resp: reqwest::Response,
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>> {
+    if let Some(expected) = options.content_type {
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !content_type.is_empty()
+            && !content_type.starts_with("application/octet-stream")
+            && !content_type.starts_with(expected)
+        {
+            return Err(Error::Download(format!(
+                "{}: content-type {} mismatches {}",
+                url, content_type, expected
+            )));
+        }
+    }
+
+    if let Some(len) = resp.content_length() {
+        if len > options.max_size as u64 {
+            return Err(Error::TooLarge(format!(
+                "{}: content-length {} exceeds max size {}",
+                url, len, options.max_size
+            )));
+        }
+    }
+
+    let mut buffer = Vec::new();
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| Error::Download(format!("{}: {}", url, e)))?
+    {
+        if buffer.len() + chunk.len() > options.max_size {
+            return Err(Error::TooLarge(format!(
+                "{}: body exceeds max size {}",
+                url, options.max_size
+            )));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer)
+}
+
+/// download url into part_path chunk by chunk. An interrupted download is resumed with a Range request
+/// if the server supports it, otherwise it is restarted from scratch. Returns the md5 of the file after
+/// the size is verified against the total size announced by server
+pub async fn download_resumable(
+    client: &GuardedClient,
+    url: &str,
+    part_path: &Path,
+    options: DownloadOptions,
+    max_attempts: u8,
+) -> Result<String> {
+    // the part file may be left by a crashed run whose pid is reused
+    if part_path.exists() {
+        tokio::fs::remove_file(part_path).await?;
+    }
+
+    let mut wait = Duration::from_secs(1);
+    let mut last_err = None;
+    for attempt in 1..=max_attempts {
+        match download_range(client, url, part_path, &options).await {
+            Ok(()) => {
+                let mut file = File::open(part_path).await?;
+                return calculate_md5_from_reader(&mut file).await;
+            }
+            // the download would never succeed by retrying if it is too large or the type mismatches
+            Err(e) if !e.is_retriable() => return Err(e),
+            Err(e) => {
+                warn!(
+                    "download {} failed: {}. attempt {}/{}",
+                    url, e, attempt, max_attempts
+                );
+                last_err = Some(e);
+            }
+        }
+        if attempt < max_attempts {
+            sleep(with_jitter(wait)).await;
+            wait *= 2;
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| Error::Download(format!("{}: no attempt is made", url))))
+}
+
+// download the remaining bytes after the current size of part_path
+async fn download_range(
+    client: &GuardedClient,
+    url: &str,
+    part_path: &Path,
+    options: &DownloadOptions,
+) -> Result<()> {
+    let offset = match tokio::fs::metadata(part_path).await {
+        Ok(m) => m.len(),
+        Err(_) => 0,
+    };
+
+    let mut req = client
+        .0
+        .get(url)
+        .timeout(Duration::from_secs(constants::DOWNLOAD_TIMEOUT_SECS))
+        // compressed body cannot be resumed by byte offset
+        .header(reqwest::header::ACCEPT_ENCODING, "identity");
+    if offset > 0 {
+        req = req.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut resp = guarded_send(url, req).await?;
+
+    // server without Range support returns the whole body with 200
+    let resumed = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let total = match resumed {
+        true => resp
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse::<u64>().ok()),
+        false => resp.content_length(),
+    };
+    if offset > 0 && !resumed {
+        debug!("{} does not support Range, restart downloading", url);
+    }
+
+    if let Some(total) = total {
+        if total > options.max_size as u64 {
+            return Err(Error::TooLarge(format!(
+                "{}: size {} exceeds max size {}",
+                url, total, options.max_size
+            )));
+        }
+    }
+
+    let mut file = match resumed {
+        true => {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(part_path)
+                .await?
+        }
+        false => File::create(part_path).await?,
+    };
+    let mut written = if resumed { offset } else { 0 };
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| Error::Download(format!("{}: {}", url, e)))?
+    {
+        written += chunk.len() as u64;
+        if written > options.max_size as u64 {
+            tokio::fs::remove_file(part_path).await?;
+            return Err(Error::TooLarge(format!(
+                "{}: body exceeds max size {}",
+                url, options.max_size
+            )));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    match total {
+        Some(total) if total != written => Err(Error::Download(format!(
+            "{}: size mismatched. want {} but get {}",
+            url, total, written
+        ))),
+        _ => Ok(()),
+    }
 }
 
 pub fn calculate_md5(blob: &[u8]) -> String {
+    to_upper_hex(&Md5::digest(blob))
+}
+
+/// calculate md5 of a large file chunk by chunk instead of loading it into memory
+pub async fn calculate_md5_from_reader<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
     let mut hasher = Md5::new();
-    hasher.input(blob);
-    let mut output = [0; 16]; // An MD5 is 16 bytes
-    hasher.result(&mut output);
-    output
-        .into_iter()
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(to_upper_hex(&hasher.finalize()))
+}
+
+// keep the uppercase hex format so that media filenames generated before are still matched
+fn to_upper_hex(digest: &[u8]) -> String {
+    digest
+        .iter()
         .map(|i| format!("{:02X}", i))
         .collect::<Vec<String>>()
         .join("")
 }
 
-pub fn get_wechat_document_dir() -> anyhow::Result<PathBuf> {
+pub fn get_wechat_document_dir() -> Result<PathBuf> {
     let basedir = match dirs::document_dir() {
         Some(d) => d,
-        None => bail!("get current user document dir failed"),
+        None => {
+            return Err(Error::Internal(
+                "get current user document dir failed".to_string(),
+            ))
+        }
     };
 
-    #[cfg(any(target_os = "windows"))]
+    #[cfg(target_os = "windows")]
     let basedir = get_wechat_document_dir_from_win_reg().unwrap_or(basedir);
 
     Ok(basedir.join("WeChat Files"))
 }
 
-#[cfg(any(target_os = "windows"))]
-pub fn get_wechat_document_dir_from_win_reg() -> anyhow::Result<PathBuf> {
+/// version of installed wechat like 3.7.0.30. It is read from registry on windows
+pub fn get_wechat_version() -> Result<String> {
+    #[cfg(target_os = "windows")]
+    {
+        get_wechat_version_from_win_reg()
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        Err(Error::NotFound("wechat version".to_string()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn get_wechat_version_from_win_reg() -> Result<String> {
+    use winreg::enums::*;
+    use winreg::RegKey;
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let cur_ver = hkcu.open_subkey("SOFTWARE\\Tencent\\WeChat")?;
+    // e.g. 0x63070030 is 3.7.0.48. the highest 4 bits are flags
+    let v: u32 = cur_ver.get_value("Version")?;
+    Ok(format!(
+        "{}.{}.{}.{}",
+        (v >> 24) & 0x0F,
+        (v >> 16) & 0xFF,
+        (v >> 8) & 0xFF,
+        v & 0xFF
+    ))
+}
+
+///
+/// resolve a path reported by wechat hook. The path may be relative to `WeChat Files`
+/// or to the account dir `WeChat Files/<wxid>`. All account dirs are scanned as fallback
+///
+pub fn resolve_wechat_file(files_dir: &Path, self_id: &str, file_path: &Path) -> Result<PathBuf> {
+    let mut candidates = vec![
+        files_dir.join(file_path),
+        files_dir.join(self_id).join(file_path),
+    ];
+    if let Ok(entries) = std::fs::read_dir(files_dir) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                candidates.push(entry.path().join(file_path));
+            }
+        }
+    }
+
+    match candidates.iter().find(|p| p.exists()) {
+        Some(p) => Ok(p.clone()),
+        None => Err(Error::NotFound(format!(
+            "file {} in {:?}",
+            file_path.display(),
+            candidates
+        ))),
+    }
+}
+
+///
+/// decode an image wechat stores as .dat, which is xored with a single byte key, and return it
+/// with its extension. The key is found by the magic number of the image format
+///
+pub fn decode_dat_image(mut data: Vec<u8>) -> Result<(Vec<u8>, &'static str)> {
+    const MAGICS: [(&[u8], &str); 3] = [
+        (&[0xFF, 0xD8, 0xFF], "jpg"),
+        (&[0x89, 0x50, 0x4E, 0x47], "png"),
+        (&[0x47, 0x49, 0x46, 0x38], "gif"),
+    ];
+
+    let first = match data.first() {
+        Some(b) => *b,
+        None => return Err(Error::Parse("empty image file".to_string())),
+    };
+    for (magic, ext) in MAGICS {
+        let key = first ^ magic[0];
+        if data.len() >= magic.len() && data.iter().zip(magic).all(|(b, m)| b ^ key == *m) {
+            data.iter_mut().for_each(|b| *b ^= key);
+            return Ok((data, ext));
+        }
+    }
+    Err(Error::Unsupported(
+        "format of the encrypted image".to_string(),
+    ))
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_wechat_document_dir_from_win_reg() -> Result<PathBuf> {
     use winreg::enums::*;
     use winreg::RegKey;
     let hkcu = RegKey::predef(HKEY_CURRENT_USER);
     let cur_ver = hkcu.open_subkey("SOFTWARE\\Tencent\\WeChat")?;
     let fsp: String = cur_ver.get_value("FileSavePath")?;
-    if fsp != "MyDocument:" && fsp != "" {
+    if fsp != "MyDocument:" && !fsp.is_empty() {
         return Ok(Path::new(&fsp).to_path_buf());
     }
     error!("get wechat FileSavePath from registry key failed");
-    bail!("get wechat FileSavePath from registry key failed")
+    Err(Error::Internal(
+        "get wechat FileSavePath from registry key failed".to_string(),
+    ))
 }
 
-pub fn kill_by_name(name: &str) {
+///
+/// kill processes of `pids` whose name is exactly `name` (or `name.exe`) and wait until they exit.
+/// pids reused by other processes are skipped. processes still alive after timeout are killed
+/// forcefully. return pids which are terminated
+///
+pub async fn kill_pids(pids: &[u32], name: &str) -> Vec<u32> {
     let mut s = System::new_all();
     s.refresh_processes();
-    for p in s.processes_by_name(name) {
-        match p.kill() {
-            true => info!("kill process {} successfully", p.name()),
-            false => warn!("kill process {} failed, please manually kill it", p.name()),
-        };
+    let pids: Vec<Pid> = pids
+        .iter()
+        .filter_map(|pid| s.process(Pid::from_u32(*pid)))
+        .filter(|p| is_process_name(p.name(), name))
+        .map(|p| {
+            match p.kill_with(Signal::Term).unwrap_or_else(|| p.kill()) {
+                true => info!("send kill signal to process {} successfully", p.name()),
+                false => warn!("send kill signal to process {} failed", p.name()),
+            };
+            p.pid()
+        })
+        .collect();
+
+    let timeout = Duration::from_secs(constants::KILL_PROCESS_TIMEOUT_SECS);
+    let mut deadline = Instant::now() + timeout;
+    let mut forced = false;
+    loop {
+        s.refresh_processes();
+        let alive: Vec<Pid> = pids
+            .iter()
+            .filter(|pid| s.process(**pid).is_some())
+            .cloned()
+            .collect();
+        if alive.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            if forced {
+                warn!(
+                    "processes {:?} are still alive, please manually kill them",
+                    alive
+                );
+                break;
+            }
+            for pid in &alive {
+                if let Some(p) = s.process(*pid) {
+                    warn!("process {} does not exit in time, kill it forcefully", pid);
+                    p.kill();
+                }
+            }
+            forced = true;
+            deadline = Instant::now() + timeout;
+            continue;
+        }
+        sleep(Duration::from_millis(200)).await;
+    }
+
+    pids.into_iter()
+        .filter(|pid| s.process(*pid).is_none())
+        .map(|pid| pid.as_u32())
+        .collect()
+}
+
+fn is_process_name(process_name: &str, name: &str) -> bool {
+    process_name.eq_ignore_ascii_case(name)
+        || process_name.eq_ignore_ascii_case(&format!("{}.exe", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    #[tokio::test]
+    async fn retriable_write_delivers_to_receiver() {
+        let (tx, mut rx) = broadcast::channel(4);
+        retriable_write_raw(tx, "event".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "event");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retriable_write_backs_off_and_reports_failure() {
+        // a broadcast channel without receivers refuses every send
+        let (tx, rx) = broadcast::channel::<String>(4);
+        drop(rx);
+
+        let started = tokio::time::Instant::now();
+        let err = retriable_write_raw(tx, "event".to_string(), Some(3))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Delivery(_)));
+        // waits 200 ms and 400 ms between the 3 attempts, but not after the last one
+        let delay = constants::DEFAULT_WRITE_WS_RETRY_DELAY_MS;
+        assert_eq!(started.elapsed(), Duration::from_millis(delay + delay * 2));
+    }
+
+    #[test]
+    fn sanitize_component_replaces_separators_and_forbidden_chars() {
+        assert_eq!(sanitize_component("../../etc/passwd"), ".._.._etc_passwd");
+        assert_eq!(sanitize_component("C:\\Windows\\a.dll"), "C__Windows_a.dll");
+        assert_eq!(sanitize_component("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize_component("line\nbreak\0"), "line_break_");
+        assert_eq!(sanitize_component("图片.jpg"), "图片.jpg");
+    }
+
+    #[test]
+    fn sanitize_component_never_yields_dot_dirs_or_empty_names() {
+        assert_eq!(sanitize_component(""), "_");
+        assert_eq!(sanitize_component(".."), "_");
+        assert_eq!(sanitize_component("name. . "), "name");
+    }
+
+    #[test]
+    fn sanitize_component_prefixes_reserved_windows_names() {
+        assert_eq!(sanitize_component("con"), "_con");
+        assert_eq!(sanitize_component("NUL.txt"), "_NUL.txt");
+        assert_eq!(sanitize_component("console.txt"), "console.txt");
+        assert_eq!(sanitize_component("CON .txt"), "_CON .txt");
+        assert_eq!(sanitize_component("com¹"), "_com¹");
+        assert_eq!(sanitize_component("LPT².log"), "_LPT².log");
+        assert_eq!(sanitize_component("COM⁴"), "COM⁴");
+    }
+
+    #[test]
+    fn sanitize_component_maps_composed_and_decomposed_names_to_nfc() {
+        let composed = "caf\u{e9}.jpg";
+        let decomposed = "cafe\u{301}.jpg";
+        assert_eq!(sanitize_component(decomposed), composed);
+        assert_eq!(sanitize_component(composed), composed);
+    }
+
+    #[test]
+    fn sanitize_component_truncates_on_char_boundary() {
+        let long = "微".repeat(constants::MAX_PATH_COMPONENT_LEN);
+        let component = sanitize_component(&long);
+        assert!(component.len() <= constants::MAX_PATH_COMPONENT_LEN);
+        assert!(component.chars().all(|c| c == '微'));
+    }
+
+    #[test]
+    fn truncate_for_error_keeps_short_payloads_and_cuts_long_ones_on_char_boundary() {
+        assert_eq!(truncate_for_error("short"), "short");
+
+        let exact = "a".repeat(constants::MAX_ERROR_PAYLOAD_LEN);
+        assert_eq!(truncate_for_error(&exact), exact);
+
+        let long = format!("a{}", "微".repeat(constants::MAX_ERROR_PAYLOAD_LEN));
+        let payload = truncate_for_error(&long);
+        let cut = payload.strip_suffix("...").unwrap();
+        assert!(cut.len() <= constants::MAX_ERROR_PAYLOAD_LEN);
+        assert!(long.starts_with(cut));
+    }
+
+    #[test]
+    fn md5_is_uppercase_hex() {
+        assert_eq!(calculate_md5(b"abc"), "900150983CD24FB0D6963F7D28E17F72");
+        assert_eq!(calculate_md5(b""), "D41D8CD98F00B204E9800998ECF8427E");
+    }
+
+    #[tokio::test]
+    async fn streamed_md5_matches_in_memory_md5() {
+        // larger than the read buffer, so that it is hashed in several chunks
+        let blob = (0..200 * 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut reader = blob.as_slice();
+        assert_eq!(
+            calculate_md5_from_reader(&mut reader).await.unwrap(),
+            calculate_md5(&blob)
+        );
+    }
+
+    #[test]
+    fn sql_quote_wxid_quotes_valid_ids() {
+        assert_eq!(sql_quote_wxid("wxid_abc-1").unwrap(), "'wxid_abc-1'");
+        assert_eq!(
+            sql_quote_wxid("12345@chatroom").unwrap(),
+            "'12345@chatroom'"
+        );
+        assert_eq!(sql_quote_wxid("a.b@openim").unwrap(), "'a.b@openim'");
+    }
+
+    #[test]
+    fn ids_are_classified_by_suffix() {
+        assert_eq!(classify_id("wxid_a"), IdKind::User);
+        assert_eq!(classify_id("12345@chatroom"), IdKind::Group);
+        assert_eq!(classify_id("1688850000000000@openim"), IdKind::Enterprise);
+        assert_eq!(classify_id("chatroom"), IdKind::User);
+    }
+
+    #[test]
+    fn sql_quote_wxid_rejects_ids_breaking_out_of_the_literal() {
+        for wxid in [
+            "",
+            "x' OR '1'='1",
+            "x\"; DROP TABLE Contact; --",
+            "wxid with space",
+            "wxid\0",
+        ] {
+            assert!(
+                matches!(sql_quote_wxid(wxid), Err(Error::InvalidRequest(_))),
+                "{:?}",
+                wxid
+            );
+        }
+        let long = "a".repeat(constants::MAX_WXID_LEN + 1);
+        assert!(sql_quote_wxid(&long).is_err());
+    }
+
+    #[test]
+    fn is_public_ip_rejects_special_purpose_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn ensure_public_url_checks_scheme_and_ip_literals() {
+        let check = |u: &str| ensure_public_url(&url::Url::parse(u).unwrap());
+        assert!(check("https://example.com/a.png").is_ok());
+        assert!(check("http://1.1.1.1/a.png").is_ok());
+        assert!(check("file:///etc/passwd").is_err());
+        assert!(check("http://127.0.0.1:8080/").is_err());
+        assert!(check("http://[::1]/").is_err());
+    }
+
+    #[tokio::test]
+    async fn guarded_client_refuses_names_resolving_to_private_addresses() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let client = GuardedClient::new(reqwest::Client::builder()).unwrap();
+        let err = guarded_send(&url, client.0.get(&url)).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidRequest(_)), "{:?}", err);
     }
 }
@@ -1,24 +1,44 @@
-use anyhow::bail;
+mod compat;
+mod info_cache;
+mod roomdata;
+
 use bytes::Bytes;
 use serde_repr::Deserialize_repr;
 
-use chrono::{serde::ts_seconds, DateTime, Utc};
-use std::{collections::HashMap, os::raw::c_int, path::Path, time::Duration, vec};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    os::raw::c_int,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+    vec,
+};
 use sysinfo::{Pid, PidExt, ProcessExt, ProcessStatus, System, SystemExt};
 
-use log::{error, info};
+use log::{debug, error, info, warn};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::{fs::File, io::AsyncWriteExt, time::sleep};
+use tokio::time::sleep;
 
+use self::info_cache::InfoCache;
 use crate::{
     constants::{self, wechat_api},
-    utils,
+    error::{Error, Result},
+    utils::{self, classify_id, IdKind},
     ws::{
         recv::{MatrixMessageType, MatrixRequestDataMessage},
-        MatrixMessageDataField, MatrixMessageDataMedia,
+        send::BatchResult,
+        AppSubtype, MatrixMessageDataBlob, MatrixMessageDataContact, MatrixMessageDataField,
+        MatrixMessageDataMedia,
     },
 };
 
+// numbers part files of outbound media so that concurrent sends of the same media never share one
+static PART_FILE_SEQ: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug)]
 pub struct WechatInstance {
     pub port: u32,
@@ -26,7 +46,27 @@ pub struct WechatInstance {
     pub save_path: String,
     pub pid: u32,
     pub client: reqwest::Client,
+    // for media urls sent by the bridge
+    media_client: utils::GuardedClient,
     pub mxid: String,
+    // verify avatar urls by HEAD requests if it is set
+    pub avatar_checker: Option<utils::UrlChecker>,
+    // db name -> handle. wechat reopens databases after relogin, therefore, it is refreshed when a query fails
+    db_handles: Arc<RwLock<HashMap<String, i64>>>,
+    // logical db name like MicroMsg.db -> names wechat versions may open it with, in preference order
+    db_names: Arc<HashMap<String, Vec<String>>>,
+    db_handle_cache_hits: Arc<AtomicU64>,
+    db_handle_cache_misses: Arc<AtomicU64>,
+    // version of hooked wechat like 3.7.0.30. None if it cannot be detected
+    pub version: Option<String>,
+    // user and group infos read recently. None disables it
+    info_cache: Option<Arc<InfoCache>>,
+    // wxid of the logged in account. It is cached by get_self and cleared by logout
+    self_wxid: Arc<RwLock<Option<String>>>,
+    // held while the refused hook api is being recovered
+    hook_recovery: Arc<tokio::sync::Mutex<()>>,
+    // hook api calls and sql queries slower than it are logged. None disables it
+    pub slow_threshold: Option<Duration>,
 }
 
 impl Clone for WechatInstance {
@@ -37,7 +77,18 @@ impl Clone for WechatInstance {
             save_path: self.save_path.clone(),
             pid: self.pid,
             client: self.client.clone(),
+            media_client: self.media_client.clone(),
             mxid: self.mxid.clone(),
+            avatar_checker: self.avatar_checker.clone(),
+            db_handles: self.db_handles.clone(),
+            db_names: self.db_names.clone(),
+            db_handle_cache_hits: self.db_handle_cache_hits.clone(),
+            db_handle_cache_misses: self.db_handle_cache_misses.clone(),
+            version: self.version.clone(),
+            info_cache: self.info_cache.clone(),
+            self_wxid: self.self_wxid.clone(),
+            hook_recovery: self.hook_recovery.clone(),
+            slow_threshold: self.slow_threshold,
         }
     }
 }
@@ -54,74 +105,122 @@ struct WechatJsonSQLReq {
 #[derive(Deserialize)]
 struct WechatErrorResp {
     pub msg: String,
-    pub result: String,
+    pub result: serde_json::Value,
 }
 
+// standard fields of hook api responses
 #[derive(Deserialize)]
 struct WechatHookResp {
-    pub _msg: u32,
-    pub _result: String,
-}
-
-#[derive(Serialize)]
-struct SendTextMessageReq {
-    wxid: String,
-    msg: String,
+    #[serde(default)]
+    pub msg: serde_json::Value,
+    pub result: Option<serde_json::Value>,
 }
 
 // load injection lib
 impl WechatInstance {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         port: u32,
         save_path: String,
         msg_hook_port: u32,
         mxid: String,
-    ) -> anyhow::Result<WechatInstance> {
+        client: reqwest::Client,
+        media_client: utils::GuardedClient,
+        avatar_checker: Option<utils::UrlChecker>,
+        db_names: HashMap<String, Vec<String>>,
+    ) -> Result<WechatInstance> {
         Ok(WechatInstance {
             pid: WechatInstance::new_wechat_instance(port)?,
             port,
             message_hook_port: msg_hook_port,
-            client: reqwest::Client::new(),
+            client,
+            media_client,
             mxid,
             save_path,
+            avatar_checker,
+            db_handles: Arc::new(RwLock::new(HashMap::new())),
+            db_names: Arc::new(db_names),
+            db_handle_cache_hits: Arc::new(AtomicU64::new(0)),
+            db_handle_cache_misses: Arc::new(AtomicU64::new(0)),
+            version: None,
+            info_cache: None,
+            self_wxid: Arc::new(RwLock::new(None)),
+            hook_recovery: Arc::new(tokio::sync::Mutex::new(())),
+            slow_threshold: None,
         })
     }
 
     /**
      * inject dll into wechat.exe and return pid
      */
-    fn new_wechat_instance(port: u32) -> anyhow::Result<u32> {
-        unsafe {
-            let driver_lib_path = String::from("wxDriver64.dll");
-            let lib = libloading::Library::new(driver_lib_path)?;
+    fn new_wechat_instance(port: u32) -> Result<u32> {
+        let pid = unsafe {
+            let lib = libloading::Library::new(constants::DRIVER_LIB_PATH)?;
 
             let new_wechat: libloading::Symbol<unsafe extern "C" fn() -> u32> =
                 lib.get(b"new_wechat")?;
+            new_wechat()
+        };
+        WechatInstance::start_listen(pid, port)?;
+
+        info!(
+            "new wechat instance and start listen it at {} successfully. pid = {}",
+            port, pid
+        );
+
+        Ok(pid)
+    }
+
+    /// serve hook api of the injected wechat at port. It injects the dll again if it is gone
+    fn start_listen(pid: u32, port: u32) -> Result<()> {
+        unsafe {
+            let lib = libloading::Library::new(constants::DRIVER_LIB_PATH)?;
+
             let start_listen: libloading::Symbol<
                 unsafe extern "C" fn(pid: u32, port: c_int) -> c_int,
             > = lib.get(b"start_listen")?;
 
-            let pid = new_wechat();
-            let port: c_int = port.try_into()?;
+            let port: c_int = port
+                .try_into()
+                .map_err(|_| Error::Injection(format!("invalid port {}", port)))?;
             let ok = start_listen(pid, port);
             if ok == 0 {
-                bail!("start listen failed with return value: {}", ok)
+                return Err(Error::Injection(format!(
+                    "start listen failed with return value: {}",
+                    ok
+                )));
             }
+            Ok(())
+        }
+    }
 
-            info!(
-                "new wechat instance and start listen it at {} successfully. pid = {}",
-                port, pid
-            );
+    /// load driver lib and resolve the symbols used for injection without starting wechat
+    pub(crate) fn check_driver_lib() -> Result<()> {
+        unsafe {
+            let lib = libloading::Library::new(constants::DRIVER_LIB_PATH)?;
+            lib.get::<unsafe extern "C" fn() -> u32>(b"new_wechat")?;
+            lib.get::<unsafe extern "C" fn(pid: u32, port: c_int) -> c_int>(b"start_listen")?;
+        }
+        Ok(())
+    }
 
-            Ok(pid)
+    /// inject a throwaway wechat instance listening at port, then kill it
+    pub(crate) fn test_injection(port: u32) -> Result<u32> {
+        let pid = WechatInstance::new_wechat_instance(port)?;
+        let s = System::new_all();
+        match s.process(Pid::from_u32(pid)) {
+            Some(p) if p.kill() => Ok(pid),
+            _ => Err(Error::Injection(format!(
+                "kill injected wechat failed. pid = {}",
+                pid
+            ))),
         }
     }
 
     #[allow(dead_code)]
-    fn stop_listening(&self) -> anyhow::Result<bool> {
+    fn stop_listening(&self) -> Result<bool> {
         unsafe {
-            let driver_lib_path = String::from("wxDriver64.dll");
-            let lib = libloading::Library::new(driver_lib_path)?;
+            let lib = libloading::Library::new(constants::DRIVER_LIB_PATH)?;
 
             // TODO(xylonx): determine stop_listen function signature
             let stop_listen: libloading::Symbol<unsafe extern "C" fn() -> c_int> =
@@ -130,38 +229,252 @@ impl WechatInstance {
         }
     }
 
+    /// start_listen may succeed while the hook api never comes up. Probe it before using the instance
+    pub async fn wait_hook_api(&self) -> Result<()> {
+        let start = Instant::now();
+        for attempt in 1..=constants::HOOK_API_PROBE_ATTEMPTS {
+            match self
+                .send_hook_request(constants::WECHAT_IS_LOGIN, &WechatNilBodyReq {})
+                .await
+            {
+                Ok(_) => {
+                    info!(
+                        "hook api of instance[pid={}] is ready on port {} after {:?}",
+                        self.pid,
+                        self.port,
+                        start.elapsed()
+                    );
+                    return Ok(());
+                }
+                Err(e) => debug!(
+                    "probe hook api of instance[pid={}] failed: {}. attempt {}/{}",
+                    self.pid,
+                    e,
+                    attempt,
+                    constants::HOOK_API_PROBE_ATTEMPTS
+                ),
+            }
+            sleep(Duration::from_millis(constants::HOOK_API_PROBE_INTERVAL_MS)).await;
+        }
+        error!(
+            "hook api of instance[pid={}] does not respond on port {} after {:?}",
+            self.pid,
+            self.port,
+            start.elapsed()
+        );
+        Err(Error::HookApiNotReady(self.port))
+    }
+
+    /// hook api refuses connections if wechat reset the injected dll. Recover it once and retry
     async fn wechat_hook_post_raw<TReq: Serialize>(
         &self,
         msg_type: u32,
         body: TReq,
-    ) -> Result<Bytes, reqwest::Error> {
-        self.client
+    ) -> Result<Bytes> {
+        let start = Instant::now();
+        let resp = match self.send_hook_request(msg_type, &body).await {
+            Err(Error::Hook(e)) if is_connection_refused(&e) => {
+                warn!(
+                    "hook api of instance[pid={}] refuses connection: {}. try to recover it",
+                    self.pid, e
+                );
+                match self.recover_hook_api().await {
+                    Ok(()) => self.send_hook_request(msg_type, &body).await,
+                    Err(e) => Err(e),
+                }
+            }
+            resp => resp,
+        };
+        self.warn_if_slow(start, || format!("hook api {}", msg_type));
+        resp
+    }
+
+    // a slow operation is the sign of a degrading wechat db or a wedged hook
+    fn warn_if_slow<F: FnOnce() -> String>(&self, start: Instant, operation: F) {
+        let elapsed = start.elapsed();
+        match self.slow_threshold {
+            Some(threshold) if elapsed > threshold => warn!(
+                "{} of instance[pid={}] takes {:?}, which exceeds {:?}",
+                operation(),
+                self.pid,
+                elapsed,
+                threshold
+            ),
+            _ => {}
+        }
+    }
+
+    async fn send_hook_request<TReq: Serialize>(
+        &self,
+        msg_type: u32,
+        body: &TReq,
+    ) -> Result<Bytes> {
+        Ok(self
+            .client
             .post(wechat_api(self.port, msg_type))
-            .json(&body)
+            .json(body)
             .send()
             .await?
             .bytes()
+            .await?)
+    }
+
+    ///
+    /// listen again and re-register hooks if wechat is still alive. Concurrent callers wait for the
+    /// recovery in progress instead of starting another one
+    ///
+    async fn recover_hook_api(&self) -> Result<()> {
+        let _recovering = self.hook_recovery.lock().await;
+        // recovered by another caller while waiting
+        if self
+            .send_hook_request(constants::WECHAT_IS_LOGIN, &WechatNilBodyReq {})
             .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        // wechat restarted by someone else has another pid. The instance must be restarted
+        if !self.is_alive().unwrap_or(false) {
+            return Err(Error::InstanceCrashed(self.pid));
+        }
+
+        let recovery = async {
+            WechatInstance::start_listen(self.pid, self.port)?;
+            self.wait_hook_api().await?;
+            self.hook_wechat_message(self.save_path.clone()).await
+        };
+        match recovery.await {
+            Ok(()) => {
+                info!("hook api of instance[pid={}] is recovered", self.pid);
+                Ok(())
+            }
+            Err(e) => Err(Error::HookRecovery(self.pid, e.to_string())),
+        }
+    }
+
+    // part of hook recovery, therefore, it never recovers by itself
+    async fn wechat_hook_post_once<TReq: Serialize, TResp: DeserializeOwned>(
+        &self,
+        msg_type: u32,
+        body: TReq,
+    ) -> Result<TResp> {
+        let resp = self.send_hook_request(msg_type, &body).await?;
+        parse_hook_resp(msg_type, &resp)
+    }
+
+    /// call hook api which returns the standard result only
+    async fn wechat_hook_call<TReq: Serialize>(&self, msg_type: u32, body: TReq) -> Result<()> {
+        self.wechat_hook_post::<TReq, serde::de::IgnoredAny>(msg_type, body)
+            .await?;
+        Ok(())
+    }
+
+    /// call send api and return the id of the sent message if the hook reports it
+    async fn wechat_hook_send<TReq: Serialize>(
+        &self,
+        msg_type: u32,
+        body: TReq,
+    ) -> Result<Option<u64>> {
+        let raw = self.wechat_hook_post_raw(msg_type, body).await?;
+        let resp: serde_json::Value = match parse_hook_resp(msg_type, &raw) {
+            Ok(resp) => resp,
+            Err(e) => {
+                debug!("send by api {} failed: {}", msg_type, e);
+                return Err(hook_send_error(msg_type, &raw));
+            }
+        };
+        // some drivers report it as a string
+        let msg_id = match resp.get("msgid") {
+            Some(serde_json::Value::Number(id)) => id.as_u64(),
+            Some(serde_json::Value::String(id)) => id.parse().ok(),
+            _ => None,
+        };
+        Ok(msg_id.filter(|id| *id != 0))
     }
 
     async fn wechat_hook_post<TReq: Serialize, TResp: DeserializeOwned>(
         &self,
         msg_type: u32,
         body: TReq,
-    ) -> Result<TResp, reqwest::Error> {
-        self.client
-            .post(wechat_api(self.port, msg_type))
-            .json(&body)
-            .send()
-            .await?
-            .json()
-            .await
+    ) -> Result<TResp> {
+        let resp = self.wechat_hook_post_raw(msg_type, body).await?;
+        parse_hook_resp(msg_type, &resp)
+    }
+}
+
+/// hook apis return `{"msg": ..., "result": "ERROR"}` on failure whatever the success body is.
+/// Check it before deserializing the body so that the hook message is not lost in a serde error
+fn parse_hook_resp<T: DeserializeOwned>(msg_type: u32, body: &[u8]) -> Result<T> {
+    if let Ok(WechatHookResp {
+        result: Some(result),
+        msg,
+    }) = serde_json::from_slice(body)
+    {
+        if !is_ok_result(&result) {
+            return Err(Error::HookResult(format!(
+                "api {} returns {}: {}",
+                msg_type, result, msg
+            )));
+        }
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// why wechat refuses to send, like a receiver who is not a friend. It is the message of the hook
+/// if the body is an error response, or the raw body otherwise
+fn hook_send_error(msg_type: u32, body: &[u8]) -> Error {
+    if let Ok(resp) = serde_json::from_slice::<WechatErrorResp>(body) {
+        return Error::HookResult(format!(
+            "wechat refuses to send by api {}: {} (result {})",
+            msg_type, resp.msg, resp.result
+        ));
+    }
+    let raw = String::from_utf8_lossy(body);
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Error::HookResult(format!("api {} returns an empty body", msg_type));
     }
+    Error::HookResult(format!(
+        "api {} returns unexpected body: {}",
+        msg_type,
+        utils::truncate_for_error(raw)
+    ))
+}
+
+// connect errors on other causes, like timeout, are not fixed by listening again
+fn is_connection_refused(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            return io.kind() == std::io::ErrorKind::ConnectionRefused;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// driver builds spell success differently, like "OK", "ok", "OK!" or 1
+fn is_ok_result(result: &serde_json::Value) -> bool {
+    let ok = match result {
+        serde_json::Value::String(s) => {
+            matches!(
+                s.trim().trim_end_matches('!').to_lowercase().as_str(),
+                "ok" | "1"
+            )
+        }
+        serde_json::Value::Number(n) => n.as_i64() == Some(1),
+        serde_json::Value::Bool(b) => *b,
+        _ => false,
+    };
+    if !ok {
+        debug!("hook result {} is not a known success form", result);
+    }
+    ok
 }
 
 impl WechatInstance {
-    pub async fn hook_wechat_message(&self, save_path: String) -> anyhow::Result<()> {
-        self.wechat_hook_post::<serde_json::Value, HashMap<String, serde_json::Value>>(
+    pub async fn hook_wechat_message(&self, save_path: String) -> Result<()> {
+        self.wechat_hook_post_once::<serde_json::Value, HashMap<String, serde_json::Value>>(
             constants::WECHAT_MSG_START_HOOK,
             serde_json::json!({"port": self.message_hook_port}),
         )
@@ -171,7 +484,7 @@ impl WechatInstance {
             self.pid, self.message_hook_port
         );
 
-        self.wechat_hook_post::<serde_json::Value, HashMap<String, serde_json::Value>>(
+        self.wechat_hook_post_once::<serde_json::Value, HashMap<String, serde_json::Value>>(
             constants::WECHAT_MSG_START_IMAGE_HOOK,
             serde_json::json!({ "save_path": save_path }),
         )
@@ -181,7 +494,7 @@ impl WechatInstance {
             self.pid, save_path
         );
 
-        self.wechat_hook_post::<serde_json::Value, HashMap<String, serde_json::Value>>(
+        self.wechat_hook_post_once::<serde_json::Value, HashMap<String, serde_json::Value>>(
             constants::WECHAT_MSG_START_VOICE_HOOK,
             serde_json::json!({ "save_path": save_path }),
         )
@@ -193,14 +506,34 @@ impl WechatInstance {
 
         Ok(())
     }
+
+    /// stop calling back messages to the hook port
+    pub async fn unhook_wechat_message(&self) -> Result<()> {
+        self.wechat_hook_call(constants::WECHAT_MSG_STOP_HOOK, WechatNilBodyReq {})
+            .await?;
+        info!("unhook instance[pid={}] message successfully", self.pid);
+        Ok(())
+    }
+
+    /// stop saving images and voices. message hook is kept to receive messages after re-login
+    pub async fn unhook_wechat_media(&self) -> Result<()> {
+        self.wechat_hook_call(constants::WECHAT_MSG_STOP_IMAGE_HOOK, WechatNilBodyReq {})
+            .await?;
+        self.wechat_hook_call(constants::WECHAT_MSG_STOP_VOICE_HOOK, WechatNilBodyReq {})
+            .await?;
+        info!("unhook instance[pid={}] media successfully", self.pid);
+        Ok(())
+    }
 }
 
 #[derive(Serialize)]
 struct ContactInfo {
     username: String,
     nickname: String,
-    avatar_url: String,
+    avatar_url: Option<String>,
     remark: String,
+    // read from OpenIMContact db
+    open_im: bool,
 }
 
 impl Clone for ContactInfo {
@@ -210,13 +543,118 @@ impl Clone for ContactInfo {
             nickname: self.nickname.clone(),
             avatar_url: self.avatar_url.clone(),
             remark: self.remark.clone(),
+            open_im: self.open_im,
         }
     }
 }
 
+// handle of the first candidate in handles. Names are compared case insensitively, with the file
+// name only if wechat reports the full path
+fn find_db_handle(handles: &HashMap<String, i64>, candidates: &[String]) -> Option<i64> {
+    candidates.iter().find_map(|candidate| {
+        handles.iter().find_map(|(db_name, handle)| {
+            let file_name = db_name.rsplit(['\\', '/']).next().unwrap_or(db_name);
+            match db_name.eq_ignore_ascii_case(candidate)
+                || file_name.eq_ignore_ascii_case(candidate)
+            {
+                true => Some(*handle),
+                false => None,
+            }
+        })
+    })
+}
+
+// move completely written media to its name in dir, or its md5 if it has no name
+async fn finish_media(dir: &Path, part_path: &Path, name: &str, md5: String) -> Result<String> {
+    let filepath = match name.len() {
+        0 => dir.join(md5),
+        _ => dir.join(utils::sanitize_component(name)),
+    };
+    tokio::fs::rename(part_path, &filepath).await?;
+    match filepath.into_os_string().into_string() {
+        Ok(p) => Ok(p),
+        Err(e) => Err(Error::Parse(format!("convert filepath {:?} failed", e))),
+    }
+}
+
+// index of a shard named like <prefix><index>.db, e.g. 3 of MSG3.db
+fn shard_index(file_name: &str, prefix: &str) -> Option<u32> {
+    let (name, ext) = file_name.rsplit_once('.')?;
+    if !ext.eq_ignore_ascii_case("db") || name.len() <= prefix.len() {
+        return None;
+    }
+    let (head, index) = name.split_at(prefix.len());
+    match head.eq_ignore_ascii_case(prefix) && index.bytes().all(|b| b.is_ascii_digit()) {
+        true => index.parse().ok(),
+        false => None,
+    }
+}
+
 // wechat sql query related methods. Just wrap contact query related queries now
 impl WechatInstance {
-    async fn get_db_handle_by_name(&self, name: String) -> anyhow::Result<i64> {
+    /// hits and misses of the db handle cache
+    pub fn db_handle_cache_stats(&self) -> (u64, u64) {
+        (
+            self.db_handle_cache_hits.load(Ordering::Relaxed),
+            self.db_handle_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    // name is a logical db name. The handle of its first candidate name opened by wechat is returned,
+    // with whether it is from the cache, which may be stale after relogin
+    async fn get_db_handle_by_name(&self, name: &str) -> Result<(i64, bool)> {
+        let fallback = [name.to_string()];
+        let candidates = match self.db_names.get(name) {
+            Some(candidates) => candidates.as_slice(),
+            None => &fallback,
+        };
+        if let Ok(handles) = self.db_handles.read() {
+            if let Some(handle) = find_db_handle(&handles, candidates) {
+                self.db_handle_cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok((handle, true));
+            }
+        }
+        self.db_handle_cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        let handles = self.fetch_db_handles().await?;
+        let handle = find_db_handle(&handles, candidates);
+        if handle.is_none() {
+            warn!(
+                "none of {:?} is opened by wechat. opened dbs: {:?}",
+                candidates,
+                handles.keys().collect::<Vec<_>>()
+            );
+        }
+        handle
+            .map(|handle| (handle, false))
+            .ok_or_else(|| Error::NotFound(format!("db_name[{}]", name)))
+    }
+
+    ///
+    /// handles of sharded databases named like <prefix>0.db, <prefix>1.db, ... in shard order, e.g.
+    /// MSG0.db for messages. The list is fetched every time since wechat opens a new shard when the
+    /// last one is full
+    ///
+    pub async fn get_all_db_handles(&self, prefix: &str) -> Result<Vec<(String, i64)>> {
+        let mut shards: Vec<(u32, String, i64)> = self
+            .fetch_db_handles()
+            .await?
+            .into_iter()
+            .filter_map(|(db_name, handle)| {
+                let file_name = db_name.rsplit(['\\', '/']).next().unwrap_or(&db_name);
+                let shard = shard_index(file_name, prefix)?;
+                Some((shard, db_name, handle))
+            })
+            .collect();
+        shards.sort();
+        Ok(shards
+            .into_iter()
+            .map(|(_, db_name, handle)| (db_name, handle))
+            .collect())
+    }
+
+    // fetch handles of all databases opened by wechat and replace the cache with them
+    async fn fetch_db_handles(&self) -> Result<HashMap<String, i64>> {
         #[derive(Deserialize)]
         struct Data {
             db_name: String,
@@ -230,23 +668,91 @@ impl WechatInstance {
         let resp: WechatGetDBHandleResp = self
             .wechat_hook_post(constants::WECHAT_DATABASE_GET_HANDLES, WechatNilBodyReq {})
             .await?;
-        for i in &resp.data {
-            if i.db_name == name {
-                return Ok(i.handle);
+        let handles: HashMap<String, i64> = resp
+            .data
+            .into_iter()
+            .map(|i| (i.db_name, i.handle))
+            .collect();
+        match self.db_handles.write() {
+            Ok(mut cached) => *cached = handles.clone(),
+            Err(err) => error!("lock db handles failed: {}", err),
+        }
+        Ok(handles)
+    }
+
+    fn invalidate_db_handles(&self) {
+        match self.db_handles.write() {
+            Ok(mut handles) => handles.clear(),
+            Err(err) => error!("lock db handles failed: {}", err),
+        }
+    }
+
+    async fn exec_sql(&self, db_name: String, sql: String) -> Result<Vec<Vec<String>>> {
+        let start = Instant::now();
+        let resp = self.exec_sql_retried(&db_name, sql).await;
+        self.warn_if_slow(start, || format!("sql on {}", db_name));
+        resp
+    }
+
+    async fn exec_sql_retried(&self, db_name: &str, sql: String) -> Result<Vec<Vec<String>>> {
+        let (handle, cached) = self.get_db_handle_by_name(db_name).await?;
+        match self.exec_sql_by_handle(handle, sql.clone()).await {
+            // the cached handle may be closed after relogin. Retry with a fresh one
+            Err(Error::Db(e) | Error::HookResult(e)) if cached => {
+                warn!(
+                    "exec sql on cached handle of {} failed: {}. refresh handles",
+                    db_name, e
+                );
+                self.invalidate_db_handles();
+                let (handle, _) = self.get_db_handle_by_name(db_name).await?;
+                self.exec_sql_by_handle(handle, sql).await
             }
+            resp => resp,
+        }
+    }
+
+    ///
+    /// run sql on every shard of a sharded database like MSG and merge the rows. The column names
+    /// are only kept from the first shard. Shards which fail are skipped unless all of them fail
+    ///
+    pub async fn exec_sql_sharded(&self, prefix: &str, sql: String) -> Result<Vec<Vec<String>>> {
+        let start = Instant::now();
+        let shards = self.get_all_db_handles(prefix).await?;
+        if shards.is_empty() {
+            return Err(Error::NotFound(format!("shards of db {}", prefix)));
         }
 
-        bail!("db_name[{}] not found", name)
+        let mut rows: Vec<Vec<String>> = vec![];
+        let mut last_err = None;
+        for (db_name, handle) in shards {
+            match self.exec_sql_by_handle(handle, sql.clone()).await {
+                Ok(resp) => {
+                    let skip = match rows.is_empty() {
+                        true => 0,
+                        false => 1,
+                    };
+                    rows.extend(resp.into_iter().skip(skip));
+                }
+                Err(e) => {
+                    warn!("exec sql on shard {} failed: {}", db_name, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        self.warn_if_slow(start, || format!("sql on shards of {}", prefix));
+        match (rows.is_empty(), last_err) {
+            (true, Some(e)) => Err(e),
+            _ => Ok(rows),
+        }
     }
 
-    async fn exec_sql(&self, db_name: String, sql: String) -> anyhow::Result<Vec<Vec<String>>> {
+    async fn exec_sql_by_handle(&self, handle: i64, sql: String) -> Result<Vec<Vec<String>>> {
         #[derive(Deserialize)]
         struct ExecSqlResp {
-            result: String,
+            result: serde_json::Value,
             data: Vec<Vec<String>>,
         }
 
-        let handle = self.get_db_handle_by_name(db_name).await?;
         let resp: ExecSqlResp = self
             .wechat_hook_post(
                 constants::WECHAT_DATABASE_QUERY,
@@ -256,8 +762,8 @@ impl WechatInstance {
                 },
             )
             .await?;
-        if resp.result != "OK" {
-            bail!("exec sql failed: {}", resp.result)
+        if !is_ok_result(&resp.result) {
+            return Err(Error::Db(format!("exec sql failed: {}", resp.result)));
         }
 
         Ok(resp.data)
@@ -268,84 +774,130 @@ impl WechatInstance {
         db_name: String,
         sql: String,
         filter: Option<String>,
-    ) -> anyhow::Result<Vec<ContactInfo>> {
+    ) -> Result<Vec<ContactInfo>> {
         let query = match filter {
             Some(cond) => format!("{} {}", sql, cond),
             None => sql,
         };
+        let open_im = db_name == constants::DB_OPEN_IM_CONTACT;
         let resp = self.exec_sql(db_name, query).await?;
-        if resp.len() < 2 || resp[1].len() != 5 {
-            bail!("no contact found")
+        // the first row is column names
+        if resp.len() < 2 {
+            return Ok(vec![]);
         }
 
         let mut data: Vec<ContactInfo> = vec![];
         for i in &resp[1..] {
             if i.len() < 5 {
-                bail!("data shape wrong, want 5 but get {}", i.len())
+                return Err(Error::Db(format!(
+                    "data shape wrong, want 5 but get {}",
+                    i.len()
+                )));
             }
 
             data.push(ContactInfo {
                 username: i[0].clone(),
                 nickname: i[1].clone(),
-                avatar_url: match i[2].len() {
-                    0 => i[3].clone(),
-                    _ => i[2].clone(),
-                },
+                avatar_url: self.choose_avatar(&[&i[2], &i[3]]).await,
                 remark: i[4].clone(),
+                open_im,
             });
         }
         Ok(data)
     }
 
-    async fn get_micro_msg_contacts(
-        &self,
-        filter_id: Option<String>,
-    ) -> anyhow::Result<Vec<ContactInfo>> {
+    // the first valid url of big and small avatar. Dead urls are skipped in verification mode
+    async fn choose_avatar(&self, candidates: &[&String]) -> Option<String> {
+        for url in candidates.iter().filter(|u| utils::is_http_url(u)) {
+            match &self.avatar_checker {
+                Some(checker) if !checker.is_alive(url).await => {
+                    info!("avatar {} is dead, skip it", url);
+                }
+                _ => return Some(url.to_string()),
+            }
+        }
+        None
+    }
+
+    async fn get_micro_msg_contacts(&self, filter_id: Option<String>) -> Result<Vec<ContactInfo>> {
         self.get_contacts(
             constants::DB_MICRO_MSG.to_string(),
             String::from("SELECT c.UserName, c.NickName, i.bigHeadImgUrl, i.smallHeadImgUrl, c.Remark FROM Contact AS c LEFT JOIN ContactHeadImgUrl AS i ON c.UserName = i.usrName"),
-            filter_id.map(|id| format!("WHERE c.UserName=\"{}\"", id)),
+            match filter_id {
+                Some(id) => Some(format!("WHERE c.UserName={}", utils::sql_quote_wxid(&id)?)),
+                None => None,
+            },
         )
         .await
     }
 
-    async fn get_open_im_contacts(
-        &self,
-        filter_id: Option<String>,
-    ) -> anyhow::Result<Vec<ContactInfo>> {
+    async fn get_open_im_contacts(&self, filter_id: Option<String>) -> Result<Vec<ContactInfo>> {
         self.get_contacts(
             constants::DB_OPEN_IM_CONTACT.to_string(),
             String::from("SELECT UserName, NickName, BigHeadImgUrl, SmallHeadImgUrl, Remark FROM OpenIMContact"),
-            filter_id.map(|id| format!("WHERE UserName=\"{}\"", id)),
+            match filter_id {
+                Some(id) => Some(format!("WHERE UserName={}", utils::sql_quote_wxid(&id)?)),
+                None => None,
+            },
         )
         .await
     }
 
-    async fn get_contact_by_id(&self, wechat_id: String) -> anyhow::Result<ContactInfo> {
+    /// return all columns of the contact record as column name -> value
+    pub async fn get_raw_contact(&self, wechat_id: String) -> Result<HashMap<String, String>> {
+        let quoted_id = utils::sql_quote_wxid(&wechat_id)?;
+        let (db_name, sql) = match wechat_id.ends_with("@openim") {
+            true => (
+                constants::DB_OPEN_IM_CONTACT,
+                format!("SELECT * FROM OpenIMContact WHERE UserName={}", quoted_id),
+            ),
+            false => (
+                constants::DB_MICRO_MSG,
+                format!("SELECT * FROM Contact WHERE UserName={}", quoted_id),
+            ),
+        };
+        let resp = self.exec_sql(db_name.to_string(), sql).await?;
+        if resp.len() < 2 {
+            return Err(Error::NotFound(format!("contact {}", wechat_id)));
+        }
+
+        Ok(resp[0]
+            .iter()
+            .cloned()
+            .zip(resp[1].iter().cloned())
+            .collect())
+    }
+
+    /// None if the contact is not in database, e.g. a stranger in group or a brand-new friend
+    async fn get_contact_by_id(&self, wechat_id: String) -> Result<Option<ContactInfo>> {
         let contacts = match wechat_id.ends_with("@openim") {
             true => self.get_open_im_contacts(Some(wechat_id)).await?,
             false => self.get_micro_msg_contacts(Some(wechat_id)).await?,
         };
 
-        Ok(contacts[0].clone())
+        Ok(contacts.into_iter().next())
     }
 }
 
-impl WechatInstance {
-    pub async fn is_login(&self) -> anyhow::Result<bool> {
-        #[derive(Deserialize)]
-        struct WechatCheckLoginResp {
-            is_login: u8,
-            result: String,
-        }
+#[derive(Deserialize)]
+struct WechatCheckLoginResp {
+    #[serde(alias = "isLogin")]
+    is_login: u8,
+    result: serde_json::Value,
+}
 
+impl WechatInstance {
+    pub async fn is_login(&self) -> Result<bool> {
         let resp: WechatCheckLoginResp = self
             .wechat_hook_post(constants::WECHAT_IS_LOGIN, WechatNilBodyReq {})
             .await?;
 
-        if resp.result != "OK" {
+        if !is_ok_result(&resp.result) {
             error!("parse is_login resp failed: {}", resp.result);
-            bail!("parse is_login resp failed: {}", resp.result)
+            return Err(Error::HookResult(format!(
+                "parse is_login resp failed: {}",
+                resp.result
+            )));
         }
 
         info!("log status: {}", resp.is_login);
@@ -353,32 +905,70 @@ impl WechatInstance {
         Ok(resp.is_login == 1)
     }
 
-    pub fn is_alive(&self) -> anyhow::Result<bool> {
+    /// detect version of the hooked wechat. It is reported to the bridge by capabilities
+    pub fn detect_version(&mut self) {
+        self.version = match utils::get_wechat_version() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!(
+                    "detect wechat version of instance[pid={}] failed: {}",
+                    self.pid, e
+                );
+                None
+            }
+        };
+        if let Some(version) = &self.version {
+            compat::check_version(version);
+        }
+        info!("instance[pid={}] runs wechat {:?}", self.pid, self.version);
+    }
+
+    /// pretend to be another wechat version. It is a workaround for hooks rejected after wechat auto update
+    pub async fn set_version(&self, version: String) -> Result<()> {
+        self.wechat_hook_call(
+            constants::WECHAT_SET_VERSION,
+            serde_json::json!({ "version": version }),
+        )
+        .await
+    }
+
+    pub fn is_alive(&self) -> Result<bool> {
         let s = System::new_all();
         let proc = match s.process(Pid::from_u32(self.pid)) {
             Some(p) => p,
             None => {
-                bail!("cannot find process[{}]", self.pid)
+                return Err(Error::Internal(format!(
+                    "cannot find process[{}]",
+                    self.pid
+                )))
             }
         };
         Ok(proc.status() == ProcessStatus::Run)
     }
 
-    pub fn kill_self_process(&self) -> anyhow::Result<bool> {
+    pub fn kill_self_process(&self) -> Result<bool> {
         let s = System::new_all();
         let proc = match s.process(Pid::from_u32(self.pid)) {
             Some(p) => p,
             None => {
-                bail!("cannot find process[{}]", self.pid)
+                return Err(Error::Internal(format!(
+                    "cannot find process[{}]",
+                    self.pid
+                )))
             }
         };
         Ok(proc.kill())
     }
 
-    pub async fn get_login_qrcode<'a>(&self) -> anyhow::Result<Vec<u8>> {
+    pub async fn get_login_qrcode(&self) -> Result<Vec<u8>> {
         // FIXME(duo): skip the first qr code
         sleep(Duration::from_secs(3)).await;
 
+        self.fetch_login_qrcode().await
+    }
+
+    /// current login qr code image. It is refreshed by wechat when expired
+    pub async fn fetch_login_qrcode(&self) -> Result<Vec<u8>> {
         let resp = self
             .wechat_hook_post_raw(constants::WECHAT_GET_QRCODE_IMAGE, WechatNilBodyReq {})
             .await?;
@@ -389,36 +979,52 @@ impl WechatInstance {
                     "request for get_qrcode_image failed: {} with result {}",
                     r.msg, r.result
                 );
-                bail!("get qrcode image failed: {}", r.msg)
+                Err(Error::HookResult(format!(
+                    "get qrcode image failed: {}",
+                    r.msg
+                )))
             }
             Err(_) => Ok(Vec::from(resp)),
         }
     }
 
+    /// personal qr code image for others to add self as a friend
+    pub async fn get_self_qrcode(&self) -> Result<Vec<u8>> {
+        // wxDriver has no api type for the contact qr code. WECHAT_GET_QRCODE_IMAGE only returns the login one
+        Err(Error::Unsupported("self qr code".to_string()))
+    }
+
     #[allow(dead_code)]
-    pub async fn logout(&self) -> anyhow::Result<()> {
-        self.wechat_hook_post_raw(constants::WECHAT_LOGOUT, WechatNilBodyReq {})
+    pub async fn logout(&self) -> Result<()> {
+        self.wechat_hook_call(constants::WECHAT_LOGOUT, WechatNilBodyReq {})
             .await?;
+        self.forget_self_wxid();
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
 #[serde_with::serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WechatUserInfo {
     #[serde(rename = "wxId")]
     pub id: String,
     #[serde(rename = "wxNickName")]
     pub nickname: String,
+    // None means no avatar. It is serialized as empty string for compatibility
     #[serde(rename = "wxBigAvatar")]
-    pub avatar: String,
+    #[serde_as(as = "serde_with::NoneAsEmptyString")]
+    pub avatar: Option<String>,
     #[serde(rename = "wxRemark")]
     pub remark: Option<String>,
+    // WeWork contact from OpenIM
+    #[serde(rename = "isEnterprise", default)]
+    pub is_enterprise: bool,
 }
 
 impl From<ContactInfo> for WechatUserInfo {
     fn from(contact: ContactInfo) -> Self {
         Self {
+            is_enterprise: contact.open_im || contact.username.ends_with("@openim"),
             id: contact.username,
             nickname: contact.nickname,
             avatar: contact.avatar_url,
@@ -427,33 +1033,128 @@ impl From<ContactInfo> for WechatUserInfo {
     }
 }
 
+#[derive(Deserialize)]
+struct WechatGetSelfResp {
+    result: serde_json::Value,
+    data: WechatUserInfo,
+}
+
 // warp user related API
 impl WechatInstance {
-    pub async fn get_self(&self) -> anyhow::Result<WechatUserInfo> {
-        #[derive(Deserialize)]
-        struct WechatGetSelfResp {
-            result: String,
-            data: WechatUserInfo,
-        }
-
+    pub async fn get_self(&self) -> Result<WechatUserInfo> {
         let resp: WechatGetSelfResp = self
             .wechat_hook_post(constants::WECHAT_GET_SELF_INFO, WechatNilBodyReq {})
             .await?;
 
-        if resp.result != "OK" {
-            bail!("parse get_self resp failed: {}", resp.result)
+        if !is_ok_result(&resp.result) {
+            return Err(Error::HookResult(format!(
+                "parse get_self resp failed: {}",
+                resp.result
+            )));
+        }
+        if !resp.data.id.is_empty() {
+            self.set_self_wxid(Some(resp.data.id.clone()));
         }
         Ok(resp.data)
     }
 
-    pub async fn get_user_info(&self, wechat_id: String) -> anyhow::Result<WechatUserInfo> {
-        let info = self.get_contact_by_id(wechat_id).await?;
-        Ok(WechatUserInfo::from(info))
+    /// wxid of the logged in account if get_self has succeeded since login
+    pub fn cached_self_wxid(&self) -> Option<String> {
+        match self.self_wxid.read() {
+            Ok(wxid) => wxid.clone(),
+            Err(err) => {
+                error!("lock self wxid failed: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn forget_self_wxid(&self) {
+        self.set_self_wxid(None)
+    }
+
+    fn set_self_wxid(&self, wxid: Option<String>) {
+        match self.self_wxid.write() {
+            Ok(mut cached) => *cached = wxid,
+            Err(err) => error!("lock self wxid failed: {}", err),
+        }
+    }
+
+    /// cache user and group infos for ttl. It is called once the instance is created
+    pub fn enable_info_cache(&mut self, ttl: Duration) {
+        self.info_cache = Some(Arc::new(InfoCache::new(ttl)));
     }
 
-    pub async fn get_friend_list(&self) -> anyhow::Result<Vec<WechatUserInfo>> {
+    /// drop cached infos of wxid so that the next query reads db again
+    pub fn invalidate_info(&self, wechat_id: &str) {
+        if let Some(cache) = &self.info_cache {
+            cache.invalidate(wechat_id);
+        }
+    }
+
+    /// drop all cached infos. Remarks are of the account, therefore, they must not be answered
+    /// after another one logs in
+    pub fn clear_info_cache(&self) {
+        if let Some(cache) = &self.info_cache {
+            cache.clear();
+        }
+    }
+
+    /// hits and misses of the info cache
+    pub fn info_cache_stats(&self) -> (u64, u64) {
+        match &self.info_cache {
+            Some(cache) => cache.stats(),
+            None => (0, 0),
+        }
+    }
+
+    pub async fn get_user_info(&self, wechat_id: String) -> Result<WechatUserInfo> {
+        if let Some(info) = self.info_cache.as_ref().and_then(|c| c.user(&wechat_id)) {
+            return Ok(info);
+        }
+        self.refresh_user_info(wechat_id).await
+    }
+
+    /// read user info from db regardless of the cache
+    pub async fn refresh_user_info(&self, wechat_id: String) -> Result<WechatUserInfo> {
+        match self.get_contact_by_id(wechat_id.clone()).await? {
+            Some(info) => {
+                let info = WechatUserInfo::from(info);
+                if let Some(cache) = &self.info_cache {
+                    cache.put_user(&info);
+                }
+                Ok(info)
+            }
+            // not cached since the contact may be written to db soon
+            None => {
+                info!(
+                    "contact {} not found in database, use id as nickname",
+                    wechat_id
+                );
+                Ok(WechatUserInfo {
+                    is_enterprise: wechat_id.ends_with("@openim"),
+                    id: wechat_id.clone(),
+                    nickname: wechat_id,
+                    avatar: None,
+                    remark: None,
+                })
+            }
+        }
+    }
+
+    pub async fn get_friend_list(&self) -> Result<Vec<WechatUserInfo>> {
         let micro_msg_contacts = self.get_micro_msg_contacts(None).await?;
         let open_im_contacts = self.get_open_im_contacts(None).await?;
+        let (hits, misses) = self.db_handle_cache_stats();
+        debug!(
+            "db handle cache of instance[pid={}]: hits = {} misses = {}",
+            self.pid, hits, misses
+        );
+        let (hits, misses) = self.info_cache_stats();
+        debug!(
+            "info cache of instance[pid={}]: hits = {} misses = {}",
+            self.pid, hits, misses
+        );
         Ok(micro_msg_contacts
             .into_iter()
             .chain(
@@ -464,9 +1165,123 @@ impl WechatInstance {
             .map(WechatUserInfo::from)
             .collect())
     }
+
+    /// ids of contacts in the friend list. Avatars are not looked up, therefore, it is cheap to poll
+    pub async fn get_contact_ids(&self) -> Result<BTreeSet<String>> {
+        let queries = [
+            // Contact keeps group members met in groups as well. Friends have the lowest bit of Type
+            // set, while official accounts have a VerifyFlag
+            (
+                constants::DB_MICRO_MSG,
+                "SELECT UserName FROM Contact WHERE (Type & 1) = 1 AND VerifyFlag = 0",
+            ),
+            (
+                constants::DB_OPEN_IM_CONTACT,
+                "SELECT UserName FROM OpenIMContact",
+            ),
+        ];
+        let mut ids = BTreeSet::new();
+        for (db_name, sql) in queries {
+            let resp = self.exec_sql(db_name.to_string(), sql.to_string()).await?;
+            // the first row is column names
+            ids.extend(
+                resp.into_iter()
+                    .skip(1)
+                    .filter_map(|row| row.into_iter().next())
+                    .filter(|id| !id.is_empty() && !id.ends_with("@chatroom")),
+            );
+        }
+        Ok(ids)
+    }
+
+    /// accept a friend request by the tickets in its verify message
+    pub async fn accept_friend_request(&self, v3: String, v4: String) -> Result<()> {
+        #[derive(Deserialize)]
+        struct WechatVerifyApplyResp {
+            result: serde_json::Value,
+        }
+
+        let resp: WechatVerifyApplyResp = self
+            .wechat_hook_post(
+                constants::WECHAT_CONTACT_VERIFY_APPLY,
+                serde_json::json!({
+                    "v3": v3,
+                    "v4": v4,
+                }),
+            )
+            .await?;
+        if !is_ok_result(&resp.result) {
+            return Err(Error::HookResult(format!(
+                "accept friend request failed: {}",
+                resp.result
+            )));
+        }
+        Ok(())
+    }
+
+    ///
+    /// unread counts of chats in the chat list keyed by chat id. Unlike contacts, chats with OpenIM
+    /// contacts are kept in the same Session table as regular ones. Placeholders of folded chats
+    /// start with '@' and are skipped
+    ///
+    pub async fn get_unread_counts(&self) -> Result<BTreeMap<String, u32>> {
+        let resp = self
+            .exec_sql(
+                constants::DB_MICRO_MSG.to_string(),
+                "SELECT strUsrName, nUnReadCount FROM Session".to_string(),
+            )
+            .await?;
+        // the first row is column names
+        Ok(resp
+            .into_iter()
+            .skip(1)
+            .filter_map(|row| match row.as_slice() {
+                [id, count, ..] if !id.is_empty() && !id.starts_with('@') => {
+                    Some((id.clone(), count.parse().unwrap_or(0)))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// path of the encrypted image of an old message, which is relative to WeChat Files dir
+    pub async fn get_image_path(&self, chat_id: String, msg_id: u64) -> Result<String> {
+        let sql = format!(
+            "SELECT BytesExtra FROM MSG WHERE StrTalker={} AND MsgSvrID={} AND Type={}",
+            utils::sql_quote_wxid(&chat_id)?,
+            msg_id,
+            WechatMessageType::Image as u32
+        );
+        let resp = self
+            .exec_sql_sharded(constants::DB_MSG_SHARD_PREFIX, sql)
+            .await?;
+        // the first row is column names
+        let extra = match resp.get(1).and_then(|row| row.first()) {
+            Some(extra) if !extra.is_empty() => extra,
+            _ => {
+                return Err(Error::NotFound(format!(
+                    "image message {} in {}",
+                    msg_id, chat_id
+                )))
+            }
+        };
+        // the thumbnail is listed along with the image
+        roomdata::parse_extra_values(extra)?
+            .into_iter()
+            .filter(|value| value.to_lowercase().ends_with(".dat"))
+            .find(|value| !value.split(['\\', '/']).any(|dir| dir == "Thumb"))
+            .ok_or_else(|| Error::NotFound(format!("image path of message {}", msg_id)))
+    }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Clone, Debug)]
+pub struct WechatGroupMemberNickname {
+    #[serde(rename(serialize = "wxId"))]
+    pub id: String,
+    pub nickname: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
 #[serde_with::serde_as]
 pub struct WechatGroupInfo {
     #[serde(rename(serialize = "wxId"))]
@@ -483,6 +1298,10 @@ pub struct WechatGroupInfo {
 
     #[serde(rename(serialize = "members"))]
     pub member_ids: Vec<String>,
+
+    // member count recorded in db. It is accurate even if the member list is partial
+    #[serde(rename(serialize = "memberCount"))]
+    pub member_count: usize,
 }
 
 impl From<ContactInfo> for WechatGroupInfo {
@@ -490,31 +1309,90 @@ impl From<ContactInfo> for WechatGroupInfo {
         Self {
             id: contact.username,
             nickname: contact.nickname,
-            avatar: contact.avatar_url,
+            avatar: contact.avatar_url.unwrap_or_default(),
             notice: String::new(),
             member_ids: vec![],
+            member_count: 0,
         }
     }
 }
 
 // warp group related API
 impl WechatInstance {
-    pub async fn get_group_info(&self, wechat_id: String) -> anyhow::Result<WechatGroupInfo> {
-        let info = self.get_contact_by_id(wechat_id.clone()).await?;
-        Ok(WechatGroupInfo {
+    pub async fn get_group_info(&self, wechat_id: String) -> Result<WechatGroupInfo> {
+        if let Some(info) = self.info_cache.as_ref().and_then(|c| c.group(&wechat_id)) {
+            return Ok(info);
+        }
+        self.refresh_group_info(wechat_id).await
+    }
+
+    /// read group info from db and hook regardless of the cache
+    pub async fn refresh_group_info(&self, wechat_id: String) -> Result<WechatGroupInfo> {
+        let info = match self.get_contact_by_id(wechat_id.clone()).await? {
+            Some(info) => info,
+            None => {
+                info!("group {} not found in database, use id as name", wechat_id);
+                ContactInfo {
+                    username: wechat_id.clone(),
+                    nickname: wechat_id.clone(),
+                    avatar_url: None,
+                    remark: String::new(),
+                    open_im: false,
+                }
+            }
+        };
+        let (member_ids, member_count) = self.fetch_group_members(wechat_id).await?;
+        let avatar = match info.avatar_url {
+            Some(url) => Some(url),
+            None => self.get_group_head_img_url(&info.username).await,
+        };
+        let info = WechatGroupInfo {
             id: info.username,
             nickname: info.nickname,
-            avatar: info.avatar_url,
+            avatar: avatar.unwrap_or_default(),
             notice: String::new(),
-            member_ids: self.get_group_members(wechat_id).await?,
-        })
+            member_count,
+            member_ids,
+        };
+        if let Some(cache) = &self.info_cache {
+            cache.put_group(&info);
+        }
+        Ok(info)
     }
 
-    pub async fn get_group_members(&self, group_id: String) -> anyhow::Result<Vec<String>> {
+    /// groups often have no record in ContactHeadImgUrl while Contact may keep their avatar urls
+    async fn get_group_head_img_url(&self, group_id: &str) -> Option<String> {
+        let sql = format!(
+            "SELECT BigHeadImgUrl, SmallHeadImgUrl FROM Contact WHERE UserName={}",
+            utils::sql_quote_wxid(group_id).ok()?
+        );
+        match self
+            .exec_sql(constants::DB_MICRO_MSG.to_string(), sql)
+            .await
+        {
+            Ok(resp) => {
+                // the first row is column names
+                let row = resp.get(1)?;
+                self.choose_avatar(&row.iter().collect::<Vec<_>>()).await
+            }
+            Err(e) => {
+                warn!("read avatar urls of group {} failed: {}", group_id, e);
+                None
+            }
+        }
+    }
+
+    pub async fn get_group_members(&self, group_id: String) -> Result<Vec<String>> {
+        Ok(self.fetch_group_members(group_id).await?.0)
+    }
+
+    /// hook truncates or empties the member list of very large rooms. Members in db are merged into it.
+    /// Returns the merged members and the member count
+    async fn fetch_group_members(&self, group_id: String) -> Result<(Vec<String>, usize)> {
         #[derive(Deserialize)]
         struct WechatGetGroupMembersResp {
             members: String,
-            result: String,
+            result: serde_json::Value,
         }
 
         let resp: WechatGetGroupMembersResp = self
@@ -526,22 +1404,193 @@ impl WechatInstance {
             )
             .await?;
 
-        if resp.result != "OK" {
-            bail!("parse get group members failed: {}", resp.result)
+        if !is_ok_result(&resp.result) {
+            return Err(Error::HookResult(format!(
+                "parse get group members failed: {}",
+                resp.result
+            )));
+        }
+
+        let db_members = match self.get_group_members_from_db(&group_id).await {
+            Ok(members) => members,
+            Err(e) => {
+                warn!("read members of group {} from db failed: {}", group_id, e);
+                None
+            }
+        };
+        Ok(merge_group_members(
+            &group_id,
+            &resp.members,
+            db_members.as_deref(),
+        ))
+    }
+
+    // None if the group has no row in ChatRoom table
+    async fn get_group_members_from_db(&self, group_id: &str) -> Result<Option<String>> {
+        let sql = format!(
+            "SELECT UserNameList FROM ChatRoom WHERE ChatRoomName={}",
+            utils::sql_quote_wxid(group_id)?
+        );
+        let resp = self
+            .exec_sql(constants::DB_MICRO_MSG.to_string(), sql)
+            .await?;
+        // the first row is column names
+        Ok(resp.get(1).and_then(|row| row.first()).cloned())
+    }
+
+    /// invite qr code image of the group
+    pub async fn get_group_qrcode(&self, group_id: String) -> Result<Vec<u8>> {
+        utils::sql_quote_wxid(&group_id)?;
+        // wxDriver has no api type for group qr codes. WECHAT_GET_QRCODE_IMAGE only returns the login one
+        Err(Error::Unsupported("group qr code".to_string()))
+    }
+
+    /// create a group with members besides self and return the group id
+    pub async fn create_group(&self, member_ids: Vec<String>) -> Result<String> {
+        let mut member_ids = member_ids;
+        member_ids.sort();
+        member_ids.dedup();
+        if let Some(id) = member_ids.iter().find(|id| !utils::is_valid_wxid(id)) {
+            return Err(Error::InvalidRequest(format!("invalid wechat id {}", id)));
         }
+        if member_ids.len() < constants::MIN_CREATE_GROUP_MEMBERS {
+            return Err(Error::InvalidRequest(format!(
+                "at least {} members are required to create a group",
+                constants::MIN_CREATE_GROUP_MEMBERS
+            )));
+        }
+        // wxDriver has no api type to create a chatroom
+        Err(Error::Unsupported("create group".to_string()))
+    }
 
+    /// leave the group
+    pub async fn quit_group(&self, group_id: String) -> Result<()> {
+        utils::sql_quote_wxid(&group_id)?;
+        // wxDriver has no api type to quit or dissolve a chatroom.
+        // WECHAT_CHATROOM_DEL_MEMBER only removes other members
+        Err(Error::Unsupported("quit group".to_string()))
+    }
+
+    /// display names of group members. Group alias is preferred, then the contact nickname. A
+    /// member whose name is unknown or cannot be looked up fails alone
+    pub async fn get_group_member_nicknames(
+        &self,
+        group_id: String,
+    ) -> Result<BatchResult<WechatGroupMemberNickname>> {
+        let members = self.get_group_members(group_id.clone()).await?;
+        // members without alias are looked up in contacts then
+        let aliases = self
+            .get_group_member_aliases(&group_id)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("query member aliases of group {} failed: {}", group_id, e);
+                HashMap::new()
+            });
+        let missing = members
+            .iter()
+            .filter(|id| !aliases.contains_key(*id))
+            .collect::<Vec<&String>>();
+        let nicknames = match missing.is_empty() {
+            true => Ok(HashMap::new()),
+            false => self.get_contact_nicknames(&missing).await,
+        };
+        merge_member_nicknames(members, aliases, nicknames)
+    }
+
+    // nicknames in Contact table. Contacts without nickname are absent
+    async fn get_contact_nicknames(&self, ids: &[&String]) -> Result<HashMap<String, String>> {
+        let ids = ids
+            .iter()
+            .map(|id| utils::sql_quote_wxid(id))
+            .collect::<Result<Vec<String>>>()?;
+        let sql = format!(
+            "SELECT UserName, NickName FROM Contact WHERE UserName IN ({})",
+            ids.join(",")
+        );
+        let resp = self
+            .exec_sql(constants::DB_MICRO_MSG.to_string(), sql)
+            .await?;
+        // the first row is column names
+        Ok(resp
+            .into_iter()
+            .skip(1)
+            .filter_map(|row| match row.as_slice() {
+                [id, nickname, ..] if !nickname.is_empty() => Some((id.clone(), nickname.clone())),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// display name of one group member like get_group_member_nicknames, without reading the whole member list
+    pub async fn get_group_member_display_name(
+        &self,
+        group_id: &str,
+        wechat_id: &str,
+    ) -> Result<Option<String>> {
+        if let Some(alias) = self
+            .get_group_member_aliases(group_id)
+            .await?
+            .remove(wechat_id)
+        {
+            return Ok(Some(alias));
+        }
+        let sql = format!(
+            "SELECT NickName FROM Contact WHERE UserName={}",
+            utils::sql_quote_wxid(wechat_id)?
+        );
+        let resp = self
+            .exec_sql(constants::DB_MICRO_MSG.to_string(), sql)
+            .await?;
+        // the first row is column names
         Ok(resp
-            .members
-            .split("^G")
-            .map(|str| str.to_string())
-            .collect::<Vec<String>>())
+            .get(1)
+            .and_then(|row| row.first())
+            .filter(|nickname| !nickname.is_empty())
+            .cloned())
+    }
+
+    // group aliases of members in the RoomData of ChatRoom table
+    async fn get_group_member_aliases(&self, group_id: &str) -> Result<HashMap<String, String>> {
+        let sql = format!(
+            "SELECT RoomData FROM ChatRoom WHERE ChatRoomName={}",
+            utils::sql_quote_wxid(group_id)?
+        );
+        let resp = self
+            .exec_sql(constants::DB_MICRO_MSG.to_string(), sql)
+            .await?;
+        // the first row is column names
+        match resp.get(1).and_then(|row| row.first()) {
+            Some(room_data) if !room_data.is_empty() => roomdata::parse_member_aliases(room_data),
+            _ => Ok(HashMap::new()),
+        }
     }
 
     pub async fn get_group_member_nickname(
         &self,
         group_id: String,
         wechat_id: String,
-    ) -> anyhow::Result<String> {
+    ) -> Result<String> {
+        // the hook api is slow and returns empty string for members without group alias
+        match self
+            .get_group_member_display_name(&group_id, &wechat_id)
+            .await
+        {
+            Ok(Some(nickname)) => return Ok(nickname),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "query nickname of {} in group {} from db failed: {}",
+                wechat_id, group_id, e
+            ),
+        }
+        self.get_group_member_nickname_by_hook(group_id, wechat_id)
+            .await
+    }
+
+    async fn get_group_member_nickname_by_hook(
+        &self,
+        group_id: String,
+        wechat_id: String,
+    ) -> Result<String> {
         #[derive(Deserialize)]
         struct WechatGetGroupMemberNicknameResp {
             nickname: String,
@@ -559,7 +1608,7 @@ impl WechatInstance {
         Ok(resp.nickname)
     }
 
-    pub async fn get_group_list(&self) -> anyhow::Result<Vec<WechatGroupInfo>> {
+    pub async fn get_group_list(&self) -> Result<Vec<WechatGroupInfo>> {
         Ok(self
             .get_micro_msg_contacts(None)
             .await?
@@ -570,10 +1619,32 @@ impl WechatInstance {
     }
 }
 
+/// result of send_message, which is the response of send_message command
+#[serde_with::serde_as]
+#[derive(Serialize, Clone, Debug)]
+pub struct SendMessageAck {
+    // id of the sent message if the hook reports it
+    #[serde(rename = "msgId")]
+    pub msg_id: Option<u64>,
+    #[serde_as(as = "serde_with::TimestampMilliSeconds<i64>")]
+    #[serde(rename = "ts")]
+    pub timestamp: DateTime<Utc>,
+    // wechat messages the content is sent as
+    pub chunks: u32,
+    // local copy of the sent media
+    #[serde(rename = "mediaPath")]
+    pub media_path: Option<String>,
+}
+
 // warp message send API including text, at, image and file
 impl WechatInstance {
-    pub async fn send_message(&self, msg: MatrixRequestDataMessage) -> anyhow::Result<()> {
-        match msg {
+    pub async fn send_message(
+        &self,
+        msg: MatrixRequestDataMessage,
+        max_media_size: usize,
+    ) -> Result<SendMessageAck> {
+        let mut media_path = None;
+        let msg_id = match msg {
             MatrixRequestDataMessage {
                 target,
                 content,
@@ -598,60 +1669,125 @@ impl WechatInstance {
 
             MatrixRequestDataMessage {
                 target,
-                message_type: MatrixMessageType::Image,
-                data: Some(MatrixMessageDataField::Media(media)),
+                message_type: MatrixMessageType::Image | MatrixMessageType::Video,
+                data:
+                    Some(data @ (MatrixMessageDataField::Media(_) | MatrixMessageDataField::Blob(_))),
                 ..
+            } => {
+                let path = self.save_outbound_media(data, max_media_size).await?;
+                media_path = Some(path.clone());
+                self.send_image(target, path).await?
             }
-            | MatrixRequestDataMessage {
+
+            MatrixRequestDataMessage {
                 target,
-                message_type: MatrixMessageType::Video,
-                data: Some(MatrixMessageDataField::Media(media)),
+                message_type: MatrixMessageType::File,
+                data:
+                    Some(data @ (MatrixMessageDataField::Media(_) | MatrixMessageDataField::Blob(_))),
                 ..
             } => {
-                let path = self.save_media(media).await?;
-                self.send_image(target, path).await?;
+                let path = self.save_outbound_media(data, max_media_size).await?;
+                media_path = Some(path.clone());
+                self.send_file(target, path).await?
             }
 
             MatrixRequestDataMessage {
                 target,
-                message_type: MatrixMessageType::File,
-                data: Some(MatrixMessageDataField::Media(media)),
+                message_type: MatrixMessageType::Contact,
+                data: Some(MatrixMessageDataField::Contact(contact)),
                 ..
-            } => {
-                let path = self.save_media(media).await?;
-                self.send_file(target, path).await?;
+            } => self.send_card(target, contact).await?,
+
+            MatrixRequestDataMessage {
+                message_type: MatrixMessageType::Unknown(message_type),
+                ..
+            } => return Err(Error::Unsupported(format!("message type {}", message_type))),
+
+            _ => {
+                return Err(Error::InvalidRequest(
+                    "message type and data are mismatched".to_string(),
+                ))
             }
+        };
+        Ok(SendMessageAck {
+            msg_id,
+            timestamp: Utc::now(),
+            chunks: 1,
+            media_path,
+        })
+    }
 
-            _ => bail!("message type and data are mismatched"),
+    // path of outbound media in data, which is downloaded or written inline
+    async fn save_outbound_media(
+        &self,
+        data: MatrixMessageDataField,
+        max_size: usize,
+    ) -> Result<String> {
+        match data {
+            MatrixMessageDataField::Media(media) => self.save_media(media, max_size).await,
+            MatrixMessageDataField::Blob(blob) => self.save_blob(blob, max_size).await,
+            _ => Err(Error::InvalidRequest(
+                "message type and data are mismatched".to_string(),
+            )),
         }
-        Ok(())
     }
 
-    async fn save_media(&self, media: MatrixMessageDataMedia) -> anyhow::Result<String> {
-        let media_blob = utils::get_file_maybe_gzip_decompress(media.url).await?;
-        let filepath = match media.name.len() {
-            0 => Path::new(&self.save_path)
-                .join("matrix_media")
-                .join(utils::calculate_md5(&media_blob)),
-            _ => Path::new(&self.save_path)
-                .join("matrix_media")
-                .join(media.name),
-        };
-        let mut file = File::create(filepath.clone()).await?;
-        file.write_all(&media_blob).await?;
-        match filepath.into_os_string().into_string() {
-            Ok(p) => Ok(p),
-            Err(e) => bail!("convert filepath {:?} failed", e),
+    async fn save_media(&self, media: MatrixMessageDataMedia, max_size: usize) -> Result<String> {
+        if !utils::is_http_url(&media.url) {
+            return Err(Error::InvalidRequest(format!(
+                "media url {} is not http(s)",
+                media.url
+            )));
         }
+        let dir = Path::new(&self.save_path).join("matrix_media");
+        tokio::fs::create_dir_all(&dir).await?;
+        let part_path = self.part_path(&dir, &utils::calculate_md5(media.url.as_bytes()));
+        let md5 = utils::download_resumable(
+            &self.media_client,
+            &media.url,
+            &part_path,
+            utils::DownloadOptions {
+                max_size,
+                content_type: None,
+            },
+            constants::MAX_DOWNLOAD_ATTEMPTS,
+        )
+        .await?;
+        finish_media(&dir, &part_path, &media.name, md5).await
+    }
+
+    // <name>.<pid>.<seq>.part, unique among downloads of all instances in the save path
+    fn part_path(&self, dir: &Path, name: &str) -> PathBuf {
+        let seq = PART_FILE_SEQ.fetch_add(1, Ordering::Relaxed);
+        dir.join(format!("{}.{}.{}.part", name, self.pid, seq))
     }
 
-    pub async fn send_text(&self, recv_wechat_id: String, msg: String) -> anyhow::Result<()> {
-        self.wechat_hook_post_raw(
+    // media sent inline by the bridge, which saves downloading it again
+    async fn save_blob(&self, blob: MatrixMessageDataBlob, max_size: usize) -> Result<String> {
+        if blob.binary.is_empty() {
+            return Err(Error::InvalidRequest("media blob is empty".to_string()));
+        }
+        if blob.binary.len() > max_size {
+            return Err(Error::TooLarge(format!(
+                "media blob of {} bytes exceeds max size {}",
+                blob.binary.len(),
+                max_size
+            )));
+        }
+        let dir = Path::new(&self.save_path).join("matrix_media");
+        tokio::fs::create_dir_all(&dir).await?;
+        let md5 = utils::calculate_md5(&blob.binary);
+        let part_path = self.part_path(&dir, &md5);
+        tokio::fs::write(&part_path, &blob.binary).await?;
+        finish_media(&dir, &part_path, &blob.name.unwrap_or_default(), md5).await
+    }
+
+    pub async fn send_text(&self, recv_wechat_id: String, msg: String) -> Result<Option<u64>> {
+        self.wechat_hook_send(
             constants::WECHAT_MSG_SEND_TEXT,
             serde_json::json!({ "wxid": recv_wechat_id, "msg": msg }),
         )
-        .await?;
-        Ok(())
+        .await
     }
 
     pub async fn send_at_text(
@@ -659,9 +1795,9 @@ impl WechatInstance {
         recv_wechat_id: String,
         msg: String,
         mentions: Vec<String>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<Option<u64>> {
         let wechat_ids = mentions.join(",");
-        self.wechat_hook_post_raw(
+        self.wechat_hook_send(
             constants::WECHAT_MSG_SEND_AT,
             serde_json::json!({
                 "chatroom_id": recv_wechat_id,
@@ -670,32 +1806,63 @@ impl WechatInstance {
                 "auto_nickname": 0,
             }),
         )
-        .await?;
-        Ok(())
+        .await
+    }
+
+    /// share a contact in db as a card. Unknown contacts are rejected with not found
+    pub async fn send_card(
+        &self,
+        recv_wechat_id: String,
+        contact: MatrixMessageDataContact,
+    ) -> Result<Option<u64>> {
+        // a group is no contact of self, its name falls back to the id
+        let nickname = match (contact.nickname.is_empty(), classify_id(&contact.wxid)) {
+            (false, _) => contact.nickname,
+            (true, IdKind::Group) => self
+                .get_contact_by_id(contact.wxid.clone())
+                .await?
+                .map(|info| info.nickname)
+                .filter(|nickname| !nickname.is_empty())
+                .unwrap_or_else(|| contact.wxid.clone()),
+            (true, _) => {
+                self.get_contact_by_id(contact.wxid.clone())
+                    .await?
+                    .ok_or_else(|| Error::NotFound(format!("contact {} to share", contact.wxid)))?
+                    .nickname
+            }
+        };
+        let (api, body) = card_payload(recv_wechat_id, &contact.wxid, &nickname);
+        self.wechat_hook_send(api, body).await
     }
 
-    pub async fn send_image(&self, recv_wechat_id: String, img_path: String) -> anyhow::Result<()> {
-        self.wechat_hook_post_raw(
+    pub async fn send_image(
+        &self,
+        recv_wechat_id: String,
+        img_path: String,
+    ) -> Result<Option<u64>> {
+        self.wechat_hook_send(
             constants::WECHAT_MSG_SEND_IMAGE,
             serde_json::json!({
                 "receiver": recv_wechat_id,
                 "img_path": img_path,
             }),
         )
-        .await?;
-        Ok(())
+        .await
     }
 
-    pub async fn send_file(&self, recv_wechat_id: String, file_path: String) -> anyhow::Result<()> {
-        self.wechat_hook_post_raw(
+    pub async fn send_file(
+        &self,
+        recv_wechat_id: String,
+        file_path: String,
+    ) -> Result<Option<u64>> {
+        self.wechat_hook_send(
             constants::WECHAT_MSG_SEND_FILE,
             serde_json::json!({
                 "receiver": recv_wechat_id,
                 "file_path": file_path,
             }),
         )
-        .await?;
-        Ok(())
+        .await
     }
 }
 
@@ -705,8 +1872,6 @@ pub struct WechatMessage {
     pub pid: u32,
     #[serde(rename = "msgid")]
     pub message_id: u64,
-    #[serde(with = "ts_seconds")]
-    pub timestamp: DateTime<Utc>,
     // #[serde_as(as = "TimestampMilliSeconds<String, Flexible>")]
     // pub time: DateTime<Utc>,
     #[serde(rename = "wxid")]
@@ -753,10 +1918,20 @@ pub enum WechatMessageType {
 
 #[derive(Debug)]
 pub enum WechatMessageAppType {
+    Music = 3,
+    Video = 4,
+    Link = 5,
     File = 6,
     Sticker = 8,
+    ChatHistory = 19,
+    MiniProgram = 33,
+    // mini program shared as a link card
+    MiniProgramLink = 36,
+    // video of wechat channels
+    Channels = 51,
     Reply = 57,
     Notice = 87,
+    Transfer = 2000,
     Other,
 }
 
@@ -764,26 +1939,436 @@ impl TryFrom<u32> for WechatMessageAppType {
     type Error = ();
     fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
+            x if x == Self::Music as u32 => Ok(Self::Music),
+            x if x == Self::Video as u32 => Ok(Self::Video),
+            x if x == Self::Link as u32 => Ok(Self::Link),
             x if x == Self::File as u32 => Ok(Self::File),
             x if x == Self::Sticker as u32 => Ok(Self::Sticker),
+            x if x == Self::ChatHistory as u32 => Ok(Self::ChatHistory),
+            x if x == Self::MiniProgram as u32 => Ok(Self::MiniProgram),
+            x if x == Self::MiniProgramLink as u32 => Ok(Self::MiniProgramLink),
+            x if x == Self::Channels as u32 => Ok(Self::Channels),
             x if x == Self::Reply as u32 => Ok(Self::Reply),
             x if x == Self::Notice as u32 => Ok(Self::Notice),
+            x if x == Self::Transfer as u32 => Ok(Self::Transfer),
             _ => Ok(Self::Other),
         }
     }
 }
 
+impl WechatMessageAppType {
+    /// subtype of app messages emitted as App events. Types handled by themselves, like file, are other
+    pub fn subtype(&self) -> AppSubtype {
+        match self {
+            Self::Music => AppSubtype::Music,
+            Self::Video => AppSubtype::Video,
+            Self::Link => AppSubtype::Link,
+            Self::ChatHistory => AppSubtype::ChatHistory,
+            Self::MiniProgram | Self::MiniProgramLink => AppSubtype::MiniProgram,
+            Self::Channels => AppSubtype::Channels,
+            _ => AppSubtype::Other,
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for WechatMessageAppType {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         Ok(match u32::deserialize(deserializer)? {
+            x if x == Self::Music as u32 => Self::Music,
+            x if x == Self::Video as u32 => Self::Video,
+            x if x == Self::Link as u32 => Self::Link,
             x if x == Self::File as u32 => Self::File,
             x if x == Self::Sticker as u32 => Self::Sticker,
+            x if x == Self::ChatHistory as u32 => Self::ChatHistory,
+            x if x == Self::MiniProgram as u32 => Self::MiniProgram,
+            x if x == Self::MiniProgramLink as u32 => Self::MiniProgramLink,
+            x if x == Self::Channels as u32 => Self::Channels,
             x if x == Self::Reply as u32 => Self::Reply,
             x if x == Self::Notice as u32 => Self::Notice,
+            x if x == Self::Transfer as u32 => Self::Transfer,
             _ => Self::Other,
         })
     }
 }
+
+// a failed contact lookup fails the members it was for, while members named by neither alias nor
+// contact fail as not found
+fn merge_member_nicknames(
+    members: Vec<String>,
+    mut aliases: HashMap<String, String>,
+    contacts: Result<HashMap<String, String>>,
+) -> Result<BatchResult<WechatGroupMemberNickname>> {
+    let (mut contacts, lookup_error) = match contacts {
+        Ok(contacts) => (contacts, None),
+        Err(e) => (HashMap::new(), Some(e)),
+    };
+    let mut result = BatchResult::new();
+    let mut unlooked = vec![];
+    for id in members {
+        match aliases.remove(&id).or_else(|| contacts.remove(&id)) {
+            Some(nickname) => {
+                result.record(id.clone(), Ok(WechatGroupMemberNickname { id, nickname }))?
+            }
+            None if lookup_error.is_some() => unlooked.push(id),
+            None => {
+                let e = Error::NotFound(format!("nickname of {}", id));
+                result.record(id, Err(e))?
+            }
+        }
+    }
+    if let Some(e) = lookup_error {
+        result.record_all(unlooked, e)?;
+    }
+    Ok(result)
+}
+
+// a user is shared through the card api, which looks the id up in the contacts. A group is not
+// found there, therefore, its card is sent as xml
+fn card_payload(receiver: String, shared_id: &str, nickname: &str) -> (u32, serde_json::Value) {
+    match classify_id(shared_id) {
+        IdKind::Group => (
+            constants::WECHAT_MSG_SEND_XML,
+            serde_json::json!({
+                "receiver": receiver,
+                "xml": format!(
+                    r#"<msg username="{}" nickname="{}" fullpy="" shortpy="" alias="" imagestatus="3" scene="17" certflag="0" />"#,
+                    quick_xml::escape::escape(shared_id),
+                    quick_xml::escape::escape(nickname),
+                ),
+                "img_path": "",
+                "msg_type": constants::WECHAT_CARD_XML_MSG_TYPE,
+            }),
+        ),
+        IdKind::User | IdKind::Enterprise => (
+            constants::WECHAT_MSG_SEND_CARD,
+            serde_json::json!({
+                "receiver": receiver,
+                "shared_wxid": shared_id,
+                "nickname": nickname,
+            }),
+        ),
+    }
+}
+
+// members of the hook and the db are merged since the hook truncates very large rooms. The count
+// is never less than the merged members, e.g. when the group has no row in db
+fn merge_group_members(group_id: &str, hook: &str, db: Option<&str>) -> (Vec<String>, usize) {
+    let mut member_ids = split_group_members(group_id, hook);
+    let db_count = db.map(|db| {
+        let db_member_ids = split_group_members(group_id, db);
+        if db_member_ids.len() > member_ids.len() {
+            info!(
+                "hook returns {} members of group {} while db has {}. merge them",
+                member_ids.len(),
+                group_id,
+                db_member_ids.len()
+            );
+        }
+        let count = db_member_ids.len();
+        member_ids.extend(db_member_ids);
+        count
+    });
+    member_ids.sort();
+    member_ids.dedup();
+    let count = db_count.unwrap_or(0).max(member_ids.len());
+    (member_ids, count)
+}
+
+fn split_group_members(group_id: &str, members: &str) -> Vec<String> {
+    members
+        .split("^G")
+        .filter(|id| *id != group_id && utils::is_valid_wxid(id))
+        .map(|id| id.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::send::ErrorCode;
+
+    fn names(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(id, name)| (id.to_string(), name.to_string()))
+            .collect()
+    }
+
+    fn members() -> Vec<String> {
+        vec![
+            "wxid_a".to_string(),
+            "wxid_b".to_string(),
+            "wxid_c".to_string(),
+        ]
+    }
+
+    fn instance(db_names: HashMap<String, Vec<String>>) -> WechatInstance {
+        WechatInstance {
+            port: 0,
+            message_hook_port: 0,
+            save_path: String::new(),
+            pid: 0,
+            mxid: "@a:x".to_string(),
+            client: reqwest::Client::new(),
+            media_client: utils::GuardedClient::new(reqwest::Client::builder()).unwrap(),
+            avatar_checker: None,
+            db_handles: Arc::new(RwLock::new(HashMap::new())),
+            db_names: Arc::new(db_names),
+            db_handle_cache_hits: Arc::new(AtomicU64::new(0)),
+            db_handle_cache_misses: Arc::new(AtomicU64::new(0)),
+            version: None,
+            info_cache: None,
+            self_wxid: Arc::new(RwLock::new(None)),
+            hook_recovery: Arc::new(tokio::sync::Mutex::new(())),
+            slow_threshold: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_handles_reported_by_path_or_alias_are_known_as_cached() {
+        let ins = instance(HashMap::from([(
+            constants::DB_MICRO_MSG.to_string(),
+            vec!["MicroMsg.db".to_string(), "MicroMsg".to_string()],
+        )]));
+        *ins.db_handles.write().unwrap() = HashMap::from([(
+            r"C:\Users\a\Documents\WeChat Files\wxid_a\Msg\MicroMsg.db".to_string(),
+            7,
+        )]);
+        assert_eq!(
+            ins.get_db_handle_by_name(constants::DB_MICRO_MSG)
+                .await
+                .unwrap(),
+            (7, true)
+        );
+
+        *ins.db_handles.write().unwrap() = HashMap::from([("MicroMsg".to_string(), 8)]);
+        assert_eq!(
+            ins.get_db_handle_by_name(constants::DB_MICRO_MSG)
+                .await
+                .unwrap(),
+            (8, true)
+        );
+        assert_eq!(ins.db_handle_cache_stats(), (2, 0));
+    }
+
+    #[test]
+    fn member_nicknames_prefer_aliases_and_fail_unknown_members() {
+        let result = merge_member_nicknames(
+            members(),
+            names(&[("wxid_a", "alias a")]),
+            Ok(names(&[("wxid_a", "nick a"), ("wxid_b", "nick b")])),
+        )
+        .unwrap();
+        let succeeded = result
+            .succeeded
+            .iter()
+            .map(|m| (m.id.as_str(), m.nickname.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(succeeded, [("wxid_a", "alias a"), ("wxid_b", "nick b")]);
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].id, "wxid_c");
+        assert_eq!(result.failed[0].code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn failed_contact_lookup_fails_only_members_without_alias() {
+        let result = merge_member_nicknames(
+            members(),
+            names(&[("wxid_b", "alias b")]),
+            Err(Error::Db("locked".to_string())),
+        )
+        .unwrap();
+        assert_eq!(result.succeeded.len(), 1);
+        assert_eq!(result.succeeded[0].id, "wxid_b");
+        let failed = result
+            .failed
+            .iter()
+            .map(|f| f.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["wxid_a", "wxid_c"]);
+        assert!(result.failed.iter().all(|f| f.code == ErrorCode::Internal));
+
+        let result = merge_member_nicknames(members(), HashMap::new(), Err(Error::NotLoggedIn));
+        assert!(matches!(result, Err(Error::NotLoggedIn)));
+    }
+
+    #[test]
+    fn user_cards_are_sent_by_card_api() {
+        let (api, body) = card_payload("wxid_r".to_string(), "wxid_a", "A");
+        assert_eq!(api, constants::WECHAT_MSG_SEND_CARD);
+        assert_eq!(
+            body,
+            serde_json::json!({"receiver": "wxid_r", "shared_wxid": "wxid_a", "nickname": "A"})
+        );
+    }
+
+    #[test]
+    fn group_cards_are_sent_as_xml() {
+        let (api, body) = card_payload("wxid_r".to_string(), "1@chatroom", "a&b");
+        assert_eq!(api, constants::WECHAT_MSG_SEND_XML);
+        assert_eq!(body["receiver"], "wxid_r");
+        assert_eq!(body["msg_type"], constants::WECHAT_CARD_XML_MSG_TYPE);
+        let xml = body["xml"].as_str().unwrap();
+        assert!(xml.contains(r#"username="1@chatroom""#));
+        assert!(xml.contains(r#"nickname="a&amp;b""#));
+        assert!(body.get("shared_wxid").is_none());
+    }
+
+    #[test]
+    fn is_login_body_is_parsed_on_success() {
+        let resp: WechatCheckLoginResp = parse_hook_resp(
+            constants::WECHAT_IS_LOGIN,
+            br#"{"is_login": 1, "result": "OK"}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.is_login, 1);
+        assert!(is_ok_result(&resp.result));
+
+        // older drivers spell the field in camel case and success as a number
+        let resp: WechatCheckLoginResp = parse_hook_resp(
+            constants::WECHAT_IS_LOGIN,
+            br#"{"isLogin": 0, "result": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.is_login, 0);
+        assert!(is_ok_result(&resp.result));
+    }
+
+    #[test]
+    fn is_login_error_body_keeps_the_hook_message() {
+        let err = parse_hook_resp::<WechatCheckLoginResp>(
+            constants::WECHAT_IS_LOGIN,
+            br#"{"msg": "hook is not ready", "result": "ERROR"}"#,
+        )
+        .err()
+        .unwrap();
+        match err {
+            Error::HookResult(msg) => assert!(msg.contains("hook is not ready"), "{}", msg),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn get_self_body_is_parsed_on_success() {
+        let resp: WechatGetSelfResp = parse_hook_resp(
+            constants::WECHAT_GET_SELF_INFO,
+            r#"{"result": "OK!", "data": {"wxId": "wxid_self", "wxNickName": "me",
+                "wxBigAvatar": "", "wxRemark": null}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(is_ok_result(&resp.result));
+        assert_eq!(resp.data.id, "wxid_self");
+        assert_eq!(resp.data.nickname, "me");
+        assert_eq!(resp.data.avatar, None);
+    }
+
+    #[test]
+    fn get_self_error_body_is_not_a_parse_error() {
+        let err = parse_hook_resp::<WechatGetSelfResp>(
+            constants::WECHAT_GET_SELF_INFO,
+            br#"{"msg": "not logged in", "result": "ERROR"}"#,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, Error::HookResult(_)), "{:?}", err);
+        assert_eq!(err.code(), ErrorCode::HookFailed);
+    }
+
+    fn contact(username: &str, open_im: bool) -> ContactInfo {
+        ContactInfo {
+            username: username.to_string(),
+            nickname: "nick".to_string(),
+            avatar_url: None,
+            remark: String::new(),
+            open_im,
+        }
+    }
+
+    #[test]
+    fn micro_msg_contacts_are_enterprise_only_by_openim_suffix() {
+        let info = WechatUserInfo::from(contact("wxid_abc", false));
+        assert!(!info.is_enterprise);
+        assert_eq!(serde_json::to_value(&info).unwrap()["isEnterprise"], false);
+
+        // MicroMsg keeps WeWork contacts the account has chatted with
+        let info = WechatUserInfo::from(contact("1688850000000000@openim", false));
+        assert!(info.is_enterprise);
+    }
+
+    #[test]
+    fn open_im_contacts_are_enterprise() {
+        let info = WechatUserInfo::from(contact("1688850000000000@openim", true));
+        assert!(info.is_enterprise);
+        assert_eq!(serde_json::to_value(&info).unwrap()["isEnterprise"], true);
+
+        // the db tells the source even when the id has no suffix
+        assert!(WechatUserInfo::from(contact("wework_abc", true)).is_enterprise);
+    }
+
+    #[test]
+    fn self_info_without_enterprise_flag_is_personal() {
+        let info: WechatUserInfo = serde_json::from_str(
+            r#"{"wxId": "wxid_abc", "wxNickName": "nick", "wxBigAvatar": "", "wxRemark": null}"#,
+        )
+        .unwrap();
+        assert!(!info.is_enterprise);
+        assert_eq!(info.avatar, None);
+    }
+
+    #[test]
+    fn truncated_hook_members_are_merged_with_db() {
+        let (members, count) = merge_group_members(
+            "1@chatroom",
+            "wxid_a^Gwxid_b",
+            Some("wxid_a^Gwxid_b^Gwxid_c^G1@chatroom"),
+        );
+        assert_eq!(members, vec!["wxid_a", "wxid_b", "wxid_c"]);
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn members_missing_in_db_are_counted_from_hook() {
+        let (members, count) = merge_group_members("1@chatroom", "wxid_b^Gwxid_a", None);
+        assert_eq!(members, vec!["wxid_a", "wxid_b"]);
+        assert_eq!(count, 2);
+
+        // a row without members must not zero the count either
+        let (_, count) = merge_group_members("1@chatroom", "wxid_b^Gwxid_a", Some(""));
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn hook_success_forms_are_ok() {
+        for result in [
+            serde_json::json!("OK"),
+            serde_json::json!("ok"),
+            serde_json::json!("OK!"),
+            serde_json::json!(" Ok "),
+            serde_json::json!("1"),
+            serde_json::json!(1),
+            serde_json::json!(true),
+        ] {
+            assert!(is_ok_result(&result), "{}", result);
+        }
+    }
+
+    #[test]
+    fn hook_failure_forms_are_not_ok() {
+        for result in [
+            serde_json::json!("ERROR"),
+            serde_json::json!("okay"),
+            serde_json::json!(""),
+            serde_json::json!(0),
+            serde_json::json!(-1),
+            serde_json::json!(false),
+            serde_json::json!(null),
+            serde_json::json!({"result": "OK"}),
+        ] {
+            assert!(!is_ok_result(&result), "{}", result);
+        }
+    }
+}
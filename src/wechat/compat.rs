@@ -0,0 +1,37 @@
+use log::warn;
+
+// major.minor of wechat builds which the driver dll is known to hook
+const SUPPORTED_VERSIONS: [&str; 2] = ["3.7", "3.8"];
+
+/// whether the driver dll is known to hook the wechat version. Other versions are warned about
+/// since the hook api may reject calls or answer them in another shape
+pub fn check_version(version: &str) -> bool {
+    let supported = SUPPORTED_VERSIONS.iter().any(|v| {
+        version
+            .strip_prefix(v)
+            .map(|rest| rest.is_empty() || rest.starts_with('.'))
+            .unwrap_or(false)
+    });
+    if !supported {
+        warn!(
+            "unsupported wechat version {}. hook api may not work as expected",
+            version
+        );
+    }
+    supported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_matched_by_major_and_minor() {
+        assert!(check_version("3.7.0.30"));
+        assert!(check_version("3.8.1.26"));
+        assert!(check_version("3.8"));
+        assert!(!check_version("3.80.0.1"));
+        assert!(!check_version("3.9.2.23"));
+        assert!(!check_version(""));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use super::{WechatGroupInfo, WechatUserInfo};
+
+/// user and group infos read from wechat db, keyed by wxid. A room sync queries the same contacts
+/// again and again, therefore, they are answered from here within the ttl
+#[derive(Debug)]
+pub struct InfoCache {
+    ttl: Duration,
+    users: Mutex<HashMap<String, (Instant, WechatUserInfo)>>,
+    groups: Mutex<HashMap<String, (Instant, WechatGroupInfo)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl InfoCache {
+    pub fn new(ttl: Duration) -> InfoCache {
+        InfoCache {
+            ttl,
+            users: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn user(&self, wxid: &str) -> Option<WechatUserInfo> {
+        self.count(get(&self.users, wxid, self.ttl))
+    }
+
+    pub fn group(&self, wxid: &str) -> Option<WechatGroupInfo> {
+        self.count(get(&self.groups, wxid, self.ttl))
+    }
+
+    pub fn put_user(&self, info: &WechatUserInfo) {
+        put(&self.users, &info.id, info.clone());
+    }
+
+    pub fn put_group(&self, info: &WechatGroupInfo) {
+        put(&self.groups, &info.id, info.clone());
+    }
+
+    /// drop the infos of wxid, e.g. after a system message tells it is renamed
+    pub fn invalidate(&self, wxid: &str) {
+        remove(&self.users, wxid);
+        remove(&self.groups, wxid);
+    }
+
+    /// drop all infos, e.g. on logout since remarks belong to the account
+    pub fn clear(&self) {
+        clear(&self.users);
+        clear(&self.groups);
+    }
+
+    /// (hits, misses)
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn count<T>(&self, info: Option<T>) -> Option<T> {
+        match info.is_some() {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        info
+    }
+}
+
+// expired entries are removed when they are looked up
+fn get<T: Clone>(
+    infos: &Mutex<HashMap<String, (Instant, T)>>,
+    wxid: &str,
+    ttl: Duration,
+) -> Option<T> {
+    let mut infos = match infos.lock() {
+        Ok(infos) => infos,
+        Err(err) => {
+            error!("lock info cache failed: {}", err);
+            return None;
+        }
+    };
+    match infos.get(wxid) {
+        Some((cached_at, info)) if cached_at.elapsed() < ttl => Some(info.clone()),
+        Some(_) => {
+            infos.remove(wxid);
+            None
+        }
+        None => None,
+    }
+}
+
+fn put<T>(infos: &Mutex<HashMap<String, (Instant, T)>>, wxid: &str, info: T) {
+    match infos.lock() {
+        Ok(mut infos) => {
+            infos.insert(wxid.to_string(), (Instant::now(), info));
+        }
+        Err(err) => error!("lock info cache failed: {}", err),
+    }
+}
+
+fn remove<T>(infos: &Mutex<HashMap<String, (Instant, T)>>, wxid: &str) {
+    match infos.lock() {
+        Ok(mut infos) => {
+            infos.remove(wxid);
+        }
+        Err(err) => error!("lock info cache failed: {}", err),
+    }
+}
+
+fn clear<T>(infos: &Mutex<HashMap<String, (Instant, T)>>) {
+    match infos.lock() {
+        Ok(mut infos) => infos.clear(),
+        Err(err) => error!("lock info cache failed: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(wxid: &str) -> WechatUserInfo {
+        WechatUserInfo {
+            id: wxid.to_string(),
+            nickname: "nick".to_string(),
+            avatar: None,
+            remark: None,
+            is_enterprise: false,
+        }
+    }
+
+    #[test]
+    fn lookups_are_counted_as_hits_and_misses() {
+        let cache = InfoCache::new(Duration::from_secs(60));
+        assert!(cache.user("wxid_a").is_none());
+        cache.put_user(&user("wxid_a"));
+        assert_eq!(cache.user("wxid_a").unwrap().nickname, "nick");
+        assert!(cache.group("wxid_a").is_none());
+        assert_eq!(cache.stats(), (1, 2));
+    }
+
+    #[test]
+    fn expired_and_invalidated_infos_are_misses() {
+        let cache = InfoCache::new(Duration::ZERO);
+        cache.put_user(&user("wxid_a"));
+        assert!(cache.user("wxid_a").is_none());
+
+        let cache = InfoCache::new(Duration::from_secs(60));
+        cache.put_user(&user("wxid_a"));
+        cache.invalidate("wxid_a");
+        assert!(cache.user("wxid_a").is_none());
+        assert_eq!(cache.stats(), (0, 1));
+    }
+}
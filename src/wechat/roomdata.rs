@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use base64::Engine;
+
+use crate::error::{Error, Result};
+
+// protobuf wire types used by RoomData
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// parse member -> group alias from the RoomData blob of ChatRoom table. The blob is a protobuf message
+/// whose field 1 is repeated members of `{1: wxid, 2: alias}`. Members without alias are skipped
+pub fn parse_member_aliases(encoded: &str) -> Result<HashMap<String, String>> {
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| Error::Parse(format!("decode RoomData failed: {}", e)))?;
+
+    let mut aliases = HashMap::new();
+    for (field, value) in Fields::new(&data) {
+        if field? != 1 {
+            continue;
+        }
+        let (mut wxid, mut alias) = (None, None);
+        for (member_field, member_value) in Fields::new(value) {
+            match member_field? {
+                1 => wxid = Some(String::from_utf8_lossy(member_value).to_string()),
+                2 => alias = Some(String::from_utf8_lossy(member_value).to_string()),
+                _ => {}
+            }
+        }
+        if let (Some(wxid), Some(alias)) = (wxid, alias) {
+            if !alias.is_empty() {
+                aliases.insert(wxid, alias);
+            }
+        }
+    }
+    Ok(aliases)
+}
+
+/// values of the BytesExtra blob of MSG table. The blob is a protobuf message whose field 3 is repeated
+/// `{1: type, 2: value}`. Values are like the sender wxid in groups and paths of media
+pub fn parse_extra_values(encoded: &str) -> Result<Vec<String>> {
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| Error::Parse(format!("decode BytesExtra failed: {}", e)))?;
+
+    let mut values = vec![];
+    for (field, value) in Fields::new(&data) {
+        if field? != 3 {
+            continue;
+        }
+        for (entry_field, entry_value) in Fields::new(value) {
+            if entry_field? == 2 {
+                values.push(String::from_utf8_lossy(entry_value).to_string());
+            }
+        }
+    }
+    Ok(values)
+}
+
+// iterate (field number, payload) of a protobuf message. Payload of non length-delimited fields is empty
+struct Fields<'a> {
+    data: &'a [u8],
+    failed: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Fields {
+            data,
+            failed: false,
+        }
+    }
+
+    fn read_varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for (i, b) in self.data.iter().enumerate().take(10) {
+            value |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                self.data = &self.data[i + 1..];
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn next_field(&mut self) -> Option<(u64, &'a [u8])> {
+        let key = self.read_varint()?;
+        let payload = match key & 0x7 {
+            WIRE_VARINT => self.read_varint().map(|_| &[][..])?,
+            WIRE_FIXED64 => self.take(8).map(|_| &[][..])?,
+            WIRE_LEN => {
+                let len = self.read_varint()?;
+                self.take(len as usize)?
+            }
+            WIRE_FIXED32 => self.take(4).map(|_| &[][..])?,
+            _ => return None,
+        };
+        Some((key >> 3, payload))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (Result<u64>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.data.is_empty() {
+            return None;
+        }
+        match self.next_field() {
+            Some((field, payload)) => Some((Ok(field), payload)),
+            None => {
+                self.failed = true;
+                Some((
+                    Err(Error::Parse("malformed protobuf blob".to_string())),
+                    &[][..],
+                ))
+            }
+        }
+    }
+}
@@ -1,33 +1,151 @@
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 
+use crate::constants;
+
+///
+/// data enum which the bridge tags with "kind" to pick the variant explicitly. Data without "kind"
+/// is matched by shape against the untagged variants in order for old bridges. A tagged variant
+/// may be read as another type which converts into the payload, e.g. when the payload is no map
+///
+macro_rules! kind_tagged {
+    (
+        $(#[$attr:meta])*
+        pub enum $name:ident {
+            $($(#[$vattr:meta])* $kind:literal => $variant:ident($ty:ty $(, $tagged:ty)?)),* $(,)?
+        }
+        untagged {
+            $($untagged:ident($uty:ty)),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[serde(try_from = "serde_json::Value")]
+        pub enum $name {
+            $($(#[$vattr])* $variant($ty)),*
+        }
+
+        const _: () = {
+            #[derive(serde::Deserialize)]
+            #[serde(tag = "kind")]
+            enum Tagged {
+                $(#[serde(rename = $kind)] $variant(kind_tagged!(@type $ty $(, $tagged)?))),*
+            }
+
+            #[derive(serde::Deserialize)]
+            #[serde(untagged)]
+            enum Untagged {
+                $($untagged($uty)),*
+            }
+
+            impl TryFrom<serde_json::Value> for $name {
+                type Error = serde_json::Error;
+
+                fn try_from(value: serde_json::Value) -> std::result::Result<Self, Self::Error> {
+                    if value.get("kind").is_none() {
+                        return Ok(match serde_json::from_value::<Untagged>(value)? {
+                            $(Untagged::$untagged(d) => Self::$untagged(d)),*
+                        });
+                    }
+                    Ok(match serde_json::from_value::<Tagged>(value)? {
+                        $(Tagged::$variant(d) => Self::$variant(kind_tagged!(@into d $(, $tagged)?))),*
+                    })
+                }
+            }
+        };
+    };
+    (@type $ty:ty) => { $ty };
+    (@type $ty:ty, $tagged:ty) => { $tagged };
+    (@into $d:ident) => { $d };
+    (@into $d:ident, $tagged:ty) => { From::from($d) };
+}
+
 pub mod recv;
 pub mod send;
 
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// check protocol versions announced by the bridge in websocket upgrade response.
+/// Bridges which announce nothing predate the handshake and are assumed compatible
+///
+pub fn check_bridge_protocol(
+    version: Option<&str>,
+    min_version: Option<&str>,
+) -> Result<(), String> {
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("invalid protocol version {:?}", v))
+    };
+    if let Some(version) = version {
+        let version = parse(version)?;
+        if version < constants::MIN_BRIDGE_PROTOCOL_VERSION {
+            return Err(format!(
+                "bridge protocol version {} is older than {} required by agent. please upgrade the bridge",
+                version,
+                constants::MIN_BRIDGE_PROTOCOL_VERSION
+            ));
+        }
+    }
+    if let Some(min_version) = min_version {
+        let min_version = parse(min_version)?;
+        if min_version > constants::PROTOCOL_VERSION {
+            return Err(format!(
+                "bridge requires protocol version {} but agent speaks {}. please upgrade the agent",
+                min_version,
+                constants::PROTOCOL_VERSION
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde_with::serde_as]
 pub enum CommandType {
+    #[serde(rename = "capabilities")]
+    Capabilities,
     #[serde(rename = "connect")]
     Connect,
     #[serde(rename = "disconnect")]
     Disconnect,
+    #[serde(rename = "restart_instance")]
+    RestartInstance,
     #[serde(rename = "login_qr")]
     LoginWithQRCode,
     #[serde(rename = "is_login")]
     IsLogin,
     #[serde(rename = "get_self")]
     GetSelf,
+    #[serde(rename = "get_self_qrcode")]
+    GetSelfQRCode,
     #[serde(rename = "get_user_info")]
     GetUserInfo,
+    #[serde(rename = "batch_get_user_info")]
+    BatchGetUserInfo,
+    #[serde(rename = "get_raw_contact")]
+    GetRawContact,
     #[serde(rename = "get_group_info")]
     GetGroupInfo,
     #[serde(rename = "get_group_members")]
     GetGroupMembers,
     #[serde(rename = "get_group_member_nickname")]
     GetGroupMemberNickname,
+    #[serde(rename = "get_group_member_nicknames")]
+    GetGroupMemberNicknames,
+    #[serde(rename = "get_group_qrcode")]
+    GetGroupQRCode,
+    #[serde(rename = "create_group")]
+    CreateGroup,
+    #[serde(rename = "quit_group")]
+    QuitGroup,
     #[serde(rename = "get_friend_list")]
     GetFriendList,
     #[serde(rename = "get_group_list")]
     GetGroupList,
+    // counts are read only. The hook has no api to mark a chat read, and zeroing the Session row
+    // would neither clear the badge of the running client nor sync to the phone
+    #[serde(rename = "get_unread_counts")]
+    GetUnreadCounts,
     #[serde(rename = "send_message")]
     SendMessage,
     #[serde(rename = "response")]
@@ -36,24 +154,83 @@ pub enum CommandType {
     Error,
     #[serde(rename = "ping")]
     Ping,
+    #[serde(rename = "ack")]
+    Ack,
+    #[serde(rename = "mute_chat")]
+    MuteChat,
+    #[serde(rename = "unmute_chat")]
+    UnmuteChat,
+    #[serde(rename = "watch_group_members")]
+    WatchGroupMembers,
+    #[serde(rename = "unwatch_group_members")]
+    UnwatchGroupMembers,
+    #[serde(rename = "replay")]
+    Replay,
+    #[serde(rename = "get_avatar")]
+    GetAvatar,
+    #[serde(rename = "get_media_manifest")]
+    GetMediaManifest,
+    #[serde(rename = "get_history_image")]
+    GetHistoryImage,
+    #[serde(rename = "set_hook_port")]
+    SetHookPort,
+    #[serde(rename = "get_stats")]
+    GetStats,
+    #[serde(rename = "accept_friend_request")]
+    AcceptFriendRequest,
     #[serde(rename = "__websocket_closed")]
     WebsocketClosed,
+    // commands of newer bridges. They are answered with an unsupported error instead of being dropped
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+// bridge should set "kind" to pick the variant explicitly. Data without "kind" is matched by shape for old bridges
+kind_tagged! {
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    #[serde(untagged)]
+    pub enum MatrixMessageDataField {
+        "mentions" => Mentions(Vec<String>, MatrixMessageDataMentions),
+        "blob" => Blob(MatrixMessageDataBlob),
+        "location" => Location(MatrixMessageDataLocation),
+        "media" => Media(MatrixMessageDataMedia),
+        "link" => Link(MatrixMessageDataLink),
+        "transfer" => Transfer(MatrixMessageDataTransfer),
+        "login" => Login(MatrixMessageDataLogin),
+        // it has the shape of login, therefore, it is only matched by kind
+        "contact" => Contact(MatrixMessageDataContact),
+        // it is only sent by agent, therefore, it is only matched by kind
+        "voip" => Voip(MatrixMessageDataVoip),
+    }
+    untagged {
+        Mentions(Vec<String>),
+        Blob(MatrixMessageDataBlob),
+        Location(MatrixMessageDataLocation),
+        Media(MatrixMessageDataMedia),
+        Link(MatrixMessageDataLink),
+        Transfer(MatrixMessageDataTransfer),
+        Login(MatrixMessageDataLogin),
+    }
+}
+
+// tagged mentions. A list cannot carry "kind" by itself
+#[derive(Deserialize)]
+pub struct MatrixMessageDataMentions {
+    mentions: Vec<String>,
+}
+
+impl From<MatrixMessageDataMentions> for Vec<String> {
+    fn from(data: MatrixMessageDataMentions) -> Self {
+        data.mentions
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
-#[serde_with::serde_as]
-#[serde(untagged)]
-pub enum MatrixMessageDataField {
-    Mentions(Vec<String>),
-    Blob(MatrixMessageDataBlob),
-    Location {
-        name: String,
-        address: String,
-        longitude: f64,
-        latitude: f64,
-    },
-    Media(MatrixMessageDataMedia),
-    Link(MatrixMessageDataLink),
+pub struct MatrixMessageDataLocation {
+    pub name: String,
+    pub address: String,
+    pub longitude: f64,
+    pub latitude: f64,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -62,6 +239,9 @@ pub struct MatrixMessageDataBlob {
     pub name: Option<String>,
     #[serde_as(as = "Bytes")]
     pub binary: Vec<u8>,
+    // local file the binary is read from. It is read again to resend media which failed to deliver
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -76,4 +256,245 @@ pub struct MatrixMessageDataLink {
     pub title: String,
     pub des: String,
     pub url: String,
+    // what the app message is, so that the bridge can render a music share unlike a mini program
+    #[serde(default)]
+    pub subtype: AppSubtype,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppSubtype {
+    #[serde(rename = "link")]
+    Link,
+    #[serde(rename = "music")]
+    Music,
+    #[serde(rename = "video")]
+    Video,
+    #[serde(rename = "mini_program")]
+    MiniProgram,
+    #[serde(rename = "chat_history")]
+    ChatHistory,
+    #[serde(rename = "channels")]
+    Channels,
+    #[default]
+    #[serde(other, rename = "other")]
+    Other,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct MatrixMessageDataLogin {
+    #[serde(rename = "wxId")]
+    pub wxid: String,
+    pub nickname: String,
+}
+
+// contact shared as a card. Nickname shown on the card defaults to the one in contact db
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct MatrixMessageDataContact {
+    #[serde(rename = "wxId")]
+    pub wxid: String,
+    #[serde(default)]
+    pub nickname: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct MatrixMessageDataTransfer {
+    #[serde(rename = "transferId")]
+    pub transfer_id: String,
+    pub status: TransferStatus,
+    pub fee: String,
+    pub memo: String,
+}
+
+// outcome of a private call. Status and message are the raw ones if the outcome is unknown
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct MatrixMessageDataVoip {
+    pub outcome: VoipOutcome,
+    // seconds of a completed call
+    pub duration: Option<u32>,
+    pub status: Option<u32>,
+    pub message: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoipOutcome {
+    #[serde(rename = "started")]
+    Started,
+    #[serde(rename = "ended")]
+    Ended,
+    #[serde(rename = "completed")]
+    Completed,
+    #[serde(rename = "missed")]
+    Missed,
+    #[serde(rename = "declined")]
+    Declined,
+    #[serde(rename = "cancelled")]
+    Cancelled,
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    #[serde(rename = "sent")]
+    Sent,
+    #[serde(rename = "accepted")]
+    Accepted,
+    #[serde(rename = "refunded")]
+    Refunded,
+    #[serde(rename = "expired")]
+    Expired,
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(value: serde_json::Value) -> MatrixMessageDataField {
+        serde_json::from_value(value).unwrap()
+    }
+
+    // what agent emits is read back as the same variant, by shape and by kind
+    fn round_trip(field: MatrixMessageDataField, kind: &str) -> [MatrixMessageDataField; 2] {
+        let value = serde_json::to_value(&field).unwrap();
+        let mut tagged = value.clone();
+        tagged
+            .as_object_mut()
+            .unwrap()
+            .insert("kind".to_string(), json!(kind));
+        [parse(value), parse(tagged)]
+    }
+
+    #[test]
+    fn mentions_are_a_list_or_tagged_object() {
+        let ids = vec!["wxid_a".to_string(), "wxid_b".to_string()];
+        assert!(matches!(parse(json!(["wxid_a", "wxid_b"])),
+            MatrixMessageDataField::Mentions(m) if m == ids));
+        assert!(matches!(
+            parse(json!({"kind": "mentions", "mentions": ["wxid_a", "wxid_b"]})),
+            MatrixMessageDataField::Mentions(m) if m == ids
+        ));
+    }
+
+    #[test]
+    fn blob_round_trips() {
+        let blob = MatrixMessageDataField::Blob(MatrixMessageDataBlob {
+            name: Some("a.png".to_string()),
+            binary: vec![1, 2, 3],
+            source: None,
+        });
+        for field in round_trip(blob, "blob") {
+            match field {
+                MatrixMessageDataField::Blob(b) => {
+                    assert_eq!(b.name.as_deref(), Some("a.png"));
+                    assert_eq!(b.binary, vec![1, 2, 3]);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn location_round_trips() {
+        let location = MatrixMessageDataField::Location(MatrixMessageDataLocation {
+            name: "Tiananmen".to_string(),
+            address: "Beijing".to_string(),
+            longitude: 116.4,
+            latitude: 39.9,
+        });
+        for field in round_trip(location, "location") {
+            match field {
+                MatrixMessageDataField::Location(l) => {
+                    assert_eq!(l.name, "Tiananmen");
+                    assert_eq!(l.longitude, 116.4);
+                    assert_eq!(l.latitude, 39.9);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn media_and_link_round_trip_as_themselves() {
+        let media = MatrixMessageDataField::Media(MatrixMessageDataMedia {
+            name: "a.pdf".to_string(),
+            url: "https://example.com/a.pdf".to_string(),
+        });
+        for field in round_trip(media, "media") {
+            assert!(matches!(field, MatrixMessageDataField::Media(m) if m.name == "a.pdf"));
+        }
+
+        let link = MatrixMessageDataField::Link(MatrixMessageDataLink {
+            title: "news".to_string(),
+            des: "today".to_string(),
+            url: "https://example.com".to_string(),
+            subtype: AppSubtype::Music,
+        });
+        for field in round_trip(link, "link") {
+            match field {
+                MatrixMessageDataField::Link(l) => {
+                    assert_eq!(l.title, "news");
+                    assert_eq!(l.subtype, AppSubtype::Music);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn transfer_and_login_round_trip() {
+        let transfer = MatrixMessageDataField::Transfer(MatrixMessageDataTransfer {
+            transfer_id: "t1".to_string(),
+            status: TransferStatus::Refunded,
+            fee: "¥1.00".to_string(),
+            memo: String::new(),
+        });
+        for field in round_trip(transfer, "transfer") {
+            assert!(matches!(field, MatrixMessageDataField::Transfer(t)
+                if t.transfer_id == "t1" && t.status == TransferStatus::Refunded));
+        }
+
+        let login = MatrixMessageDataField::Login(MatrixMessageDataLogin {
+            wxid: "wxid_self".to_string(),
+            nickname: "me".to_string(),
+        });
+        for field in round_trip(login, "login") {
+            assert!(matches!(field, MatrixMessageDataField::Login(l) if l.wxid == "wxid_self"));
+        }
+    }
+
+    #[test]
+    fn contact_and_voip_are_only_matched_by_kind() {
+        let contact = json!({"wxId": "wxid_friend", "nickname": "friend"});
+        assert!(matches!(
+            parse(contact.clone()),
+            MatrixMessageDataField::Login(_)
+        ));
+        let mut tagged = contact;
+        tagged["kind"] = json!("contact");
+        assert!(matches!(parse(tagged),
+            MatrixMessageDataField::Contact(c) if c.wxid == "wxid_friend"));
+
+        let voip = json!({"kind": "voip", "outcome": "completed", "duration": 65,
+            "status": null, "message": null});
+        assert!(matches!(parse(voip),
+            MatrixMessageDataField::Voip(v) if v.outcome == VoipOutcome::Completed && v.duration == Some(65)));
+    }
+
+    #[test]
+    fn kind_decides_over_shape() {
+        // the shape of media, which the bridge means as a link without description
+        let err = serde_json::from_value::<MatrixMessageDataField>(
+            json!({"kind": "link", "name": "a", "url": "https://example.com"}),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("title"), "{}", err);
+
+        let err = serde_json::from_value::<MatrixMessageDataField>(json!({"kind": "sticker"}))
+            .unwrap_err();
+        assert!(err.to_string().contains("sticker"), "{}", err);
+    }
 }
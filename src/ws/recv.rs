@@ -10,13 +10,160 @@ pub struct WebsocketMatrixRequest {
     pub req_id: i32,
     pub command: CommandType,
     pub data: Option<MatrixRequestDataField>,
+    // data as sent, kept to explain why it does not fit the command
+    #[serde(skip)]
+    pub raw_data: Option<serde_json::Value>,
 }
 
-#[derive(serde::Deserialize, Debug)]
-#[serde(untagged)]
-pub enum MatrixRequestDataField {
-    Query(MatrixRequestDataQuery),
-    Message(MatrixRequestDataMessage),
+impl WebsocketMatrixRequest {
+    ///
+    /// parse a command and keep its raw data. Data of unknown commands may be of any shape, it is
+    /// skipped so that the command can still be answered
+    ///
+    pub fn parse(raw: &str) -> serde_json::Result<WebsocketMatrixRequest> {
+        let mut value = serde_json::from_str::<serde_json::Value>(raw)?;
+        let raw_data = value.get("data").filter(|data| !data.is_null()).cloned();
+        let mut msg = match serde_json::from_value::<WebsocketMatrixRequest>(value.clone()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                if let Some(envelope) = value.as_object_mut() {
+                    envelope.remove("data");
+                }
+                match serde_json::from_value::<WebsocketMatrixRequest>(value) {
+                    Ok(msg) if matches!(msg.command, CommandType::Unknown(_)) => msg,
+                    _ => return Err(e),
+                }
+            }
+        };
+        if matches!(msg.command, CommandType::Unknown(_)) {
+            msg.data = None;
+        }
+        msg.raw_data = raw_data;
+        Ok(msg)
+    }
+
+    ///
+    /// extract (mxid, req id) from a command which fails to parse so that the error can be sent
+    /// back to the bridge. Fields are scanned from the raw text if it is not even valid json
+    ///
+    pub fn peek_target(raw: &str) -> Option<(String, i32)> {
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(raw) {
+            let mxid = value.get("mxid")?.as_str()?.to_string();
+            let req_id = i32::try_from(value.get("req")?.as_i64()?).ok()?;
+            return Some((mxid, req_id));
+        }
+
+        let mxid = scan_field(raw, "mxid").and_then(|v| {
+            let end = string_end(v)?;
+            serde_json::from_str::<String>(&v[..end]).ok()
+        })?;
+        let req_id = scan_field(raw, "req").and_then(|v| {
+            let end = v
+                .char_indices()
+                .find(|(i, c)| !(c.is_ascii_digit() || (*i == 0 && *c == '-')))
+                .map(|(i, _)| i)
+                .unwrap_or(v.len());
+            v[..end].parse::<i32>().ok()
+        })?;
+        Some((mxid, req_id))
+    }
+}
+
+// text right after `"key":` with leading whitespace trimmed
+fn scan_field<'a>(raw: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!("\"{}\"", key);
+    raw.match_indices(&pattern).find_map(|(i, _)| {
+        let rest = raw[i + pattern.len()..].trim_start();
+        rest.strip_prefix(':').map(|v| v.trim_start())
+    })
+}
+
+// end of the json string literal at the beginning of v, closing quote included
+fn string_end(v: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in v.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+// bridge should set "kind" to pick the variant explicitly. Data without "kind" is matched by shape
+// for old bridges, which may take it as data of another command
+kind_tagged! {
+    #[derive(serde::Deserialize, Debug)]
+    pub enum MatrixRequestDataField {
+        "query" => Query(MatrixRequestDataQuery),
+        "message" => Message(MatrixRequestDataMessage),
+        "ack" => Ack(MatrixRequestDataAck),
+        "chat" => Chat(MatrixRequestDataChat),
+        "replay" => Replay(MatrixRequestDataReplay),
+        "port" => Port(MatrixRequestDataPort),
+        "login" => Login(MatrixRequestDataLogin),
+        "members" => Members(MatrixRequestDataMembers),
+        "avatar" => Avatar(MatrixRequestDataAvatar),
+        "page" => Page(MatrixRequestDataPage),
+        "history" => History(MatrixRequestDataHistory),
+        "friend_request" => FriendRequest(MatrixRequestDataFriendRequest),
+    }
+    untagged {
+        Query(MatrixRequestDataQuery),
+        Message(MatrixRequestDataMessage),
+        Ack(MatrixRequestDataAck),
+        // it has the fields of chat, therefore, it must be tried before
+        History(MatrixRequestDataHistory),
+        Chat(MatrixRequestDataChat),
+        Replay(MatrixRequestDataReplay),
+        Port(MatrixRequestDataPort),
+        Login(MatrixRequestDataLogin),
+        Members(MatrixRequestDataMembers),
+        Avatar(MatrixRequestDataAvatar),
+        FriendRequest(MatrixRequestDataFriendRequest),
+        // all fields are optional, therefore, it must be the last one
+        Page(MatrixRequestDataPage),
+    }
+}
+
+impl MatrixRequestDataField {
+    /// check fields which parse but cannot be right, e.g. empty ids. The error names the field
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            MatrixRequestDataField::Query(q) => q.validate(),
+            MatrixRequestDataField::Message(m) => m.validate(),
+            MatrixRequestDataField::Ack(a) => a.validate(),
+            MatrixRequestDataField::Chat(c) => c.validate(),
+            MatrixRequestDataField::Replay(r) => r.validate(),
+            MatrixRequestDataField::Port(p) => p.validate(),
+            MatrixRequestDataField::Login(l) => l.validate(),
+            MatrixRequestDataField::Members(m) => m.validate(),
+            MatrixRequestDataField::Avatar(a) => a.validate(),
+            MatrixRequestDataField::Page(p) => p.validate(),
+            MatrixRequestDataField::History(h) => h.validate(),
+            MatrixRequestDataField::FriendRequest(f) => f.validate(),
+        }
+    }
+}
+
+///
+/// data of a command. `SHAPE` tells the bridge what is expected when the data does not fit
+///
+pub trait RequestData: serde::de::DeserializeOwned {
+    const SHAPE: &'static str;
+
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn non_empty(field: &str, value: &str) -> Result<(), String> {
+    match value.trim().is_empty() {
+        true => Err(format!("field `{}` is empty", field)),
+        false => Ok(()),
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -25,6 +172,160 @@ pub struct MatrixRequestDataQuery {
     pub wechat_id: String,
     #[serde(rename(deserialize = "groupId"))]
     pub group_id: String,
+    // read the info again instead of answering from the cache
+    #[serde(default, rename(deserialize = "forceRefresh"))]
+    pub force_refresh: bool,
+}
+
+impl RequestData for MatrixRequestDataQuery {
+    const SHAPE: &'static str = "{\"wxId\": string, \"groupId\": string, \"forceRefresh\"?: bool}";
+
+    // commands take either of them, or both
+    fn validate(&self) -> Result<(), String> {
+        match self.wechat_id.trim().is_empty() && self.group_id.trim().is_empty() {
+            true => Err("fields `wxId` and `groupId` are both empty".to_string()),
+            false => Ok(()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataAck {
+    pub seq: u64,
+}
+
+impl RequestData for MatrixRequestDataAck {
+    const SHAPE: &'static str = "{\"seq\": number}";
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataChat {
+    #[serde(rename(deserialize = "chatId"))]
+    pub chat_id: String,
+}
+
+impl RequestData for MatrixRequestDataChat {
+    const SHAPE: &'static str = "{\"chatId\": string}";
+
+    fn validate(&self) -> Result<(), String> {
+        non_empty("chatId", &self.chat_id)
+    }
+}
+
+// a message of a chat, e.g. an old one to fetch the media of
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataHistory {
+    #[serde(rename(deserialize = "chatId"))]
+    pub chat_id: String,
+    #[serde(rename(deserialize = "msgId"))]
+    pub msg_id: u64,
+}
+
+impl RequestData for MatrixRequestDataHistory {
+    const SHAPE: &'static str = "{\"chatId\": string, \"msgId\": number}";
+
+    fn validate(&self) -> Result<(), String> {
+        non_empty("chatId", &self.chat_id)
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataPort {
+    pub port: u32,
+}
+
+impl RequestData for MatrixRequestDataPort {
+    const SHAPE: &'static str = "{\"port\": number}";
+
+    fn validate(&self) -> Result<(), String> {
+        match self.port {
+            1..=65535 => Ok(()),
+            port => Err(format!("field `port` is out of range: {}", port)),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataLogin {
+    // push refreshed qr codes as events until logged in
+    pub poll: bool,
+}
+
+impl RequestData for MatrixRequestDataLogin {
+    const SHAPE: &'static str = "{\"poll\": bool}";
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataMembers {
+    #[serde(rename(deserialize = "wxIds"))]
+    pub wechat_ids: Vec<String>,
+}
+
+impl RequestData for MatrixRequestDataMembers {
+    const SHAPE: &'static str = "{\"wxIds\": [string]}";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.wechat_ids.is_empty() {
+            return Err("field `wxIds` is empty".to_string());
+        }
+        match self.wechat_ids.iter().position(|id| id.trim().is_empty()) {
+            Some(i) => Err(format!("field `wxIds[{}]` is empty", i)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataAvatar {
+    pub url: String,
+}
+
+impl RequestData for MatrixRequestDataAvatar {
+    const SHAPE: &'static str = "{\"url\": string}";
+
+    fn validate(&self) -> Result<(), String> {
+        non_empty("url", &self.url)
+    }
+}
+
+// tickets in the verify message of a friend request
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataFriendRequest {
+    pub v3: String,
+    pub v4: String,
+}
+
+impl RequestData for MatrixRequestDataFriendRequest {
+    const SHAPE: &'static str = "{\"v3\": string, \"v4\": string}";
+
+    fn validate(&self) -> Result<(), String> {
+        non_empty("v3", &self.v3)?;
+        non_empty("v4", &self.v4)
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataPage {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+impl RequestData for MatrixRequestDataPage {
+    const SHAPE: &'static str = "{\"offset\"?: number, \"limit\"?: number}";
+}
+
+// a raw wechat callback line captured from log
+#[derive(serde::Deserialize, Debug)]
+pub struct MatrixRequestDataReplay {
+    pub line: String,
+}
+
+impl RequestData for MatrixRequestDataReplay {
+    const SHAPE: &'static str = "{\"line\": string}";
+
+    fn validate(&self) -> Result<(), String> {
+        non_empty("line", &self.line)
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -34,6 +335,21 @@ pub struct MatrixRequestDataMessage {
     pub message_type: MatrixMessageType,
     pub content: String,
     pub data: Option<MatrixMessageDataField>,
+    // a resent message with the same key is answered with the result of the first one
+    #[serde(rename(deserialize = "idempotencyKey"))]
+    pub idempotency_key: Option<String>,
+}
+
+impl RequestData for MatrixRequestDataMessage {
+    const SHAPE: &'static str = "{\"target\": string, \"type\": string, \"content\": string, \"data\"?: object, \"idempotencyKey\"?: string}";
+
+    fn validate(&self) -> Result<(), String> {
+        non_empty("target", &self.target)?;
+        match &self.idempotency_key {
+            Some(key) => non_empty("idempotencyKey", key),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -55,4 +371,116 @@ pub enum MatrixMessageType {
     Audio,
     #[serde(rename = "m.file")]
     File,
+    #[serde(rename = "m.contact")]
+    Contact,
+    // types of newer bridges. They are rejected as unsupported instead of failing the whole command
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(command: &str, data: serde_json::Value) -> String {
+        let raw = serde_json::json!({"mxid": "@a:x", "req": 1, "command": command, "data": data});
+        let msg = WebsocketMatrixRequest::parse(&raw.to_string()).unwrap();
+        msg.data.unwrap().validate().unwrap_err()
+    }
+
+    fn data(value: serde_json::Value) -> MatrixRequestDataField {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn kind_picks_the_variant_which_shape_would_miss() {
+        let history = serde_json::json!({"chatId": "wxid_a", "msgId": 7});
+        assert!(
+            matches!(data(history.clone()), MatrixRequestDataField::History(h) if h.msg_id == 7)
+        );
+
+        let mut chat = history;
+        chat["kind"] = "chat".into();
+        assert!(matches!(data(chat), MatrixRequestDataField::Chat(c) if c.chat_id == "wxid_a"));
+    }
+
+    #[test]
+    fn untagged_data_is_matched_by_shape() {
+        let request = serde_json::json!({"v3": "v3_a", "v4": "v4_a"});
+        assert!(
+            matches!(data(request), MatrixRequestDataField::FriendRequest(f) if f.v4 == "v4_a")
+        );
+
+        let tagged = serde_json::json!({"kind": "friend_request", "v3": "v3_a", "v4": "v4_a"});
+        assert!(matches!(data(tagged), MatrixRequestDataField::FriendRequest(f) if f.v3 == "v3_a"));
+
+        assert!(matches!(
+            data(serde_json::json!({})),
+            MatrixRequestDataField::Page(MatrixRequestDataPage {
+                offset: None,
+                limit: None
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_kind_or_mismatched_tagged_data_fails() {
+        let unknown = serde_json::json!({"kind": "nope", "chatId": "wxid_a"});
+        assert!(serde_json::from_value::<MatrixRequestDataField>(unknown).is_err());
+
+        let mismatched = serde_json::json!({"kind": "port", "chatId": "wxid_a"});
+        assert!(serde_json::from_value::<MatrixRequestDataField>(mismatched).is_err());
+    }
+
+    #[test]
+    fn invalid_data_names_the_field() {
+        assert_eq!(
+            invalid(
+                "send_message",
+                serde_json::json!({"target": " ", "type": "m.text", "content": "hi"})
+            ),
+            "field `target` is empty"
+        );
+        assert_eq!(
+            invalid(
+                "get_user_info",
+                serde_json::json!({"wxId": "", "groupId": ""})
+            ),
+            "fields `wxId` and `groupId` are both empty"
+        );
+        assert_eq!(
+            invalid(
+                "batch_get_user_info",
+                serde_json::json!({"wxIds": ["wxid_a", ""]})
+            ),
+            "field `wxIds[1]` is empty"
+        );
+        assert_eq!(
+            invalid("create_group", serde_json::json!({"wxIds": []})),
+            "field `wxIds` is empty"
+        );
+        assert_eq!(
+            invalid(
+                "get_group_member_nicknames",
+                serde_json::json!({"chatId": ""})
+            ),
+            "field `chatId` is empty"
+        );
+        assert_eq!(
+            invalid("connect", serde_json::json!({"port": 70000})),
+            "field `port` is out of range: 70000"
+        );
+    }
+
+    #[test]
+    fn valid_data_passes() {
+        let raw = serde_json::json!({
+            "mxid": "@a:x",
+            "req": 1,
+            "command": "send_message",
+            "data": {"target": "wxid_a", "type": "m.text", "content": "hi", "idempotencyKey": "k"},
+        });
+        let msg = WebsocketMatrixRequest::parse(&raw.to_string()).unwrap();
+        assert!(msg.data.unwrap().validate().is_ok());
+    }
 }
@@ -5,13 +5,14 @@ use serde_with::TimestampMilliSeconds;
 use ::chrono::{DateTime, Utc};
 
 use super::CommandType;
+use crate::error::Error;
 
 #[derive(serde::Serialize)]
 #[serde_with::serde_as]
 #[serde(untagged)]
 pub enum WebsocketMessage<T: Serialize> {
     Command(WebsocketCommand<T>),
-    Event(WebsocketEvent<T>),
+    Event(Box<WebsocketEvent<T>>),
 }
 
 impl<T: Serialize> WebsocketMessage<T> {
@@ -33,8 +34,97 @@ pub struct WebsocketCommand<T: Serialize> {
     pub data: T,
 }
 
+///
+/// response of commands on many items. An item which fails does not fail the others, so that the
+/// bridge can retry only the failed ids
+///
+#[derive(Serialize, Debug)]
+pub struct BatchResult<T: Serialize> {
+    pub succeeded: Vec<T>,
+    pub failed: Vec<BatchFailure>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchFailure {
+    pub id: String,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl<T: Serialize> BatchResult<T> {
+    pub fn new() -> Self {
+        BatchResult {
+            succeeded: vec![],
+            failed: vec![],
+        }
+    }
+
+    /// errors of the instance itself are returned instead since the rest would fail the same way
+    pub fn record(&mut self, id: String, result: Result<T, Error>) -> Result<(), Error> {
+        match result {
+            Ok(item) => {
+                self.succeeded.push(item);
+                Ok(())
+            }
+            Err(e) => self.record_all(vec![id], e),
+        }
+    }
+
+    /// fail ids with one error, e.g. of a query shared by them
+    pub fn record_all(&mut self, ids: Vec<String>, e: Error) -> Result<(), Error> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        match e.code() {
+            ErrorCode::HookUnreachable | ErrorCode::InstanceNotFound | ErrorCode::NotLoggedIn => {
+                Err(e)
+            }
+            code => {
+                let message = e.to_string();
+                self.failed.extend(ids.into_iter().map(|id| BatchFailure {
+                    id,
+                    code,
+                    message: message.clone(),
+                }));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: Serialize> Default for BatchResult<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    #[serde(rename = "internal")]
+    Internal,
+    #[serde(rename = "hook_unreachable")]
+    HookUnreachable,
+    #[serde(rename = "hook_failed")]
+    HookFailed,
+    #[serde(rename = "instance_not_found")]
+    InstanceNotFound,
+    #[serde(rename = "not_logged_in")]
+    NotLoggedIn,
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "invalid_request")]
+    InvalidRequest,
+    #[serde(rename = "too_large")]
+    TooLarge,
+    #[serde(rename = "unsupported")]
+    Unsupported,
+    // the command does not finish in time. It may have taken effect partially
+    #[serde(rename = "timeout")]
+    Timeout,
+}
+
 #[serde_with::serde_as]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, Debug)]
 pub struct WebsocketEvent<T: Serialize> {
     #[serde(flatten)]
     pub base: WebsocketEventBase,
@@ -42,28 +132,76 @@ pub struct WebsocketEvent<T: Serialize> {
 }
 
 #[serde_with::serde_as]
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, Debug)]
 pub struct WebsocketEventBase {
     pub mxid: String,
     pub id: u64,
+    // assigned when the event is written to websocket channel
+    pub seq: u64,
     #[serde(rename = "type")]
     pub event_type: EventType,
     #[serde_as(as = "TimestampMilliSeconds<i64, Flexible>")]
     #[serde(rename = "ts")]
     pub timestamp: DateTime<Utc>,
+    // display timezone configured by operator
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tz: Option<String>,
     pub sender: String,
+    // display name of the sender in group chats
+    #[serde(rename = "senderName", skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
     pub target: String,
     pub content: String,
     pub reply: Option<ReplyInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoke: Option<RevokeInfo>,
+    // raw wechat message which cannot be parsed. just for debugging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
 }
 
-#[derive(serde::Serialize)]
+impl<T: Serialize> WebsocketEvent<T> {
+    /// event generated by agent itself instead of a wechat message
+    pub fn agent(mxid: String, event_type: EventType, content: String, extra: Option<T>) -> Self {
+        WebsocketEvent {
+            base: WebsocketEventBase {
+                mxid,
+                id: 0,
+                seq: 0,
+                event_type,
+                timestamp: Utc::now(),
+                tz: None,
+                sender: String::new(),
+                sender_name: None,
+                target: String::new(),
+                content,
+                reply: None,
+                revoke: None,
+                raw: None,
+            },
+            extra,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
 pub struct ReplyInfo {
     pub id: u64,
     pub sender: String,
+    // descriptor like "[image]" if the quoted message is media
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+}
+
+// the recalled message. event_type is None when the original event is not in the recent event cache
+#[derive(serde::Serialize, Debug)]
+pub struct RevokeInfo {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub event_type: Option<EventType>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde_with::serde_as]
 pub enum EventType {
     #[serde(rename = "m.text")]
@@ -88,4 +226,31 @@ pub enum EventType {
     VoIP,
     #[serde(rename = "m.system")]
     System,
+    // problems of agent which need operator's attention
+    #[serde(rename = "m.alert")]
+    Alert,
+    // a new login qr code is generated
+    #[serde(rename = "m.login_qr")]
+    LoginQRCode,
+    #[serde(rename = "m.login")]
+    Login,
+    // nobody scanned the login qr code in time
+    #[serde(rename = "m.login_timeout")]
+    LoginTimeout,
+    // instance is disconnected or exited before login
+    #[serde(rename = "m.login_cancelled")]
+    LoginCancelled,
+    // wechat is forced offline by another device login or risk control
+    #[serde(rename = "m.logout")]
+    Logout,
+    // members of a watched group changed. sender is the member and target is the group
+    #[serde(rename = "m.member_join")]
+    MemberJoin,
+    #[serde(rename = "m.member_leave")]
+    MemberLeave,
+    // contacts of the account changed. extra is the user info of the contact
+    #[serde(rename = "m.friend_add")]
+    FriendAdd,
+    #[serde(rename = "m.friend_remove")]
+    FriendRemove,
 }